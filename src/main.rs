@@ -1,14 +1,30 @@
 use std::io::prelude::*;
 
 extern crate anyhow;
+extern crate chrono;
 extern crate clap;
+extern crate clap_complete;
+extern crate uuid;
 extern crate xml;
 
-use anyhow::{anyhow, bail, Result};
-use clap::{Arg, Command};
+use anyhow::{anyhow, bail, Context as _, Result};
+use clap::{Arg, ArgMatches, Command};
+use clap_complete::Shell;
 use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{BufRead, Cursor};
+use std::rc::Rc;
 use std::str::FromStr;
-use xml::reader::{EventReader, XmlEvent};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use unicode_normalization::UnicodeNormalization;
+use xml::attribute::OwnedAttribute;
+use xml::common::{Position, TextPosition};
+use xml::name::OwnedName;
+use xml::namespace::Namespace;
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
 
 #[cfg(test)]
 mod tests;
@@ -21,14 +37,602 @@ enum Action {
 
     ParentAttribute(usize, String, Filters),
     ParentAttributeWithDefault(usize, String, String, Filters),
+
+    /// The current time, with this strftime format
+    Now(String),
+
+    /// The name of the current input file, as given, or just the last path component
+    Filename,
+    Basename,
+
+    /// A new random UUID
+    Uuid(UuidVersion),
+
+    /// Byte offset of the element's start tag
+    Offset,
+
+    /// The element's position among its siblings, or among its siblings with the same name,
+    /// starting at 1
+    SiblingIndex(bool),
+
+    /// How often this action has happened (starting at 1) in this document, or since the last
+    /// time this element started
+    Counter(Option<String>, Cell<u64>),
+
+    /// How often the first `-s TAG` instruction matched in this document. Only for `-E`
+    Matched(String, Cell<u64>),
+
+    /// The data of the processing instruction
+    PiData,
+
+    /// The text of the comment
+    CommentText,
+
+    /// The text, for `--chars`
+    Text(Filters),
+
+    /// The root element name of the DOCTYPE
+    DoctypeName,
+    /// The public ID of the DOCTYPE, or nothing
+    PublicId,
+    /// The system ID of the DOCTYPE, or nothing
+    SystemId,
+
+    /// Change what happens when an attribute is missing, for the rest of the actions
+    OnMissingAttr(MissingAttrPolicy),
+
+    /// Outputs nothing, but the previous action's output is this field, for `--json-record`
+    Name(String),
+
+    /// Outputs nothing, but the instruction's output is counted rather than written, & the
+    /// counts for each distinct output are written at the end of the document
+    GroupBy,
+
+    /// Outputs nothing, but the event only happens for elements without child elements. `-s`
+    /// happen at the end tag instead
+    LeafOnly,
+    /// Outputs nothing, but the event only happens for self-closing elements (`<a/>`)
+    EmptyOnly,
+    /// Outputs nothing, but the event only happens if the element's text matches, from
+    /// `-s 'TAG{text~REGEX}'`. `-s` happen at the end tag instead
+    TextMatches(TextPattern),
+
+    /// Outputs nothing, but this (parent) attribute is a number which is aggregated, for each
+    /// group, or for all events
+    Aggregate(Reducer, usize, String, Filters),
+
+    /// This attribute of these child elements, joined with the separator. Only for `-e`
+    Collect(String, String, String, Filters),
+
+    /// A JSON array of this attribute of these child elements, or of objects with all their
+    /// attributes. Only for `-e`
+    CollectJson(String, Option<String>),
+
+    /// A PostgreSQL hstore of the key & value attributes of these child elements. Only for `-e`
+    CollectHstore(String, String, String, Filters),
+
+    /// A JSON object of the key & value attributes of these child elements. Only for `-e`
+    CollectJsonObject(String, String, String, Filters),
+
+    /// Outputs nothing, but the record is skipped if the (parent) element doesn't have this
+    /// attribute
+    Require(usize, String),
+
+    /// Outputs nothing, but the record is skipped unless an ancestor element matches
+    Ancestor(ElementPredicate),
+
+    /// Outputs nothing, but the event only happens for elements in this namespace, whatever
+    /// prefix it has
+    NamespaceUri(String),
+
+    /// Outputs nothing, but the event only happens for elements which this path selects, from `-x`
+    XPath(XPath),
+
+    /// The element, & everything inside it, as XML. `-s` happen at the end tag instead
+    Copy,
+    /// The element, & everything inside it, as a JSON object. `-s` happen at the end tag instead
+    CopyJson,
+
+    /// Whether the (parent) element has this attribute: the first string if it does, the second
+    /// if not
+    Has(usize, String, String, String),
+
+    /// The (parent) attribute, between the prefix & suffix, or nothing at all if it's missing
+    OptionalAttribute(usize, String, String, String, Filters),
+
+    /// A point from the (parent) attributes with the longitude (x) & latitude (y)
+    Point(GeometryFormat, (usize, String), (usize, String)),
 }
 
 impl Action {
     fn is_parent_attr(&self) -> bool {
-        matches!(
-            self,
-            Action::ParentAttribute(_, _, _) | Action::ParentAttributeWithDefault(_, _, _, _)
-        )
+        match self {
+            Action::ParentAttribute(_, _, _)
+            | Action::ParentAttributeWithDefault(_, _, _, _)
+            | Action::Ancestor(_)
+            | Action::XPath(_) => true,
+            Action::Aggregate(_, level, _, _)
+            | Action::Require(level, _)
+            | Action::Has(level, _, _, _)
+            | Action::OptionalAttribute(level, _, _, _, _) => *level > 0,
+            Action::Point(_, (x_level, _), (y_level, _)) => *x_level > 0 || *y_level > 0,
+            _ => false,
+        }
+    }
+}
+
+/// A regex which the text of an element must match
+#[derive(Debug)]
+struct TextPattern(regex::Regex);
+
+impl PartialEq for TextPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for TextPattern {}
+
+/// A tag & conditions on its attributes, like `way[visible=true]` for `--ancestor`, or a step
+/// of `-x`
+#[derive(Debug, PartialEq, Eq)]
+struct ElementPredicate {
+    /// The tag name, or `*` for any element
+    tag: String,
+    /// Attributes it must have, & the value they must have, if there is one
+    conditions: Vec<(String, Option<String>)>,
+}
+
+impl FromStr for ElementPredicate {
+    type Err = anyhow::Error;
+    /// Parse `TAG`, `TAG[ATTR]`, `TAG[ATTR=VALUE]` or `TAG[ATTR="VALUE"]`, with any number of
+    /// `[…]` conditions
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tag, mut rest) = s.split_at(s.find('[').unwrap_or(s.len()));
+        if tag.is_empty() {
+            bail!("Invalid --ancestor {:?}, expected TAG[ATTRIBUTE=VALUE]", s);
+        }
+        let mut conditions = vec![];
+        while !rest.is_empty() {
+            let (condition, after) = rest
+                .strip_prefix('[')
+                .and_then(|r| r.split_once(']'))
+                .ok_or_else(|| {
+                    anyhow!("Invalid --ancestor {:?}, expected TAG[ATTRIBUTE=VALUE]", s)
+                })?;
+            let condition = match condition.split_once('=') {
+                None => (condition.to_string(), None),
+                Some((attr, value)) => (attr.to_string(), Some(unquote(value).to_string())),
+            };
+            if condition.0.is_empty() {
+                bail!("Invalid --ancestor {:?}, missing the attribute name", s);
+            }
+            conditions.push(condition);
+            rest = after;
+        }
+        Ok(ElementPredicate {
+            tag: tag.to_string(),
+            conditions,
+        })
+    }
+}
+
+impl ElementPredicate {
+    /// With `--ignore-tag-case`, the tag is lowercased, to compare with the lowercased `self.tag`
+    fn matches(&self, tag: &str, attributes: &[OwnedAttribute], config: &Config) -> bool {
+        (self.tag == "*" || self.tag == tag_key(tag, config.ignore_tag_case))
+            && self.conditions.iter().all(|(attr, value)| {
+                attributes.iter().any(|a| {
+                    attr_name_matches(&a.name, attr, config.ignore_attr_case)
+                        && value.as_ref().is_none_or(|v| &a.value == v)
+                })
+            })
+    }
+}
+
+/// Remove the quotes around `"value"` or `'value'`, if there are any
+fn unquote(value: &str) -> &str {
+    ['"', '\'']
+        .iter()
+        .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+        .unwrap_or(value)
+}
+
+/// The streamable subset of XPath which `-x` takes, like `//way[@visible="true"]/nd[1]`. The
+/// child (`/`) & descendant (`//`) axes, attribute (`[@k]`, `[@k="v"]`) & positional (`[1]`)
+/// predicates. A path which doesn't start with `/` is the same as one starting with `//`
+#[derive(Debug, PartialEq, Eq)]
+struct XPath {
+    /// As it was given, for messages
+    source: String,
+    steps: Vec<XPathStep>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct XPathStep {
+    /// After a `//`, so this can be any descendant of the previous step, not just a child
+    descendant: bool,
+    element: ElementPredicate,
+    /// The position among its siblings with the same name (or all of them, for `*`), from 1
+    position: Option<u64>,
+}
+
+impl FromStr for XPath {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| anyhow!("Invalid XPath {:?}, {}", s, why);
+        let mut steps = vec![];
+        let mut rest = s;
+        let mut descendant = !s.starts_with('/');
+        loop {
+            if let Some(r) = rest.strip_prefix("//") {
+                descendant = true;
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix('/') {
+                rest = r;
+            }
+            // The step ends at the next `/` which isn't in a predicate
+            let mut end = rest.len();
+            let mut in_predicate = false;
+            let mut quote = None;
+            for (i, c) in rest.char_indices() {
+                match (c, quote) {
+                    (_, Some(q)) if c == q => quote = None,
+                    (_, Some(_)) => {}
+                    ('"' | '\'', None) if in_predicate => quote = Some(c),
+                    ('[', None) => in_predicate = true,
+                    (']', None) => in_predicate = false,
+                    ('/', None) if !in_predicate => {
+                        end = i;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            let (step, after) = rest.split_at(end);
+            let (tag, mut predicates) = step.split_at(step.find('[').unwrap_or(step.len()));
+            if tag.is_empty() {
+                return Err(invalid("expected a tag name or * in every step"));
+            }
+            if tag.contains(['@', '(', ':', '.']) {
+                return Err(invalid(
+                    "only element names & * are supported, not attributes, functions, axes, namespace prefixes or . & ..",
+                ));
+            }
+            let mut step = XPathStep {
+                descendant,
+                element: ElementPredicate {
+                    tag: tag.to_string(),
+                    conditions: vec![],
+                },
+                position: None,
+            };
+            while !predicates.is_empty() {
+                let (predicate, after) = predicates
+                    .strip_prefix('[')
+                    .and_then(|p| {
+                        // Like the step, a quoted `]` doesn't end the predicate
+                        let mut quote = None;
+                        let end = p.char_indices().find_map(|(i, c)| {
+                            match (c, quote) {
+                                (_, Some(q)) if c == q => quote = None,
+                                (_, Some(_)) => {}
+                                ('"' | '\'', None) => quote = Some(c),
+                                (']', None) => return Some(i),
+                                _ => {}
+                            }
+                            None
+                        })?;
+                        Some((&p[..end], &p[end + 1..]))
+                    })
+                    .ok_or_else(|| invalid("a predicate isn't closed with ]"))?;
+                if let Some(attr) = predicate.strip_prefix('@') {
+                    let condition = match attr.split_once('=') {
+                        None => (attr.trim().to_string(), None),
+                        Some((attr, value)) => (
+                            attr.trim().to_string(),
+                            Some(unquote(value.trim()).to_string()),
+                        ),
+                    };
+                    if condition.0.is_empty() {
+                        return Err(invalid("missing the attribute name in a predicate"));
+                    }
+                    step.element.conditions.push(condition);
+                } else {
+                    let position = predicate
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|p| *p > 0)
+                        .ok_or_else(|| {
+                            invalid(
+                                "only [@ATTRIBUTE], [@ATTRIBUTE=\"VALUE\"] & [POSITION] predicates are supported",
+                            )
+                        })?;
+                    if step.position.replace(position).is_some() {
+                        return Err(invalid("a step can only have one position"));
+                    }
+                }
+                predicates = after;
+            }
+            steps.push(step);
+            if after.is_empty() {
+                break;
+            }
+            rest = after;
+            descendant = false;
+        }
+        Ok(XPath {
+            source: s.to_string(),
+            steps,
+        })
+    }
+}
+
+/// The tag, attributes & sibling index of an open element, for matching `-x`
+type XPathElement<'a> = (&'a str, &'a [OwnedAttribute], Option<(u64, u64)>);
+
+impl XPath {
+    /// The tag name of the element which this selects, which can be `*`
+    fn tag(&self) -> &str {
+        &self.steps.last().unwrap().element.tag
+    }
+
+    /// Are sibling indices needed?
+    fn uses_positions(&self) -> bool {
+        self.steps.iter().any(|s| s.position.is_some())
+    }
+
+    /// Does this select the current element of a `-s`/`-e` event?
+    fn matches(&self, ctx: &Context, config: &Config) -> bool {
+        let (tag, attributes) = match ctx.element {
+            Some(element) => element,
+            None => return false,
+        };
+        let mut elements: Vec<XPathElement> = ctx
+            .parent_tags
+            .iter()
+            .zip(ctx.parent_attrs)
+            .enumerate()
+            .map(|(i, (tag, attributes))| {
+                (
+                    &**tag,
+                    attributes.as_slice(),
+                    ctx.parent_sibling_indices.get(i).copied(),
+                )
+            })
+            .collect();
+        elements.push((tag, attributes, ctx.sibling_index));
+        Self::matches_steps(&self.steps, &elements, config)
+    }
+
+    /// Does the last step match the last element, & the rest of the steps its ancestors?
+    fn matches_steps(steps: &[XPathStep], elements: &[XPathElement], config: &Config) -> bool {
+        let ((step, steps), ((tag, attributes, sibling_index), ancestors)) =
+            match (steps.split_last(), elements.split_last()) {
+                (Some(step), Some(element)) => (step, element),
+                _ => return false,
+            };
+        let position_matches = step.position.is_none_or(|position| {
+            sibling_index.is_some_and(|(all, same_name)| {
+                position
+                    == if step.element.tag == "*" {
+                        all
+                    } else {
+                        same_name
+                    }
+            })
+        });
+        if !position_matches || !step.element.matches(tag, attributes, config) {
+            return false;
+        }
+        if steps.is_empty() {
+            // An absolute path has to start at the root element
+            step.descendant || ancestors.is_empty()
+        } else if step.descendant {
+            (1..=ancestors.len()).any(|n| Self::matches_steps(steps, &ancestors[..n], config))
+        } else {
+            Self::matches_steps(steps, ancestors, config)
+        }
+    }
+}
+
+/// How `--aggregate` combines the numbers
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Reducer {
+    Sum,
+    Min,
+    Max,
+    Mean,
+}
+
+impl FromStr for Reducer {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(Reducer::Sum),
+            "min" => Ok(Reducer::Min),
+            "max" => Ok(Reducer::Max),
+            "mean" => Ok(Reducer::Mean),
+            x => anyhow::bail!("Unknown aggregate {}, expected sum, min, max or mean", x),
+        }
+    }
+}
+
+/// The running total etc. for one `--aggregate`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Aggregator {
+    reducer: Reducer,
+    /// How many numbers have been added
+    n: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Aggregator {
+    fn new(reducer: Reducer) -> Self {
+        Aggregator {
+            reducer,
+            n: 0,
+            sum: 0.,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.n += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// The result. Nothing for the min/max/mean of no numbers
+    fn result(&self) -> Option<f64> {
+        match self.reducer {
+            Reducer::Sum => Some(self.sum),
+            _ if self.n == 0 => None,
+            Reducer::Min => Some(self.min),
+            Reducer::Max => Some(self.max),
+            Reducer::Mean => Some(self.sum / self.n as f64),
+        }
+    }
+}
+
+/// The events with the same output, for `--group-by` & `--aggregate`
+#[derive(Debug, Clone, PartialEq)]
+struct Group {
+    count: u64,
+    /// One for each `--aggregate`, in order
+    aggregates: Vec<Aggregator>,
+}
+
+/// The group for each distinct record
+type Groups = BTreeMap<Vec<u8>, Group>;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum UuidVersion {
+    /// Random
+    V4,
+    /// Timestamp first, then random, so they sort by creation time
+    V7,
+}
+
+/// How `--wkt-point`/`--wkb-point` output a point
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum GeometryFormat {
+    /// Well known text, `POINT(X Y)`
+    Wkt,
+    /// Well known binary, hex encoded, as PostGIS outputs it
+    Wkb,
+}
+
+impl GeometryFormat {
+    /// Write this point
+    fn write_point(&self, x: f64, y: f64, output: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            GeometryFormat::Wkt => write!(output, "POINT({} {})", x, y),
+            GeometryFormat::Wkb => {
+                // Little endian, geometry type 1 (point), then the coordinates
+                let mut wkb = vec![1u8];
+                wkb.extend_from_slice(&1u32.to_le_bytes());
+                wkb.extend_from_slice(&x.to_le_bytes());
+                wkb.extend_from_slice(&y.to_le_bytes());
+                for byte in wkb {
+                    write!(output, "{:02X}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What to do when an attribute from `-v` isn't present
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+enum MissingAttrPolicy {
+    /// Stop with an error
+    #[default]
+    Error,
+    /// Don't output anything for this instruction
+    SkipRecord,
+    /// Output an empty string
+    Empty,
+    /// Output an empty string & print a warning
+    Warn,
+}
+
+impl FromStr for MissingAttrPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(MissingAttrPolicy::Error),
+            "skip-record" => Ok(MissingAttrPolicy::SkipRecord),
+            "empty" => Ok(MissingAttrPolicy::Empty),
+            "warn" => Ok(MissingAttrPolicy::Warn),
+            x => anyhow::bail!("Unknown missing attribute policy {}", x),
+        }
+    }
+}
+
+impl MissingAttrPolicy {
+    /// Deal with `attr` missing from this element. Returns false if the record should be skipped
+    fn handle(
+        &self,
+        attr: &str,
+        tag: &str,
+        attributes: &[OwnedAttribute],
+        ctx: &Context,
+        summary: &mut Summary,
+    ) -> Result<bool> {
+        let missing = || format!("No attribute {} found for element {}", attr, tag);
+        match self {
+            MissingAttrPolicy::Error | MissingAttrPolicy::SkipRecord
+                if summary.reject(ctx, missing)? =>
+            {
+                Ok(false)
+            }
+            MissingAttrPolicy::Error => Err(ctx.locate(missing_attr_error(attributes, attr, tag))),
+            MissingAttrPolicy::SkipRecord => Ok(false),
+            MissingAttrPolicy::Empty => Ok(true),
+            MissingAttrPolicy::Warn => {
+                summary.warn(missing(), ctx.location());
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// What to do when an element has more than one attribute with the same (local) name, e.g. `x`
+/// & `p:x`
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+enum DuplicateAttrPolicy {
+    /// Use the first one
+    #[default]
+    First,
+    /// Use the last one
+    Last,
+    /// Stop with an error
+    Error,
+    /// Join all the values with this separator
+    Join(String),
+}
+
+impl FromStr for DuplicateAttrPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(DuplicateAttrPolicy::First),
+            "last" => Ok(DuplicateAttrPolicy::Last),
+            "error" => Ok(DuplicateAttrPolicy::Error),
+            x => match x.strip_prefix("join:") {
+                Some(sep) => Ok(DuplicateAttrPolicy::Join(sep.to_string())),
+                None => anyhow::bail!(
+                    "Unknown duplicate attribute policy {}, expected first, last, error or join:SEP",
+                    x
+                ),
+            },
+        }
     }
 }
 
@@ -39,6 +643,112 @@ enum TextFilter {
     UnixEscape,
 
     TSVEscape,
+
+    /// For PostgreSQL's COPY text format, which has backslash escapes
+    PgCopyEscape,
+
+    /// Numbers written the way this locale writes them (e.g. `1.234,5`) become plain decimals
+    /// (`1234.5`)
+    Decimal(NumberFormat),
+
+    /// Unicode normalisation forms
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+
+    /// Remove the C0 & C1 control characters (including tabs & new lines), or replace each run
+    /// of them with this
+    StripControl(String),
+
+    /// Transliterate to ASCII (`é` → `e`, `ß` → `ss`), removing what can't be
+    Ascii,
+    /// Remove everything which isn't ASCII
+    AsciiStrip,
+}
+
+impl TextFilter {
+    /// All the names a filter can be given on the command line, used for shell completion
+    const NAMES: &'static [&'static str] = &[
+        "nothing",
+        "none",
+        "unix",
+        "tsv",
+        "pgcopy",
+        "decimal",
+        "nfc",
+        "nfd",
+        "nfkc",
+        "nfkd",
+        "strip-ctrl",
+        "ascii",
+        "ascii-strip",
+    ];
+}
+
+/// How a locale writes numbers, for `!decimal`
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+struct NumberFormat {
+    /// Between the whole number & the fraction
+    decimal: char,
+    /// Between the groups of thousands
+    groups: &'static [char],
+}
+
+impl NumberFormat {
+    /// The number format of this language (or language-country) code, e.g. `de` or `de-CH`
+    fn for_locale(locale: &str) -> Option<Self> {
+        const SPACES: &[char] = &[' ', '\u{a0}', '\u{202f}'];
+        let (decimal, groups) = match locale.to_ascii_lowercase().replace('_', "-").as_str() {
+            "de-ch" | "ch" => ('.', &['\'', '’'][..]),
+            "de" | "nl" | "es" | "it" | "pt" | "da" | "id" | "tr" | "el" | "ro" | "hr" | "sl" => {
+                (',', &['.'][..])
+            }
+            "fr" | "sv" | "nb" | "no" | "fi" | "pl" | "cs" | "sk" | "ru" | "uk" | "hu" | "et"
+            | "lv" | "lt" => (',', SPACES),
+            "en" | "ja" | "zh" | "ko" | "he" | "th" => ('.', &[','][..]),
+            _ => return None,
+        };
+        Some(NumberFormat { decimal, groups })
+    }
+
+    /// Turn this number into a plain decimal, or `None` if it isn't a number in this format
+    fn normalise(&self, s: &str) -> Option<String> {
+        let s = s.trim();
+        let (sign, digits) = match s.strip_prefix(['-', '+']) {
+            Some(rest) => (&s[..1], rest),
+            None => ("", s),
+        };
+        let (whole, fraction) = match digits.split_once(self.decimal) {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (digits, None),
+        };
+        if whole.is_empty() || !whole.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        // Group separators are only between groups of 3 digits, so e.g. `12.34` isn't 1234
+        let grouped = whole.contains(self.groups);
+        let mut number = String::with_capacity(s.len());
+        number.push_str(sign);
+        for (i, group) in whole.split(self.groups).enumerate() {
+            let size_ok = match i {
+                0 => !group.is_empty() && (!grouped || group.len() <= 3),
+                _ => group.len() == 3,
+            };
+            if !size_ok || !group.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            number.push_str(group);
+        }
+        if let Some(fraction) = fraction {
+            if fraction.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            number.push('.');
+            number.push_str(fraction);
+        }
+        Some(number)
+    }
 }
 
 impl FromStr for TextFilter {
@@ -48,6 +758,25 @@ impl FromStr for TextFilter {
             "nothing" | "none" => Ok(TextFilter::Nothing),
             "unix" => Ok(TextFilter::UnixEscape),
             "tsv" => Ok(TextFilter::TSVEscape),
+            "pgcopy" => Ok(TextFilter::PgCopyEscape),
+            "decimal" => Ok(TextFilter::Decimal(NumberFormat::for_locale("de").unwrap())),
+            "nfc" => Ok(TextFilter::Nfc),
+            "nfd" => Ok(TextFilter::Nfd),
+            "nfkc" => Ok(TextFilter::Nfkc),
+            "nfkd" => Ok(TextFilter::Nfkd),
+            "strip-ctrl" => Ok(TextFilter::StripControl(String::new())),
+            "ascii" => Ok(TextFilter::Ascii),
+            "ascii-strip" => Ok(TextFilter::AsciiStrip),
+            x if x.starts_with("strip-ctrl:") => Ok(TextFilter::StripControl(
+                x["strip-ctrl:".len()..].to_string(),
+            )),
+            x if x.starts_with("decimal:") => {
+                let locale = &x["decimal:".len()..];
+                match NumberFormat::for_locale(locale) {
+                    Some(format) => Ok(TextFilter::Decimal(format)),
+                    None => anyhow::bail!("Unknown locale {} for the decimal filter, e.g. decimal:de, decimal:fr, decimal:de-CH or decimal:en (decimal on its own is decimal:de)", locale),
+                }
+            }
 
             x => anyhow::bail!("Unknown filter {}", x),
         }
@@ -75,8 +804,95 @@ impl TextFilter {
                     s
                 }
             }
+            TextFilter::PgCopyEscape => {
+                if s.chars()
+                    .any(|c| c == '\n' || c == '\t' || c == '\r' || c == '\\')
+                {
+                    let new_s = s
+                        .replace('\\', "\\\\")
+                        .replace('\n', "\\n")
+                        .replace('\t', "\\t")
+                        .replace('\r', "\\r");
+                    Cow::Owned(new_s)
+                } else {
+                    s
+                }
+            }
+            TextFilter::Decimal(format) => match format.normalise(&s) {
+                Some(number) if number != s => Cow::Owned(number),
+                _ => s,
+            },
+            // Most text is already normalised, so check before copying
+            TextFilter::Nfc if unicode_normalization::is_nfc(&s) => s,
+            TextFilter::Nfc => Cow::Owned(s.nfc().collect()),
+            TextFilter::Nfd if unicode_normalization::is_nfd(&s) => s,
+            TextFilter::Nfd => Cow::Owned(s.nfd().collect()),
+            TextFilter::Nfkc if unicode_normalization::is_nfkc(&s) => s,
+            TextFilter::Nfkc => Cow::Owned(s.nfkc().collect()),
+            TextFilter::Nfkd if unicode_normalization::is_nfkd(&s) => s,
+            TextFilter::Nfkd => Cow::Owned(s.nfkd().collect()),
+            TextFilter::StripControl(_) if !s.contains(char::is_control) => s,
+            TextFilter::StripControl(replacement) => {
+                let mut stripped = String::with_capacity(s.len());
+                let mut control = false;
+                for c in s.chars() {
+                    if !c.is_control() {
+                        stripped.push(c);
+                    } else if !control {
+                        stripped.push_str(replacement);
+                    }
+                    control = c.is_control();
+                }
+                Cow::Owned(stripped)
+            }
+            TextFilter::Ascii | TextFilter::AsciiStrip if s.is_ascii() => s,
+            TextFilter::Ascii => Cow::Owned(to_ascii(&s)),
+            TextFilter::AsciiStrip => Cow::Owned(s.chars().filter(char::is_ascii).collect()),
+        }
+    }
+}
+
+/// Best effort transliteration to ASCII, for `!ascii`. Accents etc. are removed by decomposing
+/// the characters (`é` → `e` + `◌́`), letters without a decomposition (e.g. `ß`, `ø`) & some
+/// punctuation have their usual ASCII spelling, & everything else is removed
+fn to_ascii(s: &str) -> String {
+    let mut ascii = String::with_capacity(s.len());
+    for c in s.nfkd() {
+        if c.is_ascii() {
+            ascii.push(c);
+            continue;
         }
+        ascii.push_str(match c {
+            'ß' => "ss",
+            'ẞ' => "SS",
+            'æ' => "ae",
+            'Æ' => "AE",
+            'œ' => "oe",
+            'Œ' => "OE",
+            'ø' => "o",
+            'Ø' => "O",
+            'ł' => "l",
+            'Ł' => "L",
+            'đ' | 'ð' => "d",
+            'Đ' | 'Ð' => "D",
+            'þ' => "th",
+            'Þ' => "Th",
+            'ı' => "i",
+            'ħ' => "h",
+            'Ħ' => "H",
+            'ŋ' => "ng",
+            'Ŋ' => "NG",
+            '‘' | '’' | '‚' | '‛' | '′' => "'",
+            '“' | '”' | '„' | '‟' | '″' | '«' | '»' => "\"",
+            '‐' | '‑' | '‒' | '–' | '—' | '―' | '−' => "-",
+            '•' | '·' => "*",
+            '€' => "EUR",
+            '£' => "GBP",
+            '×' => "x",
+            _ => "",
+        });
     }
+    ascii
 }
 
 #[derive(PartialEq, Eq, Default, Debug)]
@@ -100,21 +916,61 @@ impl Filters {
         Ok((splits[0].to_string(), filters))
     }
 
+    /// Parse just the text filters, like `tsv!unix`
+    fn parse(s: &str) -> Result<Self> {
+        Ok(Filters(
+            s.split('!').map(|s| s.parse()).collect::<Result<_, _>>()?,
+        ))
+    }
+
     fn apply<'a>(&self, s: impl Into<Cow<'a, str>>) -> Cow<'a, str> {
         let mut s: Cow<'a, str> = s.into();
-        for f in self.0.iter() {
-            s = f.apply(s);
+        if self.0.is_empty() {
+            return s;
         }
-        s
+        timed(&FILTER_TIME, || {
+            for f in self.0.iter() {
+                s = f.apply(s);
+            }
+            s
+        })
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 enum Instruction {
-    StartDocument { actions: Vec<Action> },
-    StartTag { tag: String, actions: Vec<Action> },
-    EndTag { tag: String, actions: Vec<Action> },
-    EndDocument { actions: Vec<Action> },
+    StartDocument {
+        actions: Vec<Action>,
+    },
+    StartTag {
+        tag: String,
+        actions: Vec<Action>,
+    },
+    EndTag {
+        tag: String,
+        actions: Vec<Action>,
+    },
+    EndDocument {
+        actions: Vec<Action>,
+    },
+    /// A processing instruction (`<?target data?>`) with this target
+    Pi {
+        target: String,
+        actions: Vec<Action>,
+    },
+    /// An XML comment
+    Comment {
+        actions: Vec<Action>,
+    },
+    /// The `<!DOCTYPE>` declaration, if the document has one
+    Doctype {
+        actions: Vec<Action>,
+    },
+    /// Text & CDATA. If `nonblank`, text which is only whitespace is ignored
+    Chars {
+        nonblank: bool,
+        actions: Vec<Action>,
+    },
 }
 
 impl Instruction {
@@ -124,7 +980,128 @@ impl Instruction {
             Instruction::StartTag { tag: _, actions } => actions,
             Instruction::EndTag { tag: _, actions } => actions,
             Instruction::EndDocument { actions } => actions,
+            Instruction::Pi { target: _, actions } => actions,
+            Instruction::Comment { actions } => actions,
+            Instruction::Doctype { actions } => actions,
+            Instruction::Chars { actions, .. } => actions,
+        }
+    }
+    /// Is the output grouped & counted, rather than written?
+    fn is_grouped(&self) -> bool {
+        self.actions().contains(&Action::GroupBy)
+    }
+    /// Does this only happen for elements without children?
+    fn is_leaf_only(&self) -> bool {
+        self.actions().contains(&Action::LeafOnly)
+    }
+    /// The regex which the element's text must match, if there is one
+    fn text_pattern(&self) -> Option<&regex::Regex> {
+        self.actions().iter().find_map(|a| match a {
+            Action::TextMatches(pattern) => Some(&pattern.0),
+            _ => None,
+        })
+    }
+    /// The namespace URI which the element must have, from `--ns-uri`
+    fn namespace_uri(&self) -> Option<&str> {
+        self.actions().iter().find_map(|a| match a {
+            Action::NamespaceUri(uri) => Some(uri.as_str()),
+            _ => None,
+        })
+    }
+    /// The path from `-x`
+    fn xpath(&self) -> Option<&XPath> {
+        self.actions().iter().find_map(|a| match a {
+            Action::XPath(path) => Some(path),
+            _ => None,
+        })
+    }
+    /// Is this a `-s`/`-e` for this element? The other predicates (& the `-x` path) aren't checked
+    fn matches_element(&self, name: &OwnedName) -> bool {
+        match self {
+            Instruction::StartTag { tag, .. } | Instruction::EndTag { tag, .. } => {
+                (tag == &name.local_name || (tag == "*" && self.xpath().is_some()))
+                    && self
+                        .namespace_uri()
+                        .is_none_or(|uri| name.namespace.as_deref() == Some(uri))
+            }
+            _ => false,
+        }
+    }
+    /// Does this `-s` happen at the end tag, since it depends on what's in the element?
+    fn is_deferred(&self) -> bool {
+        self.is_leaf_only() || self.text_pattern().is_some() || self.is_copy()
+    }
+    /// Lowercase the element names which this mentions, for `--ignore-tag-case`
+    fn lowercase_tags(&mut self) {
+        if let Instruction::StartTag { tag, .. } | Instruction::EndTag { tag, .. } = self {
+            *tag = tag.to_lowercase();
+        }
+        for action in self.actions_mut() {
+            match action {
+                Action::Counter(Some(tag), _) | Action::Matched(tag, _) => {
+                    *tag = tag.to_lowercase()
+                }
+                Action::Ancestor(element) => element.tag = element.tag.to_lowercase(),
+                Action::XPath(path) => {
+                    for step in path.steps.iter_mut() {
+                        step.element.tag = step.element.tag.to_lowercase();
+                    }
+                }
+                Action::Collect(child, ..)
+                | Action::CollectJson(child, _)
+                | Action::CollectHstore(child, ..)
+                | Action::CollectJsonObject(child, ..) => *child = child.to_lowercase(),
+                _ => {}
+            }
+        }
+    }
+    /// The element names which this mentions, for `--warn-unknown-tags`. `*` is any element
+    fn tags(&self) -> Vec<&str> {
+        let mut tags = vec![];
+        if let Instruction::StartTag { tag, .. } | Instruction::EndTag { tag, .. } = self {
+            tags.push(tag.as_str());
         }
+        for action in self.actions() {
+            match action {
+                Action::Counter(Some(tag), _) => tags.push(tag),
+                Action::Ancestor(element) => tags.push(&element.tag),
+                Action::XPath(path) => {
+                    tags.extend(path.steps.iter().map(|step| step.element.tag.as_str()))
+                }
+                Action::Collect(child, ..)
+                | Action::CollectJson(child, _)
+                | Action::CollectHstore(child, ..)
+                | Action::CollectJsonObject(child, ..) => tags.push(child),
+                _ => {}
+            }
+        }
+        tags
+    }
+    /// Does this output the element as XML or JSON?
+    fn is_copy(&self) -> bool {
+        self.actions()
+            .iter()
+            .any(|a| matches!(a, Action::Copy | Action::CopyJson))
+    }
+    /// Does this only happen for self-closing elements?
+    fn is_empty_only(&self) -> bool {
+        self.actions().contains(&Action::EmptyOnly)
+    }
+    /// Are events grouped and/or aggregated, rather than written?
+    fn is_aggregated(&self) -> bool {
+        self.actions()
+            .iter()
+            .any(|a| matches!(a, Action::GroupBy | Action::Aggregate(..)))
+    }
+    /// Does this depend on everything before it in the document (not just the open elements), so
+    /// the input can't be split up?
+    fn needs_whole_input(&self) -> bool {
+        self.is_aggregated()
+            || self.xpath().is_some_and(|path| path.uses_positions())
+            || self
+                .actions()
+                .iter()
+                .any(|a| matches!(a, Action::SiblingIndex(_) | Action::Matched(..)))
     }
     fn actions_mut(&mut self) -> &mut Vec<Action> {
         match self {
@@ -132,359 +1109,7236 @@ impl Instruction {
             Instruction::StartTag { tag: _, actions } => actions,
             Instruction::EndTag { tag: _, actions } => actions,
             Instruction::EndDocument { actions } => actions,
+            Instruction::Pi { target: _, actions } => actions,
+            Instruction::Comment { actions } => actions,
+            Instruction::Doctype { actions } => actions,
+            Instruction::Chars { actions, .. } => actions,
+        }
+    }
+}
+
+/// Displayed like the args which created it
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Instruction::StartDocument { .. } => write!(f, "-S")?,
+            Instruction::StartTag { .. } if self.xpath().is_some() => {
+                write!(f, "-x {}", self.xpath().unwrap().source)?
+            }
+            Instruction::StartTag { tag, .. } | Instruction::EndTag { tag, .. } => {
+                let flag = match self {
+                    Instruction::StartTag { .. } => "-s",
+                    _ => "-e",
+                };
+                match self.namespace_uri() {
+                    Some(uri) => write!(f, "{} {{{}}}{}", flag, uri, tag)?,
+                    None => write!(f, "{} {}", flag, tag)?,
+                }
+            }
+            Instruction::EndDocument { .. } => write!(f, "-E")?,
+            Instruction::Pi { target, .. } => write!(f, "-P {}", target)?,
+            Instruction::Comment { .. } => write!(f, "--comment")?,
+            Instruction::Doctype { .. } => write!(f, "--doctype")?,
+            Instruction::Chars {
+                nonblank: false, ..
+            } => write!(f, "--chars")?,
+            Instruction::Chars { nonblank: true, .. } => write!(f, "--chars=nonblank")?,
+        }
+        if let Some(pattern) = self.text_pattern() {
+            write!(f, "{{text~{}}}", pattern)?;
+        }
+        Ok(())
+    }
+}
+
+/// Does this attribute name match `attr`? `attr` is a plain name (`href`, which ignores any
+/// namespace), prefixed (`xlink:href`), or in Clark notation (`{http://www.w3.org/1999/xlink}href`).
+/// With `ignore_case`, the local name can be in any case, but not the prefix or namespace
+fn attr_name_matches(name: &OwnedName, attr: &str, ignore_case: bool) -> bool {
+    let local_name_is = |local_name: &str| {
+        if ignore_case {
+            name.local_name
+                .chars()
+                .flat_map(char::to_lowercase)
+                .eq(local_name.chars().flat_map(char::to_lowercase))
+        } else {
+            name.local_name == local_name
+        }
+    };
+    if let Some(clark) = attr.strip_prefix('{') {
+        match clark.split_once('}') {
+            Some((uri, local_name)) => {
+                name.namespace.as_deref() == Some(uri) && local_name_is(local_name)
+            }
+            None => false,
         }
+    } else if let Some((prefix, local_name)) = attr.split_once(':') {
+        name.prefix.as_deref() == Some(prefix) && local_name_is(local_name)
+    } else {
+        local_name_is(attr)
     }
 }
 
-fn get_attr<'a>(
-    attributes: &'a [xml::attribute::OwnedAttribute],
+/// The value of this attribute, if it's present. `--on-duplicate-attr` decides which value is
+/// used if more than one attribute has this name
+fn find_attr<'a>(
+    attributes: &'a [OwnedAttribute],
     attr: &str,
     tag: &str,
-) -> Result<&'a str> {
-    attributes
+    config: &Config,
+) -> Result<Option<Cow<'a, str>>> {
+    let mut matching = attributes
         .iter()
-        .filter_map(|a| {
-            if a.name.local_name == attr {
-                Some(a.value.as_str())
-            } else {
-                None
+        .filter(|a| attr_name_matches(&a.name, attr, config.ignore_attr_case))
+        .map(|a| a.value.as_str());
+    let first = match matching.next() {
+        None => return Ok(None),
+        Some(first) => first,
+    };
+    let value = match &config.on_duplicate_attr {
+        DuplicateAttrPolicy::First => Cow::Borrowed(first),
+        DuplicateAttrPolicy::Last => Cow::Borrowed(matching.next_back().unwrap_or(first)),
+        DuplicateAttrPolicy::Error => {
+            if matching.next().is_some() {
+                bail!(
+                    "Attribute {} appears more than once on element {}. Attributes: {}",
+                    attr,
+                    tag,
+                    attributes_snippet(attributes)
+                );
             }
+            Cow::Borrowed(first)
+        }
+        DuplicateAttrPolicy::Join(sep) => {
+            let mut matching = matching.peekable();
+            if matching.peek().is_none() {
+                Cow::Borrowed(first)
+            } else {
+                let mut joined = first.to_string();
+                for value in matching {
+                    joined.push_str(sep);
+                    joined.push_str(value);
+                }
+                Cow::Owned(joined)
+            }
+        }
+    };
+    Ok(Some(value))
+}
+
+fn missing_attr_error(attributes: &[OwnedAttribute], attr: &str, tag: &str) -> anyhow::Error {
+    anyhow!(
+        "No attribute {} found for element {}. Attributes: {}",
+        attr,
+        tag,
+        attributes_snippet(attributes)
+    )
+}
+
+/// Short description of these attributes & their values, for error messages
+fn attributes_snippet(attributes: &[OwnedAttribute]) -> String {
+    const MAX_ATTRIBUTES: usize = 10;
+    const MAX_VALUE_CHARS: usize = 30;
+    let mut snippet = attributes
+        .iter()
+        .take(MAX_ATTRIBUTES)
+        .map(|a| {
+            if a.value.chars().count() > MAX_VALUE_CHARS {
+                let value: String = a.value.chars().take(MAX_VALUE_CHARS).collect();
+                format!("{}={:?}…", a.name.borrow().to_repr(), value)
+            } else {
+                format!("{}={:?}", a.name.borrow().to_repr(), a.value)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    if attributes.len() > MAX_ATTRIBUTES {
+        snippet.push_str(" …");
+    }
+    if snippet.is_empty() {
+        snippet.push_str("(none)");
+    }
+    snippet
+}
+
+/// Describe this XML event for `--trace-events`, with elements & PIs as the `-s`/`-e`/`-P` which
+/// match them
+fn describe_event(event: &XmlEvent) -> String {
+    /// Text is truncated to this many characters
+    const MAX_TEXT_CHARS: usize = 30;
+    let text = |kind: &str, text: &str| {
+        if text.chars().count() > MAX_TEXT_CHARS {
+            let text: String = text.chars().take(MAX_TEXT_CHARS).collect();
+            format!("{} {:?}…", kind, text)
+        } else {
+            format!("{} {:?}", kind, text)
+        }
+    };
+    match event {
+        XmlEvent::StartDocument { .. } => "-S".to_string(),
+        XmlEvent::EndDocument => "-E".to_string(),
+        XmlEvent::StartElement {
+            name, attributes, ..
+        } => {
+            let mut description = format!("-s {}", name.local_name);
+            if name.prefix.is_some() {
+                description.push_str(&format!(" ({})", name.borrow().to_repr()));
+            }
+            if !attributes.is_empty() {
+                description.push_str(&format!(" {}", attributes_snippet(attributes)));
+            }
+            description
+        }
+        XmlEvent::EndElement { name } => format!("-e {}", name.local_name),
+        XmlEvent::Characters(s) => text("text", s),
+        XmlEvent::CData(s) => text("cdata", s),
+        XmlEvent::Whitespace(s) => text("whitespace", s),
+        XmlEvent::Comment(s) => text("--comment", s),
+        XmlEvent::ProcessingInstruction { name, .. } => format!("-P {}", name),
+    }
+}
+
+/// Wraps the input, keeping track of how much has been read.
+///
+/// xml-rs reads one byte at a time, & `<` can't appear inside a tag, so the last `<` read when
+/// a StartElement event happens is the start of that tag.
+struct CountingReader<R: Read> {
+    inner: R,
+    /// Total bytes read so far
+    bytes_read: u64,
+    /// Byte offset of the last `<` read
+    last_tag_start: u64,
+    /// The last 2 bytes read, to tell if a tag was self-closing
+    last_bytes: [u8; 2],
+}
+
+impl<R: Read> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        CountingReader {
+            inner,
+            bytes_read: 0,
+            last_tag_start: 0,
+            last_bytes: [0; 2],
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(i) = buf[..n].iter().rposition(|&b| b == b'<') {
+            self.last_tag_start = self.bytes_read + i as u64;
+        }
+        match n {
+            0 => {}
+            1 => self.last_bytes = [self.last_bytes[1], buf[0]],
+            _ => self.last_bytes = [buf[n - 2], buf[n - 1]],
+        }
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+/// Counts the bytes written, for `--checkpoint`
+struct CountingWriter<W: Write> {
+    inner: W,
+    bytes_written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = timed(&WRITE_TIME, || self.inner.write(buf))?;
+        self.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Wraps the output, flushing it after every `every` lines, for `--flush-every` & `--line-buffered`
+struct FlushEvery<W: Write> {
+    inner: W,
+    every: u64,
+    /// Lines since the last flush
+    lines: u64,
+}
+
+impl<W: Write> FlushEvery<W> {
+    fn new(inner: W, every: u64) -> Self {
+        FlushEvery {
+            inner,
+            every,
+            lines: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for FlushEvery<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while let Some(i) = rest.iter().position(|&b| b == b'\n') {
+            self.inner.write_all(&rest[..=i])?;
+            rest = &rest[i + 1..];
+            self.lines += 1;
+            if self.lines == self.every {
+                self.flush()?;
+            }
+        }
+        self.inner.write_all(rest)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.lines = 0;
+        self.inner.flush()
+    }
+}
+
+/// Where `--kafka` sends the records
+#[derive(Debug)]
+#[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+struct KafkaOptions {
+    brokers: Vec<String>,
+    topic: String,
+    /// Which field (from 0) is the message key, with `--kafka-key`
+    key_field: Option<usize>,
+}
+
+/// How many records `--kafka` sends at once
+#[cfg(feature = "kafka")]
+const KAFKA_BATCH: usize = 1000;
+
+/// Sends each record (up to the record separator) as a message, for `--kafka`
+#[cfg(feature = "kafka")]
+struct KafkaSink {
+    producer: kafka::producer::Producer,
+    topic: String,
+    record_separator: Vec<u8>,
+    field_separator: Vec<u8>,
+    key_field: Option<usize>,
+    /// The start of the next record
+    partial: Vec<u8>,
+    /// Records which haven't been sent yet
+    pending: Vec<Vec<u8>>,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaSink {
+    fn connect(options: &KafkaOptions, config: &Config) -> Result<Self> {
+        let producer = kafka::producer::Producer::from_hosts(options.brokers.clone())
+            .with_required_acks(kafka::producer::RequiredAcks::All)
+            .create()
+            .with_context(|| format!("Cannot connect to --kafka {}", options.brokers.join(",")))?;
+        Ok(KafkaSink {
+            producer,
+            topic: options.topic.clone(),
+            record_separator: config
+                .record_separator
+                .as_deref()
+                .unwrap_or("\n")
+                .as_bytes()
+                .to_vec(),
+            field_separator: config
+                .field_separator
+                .as_deref()
+                .unwrap_or("\t")
+                .as_bytes()
+                .to_vec(),
+            key_field: options.key_field,
+            partial: vec![],
+            pending: vec![],
         })
-        .next()
-        .ok_or_else(|| {
-            anyhow!(
-                "No attribute {} found for element {}. Attributes: {}",
-                attr,
-                tag,
-                attributes
+    }
+
+    fn send(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let result = match self.key_field {
+            Some(field) => {
+                let messages: Vec<_> = self
+                    .pending
                     .iter()
-                    .map(|a| a.name.local_name.as_str())
-                    .collect::<Vec<_>>()
-                    .join(",")
-            )
+                    .map(|record| {
+                        let key = nth_field(record, &self.field_separator, field);
+                        kafka::producer::Record::from_key_value(&self.topic, key, record.as_slice())
+                    })
+                    .collect();
+                self.producer.send_all(&messages)
+            }
+            None => {
+                let messages: Vec<_> = self
+                    .pending
+                    .iter()
+                    .map(|record| {
+                        kafka::producer::Record::from_value(&self.topic, record.as_slice())
+                    })
+                    .collect();
+                self.producer.send_all(&messages)
+            }
+        };
+        result.map_err(std::io::Error::other)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Send everything, including a last record without a record separator
+    fn finish(&mut self) -> std::io::Result<()> {
+        if !self.partial.is_empty() {
+            let record = std::mem::take(&mut self.partial);
+            self.pending.push(record);
+        }
+        self.send()
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl Write for KafkaSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        let mut start = 0;
+        while let Some(end) = find_bytes(&self.partial[start..], &self.record_separator) {
+            let record = self.partial[start..start + end].to_vec();
+            self.pending.push(record);
+            start += end + self.record_separator.len();
+        }
+        self.partial.drain(..start);
+        if self.pending.len() >= KAFKA_BATCH {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send()
+    }
+}
+
+/// A `KafkaSink` which can still be finished after it's been wrapped in --unique etc.
+#[cfg(feature = "kafka")]
+#[derive(Clone)]
+struct SharedKafkaSink(Rc<std::cell::RefCell<KafkaSink>>);
+
+#[cfg(feature = "kafka")]
+impl Write for SharedKafkaSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Where `--pg` copies the records to
+#[derive(Debug)]
+#[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+struct PostgresOptions {
+    url: String,
+    /// The table, & maybe its columns, e.g. `nodes(id,name)`
+    table: String,
+}
+
+#[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+impl PostgresOptions {
+    /// The `COPY` statement, with the `--fs` delimiter
+    fn copy_statement(&self, field_separator: Option<&str>) -> String {
+        let mut statement = format!("COPY {} FROM STDIN", self.table);
+        if let Some(separator) = field_separator {
+            statement.push_str(&format!(
+                " WITH (DELIMITER '{}')",
+                separator.replace('\'', "''")
+            ));
+        }
+        statement
+    }
+}
+
+/// Writes the output on its own thread, for `--pg` & `--upload`, which have to be told when all of
+/// it was sent, so that an error part way through doesn't leave half of it
+#[cfg(any(feature = "postgres", feature = "s3"))]
+struct OutputThread {
+    name: &'static str,
+    sender: std::sync::mpsc::SyncSender<Vec<u8>>,
+    thread: std::thread::JoinHandle<Result<()>>,
+}
+
+#[cfg(any(feature = "postgres", feature = "s3"))]
+impl OutputThread {
+    fn spawn(
+        name: &'static str,
+        run: impl FnOnce(OutputChunks) -> Result<()> + Send + 'static,
+    ) -> Self {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(16);
+        let thread = std::thread::spawn(move || run(OutputChunks(receiver)));
+        OutputThread {
+            name,
+            sender,
+            thread,
+        }
+    }
+
+    /// Something to write the output to
+    fn writer(&self) -> OutputThreadWriter {
+        OutputThreadWriter {
+            name: self.name,
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Tells the thread that everything was sent (when `commit`), or aborts it. Every writer must
+    /// be dropped first
+    fn finish(self, commit: bool) -> Result<()> {
+        if commit {
+            // Any error is from the thread
+            let _ = self.sender.send(vec![]);
+        }
+        drop(self.sender);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| bail!("The {} thread panicked", self.name))
+    }
+}
+
+/// The output, on an `OutputThread`
+#[cfg(any(feature = "postgres", feature = "s3"))]
+struct OutputChunks(std::sync::mpsc::Receiver<Vec<u8>>);
+
+#[cfg(any(feature = "postgres", feature = "s3"))]
+impl OutputChunks {
+    /// Writes all of the output to `writer`, returning false if it was aborted
+    fn copy_to(&self, writer: &mut impl Write) -> std::io::Result<bool> {
+        // An empty chunk means everything was sent, & disconnecting before then aborts it
+        for chunk in self.0.iter() {
+            if chunk.is_empty() {
+                return Ok(true);
+            }
+            writer.write_all(&chunk)?;
+        }
+        Ok(false)
+    }
+}
+
+/// Sends the output to an `OutputThread`
+#[cfg(any(feature = "postgres", feature = "s3"))]
+struct OutputThreadWriter {
+    name: &'static str,
+    sender: std::sync::mpsc::SyncSender<Vec<u8>>,
+}
+
+#[cfg(any(feature = "postgres", feature = "s3"))]
+impl Write for OutputThreadWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !buf.is_empty() {
+            self.sender
+                .send(buf.to_vec())
+                .map_err(|_| std::io::Error::other(format!("The {} output stopped", self.name)))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Copies the output into PostgreSQL, for `--pg`. The connection is on an `OutputThread`, since
+/// the `COPY` borrows it
+#[cfg(feature = "postgres")]
+fn postgres_copy(options: &PostgresOptions, config: &Config) -> Result<OutputThread> {
+    let mut client = postgres::Client::connect(&options.url, postgres::NoTls)
+        .context("Cannot connect to --pg")?;
+    let statement = options.copy_statement(config.field_separator.as_deref());
+    // Checks the table before reading any of the input
+    let mut writer = client.copy_in(&statement).map_err(postgres_error)?;
+    writer.flush()?;
+    drop(writer);
+    let table = options.table.clone();
+    Ok(OutputThread::spawn("--pg", move |chunks| {
+        let mut writer = client.copy_in(&statement).map_err(postgres_error)?;
+        if chunks.copy_to(&mut writer).map_err(postgres_io_error)? {
+            let rows = writer.finish().map_err(postgres_error)?;
+            tracing::info!("Copied {} rows into {}", rows, table);
+        }
+        Ok(())
+    }))
+}
+
+/// Includes where a bad row is, e.g. `COPY nodes, line 3, column id: "x"`
+#[cfg(feature = "postgres")]
+fn postgres_error(error: postgres::Error) -> anyhow::Error {
+    let context = error
+        .as_db_error()
+        .and_then(|e| e.where_())
+        .map(str::to_string);
+    match context {
+        Some(context) => anyhow::Error::new(error).context(context),
+        None => anyhow::Error::new(error).context("--pg failed"),
+    }
+}
+
+/// A row error can be reported when writing
+#[cfg(feature = "postgres")]
+fn postgres_io_error(error: std::io::Error) -> anyhow::Error {
+    match error.into_inner() {
+        Some(inner) => match inner.downcast::<postgres::Error>() {
+            Ok(error) => postgres_error(*error),
+            Err(inner) => anyhow::anyhow!(inner).context("--pg failed"),
+        },
+        None => anyhow::anyhow!("--pg failed"),
+    }
+}
+
+/// Where `--upload` writes the output
+#[derive(Debug)]
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+struct UploadOptions {
+    bucket: String,
+    key: String,
+    /// For S3 compatible storage, with `--upload-endpoint`
+    endpoint: Option<String>,
+}
+
+impl std::str::FromStr for UploadOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let (bucket, key) = url
+            .strip_prefix("s3://")
+            .and_then(|path| path.split_once('/'))
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .with_context(|| format!("Invalid --upload {:?}, expected s3://BUCKET/KEY", url))?;
+        Ok(UploadOptions {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            endpoint: None,
+        })
+    }
+}
+
+/// How big each part of an `--upload` is. S3's smallest is 5 MiB, & it allows 10,000 parts
+#[cfg(feature = "s3")]
+const UPLOAD_PART_BYTES: usize = 16 * 1024 * 1024;
+
+/// A multipart upload to S3, for `--upload`, which is aborted if it's dropped before it's
+/// completed
+#[cfg(feature = "s3")]
+struct S3Upload {
+    bucket: Box<s3::Bucket>,
+    key: String,
+    /// Started when the first part is full, so small outputs are uploaded in one go
+    upload_id: Option<String>,
+    parts: Vec<s3::serde_types::Part>,
+    buffer: Vec<u8>,
+    bytes: u64,
+}
+
+#[cfg(feature = "s3")]
+impl S3Upload {
+    fn new(options: &UploadOptions) -> Result<Self> {
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_string());
+        let region = match &options.endpoint {
+            Some(endpoint) => s3::Region::Custom {
+                region,
+                endpoint: endpoint.clone(),
+            },
+            None => region.parse()?,
+        };
+        let credentials = s3::creds::Credentials::default()
+            .context("Cannot find the credentials for --upload")?;
+        let mut bucket = s3::Bucket::new(&options.bucket, region, credentials)?;
+        if options.endpoint.is_some() {
+            bucket = bucket.with_path_style();
+        }
+        Ok(S3Upload {
+            bucket,
+            key: options.key.clone(),
+            upload_id: None,
+            parts: vec![],
+            buffer: Vec::with_capacity(UPLOAD_PART_BYTES),
+            bytes: 0,
+        })
+    }
+
+    fn send_part(&mut self) -> Result<()> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id,
+            None => {
+                let response = self
+                    .bucket
+                    .initiate_multipart_upload(&self.key, "application/octet-stream")?;
+                self.upload_id.insert(response.upload_id)
+            }
+        };
+        let part = self.bucket.put_multipart_chunk(
+            &self.buffer,
+            &self.key,
+            self.parts.len() as u32 + 1,
+            upload_id,
+            "application/octet-stream",
+        )?;
+        self.parts.push(part);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Uploads the rest, returning how many bytes were uploaded
+    fn complete(mut self) -> Result<u64> {
+        match self.upload_id.is_some() {
+            true => {
+                if !self.buffer.is_empty() {
+                    self.send_part()?;
+                }
+                let upload_id = self.upload_id.take().unwrap();
+                let parts = std::mem::take(&mut self.parts);
+                let result = self
+                    .bucket
+                    .complete_multipart_upload(&self.key, &upload_id, parts);
+                if result.is_err() {
+                    let _ = self.bucket.abort_upload(&self.key, &upload_id);
+                }
+                result?;
+            }
+            false => {
+                self.bucket.put_object(&self.key, &self.buffer)?;
+            }
+        }
+        Ok(self.bytes)
+    }
+}
+
+#[cfg(feature = "s3")]
+impl Write for S3Upload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        self.bytes += buf.len() as u64;
+        if self.buffer.len() >= UPLOAD_PART_BYTES {
+            self.send_part().map_err(std::io::Error::other)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "s3")]
+impl Drop for S3Upload {
+    fn drop(&mut self) {
+        if let Some(upload_id) = self.upload_id.take() {
+            // Otherwise the parts are kept (& paid for)
+            let _ = self.bucket.abort_upload(&self.key, &upload_id);
+        }
+    }
+}
+
+/// Uploads the output to S3 on an `OutputThread`, for `--upload`
+#[cfg(feature = "s3")]
+fn s3_upload(options: &UploadOptions) -> Result<OutputThread> {
+    let mut upload = S3Upload::new(options)?;
+    let url = format!("s3://{}/{}", options.bucket, options.key);
+    let zstd = options.key.ends_with(".zst");
+    Ok(OutputThread::spawn("--upload", move |chunks| {
+        if zstd {
+            let mut encoder = zstd::Encoder::new(upload, 0)?;
+            if !chunks.copy_to(&mut encoder)? {
+                return Ok(());
+            }
+            upload = encoder.finish()?;
+        } else if !chunks.copy_to(&mut upload)? {
+            return Ok(());
+        }
+        let bytes = upload
+            .complete()
+            .with_context(|| format!("Cannot upload to {}", url))?;
+        tracing::info!("Uploaded {} bytes to {}", bytes, url);
+        Ok(())
+    }))
+}
+
+/// Where `needle` first is in `haystack`
+#[cfg(feature = "kafka")]
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The `n`th (from 0) of the `separator` separated fields, or empty if there aren't that many
+#[cfg(feature = "kafka")]
+fn nth_field<'a>(mut record: &'a [u8], separator: &[u8], n: usize) -> &'a [u8] {
+    for _ in 0..n {
+        match find_bytes(record, separator) {
+            Some(end) => record = &record[end + separator.len()..],
+            None => return b"",
+        }
+    }
+    match find_bytes(record, separator) {
+        Some(end) => &record[..end],
+        None => record,
+    }
+}
+
+/// Writes the output to the files too, for `--tee`
+struct Tee<W: Write> {
+    inner: W,
+    files: Vec<std::io::BufWriter<std::fs::File>>,
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write_all(buf)?;
+        for file in self.files.iter_mut() {
+            file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()?;
+        for file in self.files.iter_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Which duplicate lines `--unique` removes
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum UniqueMode {
+    /// Every line which has been output before. All distinct lines are kept in memory
+    All,
+    /// Lines which are the same as the previous line, like `uniq`, for output which is sorted
+    Adjacent,
+    /// Lines which are the same as one of this many recent distinct lines
+    Recent(usize),
+}
+
+impl FromStr for UniqueMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(UniqueMode::All),
+            "adjacent" => Ok(UniqueMode::Adjacent),
+            x => match x.parse() {
+                Ok(n) if n > 0 => Ok(UniqueMode::Recent(n)),
+                _ => anyhow::bail!(
+                    "Unknown --unique mode {}, expected all, adjacent or a number of lines",
+                    x
+                ),
+            },
+        }
+    }
+}
+
+/// A comment at the start of the output saying how it was made, for `--emit-header-comment`
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct HeaderComment {
+    style: CommentStyle,
+    /// The command line, quoted for a shell
+    command: String,
+}
+
+/// How `--emit-header-comment` comments out each line
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum CommentStyle {
+    /// `# …`, for TSV, CSV etc.
+    Hash,
+    /// `-- …`, for SQL
+    Sql,
+    /// `<!-- … -->`, for XML
+    Xml,
+}
+
+impl HeaderComment {
+    /// Write the comment, with the version, command line, inputs & date
+    fn write(
+        &self,
+        inputs: &[String],
+        date: impl std::fmt::Display,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        let inputs: Vec<_> = inputs.iter().map(|i| shell_quote(i)).collect();
+        let lines = [
+            format!("Generated by anglosaxon {}", clap::crate_version!()),
+            format!("Command: {}", self.command),
+            format!("Input: {}", inputs.join(" ")),
+            format!("Date: {}", date),
+        ];
+        for mut line in lines {
+            match self.style {
+                CommentStyle::Hash => writeln!(output, "# {}", line)?,
+                CommentStyle::Sql => writeln!(output, "-- {}", line)?,
+                CommentStyle::Xml => {
+                    // XML comments can't have `--` in them
+                    while line.contains("--") {
+                        line = line.replace("--", "- -");
+                    }
+                    writeln!(output, "<!-- {} -->", line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Quote this argument for a POSIX shell, if it needs it
+fn shell_quote(arg: &str) -> Cow<'_, str> {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c))
+    {
+        arg.into()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''")).into()
+    }
+}
+
+/// The columns of the output, with their types, for `--schema`
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+struct Schema {
+    columns: Vec<Column>,
+}
+
+/// One `NAME:TYPE` column of a `--schema`. The name is the attribute which is output
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct Column {
+    name: String,
+    column_type: ColumnType,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum ColumnType {
+    Int,
+    Float,
+    Bool,
+    Str,
+}
+
+impl FromStr for ColumnType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "int" => Ok(ColumnType::Int),
+            "float" => Ok(ColumnType::Float),
+            "bool" => Ok(ColumnType::Bool),
+            "str" => Ok(ColumnType::Str),
+            x => anyhow::bail!(
+                "Unknown column type {}, expected int, float, bool or str",
+                x
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            ColumnType::Int => "int",
+            ColumnType::Float => "float",
+            ColumnType::Bool => "bool",
+            ColumnType::Str => "str",
         })
+    }
+}
+
+impl ColumnType {
+    /// Parse this value as this type, returning it as it should be output, or giving it back as
+    /// the error if it isn't valid. Numbers & bools can have whitespace around them, floats can
+    /// have a decimal comma (`1,5`), & bools can be `1`/`0` or `yes`/`no` too
+    fn coerce<'a>(&self, value: Cow<'a, str>) -> Result<Cow<'a, str>, Cow<'a, str>> {
+        let trimmed = value.trim();
+        let valid = match self {
+            ColumnType::Str => return Ok(value),
+            ColumnType::Bool => {
+                return match trimmed.to_ascii_lowercase().as_str() {
+                    "true" | "1" | "yes" => Ok(Cow::Borrowed("true")),
+                    "false" | "0" | "no" => Ok(Cow::Borrowed("false")),
+                    _ => Err(value),
+                };
+            }
+            ColumnType::Int => trimmed.parse::<i64>().is_ok(),
+            ColumnType::Float if trimmed.matches(',').count() == 1 && !trimmed.contains('.') => {
+                let point = trimmed.replace(',', ".");
+                return match point.parse::<f64>().is_ok_and(f64::is_finite) {
+                    true => Ok(Cow::Owned(point)),
+                    false => Err(value),
+                };
+            }
+            ColumnType::Float => trimmed.parse::<f64>().is_ok_and(f64::is_finite),
+        };
+        if !valid {
+            Err(value)
+        } else if trimmed.len() == value.len() {
+            Ok(value)
+        } else {
+            Ok(Cow::Owned(trimmed.to_string()))
+        }
+    }
+}
+
+/// What to do with a value which isn't valid for its `--schema` column
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+enum InvalidValuePolicy {
+    /// Stop with an error
+    #[default]
+    Error,
+    /// Output an empty string, i.e. NULL for a database, & print a warning
+    Null,
+    /// Don't output anything for this instruction, & print a warning
+    SkipRecord,
+}
+
+impl FromStr for InvalidValuePolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(InvalidValuePolicy::Error),
+            "null" => Ok(InvalidValuePolicy::Null),
+            "skip-record" => Ok(InvalidValuePolicy::SkipRecord),
+            x => anyhow::bail!(
+                "Unknown invalid value policy {}, expected error, null or skip-record",
+                x
+            ),
+        }
+    }
+}
+
+impl FromStr for Schema {
+    type Err = anyhow::Error;
+    /// Parse `id:int,lat:float,name`. A column without a type is a `str`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut columns: Vec<Column> = vec![];
+        for column in s.split(',') {
+            let (name, column_type) = match column.split_once(':') {
+                Some((name, column_type)) => (name, column_type.parse()?),
+                None => (column, ColumnType::Str),
+            };
+            if name.is_empty() {
+                anyhow::bail!("Invalid --schema {:?}, there is a column without a name", s);
+            }
+            if columns.iter().any(|c| c.name == name) {
+                anyhow::bail!("Invalid --schema {:?}, there are 2 {} columns", s, name);
+            }
+            columns.push(Column {
+                name: name.to_string(),
+                column_type,
+            });
+        }
+        Ok(Schema { columns })
+    }
 }
 
-/// The main "inner main"
-fn process(instructions: &[Instruction], input: impl Read, mut output: impl Write) -> Result<()> {
-    let reader = EventReader::new(input);
+impl Schema {
+    /// The column for the `attr` attribute, if there is one
+    fn column(&self, attr: &str, ignore_case: bool) -> Option<&Column> {
+        self.columns.iter().find(|c| match ignore_case {
+            true => c.name.eq_ignore_ascii_case(attr),
+            false => c.name == attr,
+        })
+    }
+
+    /// The header line, the column names joined with `separator`
+    fn header(&self, separator: &str) -> String {
+        let names: Vec<&str> = self.columns.iter().map(|c| c.name.as_str()).collect();
+        names.join(separator)
+    }
+
+    /// Parse this value of the `attr` attribute as its column's type (see
+    /// [`ColumnType::coerce`]), or return the column & the value if it isn't valid. Attributes
+    /// which aren't in the schema are always valid
+    fn coerce<'a>(
+        &self,
+        attr: &str,
+        value: Cow<'a, str>,
+        ignore_case: bool,
+    ) -> Result<Cow<'a, str>, (&Column, Cow<'a, str>)> {
+        match self.column(attr, ignore_case) {
+            Some(column) => column
+                .column_type
+                .coerce(value)
+                .map_err(|value| (column, value)),
+            None => Ok(value),
+        }
+    }
+}
+
+/// Output each record as a GeoJSON point feature, for `--output-format geojson|geojson-lines`
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct GeoJson {
+    /// The attributes with the latitude & longitude
+    lat_attr: String,
+    lon_attr: String,
+    /// One feature per line, rather than a FeatureCollection
+    lines: bool,
+}
+
+/// Wraps the output, putting a comma between each line, so one feature per line becomes the
+/// features array of a FeatureCollection. The newline after the last line isn't written
+struct CommaSeparatedLines<W: Write> {
+    inner: W,
+    /// A line has ended, but the next one hasn't started
+    line_ended: bool,
+}
+
+impl<W: Write> CommaSeparatedLines<W> {
+    fn new(inner: W) -> Self {
+        CommaSeparatedLines {
+            inner,
+            line_ended: false,
+        }
+    }
+}
+
+impl<W: Write> Write for CommaSeparatedLines<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            if self.line_ended {
+                self.inner.write_all(b",\n")?;
+            }
+            self.line_ended = line.ends_with(b"\n");
+            self.inner
+                .write_all(line.strip_suffix(b"\n").unwrap_or(line))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Wraps the output, removing duplicate lines.
+///
+/// Lines are only written once they're complete, so `flush` writes any partial line.
+struct UniqueLines<W: Write> {
+    inner: W,
+    mode: UniqueMode,
+    /// The current line, which hasn't been written yet
+    line: Vec<u8>,
+    /// Lines which have been written
+    seen: HashSet<Vec<u8>>,
+    /// The order lines were added to `seen`, so the oldest can be forgotten in `Recent` mode
+    order: VecDeque<Vec<u8>>,
+    /// The last line written, in `Adjacent` mode
+    previous: Option<Vec<u8>>,
+    /// The total size of `seen`, & the most it can be, for `--max-memory`
+    seen_bytes: usize,
+    max_bytes: Option<usize>,
+}
+
+impl<W: Write> UniqueLines<W> {
+    fn new(inner: W, mode: UniqueMode) -> Self {
+        UniqueLines {
+            inner,
+            mode,
+            line: vec![],
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            previous: None,
+            seen_bytes: 0,
+            max_bytes: None,
+        }
+    }
+
+    /// Write the current line, unless it's a duplicate
+    fn end_line(&mut self) -> std::io::Result<()> {
+        let line = std::mem::take(&mut self.line);
+        // The last line might not end with a newline, but it's still the same line
+        let key = line.strip_suffix(b"\n").unwrap_or(&line).to_vec();
+        let key_len = key.len();
+        let duplicate = match self.mode {
+            UniqueMode::All => !self.seen.insert(key),
+            UniqueMode::Adjacent => self.previous.replace(key.clone()) == Some(key),
+            UniqueMode::Recent(max) => {
+                if self.seen.contains(&key) {
+                    true
+                } else {
+                    if self.order.len() == max {
+                        let oldest = self.order.pop_front().unwrap();
+                        self.seen.remove(&oldest);
+                    }
+                    self.seen.insert(key.clone());
+                    self.order.push_back(key);
+                    false
+                }
+            }
+        };
+        if !duplicate {
+            if self.mode == UniqueMode::All {
+                self.seen_bytes += key_len;
+                if let Some(max) = self.max_bytes.filter(|max| self.seen_bytes > *max) {
+                    return Err(std::io::Error::other(format!(
+                        "--unique=all has more than --max-memory {} bytes of distinct lines, use --sort --unique=adjacent or --unique=N instead",
+                        max
+                    )));
+                }
+            }
+            self.inner.write_all(&line)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for UniqueLines<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while let Some(i) = rest.iter().position(|&b| b == b'\n') {
+            self.line.extend_from_slice(&rest[..=i]);
+            self.end_line()?;
+            rest = &rest[i + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.line.is_empty() {
+            self.end_line()?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Which document `DiffLines` is reading
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum DiffPass {
+    /// The old document, counting its lines
+    Old,
+    /// The new document, writing the lines which aren't in the old one
+    New,
+    /// The old document again, writing the lines which weren't in the new one
+    Removed,
+}
+
+/// Compares the output lines of 2 documents, for the `diff` subcommand. Only a hash of each line
+/// is kept, so the old document is read twice.
+struct DiffLines<W: Write> {
+    inner: W,
+    pass: DiffPass,
+    /// The current line
+    line: Vec<u8>,
+    /// How many times each line of the old document hasn't been matched by the new one (yet)
+    counts: HashMap<u64, u64>,
+    /// How many lines were written
+    differences: u64,
+}
+
+impl<W: Write> DiffLines<W> {
+    fn new(inner: W) -> Self {
+        DiffLines {
+            inner,
+            pass: DiffPass::Old,
+            line: vec![],
+            counts: HashMap::new(),
+            differences: 0,
+        }
+    }
+
+    fn end_line(&mut self) -> std::io::Result<()> {
+        use std::hash::{Hash, Hasher};
+        let mut line = std::mem::take(&mut self.line);
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        line.hash(&mut hasher);
+        let count = self.counts.entry(hasher.finish()).or_default();
+        let prefix = match self.pass {
+            DiffPass::Old => {
+                *count += 1;
+                return Ok(());
+            }
+            DiffPass::New if *count > 0 => {
+                *count -= 1;
+                return Ok(());
+            }
+            DiffPass::New => b"+",
+            DiffPass::Removed if *count > 0 => {
+                *count -= 1;
+                b"-"
+            }
+            DiffPass::Removed => return Ok(()),
+        };
+        self.differences += 1;
+        self.inner.write_all(prefix)?;
+        self.inner.write_all(&line)
+    }
+}
+
+impl<W: Write> Write for DiffLines<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while let Some(i) = rest.iter().position(|&b| b == b'\n') {
+            self.line.extend_from_slice(&rest[..=i]);
+            self.end_line()?;
+            rest = &rest[i + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.line.is_empty() {
+            self.end_line()?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Run the instructions on the old & new documents, & write the output lines which are only in
+/// one of them, `+` for the new document & `-` for the old. Returns how many lines were written
+fn diff(
+    config: &Config,
+    instructions: &[Instruction],
+    old: &str,
+    new: &str,
+    open: impl Fn(&str) -> Result<Box<dyn BufRead>>,
+    output: impl Write,
+    summary: &mut Summary,
+) -> Result<u64> {
+    if old == "-" {
+        bail!("The old document is read twice, so it can't be stdin");
+    }
+    let mut lines = DiffLines::new(output);
+    for (pass, filename) in [
+        (DiffPass::Old, old),
+        (DiffPass::New, new),
+        (DiffPass::Removed, old),
+    ] {
+        lines.pass = pass;
+        process(
+            config,
+            instructions,
+            filename,
+            open(filename)?,
+            &mut lines,
+            summary,
+        )?;
+        lines.flush()?;
+    }
+    Ok(lines.differences)
+}
+
+/// What the `bench` subcommand does
+#[derive(Debug, PartialEq, Eq)]
+struct Bench {
+    /// How many times to process the input
+    iterations: usize,
+    /// Generate an OSM-like document of about this many bytes, rather than reading the input
+    synthetic: Option<usize>,
+}
+
+/// An OSM-like document of about this many bytes, with nodes, ways & relations, & tags on some of
+/// them, for `bench --synthetic`. It's always the same for the same size
+fn synthetic_osm(bytes: usize) -> Vec<u8> {
+    let mut xml =
+        String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<osm version=\"0.6\">\n");
+    let mut id: u64 = 0;
+    while xml.len() < bytes {
+        id += 1;
+        match id % 10 {
+            0..=6 => {
+                let (lat, lon) = ((id * 7919) % 1800000, (id * 104729) % 3600000);
+                xml.push_str(&format!(
+                    " <node id=\"{}\" version=\"1\" lat=\"{}.{:05}\" lon=\"{}.{:05}\"",
+                    id,
+                    lat / 100000 % 90,
+                    lat % 100000,
+                    lon / 100000 % 180,
+                    lon % 100000
+                ));
+                if id.is_multiple_of(3) {
+                    xml.push_str(&format!(
+                        ">\n  <tag k=\"name\" v=\"Node {}\"/>\n  <tag k=\"amenity\" v=\"bench\"/>\n </node>\n",
+                        id
+                    ));
+                } else {
+                    xml.push_str("/>\n");
+                }
+            }
+            7 | 8 => {
+                xml.push_str(&format!(" <way id=\"{}\" version=\"1\">\n", id));
+                for i in 0..(id % 5 + 2) {
+                    xml.push_str(&format!(
+                        "  <nd ref=\"{}\"/>\n",
+                        id.saturating_sub(i * 10 + 1)
+                    ));
+                }
+                xml.push_str(&format!(
+                    "  <tag k=\"highway\" v=\"residential\"/>\n  <tag k=\"name\" v=\"Street {}\"/>\n </way>\n",
+                    id
+                ));
+            }
+            _ => {
+                xml.push_str(&format!(
+                    " <relation id=\"{}\" version=\"1\">\n  <member type=\"way\" ref=\"{}\" role=\"outer\"/>\n  <member type=\"node\" ref=\"{}\" role=\"\"/>\n  <tag k=\"type\" v=\"multipolygon\"/>\n </relation>\n",
+                    id,
+                    id - 1,
+                    id - 3
+                ));
+            }
+        }
+    }
+    xml.push_str("</osm>\n");
+    xml.into_bytes()
+}
+
+/// Process the inputs (which are in memory, so reading them isn't timed) `bench.iterations`
+/// times, throwing away the output, & write how long it took
+fn bench(
+    config: &Config,
+    instructions: &[Instruction],
+    bench: &Bench,
+    inputs: &[(String, Vec<u8>)],
+    output: impl Write,
+) -> Result<()> {
+    let mut times = vec![];
+    let mut records = 0;
+    for _ in 0..bench.iterations {
+        let mut summary = Summary::default();
+        let start = std::time::Instant::now();
+        for (filename, input) in inputs {
+            process(
+                config,
+                instructions,
+                filename,
+                input.as_slice(),
+                std::io::sink(),
+                &mut summary,
+            )?;
+        }
+        times.push(start.elapsed());
+        records = summary.records;
+    }
+    let bytes = inputs.iter().map(|(_, input)| input.len()).sum();
+    write_bench_report(bytes, records, times, output)
+}
+
+/// Write the fastest, slowest & some percentiles of the times, & the throughput for each
+fn write_bench_report(
+    bytes: usize,
+    records: u64,
+    mut times: Vec<std::time::Duration>,
+    mut output: impl Write,
+) -> Result<()> {
+    times.sort();
+    writeln!(
+        output,
+        "{} bytes, {} records, {} iterations",
+        bytes,
+        records,
+        times.len()
+    )?;
+    writeln!(output, "\tseconds\tMB/s")?;
+    if times.is_empty() {
+        return Ok(());
+    }
+    for (name, percentile) in [
+        ("fastest", 0.),
+        ("p50", 0.5),
+        ("p90", 0.9),
+        ("p99", 0.99),
+        ("slowest", 1.),
+    ] {
+        // Nearest rank
+        let rank = ((percentile * times.len() as f64).ceil() as usize).max(1);
+        let seconds = times[rank - 1].as_secs_f64();
+        writeln!(
+            output,
+            "{}\t{:.3}\t{:.1}",
+            name,
+            seconds,
+            bytes as f64 / 1e6 / seconds.max(1e-9)
+        )?;
+    }
+    Ok(())
+}
+
+/// What `--sort` sorts the lines by
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+struct SortKey {
+    /// Sort by this field (starting at 1), or the whole line
+    field: Option<usize>,
+    /// What separates the fields
+    delimiter: u8,
+}
+
+impl SortKey {
+    fn key<'a>(&self, line: &'a [u8]) -> &'a [u8] {
+        match self.field {
+            None => line,
+            Some(field) => line
+                .split(|&b| b == self.delimiter)
+                .nth(field - 1)
+                .unwrap_or(b""),
+        }
+    }
+}
+
+/// Lines are sorted in memory until there are this many bytes, then written to a temporary file
+const SORT_BUFFER_BYTES: usize = 64 * 1024 * 1024;
+
+/// Used to give every temporary sort file a different name
+static SORT_RUNS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Wraps the output, sorting the lines (by `key`). The sort is stable.
+///
+/// Lines are kept in memory. When there are too many, they are sorted & written to a temporary
+/// file, & all the files are merged at the end. Nothing is written until `flush`, which writes
+/// all the lines so far. Every line is written with a newline, even if the last one didn't have
+/// one.
+struct SortedLines<W: Write> {
+    inner: W,
+    key: SortKey,
+    /// The current line, which isn't complete yet
+    line: Vec<u8>,
+    /// Lines in memory, without the newlines
+    lines: Vec<Vec<u8>>,
+    /// Total size of `lines`
+    buffered_bytes: usize,
+    max_buffered_bytes: usize,
+    /// Temporary files of sorted lines
+    runs: Vec<std::path::PathBuf>,
+}
+
+impl<W: Write> SortedLines<W> {
+    fn new(inner: W, key: SortKey, max_buffered_bytes: usize) -> Self {
+        SortedLines {
+            inner,
+            key,
+            line: vec![],
+            lines: vec![],
+            buffered_bytes: 0,
+            max_buffered_bytes,
+            runs: vec![],
+        }
+    }
+
+    fn end_line(&mut self) -> std::io::Result<()> {
+        let line = std::mem::take(&mut self.line);
+        self.buffered_bytes += line.len();
+        self.lines.push(line);
+        if self.buffered_bytes >= self.max_buffered_bytes {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    /// Sort the lines in memory, and write them to a new temporary file
+    fn write_run(&mut self) -> std::io::Result<()> {
+        let key = self.key;
+        self.lines.sort_by(|a, b| key.key(a).cmp(key.key(b)));
+        let path = std::env::temp_dir().join(format!(
+            "anglosaxon-sort-{}-{}",
+            std::process::id(),
+            SORT_RUNS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        self.runs.push(path);
+        for line in self.lines.drain(..) {
+            file.write_all(&line)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+
+    /// Merge the temporary files & the lines in memory, and write them all
+    fn write_sorted(&mut self) -> std::io::Result<()> {
+        let key = self.key;
+        self.lines.sort_by(|a, b| key.key(a).cmp(key.key(b)));
+        self.buffered_bytes = 0;
+        let mut sources: Vec<Box<dyn Iterator<Item = std::io::Result<Vec<u8>>>>> = vec![];
+        for path in self.runs.iter() {
+            let file = std::io::BufReader::new(std::fs::File::open(path)?);
+            sources.push(Box::new(file.split(b'\n')));
+        }
+        sources.push(Box::new(
+            std::mem::take(&mut self.lines).into_iter().map(Ok),
+        ));
+
+        // Smallest key first, & for equal keys, the earliest source, so the sort is stable
+        let mut heads = std::collections::BinaryHeap::new();
+        for (i, source) in sources.iter_mut().enumerate() {
+            if let Some(line) = source.next() {
+                let line = line?;
+                heads.push(std::cmp::Reverse((key.key(&line).to_vec(), i, line)));
+            }
+        }
+        while let Some(std::cmp::Reverse((_, i, line))) = heads.pop() {
+            self.inner.write_all(&line)?;
+            self.inner.write_all(b"\n")?;
+            if let Some(line) = sources[i].next() {
+                let line = line?;
+                heads.push(std::cmp::Reverse((key.key(&line).to_vec(), i, line)));
+            }
+        }
+
+        for path in self.runs.drain(..) {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for SortedLines<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while let Some(i) = rest.iter().position(|&b| b == b'\n') {
+            self.line.extend_from_slice(&rest[..i]);
+            self.end_line()?;
+            rest = &rest[i + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.line.is_empty() {
+            self.end_line()?;
+        }
+        self.write_sorted()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for SortedLines<W> {
+    /// Don't leave temporary files behind if there was an error
+    fn drop(&mut self) {
+        for path in self.runs.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Where an element being copied starts in the XML & the JSON
+type CopyStart = (usize, usize);
+
+/// Writes the XML & JSON of the elements being copied, & everything inside them, for `--copy` &
+/// `--copy-json`
+#[derive(Debug, Default)]
+struct Copier {
+    /// The XML since the outermost element being copied started
+    xml: String,
+    /// The same as JSON, `{"name":…,"attributes":{…},"children":[…]}`, where the children are
+    /// objects for elements & strings for text
+    json: String,
+    /// Each open element inside (or starting) a copy: its depth, where it starts in `xml` & `json`
+    /// if it's being copied itself, & its namespaces
+    open: Vec<(usize, Option<CopyStart>, Namespace)>,
+    /// Nothing has been written since the last start tag, so it can become `<tag/>`
+    empty: bool,
+    /// The last thing in `json` is a text string, which more text can be added to
+    json_text: bool,
+    /// Whitespace since the last tag, which is only added to `json` if more text follows
+    json_space: String,
+    /// Tags which are left out, with everything inside them, from `--copy-exclude`
+    exclude: Vec<String>,
+    /// The depth of the element being left out, if there is one
+    excluded: Option<usize>,
+    /// Sort the attributes, & normalise the whitespace, from `--canonical`
+    canonical: bool,
+}
+
+impl Copier {
+    /// Is this inside an element being copied (& not inside one being left out)?
+    fn is_copying(&self) -> bool {
+        !self.open.is_empty() && self.excluded.is_none()
+    }
+
+    /// Write the start tag. `copied` if this is an element being copied, rather than one inside it
+    fn start(
+        &mut self,
+        depth: usize,
+        copied: bool,
+        name: &OwnedName,
+        attributes: &[OwnedAttribute],
+        namespace: &Namespace,
+    ) -> std::fmt::Result {
+        if !copied && self.exclude.contains(&name.local_name) {
+            self.excluded = Some(depth);
+            return Ok(());
+        }
+        if self.open.is_empty() {
+            self.xml.clear();
+            self.json.clear();
+        } else if !self.json.ends_with('[') {
+            self.json.push(',');
+        }
+        let start = (self.xml.len(), self.json.len());
+        let mut sorted;
+        let attributes = if self.canonical {
+            sorted = attributes.to_vec();
+            sorted.sort_by_cached_key(|a| a.name.borrow().to_repr());
+            &sorted
+        } else {
+            attributes
+        };
+        let outer = self.open.last().map(|(_, _, namespace)| namespace);
+        write_start_tag(name, attributes, namespace, outer, &mut self.xml);
+        self.xml.push('>');
+        self.json.push_str("{\"name\":");
+        write_json_string(&name.borrow().to_repr(), &mut self.json)?;
+        self.json.push_str(",\"attributes\":{");
+        for (i, attr) in attributes.iter().enumerate() {
+            if i > 0 {
+                self.json.push(',');
+            }
+            write_json_string(&attr.name.borrow().to_repr(), &mut self.json)?;
+            self.json.push(':');
+            write_json_string(&attr.value, &mut self.json)?;
+        }
+        self.json.push_str("},\"children\":[");
+        self.json_text = false;
+        self.json_space.clear();
+
+        self.open
+            .push((depth, copied.then_some(start), namespace.clone()));
+        self.empty = true;
+        Ok(())
+    }
+
+    /// Write the end tag, if this element is being copied or is inside one. The XML & JSON of the
+    /// element, if it's being copied itself, start at the returned indices of `xml` & `json`
+    fn end(&mut self, depth: usize, name: &OwnedName) -> Option<CopyStart> {
+        if self.excluded == Some(depth) {
+            self.excluded = None;
+            return None;
+        }
+        if self.open.last().map(|(d, _, _)| *d) != Some(depth) {
+            return None;
+        }
+        if self.empty {
+            self.xml.pop();
+            self.xml.push_str("/>");
+        } else {
+            self.xml.push_str("</");
+            self.xml.push_str(&name.borrow().to_repr());
+            self.xml.push('>');
+        }
+        self.json.push_str("]}");
+        self.empty = false;
+        self.json_text = false;
+        self.json_space.clear();
+        self.open.pop().and_then(|(_, start, _)| start)
+    }
+
+    /// Write the text. Whitespace between tags (e.g. indentation) is left out of the JSON
+    fn text(&mut self, text: &str) -> std::fmt::Result {
+        if !self.is_copying() || (self.canonical && text.trim().is_empty()) {
+            return Ok(());
+        }
+        let collapsed;
+        let text = if self.canonical {
+            collapsed = collapse_whitespace(text, self.xml.ends_with(' '));
+            collapsed.as_str()
+        } else {
+            text
+        };
+        write_xml_escaped(text, &mut self.xml);
+        self.empty = false;
+        if self.json_text {
+            // Continue the string, e.g. after a CDATA section
+            self.json.pop();
+            let mut string = String::new();
+            write_json_string(text, &mut string)?;
+            self.json.push_str(&string[1..]);
+        } else if text.trim().is_empty() {
+            self.json_space.push_str(text);
+        } else {
+            if !self.json.ends_with('[') {
+                self.json.push(',');
+            }
+            let text = std::mem::take(&mut self.json_space) + text;
+            write_json_string(&text, &mut self.json)?;
+            self.json_text = true;
+        }
+        Ok(())
+    }
+
+    fn comment(&mut self, comment: &str) {
+        if self.is_copying() {
+            self.xml.push_str("<!--");
+            self.xml.push_str(comment);
+            self.xml.push_str("-->");
+            self.empty = false;
+        }
+    }
+
+    fn pi(&mut self, target: &str, data: Option<&str>) {
+        if self.is_copying() {
+            self.xml.push_str("<?");
+            self.xml.push_str(target);
+            if let Some(data) = data {
+                self.xml.push(' ');
+                self.xml.push_str(data);
+            }
+            self.xml.push_str("?>");
+            self.empty = false;
+        }
+    }
+}
+
+/// Write the start tag, without the final `>`. Only the namespaces which aren't declared by the
+/// `outer` element are declared
+fn write_start_tag(
+    name: &OwnedName,
+    attributes: &[OwnedAttribute],
+    namespace: &Namespace,
+    outer: Option<&Namespace>,
+    xml: &mut String,
+) {
+    xml.push('<');
+    xml.push_str(&name.borrow().to_repr());
+    for (prefix, uri) in namespace.0.iter() {
+        if prefix == "xml"
+            || prefix == "xmlns"
+            || outer.map_or(prefix.is_empty() && uri.is_empty(), |outer| {
+                outer.0.get(prefix) == Some(uri)
+            })
+        {
+            continue;
+        }
+        xml.push_str(" xmlns");
+        if !prefix.is_empty() {
+            xml.push(':');
+            xml.push_str(prefix);
+        }
+        xml.push_str("=\"");
+        write_xml_escaped(uri, xml);
+        xml.push('"');
+    }
+    for attr in attributes {
+        xml.push(' ');
+        xml.push_str(&attr.name.borrow().to_repr());
+        xml.push_str("=\"");
+        write_xml_escaped(&attr.value, xml);
+        xml.push('"');
+    }
+}
+
+/// The start tag of an open element, as one line, for the parser to be given again when carrying
+/// on part way through the input
+fn replayed_start_tag(
+    name: &OwnedName,
+    attributes: &[OwnedAttribute],
+    namespace: &Namespace,
+    outer: Option<&Namespace>,
+) -> String {
+    let mut xml = String::new();
+    write_start_tag(name, attributes, namespace, outer, &mut xml);
+    xml.push('>');
+    xml.replace('\n', "&#10;")
+        .replace('\r', "&#13;")
+        .replace('\t', "&#9;")
+}
+
+/// Replace each run of whitespace with one space. If `after_space`, whitespace at the start is
+/// removed
+fn collapse_whitespace(text: &str, after_space: bool) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut space = after_space;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !space {
+                collapsed.push(' ');
+            }
+            space = true;
+        } else {
+            collapsed.push(c);
+            space = false;
+        }
+    }
+    collapsed
+}
+
+/// Escape `&`, `<`, `>` & `"`, so the text can go in XML text or an attribute value
+fn write_xml_escaped(s: &str, output: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            c => output.push(c),
+        }
+    }
+}
+
+/// Collects the values of an attribute of child elements, for `--collect` in the `-e` of the
+/// parent
+#[derive(Debug)]
+struct Collector {
+    parent: String,
+    child: String,
+    what: Collect,
+    /// The values for each open parent element, innermost last
+    values: Vec<Vec<Rc<str>>>,
+}
+
+/// How many of each kind of buffer `Spares` keeps
+const SPARE_BUFFERS: usize = 64;
+
+/// The biggest buffer which `Spares` keeps, so one huge element doesn't keep its memory
+const SPARE_MAX_BYTES: usize = 64 * 1024;
+
+/// Buffers from elements which have ended, which are reused for the next ones, rather than
+/// allocating new ones for each element
+#[derive(Debug, Default)]
+struct Spares {
+    /// For TAG{text~REGEX}
+    text: Vec<String>,
+    /// For --collect etc.
+    values: Vec<Vec<Rc<str>>>,
+}
+
+impl Spares {
+    fn text(&mut self) -> String {
+        self.text.pop().unwrap_or_default()
+    }
+
+    fn values(&mut self) -> Vec<Rc<str>> {
+        self.values.pop().unwrap_or_default()
+    }
+
+    fn reuse_text(&mut self, mut text: String) {
+        if self.text.len() < SPARE_BUFFERS && text.capacity() <= SPARE_MAX_BYTES {
+            text.clear();
+            self.text.push(text);
+        }
+    }
+
+    fn reuse_values(&mut self, mut values: Vec<Rc<str>>) {
+        let bytes = values.capacity() * std::mem::size_of::<Rc<str>>();
+        if self.values.len() < SPARE_BUFFERS && bytes <= SPARE_MAX_BYTES {
+            values.clear();
+            self.values.push(values);
+        }
+    }
+}
+
+/// The longest string which an `Interner` keeps
+const INTERN_MAX_BYTES: usize = 64;
+
+/// How many strings an `Interner` keeps, before it starts again
+const INTERN_MAX_STRINGS: usize = 100_000;
+
+/// Shares one copy of strings which repeat, e.g. `k="highway"`, rather than allocating each time.
+/// Long strings aren't kept, & it's emptied when it's full, so lots of different values (e.g.
+/// names) don't use up memory
+#[derive(Debug, Default)]
+struct Interner {
+    strings: HashSet<Rc<str>>,
+}
+
+impl Interner {
+    fn intern(&mut self, s: &str) -> Rc<str> {
+        if s.len() > INTERN_MAX_BYTES {
+            return Rc::from(s);
+        }
+        if let Some(interned) = self.strings.get(s) {
+            return interned.clone();
+        }
+        if self.strings.len() >= INTERN_MAX_STRINGS {
+            self.strings.clear();
+        }
+        let interned: Rc<str> = Rc::from(s);
+        self.strings.insert(interned.clone());
+        interned
+    }
+}
+
+/// What a `Collector` collects from each child element
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Collect {
+    /// The value of this attribute
+    Attribute(String),
+    /// All the attributes, as a JSON object
+    JsonObject,
+    /// The key & value attributes, as an hstore `"KEY"=>"VALUE"` item, or a JSON object
+    /// `"KEY":"VALUE"` member. Children without the key are skipped, & a missing value is `NULL`
+    /// or `null`
+    KeyValue(KeyValueFormat, String, String),
+}
+
+/// How `Collect::KeyValue` writes each key & value
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum KeyValueFormat {
+    Hstore,
+    Json,
+}
+
+impl KeyValueFormat {
+    /// The key & value, as an item of an hstore or JSON object
+    fn item(&self, key: &str, value: Option<&str>) -> Result<String> {
+        let mut item = String::new();
+        match self {
+            KeyValueFormat::Hstore => {
+                write_hstore_string(key, &mut item);
+                item.push_str("=>");
+                match value {
+                    Some(value) => write_hstore_string(value, &mut item),
+                    None => item.push_str("NULL"),
+                }
+            }
+            KeyValueFormat::Json => {
+                write_json_string(key, &mut item)?;
+                item.push(':');
+                match value {
+                    Some(value) => write_json_string(value, &mut item)?,
+                    None => item.push_str("null"),
+                }
+            }
+        }
+        Ok(item)
+    }
+}
+
+impl Collector {
+    /// The collectors which these instructions need
+    fn for_instructions(instructions: &[Instruction]) -> Vec<Collector> {
+        let mut collectors: Vec<Collector> = vec![];
+        for instruction in instructions {
+            if let Instruction::EndTag { tag, actions } = instruction {
+                for action in actions {
+                    let (child, what) = match action {
+                        Action::Collect(child, attr, _, _) => {
+                            (child, Collect::Attribute(attr.clone()))
+                        }
+                        Action::CollectJson(child, Some(attr)) => {
+                            (child, Collect::Attribute(attr.clone()))
+                        }
+                        Action::CollectJson(child, None) => (child, Collect::JsonObject),
+                        Action::CollectHstore(child, key, value, _) => {
+                            let what = Collect::KeyValue(
+                                KeyValueFormat::Hstore,
+                                key.clone(),
+                                value.clone(),
+                            );
+                            (child, what)
+                        }
+                        Action::CollectJsonObject(child, key, value, _) => {
+                            let what =
+                                Collect::KeyValue(KeyValueFormat::Json, key.clone(), value.clone());
+                            (child, what)
+                        }
+                        _ => continue,
+                    };
+                    if !collectors.iter().any(|c| c.is(tag, child, &what)) {
+                        collectors.push(Collector {
+                            parent: tag.clone(),
+                            child: child.clone(),
+                            what,
+                            values: vec![],
+                        });
+                    }
+                }
+            }
+        }
+        collectors
+    }
+
+    fn is(&self, parent: &str, child: &str, what: &Collect) -> bool {
+        self.parent == parent && self.child == child && &self.what == what
+    }
+
+    /// The values collected for the current element, for the action in this context
+    fn values<'a>(ctx: &Context<'a>, child: &str, what: &Collect) -> Result<&'a [Rc<str>]> {
+        ctx.element
+            .and_then(|(parent, _)| ctx.collected.iter().find(|c| c.is(parent, child, what)))
+            .and_then(|c| c.values.last())
+            .map(|v| v.as_slice())
+            .ok_or_else(|| ctx.locate(anyhow!("--collect is only available for -e events")))
+    }
+}
+
+/// Write this string as a PostgreSQL hstore key or value, in quotes
+fn write_hstore_string(s: &str, output: &mut String) {
+    output.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+    output.push('"');
+}
+
+/// Write this string as a JSON string, with quotes
+fn write_json_string(s: &str, output: &mut impl std::fmt::Write) -> std::fmt::Result {
+    output.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => output.write_str("\\\"")?,
+            '\\' => output.write_str("\\\\")?,
+            '\n' => output.write_str("\\n")?,
+            '\r' => output.write_str("\\r")?,
+            '\t' => output.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(output, "\\u{:04x}", c as u32)?,
+            c => output.write_char(c)?,
+        }
+    }
+    output.write_char('"')
+}
+
+/// What actions have access to when an event happens
+struct Context<'a> {
+    /// Name of the current input file, `-` for stdin
+    filename: &'a str,
+
+    /// Where the event happened in the input, for error messages
+    position: TextPosition,
+
+    /// Byte offset in the input of the start tag of the current element
+    offset: Option<u64>,
+
+    /// Position of the current element among its siblings, & among its siblings with the same
+    /// name. Only for `-s`/`-e` events, if an action needs it
+    sibling_index: Option<(u64, u64)>,
+
+    /// The tag name & attributes of the current element. Only for `-s`/`-e` events, & the root
+    /// element for `-S`/`-E`
+    element: Option<(&'a str, &'a [OwnedAttribute])>,
+
+    /// Attributes & tag names of the ancestors of the current element, the root first.
+    /// `parent_attrs` is only filled in if an action needs it
+    parent_attrs: &'a [Vec<OwnedAttribute>],
+    parent_tags: &'a [Rc<str>],
+    /// Sibling indices of the ancestors, if an action needs them
+    parent_sibling_indices: &'a [(u64, u64)],
+
+    /// The data of the current processing instruction. Only for `-P` events
+    pi_data: Option<&'a str>,
+    /// The text of the current comment. Only for `--comment` events
+    comment: Option<&'a str>,
+    /// Only for `--doctype` events
+    doctype: Option<&'a Doctype>,
+    /// The text. Only for `--chars` events
+    text: Option<&'a str>,
+
+    /// The child attributes collected for `--collect`
+    collected: &'a [Collector],
+
+    /// The element as XML & as JSON. Only for the end tags of elements with `--copy`/`--copy-json`
+    copied: Option<(&'a str, &'a str)>,
+}
+
+impl<'a> Context<'a> {
+    /// The attributes of the current element
+    fn attributes(&self) -> Result<(&'a str, &'a [OwnedAttribute])> {
+        self.element.ok_or_else(|| {
+            self.locate(anyhow!(
+                "XML attributes are only available for -s, -e, -S & -E events"
+            ))
+        })
+    }
+
+    /// The tag & attributes of the ancestor `level` levels up
+    fn parent(&self, level: usize) -> Result<(&'a str, &'a [OwnedAttribute])> {
+        if level > self.parent_attrs.len() {
+            return Err(self.locate(anyhow!(
+                "Cannot get attribute {} level(s) up, the element is only nested {} deep",
+                level,
+                self.parent_attrs.len()
+            )));
+        }
+        let idx = self.parent_attrs.len() - level;
+        Ok((&*self.parent_tags[idx], &self.parent_attrs[idx]))
+    }
+
+    /// Where this happened, like `file.xml:1:15`
+    fn location(&self) -> String {
+        format!("{}:{}", self.filename, self.position)
+    }
+
+    /// The path to the current element
+    fn path(&self) -> String {
+        let mut path = element_path(self.parent_tags);
+        if let Some((tag, _)) = self.element {
+            if !path.ends_with('/') {
+                path.push('/');
+            }
+            path.push_str(tag);
+        }
+        path
+    }
+
+    /// Add where this happened to the error
+    fn locate(&self, error: anyhow::Error) -> anyhow::Error {
+        error.context(format!(
+            "At {}:{} in element {}",
+            self.filename,
+            self.position,
+            self.path()
+        ))
+    }
+
+    /// For `--debug-matches`, log what this event did
+    fn debug_match(&self, event: &str, result: &str) {
+        let (location, path) = (self.location(), self.path());
+        tracing::debug!(
+            target: DEBUG_MATCHES,
+            %location,
+            %path,
+            event,
+            result,
+            "{} {}\t{}\t{}",
+            location,
+            path,
+            event,
+            result
+        );
+    }
+}
+
+/// Do these actions use the current element's attributes? -S with these happen at the root element
+fn uses_attributes(actions: &[Action]) -> bool {
+    actions.iter().any(|a| {
+        matches!(
+            a,
+            Action::Attribute(..)
+                | Action::AttributeWithDefault(..)
+                | Action::Has(0, ..)
+                | Action::Require(0, _)
+                | Action::OptionalAttribute(0, ..)
+                | Action::Aggregate(_, 0, ..)
+                | Action::XPath(_)
+        ) || matches!(a, Action::Point(_, (x_level, _), (y_level, _)) if *x_level == 0 || *y_level == 0)
+    })
+}
+
+/// The element name to compare with the instructions' tags, which are lowercase with
+/// `--ignore-tag-case`
+fn tag_key(tag: &str, ignore_case: bool) -> Cow<'_, str> {
+    match ignore_case {
+        true => Cow::Owned(tag.to_lowercase()),
+        false => Cow::Borrowed(tag),
+    }
+}
+
+/// The element name, as in [`tag_key`]
+fn name_key(name: &OwnedName, ignore_case: bool) -> Cow<'_, OwnedName> {
+    match ignore_case {
+        true => Cow::Owned(OwnedName {
+            local_name: name.local_name.to_lowercase(),
+            ..name.clone()
+        }),
+        false => Cow::Borrowed(name),
+    }
+}
+
+/// The path to the current element, like `/osm/way/nd`
+fn element_path(tags: &[Rc<str>]) -> String {
+    if tags.is_empty() {
+        return "/".to_string();
+    }
+    tags.iter().fold(String::new(), |mut path, tag| {
+        path.push('/');
+        path.push_str(tag);
+        path
+    })
+}
+
+/// Parse this attribute value as its `--schema` column's type. Returns `None` if the record
+/// should be skipped
+fn schema_value<'a>(
+    config: &Config,
+    attr: &str,
+    value: Cow<'a, str>,
+    ctx: &Context,
+    summary: &mut Summary,
+) -> Result<Option<Cow<'a, str>>> {
+    let schema = match &config.schema {
+        Some(schema) => schema,
+        None => return Ok(Some(value)),
+    };
+    let (column, original) = match schema.coerce(attr, value, config.ignore_attr_case) {
+        Ok(value) => return Ok(Some(value)),
+        Err(invalid) => invalid,
+    };
+    let invalid = || {
+        format!(
+            "Value {:?} of the {} column isn't a valid {}",
+            original, column.name, column.column_type
+        )
+    };
+    match config.on_invalid {
+        InvalidValuePolicy::Error | InvalidValuePolicy::SkipRecord
+            if summary.reject(ctx, invalid)? =>
+        {
+            Ok(None)
+        }
+        InvalidValuePolicy::Error => Err(ctx.locate(anyhow!(invalid()))),
+        InvalidValuePolicy::Null => {
+            summary.warn(
+                format!(
+                    "Invalid {} values of the {} column were output as empty",
+                    column.column_type, column.name
+                ),
+                ctx.location(),
+            );
+            Ok(Some(Cow::Borrowed("")))
+        }
+        InvalidValuePolicy::SkipRecord => {
+            summary.warn(
+                format!(
+                    "Records with invalid {} values of the {} column were skipped",
+                    column.column_type, column.name
+                ),
+                ctx.location(),
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Run these actions, writing the output, & adding the numbers for `--aggregate` to `values`
+/// (nothing if the attribute is missing), & where each action's output ends to `ends`, if it's
+/// given. Returns false if this record should be skipped
+fn run_actions(
+    config: &Config,
+    actions: &[Action],
+    ctx: &Context,
+    output: &mut Vec<u8>,
+    values: &mut Vec<Option<f64>>,
+    mut ends: Option<&mut Vec<usize>>,
+    summary: &mut Summary,
+) -> Result<bool> {
+    let mut on_missing_attr = config.on_missing_attr;
+    for action in actions {
+        match action {
+            Action::RawString(s) => {
+                output.write_all(s.as_bytes())?;
+            }
+            Action::Attribute(attr, filters) => {
+                let (tag, attributes) = ctx.attributes()?;
+                match find_attr(attributes, attr, tag, config).map_err(|e| ctx.locate(e))? {
+                    Some(value) => {
+                        let value = filters.apply(value);
+                        let value = match schema_value(config, attr, value, ctx, summary)? {
+                            Some(value) => value,
+                            None => return Ok(false),
+                        };
+                        output.write_all(value.as_bytes())?;
+                    }
+                    None => {
+                        if !on_missing_attr.handle(attr, tag, attributes, ctx, summary)? {
+                            return Ok(false);
+                        }
+                    }
+                }
+            }
+            Action::AttributeWithDefault(attr, default, filters) => {
+                let (tag, attributes) = ctx.attributes()?;
+                let value = find_attr(attributes, attr, tag, config)
+                    .map_err(|e| ctx.locate(e))?
+                    .unwrap_or(Cow::Borrowed(default));
+                let value = filters.apply(value);
+                let value = match schema_value(config, attr, value, ctx, summary)? {
+                    Some(value) => value,
+                    None => return Ok(false),
+                };
+                output.write_all(value.as_bytes())?;
+            }
+            Action::ParentAttribute(level, attr, filters) => {
+                let (tag, attributes) = ctx.parent(*level)?;
+                match find_attr(attributes, attr, tag, config).map_err(|e| ctx.locate(e))? {
+                    Some(value) => {
+                        let value = filters.apply(value);
+                        let value = match schema_value(config, attr, value, ctx, summary)? {
+                            Some(value) => value,
+                            None => return Ok(false),
+                        };
+                        output.write_all(value.as_bytes())?;
+                    }
+                    None => {
+                        if !on_missing_attr.handle(attr, tag, attributes, ctx, summary)? {
+                            return Ok(false);
+                        }
+                    }
+                }
+            }
+            Action::ParentAttributeWithDefault(level, attr, default, filters) => {
+                let (tag, attributes) = ctx.parent(*level)?;
+                let value = find_attr(attributes, attr, tag, config)
+                    .map_err(|e| ctx.locate(e))?
+                    .unwrap_or(Cow::Borrowed(default));
+                let value = filters.apply(value);
+                let value = match schema_value(config, attr, value, ctx, summary)? {
+                    Some(value) => value,
+                    None => return Ok(false),
+                };
+                output.write_all(value.as_bytes())?;
+            }
+            Action::OnMissingAttr(policy) => {
+                on_missing_attr = *policy;
+            }
+            Action::GroupBy
+            | Action::Name(_)
+            | Action::LeafOnly
+            | Action::EmptyOnly
+            | Action::TextMatches(_)
+            | Action::NamespaceUri(_)
+            | Action::XPath(_) => {}
+            Action::OptionalAttribute(level, attr, prefix, suffix, filters) => {
+                let (tag, attributes) = if *level == 0 {
+                    ctx.attributes()?
+                } else {
+                    ctx.parent(*level)?
+                };
+                if let Some(value) =
+                    find_attr(attributes, attr, tag, config).map_err(|e| ctx.locate(e))?
+                {
+                    output.write_all(prefix.as_bytes())?;
+                    output.write_all(filters.apply(value).as_bytes())?;
+                    output.write_all(suffix.as_bytes())?;
+                }
+            }
+            Action::Collect(child, attr, separator, filters) => {
+                let values = Collector::values(ctx, child, &Collect::Attribute(attr.clone()))?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        output.write_all(separator.as_bytes())?;
+                    }
+                    output.write_all(filters.apply(&**value).as_bytes())?;
+                }
+            }
+            Action::CollectJson(child, attr) => {
+                let what = match attr {
+                    Some(attr) => Collect::Attribute(attr.clone()),
+                    None => Collect::JsonObject,
+                };
+                let values = Collector::values(ctx, child, &what)?;
+                let mut json = String::from("[");
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    if attr.is_some() {
+                        write_json_string(value, &mut json)?;
+                    } else {
+                        // Already a JSON object
+                        json.push_str(value);
+                    }
+                }
+                json.push(']');
+                output.write_all(json.as_bytes())?;
+            }
+            Action::CollectHstore(child, key, value, filters) => {
+                let what = Collect::KeyValue(KeyValueFormat::Hstore, key.clone(), value.clone());
+                let hstore = Collector::values(ctx, child, &what)?.join(", ");
+                output.write_all(filters.apply(hstore).as_bytes())?;
+            }
+            Action::CollectJsonObject(child, key, value, filters) => {
+                let what = Collect::KeyValue(KeyValueFormat::Json, key.clone(), value.clone());
+                let object = format!("{{{}}}", Collector::values(ctx, child, &what)?.join(","));
+                output.write_all(filters.apply(object).as_bytes())?;
+            }
+            Action::Require(level, attr) => {
+                let (tag, attributes) = if *level == 0 {
+                    ctx.attributes()?
+                } else {
+                    ctx.parent(*level)?
+                };
+                if !attributes
+                    .iter()
+                    .any(|a| attr_name_matches(&a.name, attr, config.ignore_attr_case))
+                {
+                    if on_missing_attr == MissingAttrPolicy::Warn {
+                        summary.warn(
+                            format!(
+                                "Skipped records for element {} without required attribute {}",
+                                tag, attr
+                            ),
+                            ctx.location(),
+                        );
+                    }
+                    return Ok(false);
+                }
+            }
+            Action::Copy | Action::CopyJson => {
+                let (xml, json) = ctx.copied.ok_or_else(|| {
+                    ctx.locate(anyhow!(
+                        "--copy & --copy-json can only be used after a -s/-e"
+                    ))
+                })?;
+                let copied = if action == &Action::Copy { xml } else { json };
+                output.write_all(copied.as_bytes())?;
+            }
+            Action::Ancestor(predicate) => {
+                let mut ancestors = ctx.parent_tags.iter().zip(ctx.parent_attrs);
+                if !ancestors.any(|(tag, attributes)| predicate.matches(tag, attributes, config)) {
+                    return Ok(false);
+                }
+            }
+            Action::Has(level, attr, if_true, if_false) => {
+                let (_, attributes) = if *level == 0 {
+                    ctx.attributes()?
+                } else {
+                    ctx.parent(*level)?
+                };
+                let has = attributes
+                    .iter()
+                    .any(|a| attr_name_matches(&a.name, attr, config.ignore_attr_case));
+                output.write_all(if has { if_true } else { if_false }.as_bytes())?;
+            }
+            Action::Aggregate(_, level, attr, filters) => {
+                let (tag, attributes) = if *level == 0 {
+                    ctx.attributes()?
+                } else {
+                    ctx.parent(*level)?
+                };
+                match find_attr(attributes, attr, tag, config).map_err(|e| ctx.locate(e))? {
+                    Some(value) => {
+                        let value = filters.apply(value);
+                        let number = value.trim().parse().map_err(|_| {
+                            ctx.locate(anyhow!(
+                                "Attribute {} is {:?}, which isn't a number, for --aggregate",
+                                attr,
+                                value
+                            ))
+                        })?;
+                        values.push(Some(number));
+                    }
+                    None => {
+                        if !on_missing_attr.handle(attr, tag, attributes, ctx, summary)? {
+                            return Ok(false);
+                        }
+                        values.push(None);
+                    }
+                }
+            }
+            Action::Point(format, x, y) => {
+                let mut coordinates = vec![];
+                for (level, attr) in [x, y] {
+                    let (tag, attributes) = if *level == 0 {
+                        ctx.attributes()?
+                    } else {
+                        ctx.parent(*level)?
+                    };
+                    match find_attr(attributes, attr, tag, config).map_err(|e| ctx.locate(e))? {
+                        Some(value) => {
+                            let number: f64 = value.trim().parse().map_err(|_| {
+                                ctx.locate(anyhow!(
+                                    "Attribute {} is {:?}, which isn't a number, for a point",
+                                    attr,
+                                    value
+                                ))
+                            })?;
+                            coordinates.push(number);
+                        }
+                        None => {
+                            if !on_missing_attr.handle(attr, tag, attributes, ctx, summary)? {
+                                return Ok(false);
+                            }
+                        }
+                    }
+                }
+                // Nothing is output for a point with a missing coordinate
+                if let [x, y] = coordinates[..] {
+                    format.write_point(x, y, output)?;
+                }
+            }
+            Action::Now(format) => {
+                write!(output, "{}", chrono::Local::now().format(format))?;
+            }
+            Action::Uuid(version) => {
+                let uuid = match version {
+                    UuidVersion::V4 => uuid::Uuid::new_v4(),
+                    UuidVersion::V7 => uuid::Uuid::now_v7(),
+                };
+                write!(output, "{}", uuid.hyphenated())?;
+            }
+            Action::SiblingIndex(same_name) => {
+                let (all, same) = ctx.sibling_index.ok_or_else(|| {
+                    ctx.locate(anyhow!(
+                        "--sibling-index is only available for -s/-e events"
+                    ))
+                })?;
+                write!(output, "{}", if *same_name { same } else { all })?;
+            }
+            Action::Counter(_, count) => {
+                count.set(count.get() + 1);
+                write!(output, "{}", count.get())?;
+            }
+            Action::Matched(_, count) => {
+                write!(output, "{}", count.get())?;
+            }
+            Action::Offset => {
+                let offset = ctx.offset.ok_or_else(|| {
+                    ctx.locate(anyhow!("--offset is only available for -s/-e events"))
+                })?;
+                write!(output, "{}", offset)?;
+            }
+            Action::PiData => {
+                let data = ctx.pi_data.ok_or_else(|| {
+                    ctx.locate(anyhow!("--pi-data is only available for -P events"))
+                })?;
+                output.write_all(data.as_bytes())?;
+            }
+            Action::DoctypeName | Action::PublicId | Action::SystemId => {
+                let doctype = ctx.doctype.ok_or_else(|| {
+                    ctx.locate(anyhow!(
+                        "--doctype-name/--public-id/--system-id are only available for --doctype events"
+                    ))
+                })?;
+                let value = match action {
+                    Action::DoctypeName => Some(&doctype.name),
+                    Action::PublicId => doctype.public_id.as_ref(),
+                    _ => doctype.system_id.as_ref(),
+                };
+                output.write_all(value.map_or("", |v| v.as_str()).as_bytes())?;
+            }
+            Action::Text(filters) => {
+                let text = ctx.text.ok_or_else(|| {
+                    ctx.locate(anyhow!("--text is only available for --chars events"))
+                })?;
+                output.write_all(filters.apply(text).as_bytes())?;
+            }
+            Action::CommentText => {
+                let comment = ctx.comment.ok_or_else(|| {
+                    ctx.locate(anyhow!(
+                        "--comment-text is only available for --comment events"
+                    ))
+                })?;
+                output.write_all(comment.as_bytes())?;
+            }
+            Action::Filename => {
+                output.write_all(ctx.filename.as_bytes())?;
+            }
+            Action::Basename => {
+                let basename = std::path::Path::new(ctx.filename)
+                    .file_name()
+                    .map_or(Cow::Borrowed(ctx.filename), |f| f.to_string_lossy());
+                output.write_all(basename.as_bytes())?;
+            }
+        }
+        if let Some(ends) = ends.as_mut() {
+            ends.push(output.len());
+        }
+    }
+    Ok(true)
+}
+
+/// Run the actions for one matched instruction, the `index`th. The output is buffered, so nothing
+/// is written if the record is skipped, & grouped instructions only count it. Returns whether the
+/// record was written
+#[allow(clippy::too_many_arguments)]
+fn run_instruction(
+    config: &Config,
+    actions: &[Action],
+    index: usize,
+    ctx: &Context,
+    record: &mut Vec<u8>,
+    output: &mut impl Write,
+    summary: &mut Summary,
+    groups: Option<&mut Groups>,
+) -> Result<bool> {
+    if summary.timing.is_none() {
+        return output_record(config, actions, ctx, record, output, summary, groups);
+    }
+    let started = std::time::Instant::now();
+    let writing = stage_time(&WRITE_TIME);
+    let result = output_record(config, actions, ctx, record, output, summary, groups);
+    // Writing is counted separately
+    let elapsed = started
+        .elapsed()
+        .saturating_sub(stage_time(&WRITE_TIME).saturating_sub(writing));
+    let timing = summary.timing.as_mut().unwrap();
+    if timing.instructions.len() <= index {
+        timing.instructions.resize(index + 1, Duration::ZERO);
+    }
+    timing.instructions[index] += elapsed;
+    result
+}
+
+/// The actions of `run_instruction`, & writing the record
+fn output_record(
+    config: &Config,
+    actions: &[Action],
+    ctx: &Context,
+    record: &mut Vec<u8>,
+    output: &mut impl Write,
+    summary: &mut Summary,
+    groups: Option<&mut Groups>,
+) -> Result<bool> {
+    record.clear();
+    let mut values = vec![];
+    let mut ends = vec![];
+    let record_ends = config.record_format.is_some().then_some(&mut ends);
+    if !run_actions(
+        config,
+        actions,
+        ctx,
+        record,
+        &mut values,
+        record_ends,
+        summary,
+    )? {
+        return Ok(false);
+    }
+    if let Some(geojson) = &config.geojson {
+        return write_feature(config, geojson, actions, ctx, output, summary);
+    }
+    if let Some(format) = config.record_format {
+        format.write(&record_fields(config, actions, &ends, record, ctx)?, output)?;
+        return Ok(true);
+    }
+    match groups {
+        None => {
+            output.write_all(record)?;
+            Ok(true)
+        }
+        Some(groups) => {
+            // Without --group-by, all events are aggregated together
+            if !actions.contains(&Action::GroupBy) {
+                record.clear();
+            }
+            if !groups.contains_key(record.as_slice()) {
+                summary.grouped_bytes += record.len()
+                    + std::mem::size_of::<Group>()
+                    + values.len() * std::mem::size_of::<Aggregator>();
+            }
+            let group = groups.entry(record.clone()).or_insert_with(|| Group {
+                count: 0,
+                aggregates: actions
+                    .iter()
+                    .filter_map(|a| match a {
+                        Action::Aggregate(reducer, ..) => Some(Aggregator::new(*reducer)),
+                        _ => None,
+                    })
+                    .collect(),
+            });
+            group.count += 1;
+            for (aggregate, value) in group.aggregates.iter_mut().zip(values) {
+                if let Some(value) = value {
+                    aggregate.add(value);
+                }
+            }
+            Ok(false)
+        }
+    }
+}
+
+/// Write the element as a GeoJSON point feature, on one line, with the `-v`/`-V` attributes as
+/// its properties, or all its other attributes if there aren't any. Returns false, with a
+/// warning, if it doesn't have valid coordinates
+fn write_feature(
+    config: &Config,
+    geojson: &GeoJson,
+    actions: &[Action],
+    ctx: &Context,
+    output: &mut impl Write,
+    summary: &mut Summary,
+) -> Result<bool> {
+    let (tag, attributes) = ctx.attributes()?;
+    let attr = |attr: &str| find_attr(attributes, attr, tag, config).map_err(|e| ctx.locate(e));
+    let coordinate = |name: &str| -> Result<Option<f64>> {
+        Ok(attr(name)?
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|v| v.is_finite()))
+    };
+    let (lat, lon) = match (
+        coordinate(&geojson.lat_attr)?,
+        coordinate(&geojson.lon_attr)?,
+    ) {
+        (Some(lat), Some(lon)) => (lat, lon),
+        _ => {
+            summary.warn(
+                format!(
+                    "Skipped {} without a valid {} & {} for GeoJSON",
+                    tag, geojson.lat_attr, geojson.lon_attr
+                ),
+                ctx.location(),
+            );
+            return Ok(false);
+        }
+    };
+
+    let mut json = format!(
+        "{{\"type\":\"Feature\",\"geometry\":{{\"type\":\"Point\",\"coordinates\":[{},{}]}},\"properties\":{{",
+        lon, lat
+    );
+    let mut properties = vec![];
+    for action in actions {
+        let (name, value) = match action {
+            Action::Attribute(name, filters) => (name, attr(name)?.map(|v| filters.apply(v))),
+            Action::AttributeWithDefault(name, default, filters) => (
+                name,
+                Some(attr(name)?.map_or_else(|| default.into(), |v| filters.apply(v))),
+            ),
+            _ => continue,
+        };
+        properties.push((Cow::from(name.as_str()), value));
+    }
+    if properties.is_empty() {
+        for a in attributes {
+            let name = a.name.borrow().to_repr();
+            if ![&geojson.lat_attr, &geojson.lon_attr].contains(&&name) {
+                properties.push((name.into(), Some(a.value.as_str().into())));
+            }
+        }
+    }
+    for (i, (name, value)) in properties.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_json_string(name, &mut json)?;
+        json.push(':');
+        match value {
+            Some(value) => write_json_string(value, &mut json)?,
+            None => json.push_str("null"),
+        }
+    }
+    json.push_str("}}\n");
+    output.write_all(json.as_bytes())?;
+    Ok(true)
+}
+
+/// One field of a record, for `--json-record` & `--output-format kv`
+struct Field<'a> {
+    /// How many levels up the element is, for `../` attributes
+    level: usize,
+    name: &'a str,
+    /// The action's output, or `None` if the attribute is missing
+    value: Option<String>,
+    /// The value is JSON already, e.g. a number, rather than a string
+    json: bool,
+}
+
+/// The fields of the record: each `-v`/`-V` attribute, & each action named with `--name`, with
+/// that action's output as its value. `ends` is where each action's output ends in `record`
+fn record_fields<'a>(
+    config: &Config,
+    actions: &'a [Action],
+    ends: &[usize],
+    record: &[u8],
+    ctx: &Context,
+) -> Result<Vec<Field<'a>>> {
+    let mut fields = vec![];
+    let mut start = 0;
+    for (i, (action, end)) in actions.iter().zip(ends).enumerate() {
+        let text = String::from_utf8_lossy(&record[start..*end]);
+        start = *end;
+        let (level, attr, optional) = match action {
+            Action::Attribute(attr, _) => (0, Some(attr), true),
+            Action::AttributeWithDefault(attr, ..) => (0, Some(attr), false),
+            Action::ParentAttribute(level, attr, _) => (*level, Some(attr), true),
+            Action::ParentAttributeWithDefault(level, attr, ..) => (*level, Some(attr), false),
+            _ => (0, None, false),
+        };
+        let name = match (actions.get(i + 1), attr) {
+            (Some(Action::Name(name)), _) => name,
+            (_, Some(attr)) => attr,
+            _ => continue,
+        };
+        let column_type = attr
+            .and_then(|attr| {
+                config
+                    .schema
+                    .as_ref()?
+                    .column(attr, config.ignore_attr_case)
+            })
+            .map(|c| c.column_type);
+        let missing = optional && {
+            let (tag, attributes) = match level {
+                0 => ctx.attributes()?,
+                level => ctx.parent(level)?,
+            };
+            find_attr(attributes, attr.unwrap(), tag, config)?.is_none()
+        };
+        let (value, json) = match (action, column_type) {
+            _ if missing => (None, false),
+            (_, Some(ColumnType::Int)) => (text.parse::<i64>().ok().map(|n| n.to_string()), true),
+            (_, Some(ColumnType::Float)) => (text.parse::<f64>().ok().map(|n| n.to_string()), true),
+            (_, Some(ColumnType::Bool)) => ((!text.is_empty()).then(|| text.to_string()), true),
+            (
+                Action::Counter(..)
+                | Action::Matched(..)
+                | Action::Offset
+                | Action::SiblingIndex(_)
+                | Action::CollectJson(..)
+                | Action::CopyJson,
+                _,
+            ) if !text.is_empty() => (Some(text.to_string()), true),
+            _ => (Some(text.to_string()), false),
+        };
+        fields.push(Field {
+            level,
+            name,
+            value,
+            json,
+        });
+    }
+    Ok(fields)
+}
+
+/// How `--json-record` & `--output-format kv` write each record
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum RecordFormat {
+    /// A JSON object, with `../` attributes in nested `"parent"` objects
+    Json,
+    /// `NAME=VALUE` pairs, separated by spaces, with the values quoted for a POSIX shell
+    Kv,
+    /// `NAME=VALUE` pairs, like `Kv`, with the values in double quotes if they need it
+    Logfmt,
+}
+
+impl RecordFormat {
+    /// The option which sets this format, for error messages
+    fn option(&self) -> &'static str {
+        match self {
+            RecordFormat::Json => "--json-record",
+            RecordFormat::Kv => "--output-format kv",
+            RecordFormat::Logfmt => "--output-format logfmt",
+        }
+    }
+
+    /// Write the record, on one line
+    fn write(&self, fields: &[Field], output: &mut impl Write) -> Result<()> {
+        let mut line = String::new();
+        match self {
+            RecordFormat::Json => write_json_object(fields, 0, &mut line)?,
+            RecordFormat::Kv | RecordFormat::Logfmt => {
+                for (i, field) in fields.iter().enumerate() {
+                    if i > 0 {
+                        line.push(' ');
+                    }
+                    line.push_str(&flat_key(field));
+                    line.push('=');
+                    match (self, &field.value) {
+                        (RecordFormat::Kv, value) => {
+                            line.push_str(&shell_quote(value.as_deref().unwrap_or_default()))
+                        }
+                        // A missing attribute is an empty value, which isn't the same as ""
+                        (_, None) => {}
+                        (_, Some(value)) => write_logfmt_value(value, &mut line),
+                    }
+                }
+            }
+        }
+        line.push('\n');
+        output.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Write the value for logfmt, in double quotes, with `\"`, `\\`, `\n` etc. escapes, if it's
+/// empty or has spaces, `=`, `"` or control characters
+fn write_logfmt_value(value: &str, line: &mut String) {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c == ' ' || c == '=' || c == '"' || c == '\\' || c.is_control())
+    {
+        line.push_str(value);
+        return;
+    }
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\t' => line.push_str("\\t"),
+            '\r' => line.push_str("\\r"),
+            c if c.is_control() => line.push_str(&format!("\\u{:04x}", c as u32)),
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// The field's name with a `parent.` for each level up, & anything other than letters, digits,
+/// `_`, `-` & `.` replaced with `_`, so it's easy to grep & split
+fn flat_key(field: &Field) -> String {
+    let mut key = "parent.".repeat(field.level);
+    key.extend(field.name.chars().map(|c| {
+        if c.is_alphanumeric() || "_-.".contains(c) {
+            c
+        } else {
+            '_'
+        }
+    }));
+    key
+}
+
+/// The fields at this parent level, then the ones further up in a `"parent"` object
+fn write_json_object(fields: &[Field], level: usize, json: &mut String) -> std::fmt::Result {
+    json.push('{');
+    let mut first = true;
+    for field in fields.iter().filter(|f| f.level == level) {
+        if !first {
+            json.push(',');
+        }
+        first = false;
+        write_json_string(field.name, json)?;
+        json.push(':');
+        match &field.value {
+            None => json.push_str("null"),
+            Some(value) if field.json => json.push_str(value),
+            Some(value) => write_json_string(value, json)?,
+        }
+    }
+    if fields.iter().any(|f| f.level > level) {
+        if !first {
+            json.push(',');
+        }
+        json.push_str("\"parent\":");
+        write_json_object(fields, level + 1, json)?;
+    }
+    json.push('}');
+    Ok(())
+}
+
+/// Write the groups for an instruction, with `--group-by` as `OUTPUT<TAB>COUNT`, then the
+/// aggregates, or without, only the aggregates. Returns how many lines were written
+fn write_groups(grouped: bool, groups: Groups, output: &mut impl Write) -> Result<u64> {
+    let mut lines = 0;
+    for (key, group) in groups {
+        let mut columns = vec![];
+        if grouped {
+            columns.push(String::from_utf8_lossy(&key).into_owned());
+            columns.push(group.count.to_string());
+        }
+        for aggregate in group.aggregates {
+            columns.push(match aggregate.result() {
+                None => String::new(),
+                // 3.0 is written as 3
+                Some(n) if n.fract() == 0. && n.abs() < 2f64.powi(53) => format!("{}", n as i64),
+                Some(n) => n.to_string(),
+            });
+        }
+        writeln!(output, "{}", columns.join("\t"))?;
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+/// Skip a UTF-8 byte order mark at the start of the input, which xml-rs can't handle. Returns
+/// how many bytes were skipped
+fn skip_bom(input: &mut impl BufRead) -> Result<u64> {
+    if input.fill_buf()?.starts_with(b"\xEF\xBB\xBF") {
+        input.consume(3);
+        Ok(3)
+    } else {
+        Ok(0)
+    }
+}
+
+/// Skip everything before the first `<`. Returns how many bytes were skipped
+fn skip_to_first_tag(input: &mut impl BufRead) -> Result<u64> {
+    let mut junk = 0;
+    loop {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let (len, found) = match buf.iter().position(|&b| b == b'<') {
+            Some(i) => (i, true),
+            None => (buf.len(), false),
+        };
+        input.consume(len);
+        junk += len as u64;
+        if found {
+            break;
+        }
+    }
+    Ok(junk)
+}
+
+/// The `<!DOCTYPE …>` declaration of a document
+#[derive(Debug, PartialEq, Eq)]
+struct Doctype {
+    /// Name of the root element
+    name: String,
+    public_id: Option<String>,
+    system_id: Option<String>,
+}
+
+impl std::fmt::Display for Doctype {
+    /// The declaration, without any internal subset
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<!DOCTYPE {}", self.name)?;
+        match (&self.public_id, &self.system_id) {
+            (Some(public_id), system_id) => {
+                write!(f, " PUBLIC \"{}\"", public_id)?;
+                if let Some(system_id) = system_id {
+                    write!(f, " \"{}\"", system_id)?;
+                }
+            }
+            (None, Some(system_id)) => write!(f, " SYSTEM \"{}\"", system_id)?,
+            (None, None) => {}
+        }
+        write!(f, ">")
+    }
+}
+
+impl FromStr for Doctype {
+    type Err = anyhow::Error;
+    /// Parse the declaration, e.g. `<!DOCTYPE html PUBLIC "pub" "sys" [ … ]>`. The internal subset
+    /// (in `[]`) is ignored
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        /// Take a quoted ID from the start of `rest`, if there is one
+        fn quoted(rest: &mut &str) -> Option<String> {
+            *rest = rest.trim_start();
+            let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+            let end = rest[1..].find(quote)? + 1;
+            let id = rest[1..end].to_string();
+            *rest = &rest[end + 1..];
+            Some(id)
+        }
+
+        let mut rest = s
+            .strip_prefix("<!DOCTYPE")
+            .ok_or_else(|| anyhow!("Invalid DOCTYPE {:?}", s))?
+            .trim_start();
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '[' || c == '>')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_string();
+        rest = rest[name_end..].trim_start();
+
+        let (public_id, system_id) = if let Some(mut after) = rest.strip_prefix("SYSTEM") {
+            (None, quoted(&mut after))
+        } else if let Some(mut after) = rest.strip_prefix("PUBLIC") {
+            (quoted(&mut after), quoted(&mut after))
+        } else {
+            (None, None)
+        };
+
+        Ok(Doctype {
+            name,
+            public_id,
+            system_id,
+        })
+    }
+}
+
+/// The prolog is only read this far looking for the DOCTYPE
+const MAX_PROLOG_BYTES: usize = 1024 * 1024;
+
+/// Read the prolog of the document (everything before the root element), & parse the DOCTYPE in
+/// it, if there is one. The bytes read are returned, so that they can still be parsed as XML.
+///
+/// xml-rs doesn't have DOCTYPE events, & never fetches external DTDs.
+fn read_prolog(input: &mut impl BufRead) -> Result<(Vec<u8>, Option<Doctype>)> {
+    /// Read one byte, adding it to the prolog
+    fn next(input: &mut impl BufRead, prolog: &mut Vec<u8>) -> Result<Option<u8>> {
+        if prolog.len() >= MAX_PROLOG_BYTES {
+            return Ok(None);
+        }
+        let byte = input.fill_buf()?.first().copied();
+        if let Some(byte) = byte {
+            input.consume(1);
+            prolog.push(byte);
+        }
+        Ok(byte)
+    }
+    /// Read up to & including `end`. Returns false if the input ended first
+    fn read_past(input: &mut impl BufRead, prolog: &mut Vec<u8>, end: &[u8]) -> Result<bool> {
+        while !prolog.ends_with(end) {
+            if next(input, prolog)?.is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    let mut prolog = vec![];
+    loop {
+        match next(input, &mut prolog)? {
+            Some(b) if b.is_ascii_whitespace() => continue,
+            Some(b'<') => {}
+            _ => break,
+        }
+        let start = prolog.len() - 1;
+        match (next(input, &mut prolog)?, next(input, &mut prolog)?) {
+            (Some(b'?'), _) => {
+                if !read_past(input, &mut prolog, b"?>")? {
+                    break;
+                }
+            }
+            (Some(b'!'), Some(b'-')) => {
+                if !read_past(input, &mut prolog, b"-->")? {
+                    break;
+                }
+            }
+            (Some(b'!'), Some(b'D')) => {
+                // The DOCTYPE ends at the first `>` which isn't quoted or in the internal subset
+                let mut quote = None;
+                let mut depth = 0;
+                loop {
+                    match (next(input, &mut prolog)?, quote) {
+                        (None, _) => return Ok((prolog, None)),
+                        (Some(b), Some(q)) if b == q => quote = None,
+                        (Some(_), Some(_)) => {}
+                        (Some(b @ (b'"' | b'\'')), None) => quote = Some(b),
+                        (Some(b'['), None) => depth += 1,
+                        (Some(b']'), None) => depth -= 1,
+                        (Some(b'>'), None) if depth <= 0 => break,
+                        (Some(_), None) => {}
+                    }
+                }
+                let doctype = String::from_utf8_lossy(&prolog[start..]).parse()?;
+                return Ok((prolog, Some(doctype)));
+            }
+            // The root element
+            _ => break,
+        }
+    }
+    Ok((prolog, None))
+}
+
+/// The input that xml-rs reads from. The first part is for synthetic XML when recovering from
+/// errors, & the last part is the end tag of `--fragment`'s root element
+type Source<R> =
+    std::io::Chain<std::io::Chain<Cursor<Vec<u8>>, CountingReader<R>>, Cursor<Vec<u8>>>;
+
+/// Recover from this XML error, by skipping to the next `<` in the input, and carrying on from
+/// there with a new parser. The new parser is first given start tags for the elements which are
+/// currently open, so that it's in the same state.
+///
+/// Returns the new reader, and true if the end of the input was reached instead.
+fn recover<R: Read>(
+    reader: EventReader<Source<R>>,
+    parent_tags: &[Rc<str>],
+    filename: &str,
+    error: xml::reader::Error,
+    summary: &mut Summary,
+) -> Result<(EventReader<Source<R>>, bool)> {
+    let (start, end) = reader.into_inner().into_inner();
+    let (_synthetic, mut source) = start.into_inner();
+    let error_offset = source.bytes_read;
+
+    let mut byte = [0u8];
+    let found = loop {
+        if source.read(&mut byte)? == 0 {
+            break false;
+        }
+        if byte[0] == b'<' {
+            break true;
+        }
+    };
+    summary.warn(
+        format!(
+            "Skipped malformed XML in element {}: {}",
+            element_path(parent_tags),
+            error.msg()
+        ),
+        format!(
+            "{}:{}, bytes {} to {}",
+            filename,
+            error.position(),
+            error_offset,
+            source.bytes_read - found as u64
+        ),
+    );
+
+    let mut synthetic = vec![];
+    if found {
+        for tag in parent_tags {
+            write!(synthetic, "<{}>", tag)?;
+        }
+        synthetic.push(b'<');
+    }
+    Ok((
+        xml_reader(Cursor::new(synthetic).chain(source).chain(end)),
+        !found,
+    ))
+}
+
+/// The length of the XML declaration (`<?xml version="1.0"?>`) at the start of this prolog, or 0
+fn xml_declaration_len(prolog: &[u8]) -> usize {
+    let is_declaration = prolog.starts_with(b"<?xml")
+        && prolog
+            .get(5)
+            .is_some_and(|b| b.is_ascii_whitespace() || *b == b'?');
+    if !is_declaration {
+        return 0;
+    }
+    prolog
+        .windows(2)
+        .position(|w| w == b"?>")
+        .map_or(0, |end| end + 2)
+}
+
+/// Parse XML from this source, with comments, for `--comment` events
+fn xml_reader<R: Read>(source: R) -> EventReader<R> {
+    ParserConfig::new()
+        .ignore_comments(false)
+        .create_reader(source)
+}
+
+/// The main "inner main". `filename` is the name of the input, for actions which output it.
+/// What happened is added to `summary`
+fn process(
+    config: &Config,
+    instructions: &[Instruction],
+    filename: &str,
+    mut input: impl BufRead,
+    output: impl Write,
+    summary: &mut Summary,
+) -> Result<()> {
+    summary.matched.resize(instructions.len(), 0);
+    // With --resume, the input starts at a start tag, & the parser is given the start tags of the
+    // elements which were open there first
+    let (mut prolog, mut doctype, mut replayed_tags) = (vec![], None, vec![]);
+    let skipped = match &config.resume {
+        Some(resume) => {
+            summary.records = resume.records;
+            replayed_tags = resume.open.concat().into_bytes();
+            resume.offset
+        }
+        None => {
+            let mut skipped = skip_bom(&mut input)?;
+            if config.skip_leading_junk {
+                let junk = skip_to_first_tag(&mut input)?;
+                if junk > 0 {
+                    summary.warn(
+                        "Skipped junk before the first tag".to_string(),
+                        format!("{} ({} bytes)", filename, junk),
+                    );
+                }
+                skipped += junk;
+            }
+            (prolog, doctype) = read_prolog(&mut input)?;
+            skipped
+        }
+    };
+    if config.forbid_dtd && doctype.is_some() {
+        bail!("{} has a DOCTYPE, which --forbid-dtd forbids", filename);
+    }
+    let mut skipped = skipped;
+    let mut fragment_end = vec![];
+    if let Some(root) = &config.fragment {
+        if doctype.is_some() {
+            bail!(
+                "{} has a DOCTYPE, so it's a document, not a --fragment",
+                filename
+            );
+        }
+        // The XML declaration has to stay before the root element
+        let declaration = xml_declaration_len(&prolog);
+        replayed_tags = prolog.drain(..declaration).collect();
+        skipped += declaration as u64;
+        write!(replayed_tags, "<{}>", root)?;
+        write!(fragment_end, "</{}>", root)?;
+    }
+    let mut source = CountingReader::new(Cursor::new(prolog).chain(input));
+    source.bytes_read = skipped;
+    let mut reader = xml_reader(
+        Cursor::new(replayed_tags)
+            .chain(source)
+            .chain(Cursor::new(fragment_end)),
+    );
+    // How many of the next start tags are the replayed open elements, which only update the
+    // state, since their instructions already happened
+    let mut replaying = config.resume.as_ref().map_or(0, |r| r.open.len());
+    let mut output = CountingWriter {
+        inner: output,
+        bytes_written: summary.bytes_written,
+    };
+    let mut last_checkpoint = std::time::Instant::now();
+    // After recovering from an XML error, the new parser sees (& we ignore) a new StartDocument,
+    // and start tags for the elements which were open.
+    let mut recovered = false;
+    let mut synthetic_starts = 0;
+    // How many open elements are being ignored because of --max-nesting
+    let mut pruned_depth = 0;
+    // The tag & attributes of the root element, for -S/-E
+    let mut root: Option<(String, Vec<OwnedAttribute>)> = None;
+    let mut collected = Collector::for_instructions(instructions);
+    // The collected values often repeat
+    let mut interner = Interner::default();
+    let mut spares = Spares::default();
+    // The total size of the values in `collected`
+    let mut collected_bytes = 0;
+    let has_sibling_index = instructions.iter().any(|i| {
+        i.actions()
+            .iter()
+            .any(|a| matches!(a, Action::SiblingIndex(_)))
+            || i.xpath().is_some_and(|path| path.uses_positions())
+    });
+    // How many child elements each open element (& the document) has had so far, in total & by
+    // name, & the sibling index of each open element
+    let mut child_counts: Vec<(u64, HashMap<String, u64>)> = vec![(0, HashMap::new())];
+    let mut sibling_indices: Vec<(u64, u64)> = vec![];
+    let has_counter_per = instructions.iter().any(|i| {
+        i.actions()
+            .iter()
+            .any(|a| matches!(a, Action::Counter(Some(_), _)))
+    });
+
+    // -e need the attributes of the element from its start tag too
+    let has_parent_attributes = config.geojson.is_some()
+        || config.checkpoint.is_some()
+        || instructions.iter().any(|i| {
+            i.actions().iter().any(|a| a.is_parent_attr())
+                || matches!(i, Instruction::EndTag { actions, .. } if uses_attributes(actions))
+                || (i.is_deferred() && uses_attributes(i.actions()))
+        });
+    // For --leaf-only & --empty-only, whether each open element has had child elements, & if it's
+    // self-closing
+    let has_leaf_filters = instructions
+        .iter()
+        .any(|i| i.is_leaf_only() || i.is_empty_only());
+    let mut open_elements: Vec<(bool, bool)> = vec![];
+    // For TAG{text~REGEX}, the text so far of each open element, if it has a pattern
+    let has_text_patterns = instructions.iter().any(|i| i.text_pattern().is_some());
+    let mut text_buffers: Vec<Option<String>> = vec![];
+    let has_copy = instructions.iter().any(|i| i.is_copy());
+    // With only -o/--nl/--tab, nothing looks at the attributes, so none are kept
+    let raw_strings_only = config.geojson.is_none()
+        && instructions.iter().all(|i| {
+            i.actions()
+                .iter()
+                .all(|a| matches!(a, Action::RawString(_)))
+        });
+    // The element names which the instructions mention, for --warn-unknown-tags
+    let known_tags: Option<HashSet<&str>> = config
+        .warn_unknown_tags
+        .then(|| instructions.iter().flat_map(|i| i.tags()).collect())
+        .filter(|tags: &HashSet<&str>| !tags.contains("*"));
+    // When nothing needs the ancestors, positions, or what's inside other elements, the elements
+    // which no instruction mentions only go on the stack of open element names, & skip the rest
+    let relevant_tags: Option<HashSet<&str>> = (!has_parent_attributes
+        && !has_sibling_index
+        && !has_copy
+        && !has_leaf_filters
+        && !has_text_patterns
+        && known_tags.is_none()
+        && config.checkpoint.is_none()
+        && config.end_offset.is_none()
+        && config.debug_matches.is_none())
+    .then(|| instructions.iter().flat_map(|i| i.tags()).collect())
+    .filter(|tags: &HashSet<&str>| !tags.contains("*"));
+    let mut copier = Copier {
+        exclude: config.copy_exclude.clone(),
+        canonical: config.canonical,
+        ..Default::default()
+    };
+    let mut parent_attrs: Vec<Vec<OwnedAttribute>> = vec![];
+    // The tag names repeat, so they're shared with the interner
+    let mut parent_tags: Vec<Rc<str>> = vec![];
+    // The full names of the open elements, with their prefixes, for --checkpoint to replay them
+    let mut parent_names: Vec<OwnedName> = vec![];
+    // byte offsets of the start tags of the currently open elements
+    let mut start_offsets: Vec<u64> = vec![];
+    let mut record: Vec<u8> = vec![];
+    // Counts for instructions with --group-by
+    let mut groups: Vec<Option<Groups>> = instructions
+        .iter()
+        .map(|i| i.is_aggregated().then(Groups::new))
+        .collect();
+    // With --merge-root, whether this is the first & the last input, & whether the merged root
+    // element is open
+    let continuous = config.continuous || config.merge_root.is_some();
+    let first_input = !continuous || summary.merged.inputs == 0;
+    let last_input = !continuous || summary.merged.inputs + 1 >= config.inputs.len() as u64;
+    let mut merged_depth = 0;
+    // How many start & end tags have been printed by --debug-matches
+    let mut debugged = 0;
+    // Updated as we go, so it's right even if there's an error (e.g. a broken pipe)
+    let bytes_read_before = summary.bytes_read;
+    // For --timing-detail
+    if config.timing_detail && summary.timing.is_none() {
+        summary.timing = Some(Timing::default());
+    }
+    // Reset every time, since an earlier error on this thread (e.g. a --threads chunk) can leave
+    // its time behind
+    let timing = summary.timing.is_some();
+    FILTER_TIME.set(timing.then_some(Duration::ZERO));
+    WRITE_TIME.set(timing.then_some(Duration::ZERO));
+    let started = std::time::Instant::now();
+    let mut parsing = Duration::ZERO;
+    let instructions_before = summary
+        .timing
+        .as_ref()
+        .map_or(Duration::ZERO, |t| t.instructions.iter().sum());
+
+    loop {
+        if config.interrupted.load(Ordering::Relaxed) {
+            summary.interrupted = Some(format!(
+                "{}:{}, byte {}",
+                filename,
+                reader.position(),
+                reader.source().get_ref().0.get_ref().1.bytes_read
+            ));
+            break;
+        }
+        let next = if summary.output_limit_reached {
+            // The rest of the input is skipped, but the document is still ended properly
+            Ok(XmlEvent::EndDocument)
+        } else if timing {
+            let started = std::time::Instant::now();
+            let next = reader.next();
+            parsing += started.elapsed();
+            next
+        } else {
+            reader.next()
+        };
+        let wev = match next {
+            Ok(wev) => wev,
+            Err(e) if config.recover => {
+                let eof;
+                (reader, eof) =
+                    recover(reader, &parent_tags[merged_depth..], filename, e, summary)?;
+                if eof {
+                    XmlEvent::EndDocument
+                } else {
+                    recovered = true;
+                    synthetic_starts = parent_tags.len() - merged_depth;
+                    pruned_depth = 0;
+                    continue;
+                }
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Invalid XML in {} in element {}",
+                        filename,
+                        element_path(&parent_tags)
+                    )
+                });
+            }
+        };
+        summary.bytes_read = bytes_read_before + reader.source().get_ref().0.get_ref().1.bytes_read;
+        summary.tick_progress();
+        let position = reader.position();
+        if config.trace_events {
+            let synthetic = match wev {
+                XmlEvent::StartDocument { .. } => recovered,
+                XmlEvent::StartElement { .. } => synthetic_starts > 0,
+                _ => false,
+            };
+            if !synthetic {
+                let depth = parent_tags.len()
+                    + pruned_depth
+                    + matches!(wev, XmlEvent::StartElement { .. }) as usize;
+                let event = describe_event(&wev);
+                tracing::trace!(
+                    target: TRACE_EVENTS,
+                    %position,
+                    depth,
+                    %event,
+                    "{}:{}\t{}\t{}",
+                    filename,
+                    position,
+                    depth,
+                    event
+                );
+            }
+        }
+        match wev {
+            XmlEvent::StartDocument { .. } if recovered => {}
+            XmlEvent::StartElement { .. } if synthetic_starts > 0 => {
+                synthetic_starts -= 1;
+            }
+
+            // Elements which are nested too deep are ignored, along with everything inside them
+            XmlEvent::StartElement { name, .. }
+                if pruned_depth > 0
+                    || config
+                        .max_nesting
+                        .is_some_and(|max| parent_tags.len() >= max) =>
+            {
+                if !config.prune_nesting {
+                    let mut path = element_path(&parent_tags);
+                    path.push('/');
+                    path.push_str(&name.local_name);
+                    bail!(
+                        "At {}:{} element {} is nested deeper than --max-nesting {}",
+                        filename,
+                        position,
+                        path,
+                        config.max_nesting.unwrap()
+                    );
+                }
+                pruned_depth += 1;
+            }
+            XmlEvent::EndElement { .. } if pruned_depth > 0 => {
+                pruned_depth -= 1;
+            }
+
+            // The root element is needed for -S/-E, even if no instruction mentions it
+            XmlEvent::StartElement { name, .. }
+                if root.is_some()
+                    && replaying == 0
+                    && relevant_tags.as_ref().is_some_and(|tags| {
+                        !tags.contains(&*tag_key(&name.local_name, config.ignore_tag_case))
+                    }) =>
+            {
+                parent_tags.push(interner.intern(&name.local_name));
+            }
+            XmlEvent::EndElement { name }
+                if parent_tags.len() > 1
+                    && relevant_tags.as_ref().is_some_and(|tags| {
+                        !tags.contains(&*tag_key(&name.local_name, config.ignore_tag_case))
+                    }) =>
+            {
+                parent_tags.pop();
+            }
+
+            XmlEvent::StartDocument {
+                version: _,
+                encoding: _,
+                standalone: _,
+            } => {
+                if let Some(tag) = &config.merge_root {
+                    // -S/-E have the merged root element's (lack of) attributes
+                    root = Some((tag.clone(), vec![]));
+                }
+                if !first_input {
+                    groups = std::mem::take(&mut summary.merged.groups);
+                }
+                let mut resumed_counts = config.resume.iter().flat_map(|r| r.counters.iter());
+                for instruction in instructions.iter().filter(|_| first_input) {
+                    for action in instruction.actions() {
+                        match action {
+                            Action::Counter(_, count) => {
+                                count.set(resumed_counts.next().copied().unwrap_or(0));
+                            }
+                            // How often it had matched before this document, which -E subtracts
+                            Action::Matched(tag, count) => {
+                                count.set(summary.matched[matched_index(instructions, tag)]);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                let ctx = Context {
+                    filename,
+                    position,
+                    offset: None,
+                    sibling_index: None,
+                    element: root
+                        .as_ref()
+                        .map(|(tag, attributes)| (tag.as_str(), attributes.as_slice())),
+                    parent_attrs: &parent_attrs,
+                    parent_tags: &parent_tags,
+                    parent_sibling_indices: &[],
+                    pi_data: None,
+                    comment: None,
+                    doctype: None,
+                    text: None,
+                    collected: &collected,
+                    copied: None,
+                };
+                let resumed = config.resume.is_some();
+                for (i, instruction) in instructions
+                    .iter()
+                    .enumerate()
+                    .filter(|_| first_input && !resumed)
+                {
+                    if let Instruction::StartDocument { actions } = instruction {
+                        if uses_attributes(actions) && config.merge_root.is_none() {
+                            // Happens at the root element instead
+                            continue;
+                        }
+                        // -S/-E always happen, so don't count as records
+                        run_instruction(
+                            config,
+                            actions,
+                            i,
+                            &ctx,
+                            &mut record,
+                            &mut output,
+                            summary,
+                            None,
+                        )?;
+                    }
+                }
+                if let Some(doctype) = &doctype {
+                    let ctx = Context {
+                        element: None,
+                        doctype: Some(doctype),
+                        ..ctx
+                    };
+                    for (i, instruction) in instructions.iter().enumerate() {
+                        if let Instruction::Doctype { actions } = instruction {
+                            summary.matched[i] += 1;
+                            summary.records += run_instruction(
+                                config,
+                                actions,
+                                i,
+                                &ctx,
+                                &mut record,
+                                &mut output,
+                                summary,
+                                groups[i].as_mut(),
+                            )? as u64;
+                        }
+                    }
+                }
+                if let Some(tag) = &config.merge_root {
+                    // The input's root element is the next child of the merged root element
+                    merged_depth = 1;
+                    parent_tags.push(interner.intern(tag));
+                    if has_parent_attributes {
+                        parent_attrs.push(vec![]);
+                    }
+                    if config.checkpoint.is_some() {
+                        parent_names.push(OwnedName::local(tag.as_str()));
+                    }
+                    if has_sibling_index {
+                        sibling_indices.push((1, 1));
+                        child_counts.push(std::mem::take(&mut summary.merged.children));
+                    }
+                }
+            }
+
+            XmlEvent::StartElement {
+                name,
+                attributes,
+                namespace,
+            } => {
+                let offset = reader.source().get_ref().0.get_ref().1.last_tag_start;
+                let replayed = replaying > 0;
+                let key = name_key(&name, config.ignore_tag_case);
+                if let Some(known_tags) = &known_tags {
+                    // The root element is for -S/-E, & elements inside --copy are output too
+                    if !replayed
+                        && parent_tags.len() > merged_depth
+                        && copier.open.is_empty()
+                        && !known_tags.contains(key.local_name.as_str())
+                    {
+                        summary.warn(
+                            format!("No instruction for <{}> elements", name.local_name),
+                            format!("{}:{}", filename, position),
+                        );
+                    }
+                }
+                if replayed {
+                    replaying -= 1;
+                } else if config.end_offset.is_some_and(|end| offset >= end)
+                    && parent_tags.len() == 1
+                    && config.sync_tags.contains(&name.local_name)
+                {
+                    // The rest is in the next shard
+                    summary.reached_end_offset = true;
+                    break;
+                } else if let Some(path) = &config.checkpoint {
+                    // Only between records, when nothing is half collected or copied
+                    if last_checkpoint.elapsed() >= config.checkpoint_interval
+                        && copier.open.is_empty()
+                        && collected.iter().all(|c| c.values.is_empty())
+                        && text_buffers.iter().all(Option::is_none)
+                    {
+                        output.flush()?;
+                        // The first tag declares all the namespaces which are in scope
+                        let open = parent_names
+                            .iter()
+                            .zip(&parent_attrs)
+                            .enumerate()
+                            .map(|(i, (name, attributes))| {
+                                replayed_start_tag(
+                                    name,
+                                    attributes,
+                                    &namespace,
+                                    (i > 0).then_some(&namespace),
+                                )
+                            })
+                            .collect();
+                        let checkpoint = Checkpoint {
+                            input: filename.to_string(),
+                            offset,
+                            output: output.bytes_written,
+                            records: summary.records,
+                            counters: instructions
+                                .iter()
+                                .flat_map(|i| i.actions())
+                                .filter_map(|a| match a {
+                                    Action::Counter(_, count) => Some(count.get()),
+                                    _ => None,
+                                })
+                                .collect(),
+                            open,
+                        };
+                        checkpoint.save(path)?;
+                        last_checkpoint = std::time::Instant::now();
+                    }
+                }
+                start_offsets.push(offset);
+                let sibling_index = if has_sibling_index {
+                    let (all, same_name) = child_counts.last_mut().unwrap();
+                    *all += 1;
+                    // Only a new name is allocated
+                    let same_name = match same_name.get_mut(key.local_name.as_str()) {
+                        Some(count) => count,
+                        None => same_name.entry(key.local_name.clone()).or_insert(0),
+                    };
+                    *same_name += 1;
+                    sibling_indices.push((*all, *same_name));
+                    child_counts.push((0, HashMap::new()));
+                    sibling_indices.last().copied()
+                } else {
+                    None
+                };
+                let ctx = Context {
+                    filename,
+                    position,
+                    offset: Some(offset),
+                    sibling_index,
+                    element: Some((&key.local_name, &attributes)),
+                    parent_attrs: &parent_attrs,
+                    parent_tags: &parent_tags,
+                    parent_sibling_indices: &sibling_indices
+                        [..sibling_indices.len().saturating_sub(1)],
+                    pi_data: None,
+                    comment: None,
+                    doctype: None,
+                    text: None,
+                    collected: &collected,
+                    copied: None,
+                };
+                if has_counter_per && !replayed {
+                    for instruction in instructions.iter() {
+                        for action in instruction.actions() {
+                            match action {
+                                Action::Counter(Some(tag), count) if tag == &key.local_name => {
+                                    count.set(0);
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                if root.is_none() {
+                    // -S which use the root element's attributes
+                    for (i, instruction) in instructions
+                        .iter()
+                        .enumerate()
+                        .filter(|_| !replayed && first_input)
+                    {
+                        match instruction {
+                            Instruction::StartDocument { actions } if uses_attributes(actions) => {
+                                run_instruction(
+                                    config,
+                                    actions,
+                                    i,
+                                    &Context {
+                                        offset: None,
+                                        ..ctx
+                                    },
+                                    &mut record,
+                                    &mut output,
+                                    summary,
+                                    None,
+                                )?;
+                            }
+                            _ => {}
+                        }
+                    }
+                    let attributes = match raw_strings_only {
+                        true => vec![],
+                        false => attributes.clone(),
+                    };
+                    root = Some((name.local_name.clone(), attributes));
+                }
+                let self_closing = if has_leaf_filters {
+                    if let Some((has_children, _)) = open_elements.last_mut() {
+                        *has_children = true;
+                    }
+                    let self_closing = reader.source().get_ref().0.get_ref().1.last_bytes == *b"/>";
+                    open_elements.push((false, self_closing));
+                    self_closing
+                } else {
+                    false
+                };
+                if has_text_patterns {
+                    let has_pattern = instructions
+                        .iter()
+                        .any(|i| i.matches_element(&key) && i.text_pattern().is_some());
+                    text_buffers.push(has_pattern.then(|| spares.text()));
+                }
+                let debug = !replayed && config.debug_matches.is_some_and(|max| debugged < max);
+                let mut matched_any = false;
+                for (i, instruction) in instructions.iter().enumerate().filter(|_| !replayed) {
+                    match instruction {
+                        Instruction::StartTag { actions, .. }
+                            if instruction.matches_element(&key)
+                                && !instruction.is_deferred()
+                                && instruction
+                                    .xpath()
+                                    .is_none_or(|path| path.matches(&ctx, config))
+                                && (self_closing || !instruction.is_empty_only()) =>
+                        {
+                            summary.matched[i] += 1;
+                            matched_any = true;
+                            let written = run_instruction(
+                                config,
+                                actions,
+                                i,
+                                &ctx,
+                                &mut record,
+                                &mut output,
+                                summary,
+                                groups[i].as_mut(),
+                            )?;
+                            summary.records += written as u64;
+                            if debug {
+                                let result = if written || groups[i].is_some() {
+                                    format!("{:?}", String::from_utf8_lossy(&record))
+                                } else {
+                                    "skipped record".to_string()
+                                };
+                                ctx.debug_match(&format!("{} (#{})", instruction, i + 1), &result);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                if debug {
+                    if !matched_any {
+                        ctx.debug_match(
+                            &format!("-s {}", name.local_name),
+                            "no instructions matched",
+                        );
+                    }
+                    debugged += 1;
+                }
+
+                for collector in collected.iter_mut() {
+                    if collector.child == key.local_name {
+                        if let Some(values) = collector.values.last_mut() {
+                            let count = values.len();
+                            match &collector.what {
+                                Collect::Attribute(attr) => {
+                                    if let Some(value) =
+                                        find_attr(&attributes, attr, &name.local_name, config)
+                                            .with_context(|| {
+                                                format!("At {}:{}", filename, position)
+                                            })?
+                                    {
+                                        values.push(interner.intern(&value));
+                                    }
+                                }
+                                Collect::JsonObject => {
+                                    let mut object = String::from("{");
+                                    for (i, a) in attributes.iter().enumerate() {
+                                        if i > 0 {
+                                            object.push(',');
+                                        }
+                                        write_json_string(&a.name.borrow().to_repr(), &mut object)?;
+                                        object.push(':');
+                                        write_json_string(&a.value, &mut object)?;
+                                    }
+                                    object.push('}');
+                                    values.push(interner.intern(&object));
+                                }
+                                Collect::KeyValue(format, key, value) => {
+                                    let attr = |attr| {
+                                        find_attr(&attributes, attr, &name.local_name, config)
+                                            .with_context(|| {
+                                                format!("At {}:{}", filename, position)
+                                            })
+                                    };
+                                    if let Some(key) = attr(key)? {
+                                        let item = format.item(&key, attr(value)?.as_deref())?;
+                                        values.push(interner.intern(&item));
+                                    }
+                                }
+                            }
+                            collected_bytes +=
+                                values[count..].iter().map(|v| v.len()).sum::<usize>();
+                        }
+                    }
+                    if collector.parent == key.local_name {
+                        collector.values.push(spares.values());
+                    }
+                }
+                if has_copy && !replayed {
+                    let copied = instructions
+                        .iter()
+                        .any(|i| i.is_copy() && i.matches_element(&key));
+                    if copied || copier.is_copying() {
+                        copier.start(parent_tags.len(), copied, &name, &attributes, &namespace)?;
+                    }
+                }
+                if has_parent_attributes {
+                    parent_attrs.push(attributes);
+                }
+                parent_tags.push(interner.intern(&name.local_name));
+                if config.checkpoint.is_some() {
+                    parent_names.push(name);
+                }
+            }
+
+            XmlEvent::EndElement { name } => {
+                let key = name_key(&name, config.ignore_tag_case);
+                // The element is still on the stacks of open elements
+                let depth = parent_tags.len().saturating_sub(1);
+                let attributes = parent_attrs.get(depth).map_or(&[][..], |a| a.as_slice());
+                let sibling_index = if has_sibling_index {
+                    child_counts.pop();
+                    sibling_indices.pop()
+                } else {
+                    None
+                };
+                let copied = copier
+                    .end(depth, &name)
+                    .map(|(xml, json)| (&copier.xml[xml..], &copier.json[json..]));
+                let ctx = Context {
+                    filename,
+                    position,
+                    offset: start_offsets.pop(),
+                    sibling_index,
+                    element: Some((&key.local_name, attributes)),
+                    parent_attrs: &parent_attrs[..depth.min(parent_attrs.len())],
+                    parent_tags: &parent_tags[..depth],
+                    parent_sibling_indices: &sibling_indices,
+                    pi_data: None,
+                    comment: None,
+                    doctype: None,
+                    text: None,
+                    collected: &collected,
+                    copied,
+                };
+                let (has_children, self_closing) = open_elements.pop().unwrap_or_default();
+                let text = text_buffers.pop().flatten();
+                let debug = config.debug_matches.is_some_and(|max| debugged < max);
+                let mut matched_any = false;
+                for (i, instruction) in instructions.iter().enumerate() {
+                    let happens = match instruction {
+                        // --leaf-only & TAG{text~REGEX} -s happen now, when what's inside is known
+                        Instruction::StartTag { .. } => instruction.is_deferred(),
+                        Instruction::EndTag { .. } => true,
+                        _ => false,
+                    } && instruction.matches_element(&key)
+                        && instruction
+                            .xpath()
+                            .is_none_or(|path| path.matches(&ctx, config))
+                        && !(has_children && instruction.is_leaf_only())
+                        && (self_closing || !instruction.is_empty_only())
+                        && instruction
+                            .text_pattern()
+                            .is_none_or(|pattern| pattern.is_match(text.as_deref().unwrap_or("")));
+                    if happens {
+                        let actions = instruction.actions();
+                        summary.matched[i] += 1;
+                        matched_any = true;
+                        let written = run_instruction(
+                            config,
+                            actions,
+                            i,
+                            &ctx,
+                            &mut record,
+                            &mut output,
+                            summary,
+                            groups[i].as_mut(),
+                        )?;
+                        summary.records += written as u64;
+                        if debug {
+                            let result = if written || groups[i].is_some() {
+                                format!("{:?}", String::from_utf8_lossy(&record))
+                            } else {
+                                "skipped record".to_string()
+                            };
+                            ctx.debug_match(&format!("{} (#{})", instruction, i + 1), &result);
+                        }
+                    }
+                }
+                if debug {
+                    if !matched_any {
+                        ctx.debug_match(
+                            &format!("-e {}", name.local_name),
+                            "no instructions matched",
+                        );
+                    }
+                    debugged += 1;
+                }
+                for collector in collected.iter_mut() {
+                    if collector.parent == key.local_name {
+                        if let Some(values) = collector.values.pop() {
+                            collected_bytes -= values.iter().map(|v| v.len()).sum::<usize>();
+                            spares.reuse_values(values);
+                        }
+                    }
+                }
+                if let Some(text) = text {
+                    spares.reuse_text(text);
+                }
+                if has_parent_attributes {
+                    parent_attrs.pop();
+                }
+                parent_tags.pop();
+                parent_names.pop();
+            }
+
+            XmlEvent::EndDocument => {
+                if merged_depth > 0 {
+                    // Elements could still be open, if --recover reached the end of the input
+                    parent_tags.remove(0);
+                    if has_parent_attributes {
+                        parent_attrs.remove(0);
+                    }
+                    if config.checkpoint.is_some() {
+                        parent_names.remove(0);
+                    }
+                    if has_sibling_index {
+                        sibling_indices.remove(0);
+                        summary.merged.children = child_counts.remove(1);
+                    }
+                }
+                if continuous {
+                    summary.merged.inputs += 1;
+                    if !last_input && !summary.output_limit_reached {
+                        // The next input carries on the same document
+                        summary.merged.groups = std::mem::take(&mut groups);
+                        break;
+                    }
+                }
+                let ctx = Context {
+                    filename,
+                    position,
+                    offset: None,
+                    sibling_index: None,
+                    element: root
+                        .as_ref()
+                        .map(|(tag, attributes)| (tag.as_str(), attributes.as_slice())),
+                    parent_attrs: &parent_attrs,
+                    parent_tags: &parent_tags,
+                    parent_sibling_indices: &[],
+                    pi_data: None,
+                    comment: None,
+                    doctype: None,
+                    text: None,
+                    collected: &collected,
+                    copied: None,
+                };
+                for (instruction, groups) in instructions.iter().zip(groups.iter_mut()) {
+                    if let Some(groups) = groups.take() {
+                        summary.records +=
+                            write_groups(instruction.is_grouped(), groups, &mut output)?;
+                        summary.grouped_bytes = 0;
+                    }
+                }
+                for (i, instruction) in instructions.iter().enumerate() {
+                    if let Instruction::EndDocument { actions } = instruction {
+                        for action in actions {
+                            if let Action::Matched(tag, count) = action {
+                                let matched = summary.matched[matched_index(instructions, tag)];
+                                count.set(matched - count.get());
+                            }
+                        }
+                        run_instruction(
+                            config,
+                            actions,
+                            i,
+                            &ctx,
+                            &mut record,
+                            &mut output,
+                            summary,
+                            None,
+                        )?;
+                    }
+                }
+                if let Some(path) = &config.checkpoint {
+                    // It's finished, so there's nothing to resume
+                    match std::fs::remove_file(path) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                            return Err(e).with_context(|| {
+                                format!("Cannot delete checkpoint {}", path.display())
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+                break;
+            }
+
+            XmlEvent::ProcessingInstruction { .. } if pruned_depth > 0 => {}
+            XmlEvent::ProcessingInstruction { name, data } => {
+                copier.pi(&name, data.as_deref());
+                let ctx = Context {
+                    filename,
+                    position,
+                    offset: None,
+                    sibling_index: None,
+                    element: None,
+                    parent_attrs: &parent_attrs,
+                    parent_tags: &parent_tags,
+                    parent_sibling_indices: &[],
+                    pi_data: Some(data.as_deref().unwrap_or("")),
+                    comment: None,
+                    doctype: None,
+                    text: None,
+                    collected: &collected,
+                    copied: None,
+                };
+                for (i, instruction) in instructions.iter().enumerate() {
+                    match instruction {
+                        Instruction::Pi { target, actions } if target == &name => {
+                            summary.matched[i] += 1;
+                            summary.records += run_instruction(
+                                config,
+                                actions,
+                                i,
+                                &ctx,
+                                &mut record,
+                                &mut output,
+                                summary,
+                                groups[i].as_mut(),
+                            )? as u64;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            XmlEvent::Comment(_) if pruned_depth > 0 => {}
+            XmlEvent::Comment(comment) => {
+                copier.comment(&comment);
+                let ctx = Context {
+                    filename,
+                    position,
+                    offset: None,
+                    sibling_index: None,
+                    element: None,
+                    parent_attrs: &parent_attrs,
+                    parent_tags: &parent_tags,
+                    parent_sibling_indices: &[],
+                    pi_data: None,
+                    comment: Some(&comment),
+                    doctype: None,
+                    text: None,
+                    collected: &collected,
+                    copied: None,
+                };
+                for (i, instruction) in instructions.iter().enumerate() {
+                    if let Instruction::Comment { actions } = instruction {
+                        summary.matched[i] += 1;
+                        summary.records += run_instruction(
+                            config,
+                            actions,
+                            i,
+                            &ctx,
+                            &mut record,
+                            &mut output,
+                            summary,
+                            groups[i].as_mut(),
+                        )? as u64;
+                    }
+                }
+            }
+
+            XmlEvent::Characters(_) | XmlEvent::CData(_) | XmlEvent::Whitespace(_)
+                if pruned_depth > 0 => {}
+            XmlEvent::Characters(text) | XmlEvent::CData(text) | XmlEvent::Whitespace(text) => {
+                for buffer in text_buffers.iter_mut().flatten() {
+                    buffer.push_str(&text);
+                }
+                copier.text(&text)?;
+                let ctx = Context {
+                    filename,
+                    position,
+                    offset: None,
+                    sibling_index: None,
+                    element: None,
+                    parent_attrs: &parent_attrs,
+                    parent_tags: &parent_tags,
+                    parent_sibling_indices: &[],
+                    pi_data: None,
+                    comment: None,
+                    doctype: None,
+                    text: Some(&text),
+                    collected: &collected,
+                    copied: None,
+                };
+                let blank = text.trim().is_empty();
+                for (i, instruction) in instructions.iter().enumerate() {
+                    match instruction {
+                        Instruction::Chars { nonblank, actions } if !(*nonblank && blank) => {
+                            summary.matched[i] += 1;
+                            summary.records += run_instruction(
+                                config,
+                                actions,
+                                i,
+                                &ctx,
+                                &mut record,
+                                &mut output,
+                                summary,
+                                groups[i].as_mut(),
+                            )? as u64;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // Roughly how much memory is being used for what's buffered
+        let buffered = [
+            ("--collect etc.", collected_bytes),
+            ("--copy", copier.xml.len() + copier.json.len()),
+            (
+                "TAG{text~REGEX}",
+                text_buffers.iter().flatten().map(String::len).sum(),
+            ),
+            ("--group-by/--aggregate", summary.grouped_bytes),
+        ];
+        if config.max_records.is_some_and(|max| summary.records >= max)
+            || config
+                .max_output_bytes
+                .is_some_and(|max| output.bytes_written >= max)
+        {
+            summary.output_limit_reached = true;
+        }
+
+        let total = buffered.iter().map(|(_, bytes)| bytes).sum();
+        summary.peak_buffered = summary.peak_buffered.max(total);
+        if let Some(max) = config.max_memory.filter(|max| total > *max) {
+            let (what, _) = buffered.iter().max_by_key(|(_, bytes)| bytes).unwrap();
+            bail!(
+                "At {}:{} more than --max-memory {} bytes are buffered, mostly for {}",
+                filename,
+                position,
+                max,
+                what
+            );
+        }
+    }
+    summary.bytes_written = output.bytes_written;
+    if let Some(timing) = &mut summary.timing {
+        let writing = WRITE_TIME.take().unwrap_or_default();
+        let instructions = timing.instructions.iter().sum::<Duration>() - instructions_before;
+        timing.parsing += parsing;
+        timing.writing += writing;
+        timing.filters += FILTER_TIME.take().unwrap_or_default();
+        timing.other += started
+            .elapsed()
+            .saturating_sub(parsing + instructions + writing);
+    }
+
+    Ok(())
+}
+
+/// What anglosaxon has been asked to do, i.e. which subcommand
+#[derive(Debug, Default, PartialEq, Eq)]
+enum Mode {
+    /// Run the instructions & output text
+    #[default]
+    Extract,
+    /// Count how often each instruction matches
+    Count,
+    /// Output statistics about the document
+    Stats,
+    /// Output how often each element (or path) happens
+    Histogram { paths: bool },
+    /// Check that the document is well formed, & has the structure from the instructions
+    Validate,
+    /// Output the lines which are only in the old or the new document
+    Diff,
+    /// Time how long the instructions take
+    Bench(Bench),
+    /// Copy the document, changing it
+    Rewrite(Rewrite),
+    /// Print a shell completion script for this shell, rather than processing any XML
+    Completions(Shell),
+}
+
+/// Global settings, which aren't tied to any one instruction
+#[derive(Debug, Default)]
+struct Config {
+    mode: Mode,
+
+    /// Expand `${VAR}` in raw strings
+    expand_env: bool,
+
+    /// Output after every instruction's actions, from `--rs`
+    record_separator: Option<String>,
+
+    /// Output between the `--fields` attributes, from `--fs`. Tab by default
+    field_separator: Option<String>,
+
+    /// The output's columns & their types, from `--schema`
+    schema: Option<Schema>,
+
+    /// What to do with values which aren't valid for their `--schema` column
+    on_invalid: InvalidValuePolicy,
+
+    /// Output each record as a JSON object etc., with the attributes & `--name`d actions as
+    /// fields
+    record_format: Option<RecordFormat>,
+
+    /// Write records with missing attributes or invalid values here, rather than stopping or
+    /// skipping them silently
+    invalid_to: Option<std::path::PathBuf>,
+
+    /// Files to read, `-` is stdin
+    inputs: Vec<String>,
+
+    /// What to do when a `-v` attribute is missing, unless the instruction overrides it
+    on_missing_attr: MissingAttrPolicy,
+
+    /// Which value to use when an element has several attributes with the same name
+    on_duplicate_attr: DuplicateAttrPolicy,
+
+    /// Element names match the instructions' tags whatever their case
+    ignore_tag_case: bool,
+    /// Attribute names match whatever their case
+    ignore_attr_case: bool,
+
+    /// Skip over XML errors
+    recover: bool,
+
+    /// Skip everything before the first `<`
+    skip_leading_junk: bool,
+
+    /// Documents can't have a DOCTYPE
+    forbid_dtd: bool,
+
+    /// Elements can't be nested deeper than this
+    max_nesting: Option<usize>,
+    /// Ignore elements which are too deep, rather than stopping with an error
+    prune_nesting: bool,
+
+    /// Exit with an error if no -s/-e records were output
+    fail_if_empty: bool,
+    /// Warn about the elements which no instruction mentions
+    warn_unknown_tags: bool,
+    /// Exit with an error if any -s/-e/-P/--comment/--chars instruction never matched
+    strict_unmatched: bool,
+
+    /// Treat the inputs as one document, with a root element with this name around their root
+    /// elements
+    merge_root: Option<String>,
+
+    /// Like `merge_root`, -S/-E happen once & counters etc. carry on, but without the root element
+    continuous: bool,
+
+    /// Wrap the output in an XML declaration & a root element with this name
+    copy_root: Option<String>,
+
+    /// The inputs are a sequence of elements without a root element, so they're put inside a
+    /// root element with this name
+    fragment: Option<String>,
+
+    /// Output the records as GeoJSON features
+    geojson: Option<GeoJson>,
+
+    /// Start the output with a comment saying how it was made
+    header_comment: Option<HeaderComment>,
+
+    /// Save how far through the input we are to this file
+    checkpoint: Option<std::path::PathBuf>,
+    /// How often to save the checkpoint
+    checkpoint_interval: std::time::Duration,
+    /// Carry on from this checkpoint
+    resume: Option<Checkpoint>,
+
+    /// Only process the top level elements which start in this byte range, for sharding
+    start_offset: Option<u64>,
+    end_offset: Option<u64>,
+    /// The top level elements which `--start-offset` looks for
+    sync_tags: Vec<String>,
+    /// Split the input into chunks, & process them on this many threads
+    threads: Option<usize>,
+    /// How many chunks `--threads` can be ahead of the output
+    queue_size: Option<usize>,
+
+    /// Stop with an error, rather than buffer more than this many bytes, & sort with temporary
+    /// files after this many
+    max_memory: Option<usize>,
+
+    /// Flush the output after this many lines, rather than when the buffer is full
+    flush_every: Option<u64>,
+
+    /// Write the output to these files too
+    tee: Vec<std::path::PathBuf>,
+
+    /// Send the records to Kafka, rather than stdout
+    kafka: Option<KafkaOptions>,
+
+    /// Copy the records into PostgreSQL, rather than stdout
+    postgres: Option<PostgresOptions>,
+
+    /// Upload the output to S3, rather than stdout
+    upload: Option<UploadOptions>,
+
+    /// A shell command to write the output to, rather than stdout
+    pipe: Option<String>,
+
+    /// Stop after this many -s/-e records, or this many bytes of output
+    max_records: Option<u64>,
+    max_output_bytes: Option<u64>,
+
+    /// Write how far through the input we are as JSON lines
+    progress: Option<ProgressOptions>,
+    /// Write a JSON report at the end to this file, or `-` for stderr
+    summary_json: Option<String>,
+
+    /// Leave these elements out of `--copy` & `--copy-json`
+    copy_exclude: Vec<String>,
+    /// Sort the attributes & normalise the whitespace in `--copy` & `--copy-json`
+    canonical: bool,
+
+    /// Print how many records were output & bytes read at the end
+    print_stats: bool,
+
+    /// Print where the time went at the end
+    timing_detail: bool,
+
+    /// Which messages are logged to stderr, from `-q` & `--log-level`. Info by default
+    log_level: Option<tracing::level_filters::LevelFilter>,
+
+    /// Write the messages on stderr as JSON lines, with `--log-format json`
+    log_json: bool,
+
+    /// Print every XML event to stderr
+    trace_events: bool,
+
+    /// Print which instructions matched, & what they output, for this many start & end tags
+    debug_matches: Option<usize>,
+
+    /// Remove duplicate lines from the output
+    unique: Option<UniqueMode>,
+
+    /// Sort the output lines
+    sort: Option<SortKey>,
+
+    /// Set on Ctrl-C, to stop processing
+    interrupted: Arc<AtomicBool>,
+}
+
+/// How far through the input a run got, for `--checkpoint` & `--resume`. It's saved as lines of
+/// `NAME VALUE`
+#[derive(Debug, PartialEq, Eq, Default, Clone)]
+struct Checkpoint {
+    /// The input file
+    input: String,
+    /// The byte offset of the start tag to carry on from
+    offset: u64,
+    /// How many bytes had been output before it
+    output: u64,
+    /// How many records had been output before it
+    records: u64,
+    /// The value of each `--counter`, in order
+    counters: Vec<u64>,
+    /// The start tags of the open elements, outermost first
+    open: Vec<String>,
+}
+
+impl std::fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "input {}", self.input)?;
+        writeln!(f, "offset {}", self.offset)?;
+        writeln!(f, "output {}", self.output)?;
+        writeln!(f, "records {}", self.records)?;
+        let counters: Vec<_> = self.counters.iter().map(|c| c.to_string()).collect();
+        writeln!(f, "counters {}", counters.join(" "))?;
+        for tag in &self.open {
+            writeln!(f, "open {}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Checkpoint {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut checkpoint = Checkpoint::default();
+        for line in s.lines() {
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid {} {:?} in checkpoint", name, value))
+            };
+            match name {
+                "input" => checkpoint.input = value.to_string(),
+                "offset" => checkpoint.offset = number()?,
+                "output" => checkpoint.output = number()?,
+                "records" => checkpoint.records = number()?,
+                "counters" => {
+                    checkpoint.counters = value
+                        .split_whitespace()
+                        .map(|c| c.parse())
+                        .collect::<Result<_, _>>()
+                        .with_context(|| format!("Invalid counters {:?} in checkpoint", value))?;
+                }
+                "open" => checkpoint.open.push(value.to_string()),
+                _ => bail!("Unknown line {:?} in checkpoint", line),
+            }
+        }
+        Ok(checkpoint)
+    }
+}
+
+impl Checkpoint {
+    /// Save it to this file, replacing it all at once, so a crash can't leave half a checkpoint
+    fn save(&self, path: &std::path::Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.to_string())
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .with_context(|| format!("Cannot save checkpoint to {}", path.display()))
+    }
+}
+
+/// Where & how often `--progress-json` writes
+#[derive(Debug, PartialEq, Eq)]
+struct ProgressOptions {
+    /// The file (e.g. a named pipe) to write to, or stderr
+    path: Option<std::path::PathBuf>,
+    interval: std::time::Duration,
+}
+
+/// Writes JSON lines of how far through the input we are, for `--progress-json`
+struct Progress {
+    output: Box<dyn Write + Send>,
+    interval: std::time::Duration,
+    /// The total size of the inputs, if they're all files
+    total_bytes: Option<u64>,
+    started: std::time::Instant,
+    last: std::time::Instant,
+    /// Events since the time was last checked
+    events: u32,
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress")
+            .field("interval", &self.interval)
+            .field("total_bytes", &self.total_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Progress {
+    fn open(options: &ProgressOptions, inputs: &[String]) -> Result<Self> {
+        let output: Box<dyn Write + Send> = match &options.path {
+            None => Box::new(std::io::stderr()),
+            Some(path) => Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| {
+                        format!("Cannot open {} for --progress-json", path.display())
+                    })?,
+            ),
+        };
+        let total_bytes = inputs
+            .iter()
+            .map(|filename| {
+                (filename != "-")
+                    .then(|| std::fs::metadata(filename).ok().map(|m| m.len()))
+                    .flatten()
+            })
+            .sum();
+        let now = std::time::Instant::now();
+        Ok(Progress {
+            output,
+            interval: options.interval,
+            total_bytes,
+            started: now,
+            last: now,
+            events: 0,
+        })
+    }
+
+    /// Called for every event. Only looks at the time every so often, since that's slower
+    fn tick(&mut self, bytes_read: u64, records: u64) -> std::io::Result<()> {
+        self.events += 1;
+        if self.events < 1024 {
+            return Ok(());
+        }
+        self.events = 0;
+        let now = std::time::Instant::now();
+        if now.duration_since(self.last) >= self.interval {
+            self.last = now;
+            self.write("progress", bytes_read, records)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, event: &str, bytes_read: u64, records: u64) -> std::io::Result<()> {
+        let line = progress_json(
+            event,
+            bytes_read,
+            self.total_bytes,
+            records,
+            self.started.elapsed(),
+        );
+        self.output.write_all(line.as_bytes())?;
+        self.output.flush()
+    }
+}
+
+/// One line of `--progress-json`, with the rates & estimated time left worked out
+fn progress_json(
+    event: &str,
+    bytes_read: u64,
+    total_bytes: Option<u64>,
+    records: u64,
+    elapsed: std::time::Duration,
+) -> String {
+    let seconds = elapsed.as_secs_f64();
+    let rate = |n: u64| if seconds > 0. { n as f64 / seconds } else { 0. };
+    let optional = |value: Option<f64>| value.map_or("null".to_string(), |v| format!("{:.1}", v));
+    let percent = total_bytes
+        .filter(|total| *total > 0)
+        .map(|total| (bytes_read as f64 / total as f64 * 100.).min(100.));
+    let eta = total_bytes
+        .filter(|_| bytes_read > 0)
+        .map(|total| total.saturating_sub(bytes_read) as f64 / rate(bytes_read));
+    format!(
+        "{{\"event\":\"{}\",\"bytes_read\":{},\"total_bytes\":{},\"percent\":{},\"records\":{},\"elapsed_seconds\":{:.1},\"bytes_per_second\":{:.0},\"records_per_second\":{:.0},\"eta_seconds\":{}}}\n",
+        event,
+        bytes_read,
+        total_bytes.map_or("null".to_string(), |t| t.to_string()),
+        optional(percent),
+        records,
+        seconds,
+        rate(bytes_read),
+        rate(records),
+        optional(eta)
+    )
+}
+
+/// Writes the records which were rejected, for `--invalid-to`. Each line is the position, the
+/// path of the element, why it was rejected, & its start tag, separated by tabs
+struct InvalidRecords {
+    output: Box<dyn Write + Send>,
+}
+
+impl std::fmt::Debug for InvalidRecords {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InvalidRecords").finish_non_exhaustive()
+    }
+}
+
+impl InvalidRecords {
+    /// Create the file, or add to it if `append`, e.g. for `--resume`
+    fn open(path: &std::path::Path, append: bool) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .with_context(|| format!("Cannot open {} for --invalid-to", path.display()))?;
+        Ok(InvalidRecords {
+            output: Box::new(std::io::BufWriter::new(file)),
+        })
+    }
+
+    fn write(&mut self, ctx: &Context, reason: &str) -> std::io::Result<()> {
+        let mut element = String::new();
+        if let Some((tag, attributes)) = ctx.element {
+            element.push('<');
+            element.push_str(tag);
+            for attribute in attributes {
+                element.push(' ');
+                element.push_str(&attribute.name.borrow().to_repr());
+                element.push_str("=\"");
+                write_xml_escaped(&attribute.value, &mut element);
+                element.push('"');
+            }
+            element.push('>');
+        }
+        let columns = [ctx.location(), ctx.path(), reason.to_string(), element];
+        let columns: Vec<_> = columns
+            .into_iter()
+            .map(|c| TextFilter::TSVEscape.apply(Cow::Owned(c)))
+            .collect();
+        writeln!(self.output, "{}", columns.join("\t"))
+    }
+}
+
+/// What happened while processing the inputs, which is reported at the end
+#[derive(Debug, Default)]
+struct Summary {
+    /// How many records were output for -s/-e instructions
+    records: u64,
+    /// How often each instruction matched
+    matched: Vec<u64>,
+    /// How many bytes of input were read
+    bytes_read: u64,
+    /// How many bytes were output, for `--checkpoint`
+    bytes_written: u64,
+    /// Where processing stopped, if it was interrupted
+    interrupted: Option<String>,
+    /// Processing stopped at `--end-offset`, before the end of the document
+    reached_end_offset: bool,
+    /// `--max-records` or `--max-output-bytes` has been reached, so the rest of the input is
+    /// skipped
+    output_limit_reached: bool,
+    /// Roughly how many bytes the `--group-by`/`--aggregate` groups use
+    grouped_bytes: usize,
+    /// The most bytes which were buffered at once, for `--max-memory`
+    peak_buffered: usize,
+    /// Each distinct warning, how often it happened, & where it first happened
+    warnings: BTreeMap<String, (u64, String)>,
+    /// Carried over from one input to the next, for `--merge-root`
+    merged: Merged,
+    /// Where `--progress-json` is written
+    progress: Option<Progress>,
+    /// Where `--invalid-to` writes the rejected records
+    invalid: Option<InvalidRecords>,
+    /// Where the time went, for `--timing-detail`
+    timing: Option<Timing>,
+}
+
+thread_local! {
+    /// The time spent in filters & writing the output on this thread, when `--timing-detail` is
+    /// timing it, since they happen deep inside the actions
+    static FILTER_TIME: Cell<Option<Duration>> = const { Cell::new(None) };
+    static WRITE_TIME: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+/// Runs `f`, & adds how long it took to this time, if it's being timed
+fn timed<T>(
+    time: &'static std::thread::LocalKey<Cell<Option<Duration>>>,
+    f: impl FnOnce() -> T,
+) -> T {
+    match time.get() {
+        None => f(),
+        Some(before) => {
+            let started = std::time::Instant::now();
+            let result = f();
+            time.set(Some(before + started.elapsed()));
+            result
+        }
+    }
+}
+
+/// The time so far
+fn stage_time(time: &'static std::thread::LocalKey<Cell<Option<Duration>>>) -> Duration {
+    time.get().unwrap_or_default()
+}
+
+/// Where the time went, for `--timing-detail`. With `--threads`, it's the total of all the
+/// threads
+#[derive(Debug, Default)]
+struct Timing {
+    /// Reading & parsing the XML
+    parsing: Duration,
+    /// Running each instruction's actions, including the filters, but not writing the output
+    instructions: Vec<Duration>,
+    /// The filters, which are part of the instructions' time
+    filters: Duration,
+    /// Writing the output, including --sort, --unique etc.
+    writing: Duration,
+    /// Matching the instructions, & everything else
+    other: Duration,
+}
+
+impl Timing {
+    fn add(&mut self, other: Timing) {
+        self.parsing += other.parsing;
+        if self.instructions.len() < other.instructions.len() {
+            self.instructions
+                .resize(other.instructions.len(), Duration::ZERO);
+        }
+        for (time, more) in self.instructions.iter_mut().zip(other.instructions) {
+            *time += more;
+        }
+        self.filters += other.filters;
+        self.writing += other.writing;
+        self.other += other.other;
+    }
+
+    /// The report, with how long each instruction took
+    fn report(&self, instructions: &[Instruction]) -> String {
+        let instructions_total: Duration = self.instructions.iter().sum();
+        let total = self.parsing + instructions_total + self.writing + self.other;
+        let percent = |time: Duration| match total.is_zero() {
+            true => 0.0,
+            false => 100.0 * time.as_secs_f64() / total.as_secs_f64(),
+        };
+        let mut report = String::new();
+        let mut line = |what: &str, time: Duration| {
+            report.push_str(&format!(
+                "{:>10.3}s {:>5.1}%  {}\n",
+                time.as_secs_f64(),
+                percent(time),
+                what
+            ));
+        };
+        line("reading & parsing the XML", self.parsing);
+        line("matching the instructions, & everything else", self.other);
+        line("running the instructions:", instructions_total);
+        for (i, time) in self.instructions.iter().enumerate() {
+            if !time.is_zero() {
+                line(&format!("  {} (#{})", instructions[i], i + 1), *time);
+            }
+        }
+        line("  (of which the filters)", self.filters);
+        line("writing the output", self.writing);
+        report
+    }
+}
+
+/// What `--merge-root` keeps between inputs, since they're one document
+#[derive(Debug, Default)]
+struct Merged {
+    /// How many inputs have been read
+    inputs: u64,
+    /// How many child elements (the inputs' root elements) the merged root element has had, in
+    /// total & by name
+    children: (u64, HashMap<String, u64>),
+    /// The counts for instructions with --group-by, which are output after the last input
+    groups: Vec<Option<Groups>>,
+}
+
+impl Summary {
+    /// Record this non-fatal problem, which happened at `location`
+    fn warn(&mut self, message: String, location: String) {
+        self.warnings
+            .entry(message)
+            .or_insert_with(|| (0, location))
+            .0 += 1;
+    }
+
+    /// With `--invalid-to`, write this rejected record there, with the reason, & return true, so
+    /// it's skipped rather than stopping everything
+    fn reject(&mut self, ctx: &Context, reason: impl FnOnce() -> String) -> Result<bool> {
+        let invalid = match &mut self.invalid {
+            Some(invalid) => invalid,
+            None => return Ok(false),
+        };
+        invalid
+            .write(ctx, &reason())
+            .context("Cannot write to --invalid-to")?;
+        self.warn(
+            "Rejected records were written to --invalid-to".to_string(),
+            ctx.location(),
+        );
+        Ok(true)
+    }
+
+    /// Write `--progress-json`, if it's time to. If it can't be written (e.g. nothing is reading
+    /// the pipe any more), there's a warning, & no more progress
+    fn tick_progress(&mut self) {
+        if let Some(progress) = &mut self.progress {
+            if let Err(e) = progress.tick(self.bytes_read, self.records) {
+                self.progress = None;
+                self.warn(
+                    format!("Cannot write --progress-json: {}", e),
+                    String::new(),
+                );
+            }
+        }
+    }
+
+    /// Add what happened in the next chunk of the input, for `--threads`
+    fn add(&mut self, chunk: Summary) {
+        self.records += chunk.records;
+        self.matched
+            .resize(self.matched.len().max(chunk.matched.len()), 0);
+        for (matched, more) in self.matched.iter_mut().zip(chunk.matched) {
+            *matched += more;
+        }
+        self.bytes_read += chunk.bytes_read;
+        self.peak_buffered = self.peak_buffered.max(chunk.peak_buffered);
+        self.interrupted = self.interrupted.take().or(chunk.interrupted);
+        for (message, (count, location)) in chunk.warnings {
+            self.warnings.entry(message).or_insert((0, location)).0 += count;
+        }
+        if let Some(timing) = chunk.timing {
+            self.timing.get_or_insert_with(Timing::default).add(timing);
+        }
+    }
+
+    /// The -s/-e/-P/--comment/--chars instructions which never matched
+    fn never_matched<'a>(
+        &'a self,
+        instructions: &'a [Instruction],
+    ) -> impl Iterator<Item = &'a Instruction> {
+        instructions
+            .iter()
+            .zip(self.matched.iter())
+            .filter(|(instruction, matched)| {
+                **matched == 0
+                    && matches!(
+                        instruction,
+                        Instruction::StartTag { .. }
+                            | Instruction::EndTag { .. }
+                            | Instruction::Pi { .. }
+                            | Instruction::Comment { .. }
+                            | Instruction::Chars { .. }
+                    )
+            })
+            .map(|(instruction, _)| instruction)
+    }
+
+    /// The `--summary-json` report: what was read & output, how often each instruction matched,
+    /// the warnings, & why it stopped
+    fn to_json(
+        &self,
+        instructions: &[Instruction],
+        all_input: bool,
+        exit_reason: &str,
+        error: Option<&str>,
+        duration: std::time::Duration,
+    ) -> String {
+        use std::fmt::Write;
+        let mut json = String::new();
+        // Writing to a String can't fail
+        let _ = (|| -> std::fmt::Result {
+            write!(json, "{{\"exit_reason\":")?;
+            write_json_string(exit_reason, &mut json)?;
+            write!(json, ",\"error\":")?;
+            match error {
+                Some(error) => write_json_string(error, &mut json)?,
+                None => json.push_str("null"),
+            }
+            write!(
+                json,
+                ",\"duration_seconds\":{:.3},\"bytes_read\":{},\"bytes_written\":{},\"records\":{},\"instructions\":[",
+                duration.as_secs_f64(),
+                self.bytes_read,
+                self.bytes_written,
+                self.records
+            )?;
+            for (i, instruction) in instructions.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                json.push_str("{\"instruction\":");
+                write_json_string(&instruction.to_string(), &mut json)?;
+                write!(json, ",\"matched\":{}}}", self.matched.get(i).unwrap_or(&0))?;
+            }
+            json.push_str("],\"warnings\":[");
+            let never_matched = self
+                .never_matched(instructions)
+                .filter(|_| all_input)
+                .map(|instruction| (format!("{} never matched", instruction), 1, String::new()));
+            let warnings = self
+                .warnings
+                .iter()
+                .map(|(message, (count, location))| (message.clone(), *count, location.clone()));
+            for (i, (message, count, location)) in never_matched.chain(warnings).enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                json.push_str("{\"message\":");
+                write_json_string(&message, &mut json)?;
+                write!(json, ",\"count\":{},\"first_at\":", count)?;
+                if location.is_empty() {
+                    json.push_str("null");
+                } else {
+                    write_json_string(&location, &mut json)?;
+                }
+                json.push('}');
+            }
+            json.push_str("]}\n");
+            Ok(())
+        })();
+        json
+    }
+
+    /// Log the warnings at the end, once each, & any -s/-e/-P/--comment/--chars instructions
+    /// which never matched, if all the input was read. What they're about are fields too
+    fn log_warnings(&self, instructions: &[Instruction], all_input: bool) {
+        for instruction in self.never_matched(instructions).filter(|_| all_input) {
+            tracing::warn!(
+                instruction = %instruction,
+                "{} never matched",
+                instruction
+            );
+        }
+        for (message, (count, location)) in self.warnings.iter() {
+            tracing::warn!(
+                count,
+                first_at = %location,
+                "{}",
+                warning_message(message, *count, location)
+            );
+        }
+    }
+}
+
+/// A warning, with how often it happened & where it first happened
+fn warning_message(message: &str, count: u64, location: &str) -> String {
+    if count == 1 {
+        format!("{} (at {})", message, location)
+    } else {
+        format!("{} ({} times, first at {})", message, count, location)
+    }
+}
+
+/// `count` subcommand: Output how often each instruction's event happened, over all inputs
+fn count<R: BufRead>(
+    instructions: &[Instruction],
+    inputs: impl IntoIterator<Item = Result<R>>,
+    mut output: impl Write,
+) -> Result<()> {
+    if let Some(instruction) = instructions.iter().find(|i| i.xpath().is_some()) {
+        bail!("The count subcommand can't count {} yet", instruction);
+    }
+    let mut counts = vec![0u64; instructions.len()];
+
+    for input in inputs {
+        let mut input = input?;
+        skip_bom(&mut input)?;
+        let (prolog, doctype) = read_prolog(&mut input)?;
+        for wev in xml_reader(Cursor::new(prolog).chain(input)) {
+            let wev = wev?;
+            for (instruction, count) in instructions.iter().zip(counts.iter_mut()) {
+                let matches = match (instruction, &wev) {
+                    (Instruction::StartDocument { .. }, XmlEvent::StartDocument { .. }) => true,
+                    (Instruction::StartTag { .. }, XmlEvent::StartElement { name, .. })
+                    | (Instruction::EndTag { .. }, XmlEvent::EndElement { name }) => {
+                        instruction.matches_element(name)
+                    }
+                    (Instruction::EndDocument { .. }, XmlEvent::EndDocument) => true,
+                    (
+                        Instruction::Pi { target, .. },
+                        XmlEvent::ProcessingInstruction { name, .. },
+                    ) => target == name,
+                    (Instruction::Comment { .. }, XmlEvent::Comment(_)) => true,
+                    (Instruction::Doctype { .. }, XmlEvent::StartDocument { .. }) => {
+                        doctype.is_some()
+                    }
+                    (
+                        Instruction::Chars { nonblank, .. },
+                        XmlEvent::Characters(text)
+                        | XmlEvent::CData(text)
+                        | XmlEvent::Whitespace(text),
+                    ) => !(*nonblank && text.trim().is_empty()),
+                    _ => false,
+                };
+                if matches {
+                    *count += 1;
+                }
+            }
+        }
+    }
+
+    for (instruction, count) in instructions.iter().zip(counts) {
+        writeln!(output, "{}\t{}", instruction, count)?;
+    }
+
+    Ok(())
+}
+
+/// `stats` subcommand: Output some simple statistics about the structure of all inputs
+fn stats<R: BufRead>(
+    inputs: impl IntoIterator<Item = Result<R>>,
+    mut output: impl Write,
+) -> Result<()> {
+    let mut elements = 0u64;
+    let mut attributes = 0u64;
+    let mut text_bytes = 0u64;
+    let mut max_depth = 0usize;
+    let mut names = std::collections::HashSet::new();
+
+    for input in inputs {
+        let mut input = input?;
+        skip_bom(&mut input)?;
+        let mut depth = 0usize;
+        for wev in EventReader::new(input) {
+            match wev? {
+                XmlEvent::StartElement {
+                    name,
+                    attributes: attrs,
+                    namespace: _,
+                } => {
+                    elements += 1;
+                    attributes += attrs.len() as u64;
+                    depth += 1;
+                    max_depth = max_depth.max(depth);
+                    names.insert(name.local_name);
+                }
+                XmlEvent::EndElement { name: _ } => {
+                    depth -= 1;
+                }
+                XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                    text_bytes += text.len() as u64;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    writeln!(output, "elements\t{}", elements)?;
+    writeln!(output, "distinct_elements\t{}", names.len())?;
+    writeln!(output, "attributes\t{}", attributes)?;
+    writeln!(output, "max_depth\t{}", max_depth)?;
+    writeln!(output, "text_bytes\t{}", text_bytes)?;
+
+    Ok(())
+}
+
+/// The structure which the `validate` instructions declare, & how the documents differ from it
+#[derive(Debug, Default)]
+struct Expectations {
+    elements: Vec<ExpectedElement>,
+    /// How often each element which isn't in the instructions was seen
+    unexpected_elements: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+struct ExpectedElement {
+    tag: String,
+    /// Attributes which every element must have (`-v`)
+    required: Vec<String>,
+    /// Attributes which elements may have (`-V`)
+    optional: Vec<String>,
+    /// How many of these elements were seen
+    seen: u64,
+    /// How many elements didn't have each required attribute
+    missing: Vec<u64>,
+    /// How often each attribute which isn't in the instructions was seen
+    unexpected_attributes: BTreeMap<String, u64>,
+}
+
+impl Expectations {
+    /// `-s`/`-e` declare an element, their `-v`/`-V` actions declare its attributes. Everything
+    /// else is ignored
+    fn new(instructions: &[Instruction]) -> Self {
+        let mut elements: Vec<ExpectedElement> = vec![];
+        for instruction in instructions {
+            let tag = match instruction {
+                // -x '//*' doesn't declare any element
+                Instruction::StartTag { tag, .. } | Instruction::EndTag { tag, .. }
+                    if tag != "*" =>
+                {
+                    tag
+                }
+                _ => continue,
+            };
+            let element = match elements.iter().position(|e| &e.tag == tag) {
+                Some(i) => &mut elements[i],
+                None => {
+                    elements.push(ExpectedElement {
+                        tag: tag.clone(),
+                        ..Default::default()
+                    });
+                    elements.last_mut().unwrap()
+                }
+            };
+            for action in instruction.actions() {
+                match action {
+                    Action::Attribute(attr, _) if !element.required.contains(attr) => {
+                        element.required.push(attr.clone());
+                        element.missing.push(0);
+                    }
+                    Action::AttributeWithDefault(attr, _, _) => {
+                        element.optional.push(attr.clone());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Expectations {
+            elements,
+            ..Default::default()
+        }
+    }
+
+    /// Check this element against the expected structure
+    fn check(&mut self, name: &OwnedName, attributes: &[OwnedAttribute]) {
+        if self.elements.is_empty() {
+            return;
+        }
+        let element = match self.elements.iter_mut().find(|e| e.tag == name.local_name) {
+            Some(element) => element,
+            None => {
+                *self
+                    .unexpected_elements
+                    .entry(name.local_name.clone())
+                    .or_default() += 1;
+                return;
+            }
+        };
+        element.seen += 1;
+        for (attr, missing) in element.required.iter().zip(element.missing.iter_mut()) {
+            if !attributes
+                .iter()
+                .any(|a| attr_name_matches(&a.name, attr, false))
+            {
+                *missing += 1;
+            }
+        }
+        for a in attributes {
+            let expected = element
+                .required
+                .iter()
+                .chain(element.optional.iter())
+                .any(|attr| attr_name_matches(&a.name, attr, false));
+            if !expected {
+                *element
+                    .unexpected_attributes
+                    .entry(a.name.borrow().to_repr())
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    /// Output TSV of everything which differs from the expected structure. Returns false if
+    /// any expected element or attribute was missing
+    fn report(&self, mut output: impl Write) -> Result<bool> {
+        let mut ok = true;
+        for element in self.elements.iter() {
+            if element.seen == 0 {
+                writeln!(output, "missing_element\t{}", element.tag)?;
+                ok = false;
+                continue;
+            }
+            for (attr, missing) in element.required.iter().zip(element.missing.iter()) {
+                if *missing > 0 {
+                    writeln!(
+                        output,
+                        "missing_attribute\t{}\t{}\t{}",
+                        element.tag, attr, missing
+                    )?;
+                    ok = false;
+                }
+            }
+            for (attr, count) in element.unexpected_attributes.iter() {
+                writeln!(
+                    output,
+                    "unexpected_attribute\t{}\t{}\t{}",
+                    element.tag, attr, count
+                )?;
+            }
+        }
+        for (tag, count) in self.unexpected_elements.iter() {
+            writeln!(output, "unexpected_element\t{}\t{}", tag, count)?;
+        }
+        Ok(ok)
+    }
+}
+
+/// `stats histogram` subcommand: Output how often each element name (or path, if `paths`)
+/// happens, in all inputs, most common first
+fn histogram<R: BufRead>(
+    paths: bool,
+    inputs: impl IntoIterator<Item = Result<R>>,
+    mut output: impl Write,
+) -> Result<()> {
+    let mut counts: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+
+    for input in inputs {
+        let mut input = input?;
+        skip_bom(&mut input)?;
+        let mut path: Vec<Rc<str>> = vec![];
+        for wev in EventReader::new(input) {
+            match wev? {
+                XmlEvent::StartElement { name, .. } => {
+                    path.push(name.local_name.into());
+                    let key = if paths {
+                        element_path(&path)
+                    } else {
+                        path.last().unwrap().to_string()
+                    };
+                    *counts.entry(key).or_default() += 1;
+                }
+                XmlEvent::EndElement { .. } => {
+                    path.pop();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(a_name, a_count), (b_name, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_name.cmp(b_name))
+    });
+    for (name, count) in counts {
+        writeln!(output, "{}\t{}", name, count)?;
+    }
+
+    Ok(())
+}
+
+/// `validate` subcommand: Read the whole document, returning the first well-formedness error,
+/// and checking every element against `expected`
+fn validate(expected: &mut Expectations, mut input: impl BufRead) -> Result<()> {
+    skip_bom(&mut input)?;
+    for wev in EventReader::new(input) {
+        if let XmlEvent::StartElement {
+            name, attributes, ..
+        } = wev?
+        {
+            expected.check(&name, &attributes);
+        }
+    }
+    Ok(())
+}
+
+/// How the `rewrite` & `reindent` subcommands change the document as they copy it
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Rewrite {
+    /// Put each element on its own line, indented with this for each level, rather than keeping
+    /// the whitespace. Once an element has text in it, everything inside it is copied as it is
+    indent: Option<String>,
+    /// Element names to rename, from `--rename-tag OLD=NEW`
+    rename_tags: Vec<(String, String)>,
+    /// Attributes to rename, from `--rename-attr TAG.OLD=NEW`. In all the attribute rules, the
+    /// tag is the name before any `--rename-tag`, or `*`
+    rename_attrs: Vec<(String, String, String)>,
+    /// Attributes to remove, from `--drop-attr TAG.ATTR`
+    drop_attrs: Vec<(String, String)>,
+    /// Attributes to add (or change), from `--add-attr TAG.ATTR=VALUE`
+    add_attrs: Vec<(String, String, String)>,
+}
+
+impl Rewrite {
+    /// Parse `OLD=NEW` for `--rename-tag`
+    fn parse_rename_tag(rule: &str) -> Result<(String, String)> {
+        match rule.split_once('=') {
+            Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+                Ok((old.to_string(), new.to_string()))
+            }
+            _ => bail!("Invalid --rename-tag {:?}, expected OLD=NEW", rule),
+        }
+    }
+
+    /// Parse `TAG.ATTR`, & `=VALUE` if there is one
+    fn parse_attr_rule(rule: &str) -> Option<(String, String, Option<String>)> {
+        let (attr, value) = match rule.split_once('=') {
+            Some((attr, value)) => (attr, Some(value.to_string())),
+            None => (rule, None),
+        };
+        match attr.split_once('.') {
+            Some((tag, attr)) if !tag.is_empty() && !attr.is_empty() => {
+                Some((tag.to_string(), attr.to_string(), value))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse `TAG.OLD=NEW` for `--rename-attr`
+    fn parse_rename_attr(rule: &str) -> Result<(String, String, String)> {
+        match Self::parse_attr_rule(rule) {
+            Some((tag, old, Some(new))) if !new.is_empty() => Ok((tag, old, new)),
+            _ => bail!("Invalid --rename-attr {:?}, expected TAG.OLD=NEW", rule),
+        }
+    }
+
+    /// Parse `TAG.ATTR` for `--drop-attr`
+    fn parse_drop_attr(rule: &str) -> Result<(String, String)> {
+        match Self::parse_attr_rule(rule) {
+            Some((tag, attr, None)) => Ok((tag, attr)),
+            _ => bail!("Invalid --drop-attr {:?}, expected TAG.ATTRIBUTE", rule),
+        }
+    }
+
+    /// Parse `TAG.ATTR=VALUE` for `--add-attr`
+    fn parse_add_attr(rule: &str) -> Result<(String, String, String)> {
+        match Self::parse_attr_rule(rule) {
+            Some((tag, attr, Some(value))) => Ok((tag, attr, value)),
+            _ => bail!(
+                "Invalid --add-attr {:?}, expected TAG.ATTRIBUTE=VALUE",
+                rule
+            ),
+        }
+    }
+
+    /// The new name of this element
+    fn tag(&self, name: &mut OwnedName) {
+        if let Some((_, new)) = self
+            .rename_tags
+            .iter()
+            .find(|(old, _)| old == &name.local_name)
+        {
+            name.local_name = new.clone();
+        }
+    }
+
+    /// Change the attributes of this element, which has its original name. Attributes are
+    /// dropped, then renamed, then added
+    fn attributes(&self, tag: &str, attributes: &mut Vec<OwnedAttribute>) {
+        let applies = |t: &String| t == "*" || t == tag;
+        attributes.retain(|a| {
+            !self
+                .drop_attrs
+                .iter()
+                .any(|(t, attr)| applies(t) && attr_name_matches(&a.name, attr, false))
+        });
+        for attr in attributes.iter_mut() {
+            if let Some((_, _, new)) = self
+                .rename_attrs
+                .iter()
+                .find(|(t, old, _)| applies(t) && attr_name_matches(&attr.name, old, false))
+            {
+                attr.name.local_name = new.clone();
+            }
+        }
+        for (_, attr, value) in self.add_attrs.iter().filter(|(t, _, _)| applies(t)) {
+            match attributes
+                .iter_mut()
+                .find(|a| attr_name_matches(&a.name, attr, false))
+            {
+                Some(existing) => existing.value = value.clone(),
+                None => attributes.push(OwnedAttribute {
+                    name: OwnedName::local(attr.as_str()),
+                    value: value.clone(),
+                }),
+            }
+        }
+    }
+}
+
+/// Copy the XML documents to `output`, changed by the `rules`
+fn rewrite<R: BufRead>(
+    rules: &Rewrite,
+    inputs: impl IntoIterator<Item = Result<R>>,
+    mut output: impl Write,
+) -> Result<()> {
+    let mut xml = String::new();
+    for input in inputs {
+        let mut input = input?;
+        skip_bom(&mut input)?;
+        let (prolog, doctype) = read_prolog(&mut input)?;
+        // The namespaces of the open elements
+        let mut namespaces: Vec<Namespace> = vec![];
+        // The start tag hasn't been closed with `>` yet, so it can become `<tag/>`
+        let mut pending = false;
+        // With an indent, the depth of the element which text was found in, which is copied as
+        // it is
+        let mut inline: Option<usize> = None;
+        // The last thing written was a newline
+        let mut line_start = true;
+        for wev in xml_reader(Cursor::new(prolog).chain(input)) {
+            let wev = wev?;
+            let depth = namespaces.len();
+            let is_end = matches!(wev, XmlEvent::EndElement { .. });
+            if pending && !is_end {
+                xml.push('>');
+                pending = false;
+            }
+            // Tags, comments & PIs go on their own line, unless they're in text, or it's `<tag/>`
+            if let Some(indent) = &rules.indent {
+                if inline.is_none()
+                    && !pending
+                    && !line_start
+                    && matches!(
+                        wev,
+                        XmlEvent::StartElement { .. }
+                            | XmlEvent::EndElement { .. }
+                            | XmlEvent::Comment(_)
+                            | XmlEvent::ProcessingInstruction { .. }
+                    )
+                {
+                    xml.push('\n');
+                    xml.push_str(&indent.repeat(depth - is_end as usize));
+                }
+            }
+            match wev {
+                XmlEvent::StartDocument {
+                    version,
+                    standalone,
+                    ..
+                } => {
+                    xml.push_str(&format!("<?xml version=\"{}\" encoding=\"UTF-8\"", version));
+                    if let Some(standalone) = standalone {
+                        let standalone = if standalone { "yes" } else { "no" };
+                        xml.push_str(&format!(" standalone=\"{}\"", standalone));
+                    }
+                    xml.push_str("?>\n");
+                    if let Some(doctype) = &doctype {
+                        xml.push_str(&format!("{}\n", doctype));
+                    }
+                }
+                XmlEvent::StartElement {
+                    mut name,
+                    mut attributes,
+                    namespace,
+                } => {
+                    rules.attributes(&name.local_name, &mut attributes);
+                    rules.tag(&mut name);
+                    write_start_tag(&name, &attributes, &namespace, namespaces.last(), &mut xml);
+                    namespaces.push(namespace);
+                    pending = true;
+                }
+                XmlEvent::EndElement { mut name } => {
+                    namespaces.pop();
+                    if pending {
+                        xml.push_str("/>");
+                        pending = false;
+                    } else {
+                        rules.tag(&mut name);
+                        xml.push_str("</");
+                        xml.push_str(&name.borrow().to_repr());
+                        xml.push('>');
+                    }
+                    if inline == Some(depth) {
+                        inline = None;
+                    }
+                }
+                XmlEvent::Characters(text) | XmlEvent::CData(text) | XmlEvent::Whitespace(text) => {
+                    if inline.is_none() && !text.trim().is_empty() {
+                        inline = Some(depth);
+                    }
+                    if inline.is_some() || rules.indent.is_none() {
+                        write_xml_escaped(&text, &mut xml);
+                    }
+                }
+                XmlEvent::Comment(comment) => {
+                    xml.push_str(&format!("<!--{}-->", comment));
+                }
+                XmlEvent::ProcessingInstruction { name, data } => {
+                    xml.push_str(&format!("<?{}", name));
+                    if let Some(data) = data {
+                        xml.push_str(&format!(" {}", data));
+                    }
+                    xml.push_str("?>");
+                }
+                XmlEvent::EndDocument => {
+                    if !line_start {
+                        xml.push('\n');
+                    }
+                }
+            }
+            if !xml.is_empty() {
+                line_start = xml.ends_with('\n');
+                output.write_all(xml.as_bytes())?;
+                xml.clear();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Open this input file for reading from this byte offset, for `--resume` & `--start-offset`
+fn open_input_at(filename: &str, offset: u64) -> Result<Box<dyn BufRead>> {
+    let mut file = std::fs::File::open(filename)
+        .with_context(|| format!("Cannot open input file {}", filename))?;
+    if file.metadata()?.len() < offset {
+        bail!("{} is shorter than the offset {}", filename, offset);
+    }
+    file.seek(std::io::SeekFrom::Start(offset))?;
+    Ok(Box::new(std::io::BufReader::new(file)))
+}
+
+/// For `--start-offset`, carry on from the first top level element after the start offset, inside
+/// the root element. Returns false if there isn't one, so the shard is empty
+fn start_shard(config: &mut Config, filename: &str) -> Result<bool> {
+    let start = match config.start_offset {
+        Some(start) if start > 0 => start,
+        _ => return Ok(true),
+    };
+    match find_sync_point(open_input_at(filename, start)?, start, &config.sync_tags)? {
+        Some(offset) => {
+            config.resume = Some(Checkpoint {
+                input: filename.to_string(),
+                offset,
+                open: vec![read_root_tag(open_input(filename)?)?],
+                ..Default::default()
+            });
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// How big the chunks which `--threads` splits the input into are
+const PARALLEL_CHUNK_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Process this byte range of the input, like `--start-offset` & `--end-offset`, for `--threads`
+fn process_chunk(
+    parse: impl Fn() -> Result<(Config, Vec<Instruction>)>,
+    filename: &str,
+    start: u64,
+    end: Option<u64>,
+) -> Result<(Vec<u8>, Summary)> {
+    let (mut config, instructions) = parse()?;
+    config.start_offset = Some(start);
+    config.end_offset = end;
+    let mut output = vec![];
+    let mut summary = Summary::default();
+    if start_shard(&mut config, filename)? {
+        let input = match &config.resume {
+            Some(resume) => open_input_at(filename, resume.offset)?,
+            None => open_input(filename)?,
+        };
+        process(
+            &config,
+            &instructions,
+            filename,
+            input,
+            &mut output,
+            &mut summary,
+        )?;
+    }
+    Ok((output, summary))
+}
+
+/// Split the input into chunks of about `chunk_bytes` at top level elements, & process them on
+/// this many threads, writing their output in order. A chunk isn't started if it's `queue_size`
+/// chunks ahead of the output. `parse` makes each chunk's config & instructions
+fn process_parallel(
+    filename: &str,
+    threads: usize,
+    queue_size: usize,
+    chunk_bytes: u64,
+    parse: impl Fn() -> Result<(Config, Vec<Instruction>)> + Sync,
+    mut output: impl Write,
+    summary: &mut Summary,
+) -> Result<()> {
+    let length = std::fs::metadata(filename)
+        .with_context(|| format!("Cannot open input file {}", filename))?
+        .len();
+    let chunks = length.div_ceil(chunk_bytes).max(1);
+    let next_chunk = AtomicU64::new(0);
+    // How many chunks have been output, so the threads don't get too far ahead & use too much
+    // memory, & whether to stop early
+    let output_chunks = (std::sync::Mutex::new((0, false)), std::sync::Condvar::new());
+    let ahead = queue_size.max(1) as u64;
+    // Each chunk's span is inside the input's
+    let input_span = tracing::Span::current();
+    std::thread::scope(|scope| {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let (next_chunk, output_chunks, parse) = (&next_chunk, &output_chunks, &parse);
+            let input_span = &input_span;
+            scope.spawn(move || loop {
+                let chunk = next_chunk.fetch_add(1, Ordering::Relaxed);
+                if chunk >= chunks {
+                    break;
+                }
+                let (lock, condvar) = output_chunks;
+                let stop = condvar
+                    .wait_while(lock.lock().unwrap(), |(done, stop)| {
+                        !*stop && chunk >= *done + ahead
+                    })
+                    .unwrap()
+                    .1;
+                if stop {
+                    break;
+                }
+                let start = chunk * chunk_bytes;
+                let end = (chunk + 1 < chunks).then_some(start + chunk_bytes);
+                let span = tracing::info_span!(parent: input_span, "chunk", chunk, start, end);
+                let result = span.in_scope(|| {
+                    tracing::debug!("Processing chunk {} from byte {}", chunk, start);
+                    process_chunk(parse, filename, start, end)
+                });
+                if sender.send((chunk, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        // Chunks can finish in any order
+        let mut finished = BTreeMap::new();
+        let mut done = 0;
+        let mut result = Ok(());
+        'chunks: for (chunk, chunk_result) in receiver {
+            finished.insert(chunk, chunk_result);
+            while let Some(chunk_result) = finished.remove(&done) {
+                result = chunk_result.and_then(|(chunk_output, chunk_summary)| {
+                    summary.add(chunk_summary);
+                    summary.tick_progress();
+                    output.write_all(&chunk_output)?;
+                    Ok(())
+                });
+                done += 1;
+                let stop = result.is_err() || summary.interrupted.is_some();
+                let (lock, condvar) = &output_chunks;
+                *lock.lock().unwrap() = (done, stop);
+                condvar.notify_all();
+                if stop {
+                    break 'chunks;
+                }
+            }
+        }
+        result
+    })
+}
+
+/// The start tag of the document's root element, for `--start-offset`
+fn read_root_tag(input: impl Read) -> Result<String> {
+    for wev in xml_reader(input) {
+        if let XmlEvent::StartElement {
+            name,
+            attributes,
+            namespace,
+        } = wev?
+        {
+            return Ok(replayed_start_tag(&name, &attributes, &namespace, None));
+        }
+    }
+    bail!("There is no root element")
+}
+
+/// The offset of the first start tag of one of these elements in the input, which starts at
+/// `offset`, for `--start-offset`. It doesn't know about comments or CDATA, so a `<TAG` in one
+/// counts
+fn find_sync_point(input: impl BufRead, offset: u64, tags: &[String]) -> Result<Option<u64>> {
+    let longest = tags.iter().map(|t| t.len()).max().unwrap_or(0);
+    // The offset of the last `<`, & the name after it so far
+    let mut candidate: Option<(u64, Vec<u8>)> = None;
+    for (i, byte) in input.bytes().enumerate() {
+        let byte = byte?;
+        if byte == b'<' {
+            candidate = Some((offset + i as u64, vec![]));
+        } else if let Some((start, name)) = &mut candidate {
+            if byte.is_ascii_whitespace() || byte == b'/' || byte == b'>' {
+                let local_name = name.rsplit(|b| *b == b':').next().unwrap_or_default();
+                if tags.iter().any(|t| t.as_bytes() == local_name) {
+                    return Ok(Some(*start));
+                }
+                candidate = None;
+            } else if name.len() > longest * 2 {
+                // Too long to be one of them, even with a prefix
+                candidate = None;
+            } else {
+                name.push(byte);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Open this input file for reading, `-` is stdin
+fn open_input(filename: &str) -> Result<Box<dyn BufRead>> {
+    if filename == "-" {
+        Ok(Box::new(std::io::stdin().lock()))
+    } else {
+        let file = std::fs::File::open(filename)
+            .with_context(|| format!("Cannot open input file {}", filename))?;
+        Ok(Box::new(std::io::BufReader::new(file)))
+    }
+}
+
+/// A number of bytes, with an optional K, M, G or T suffix (powers of 1024), e.g. `500M`
+fn parse_size(size: &str) -> Result<usize> {
+    let (number, scale) = match size.char_indices().last() {
+        Some((i, suffix)) if suffix.is_ascii_alphabetic() => {
+            let power = match suffix.to_ascii_uppercase() {
+                'K' => 1,
+                'M' => 2,
+                'G' => 3,
+                'T' => 4,
+                _ => bail!("Unknown suffix {:?}, expected K, M, G or T", suffix),
+            };
+            (&size[..i], 1024usize.pow(power))
+        }
+        _ => (size, 1),
+    };
+    let number: usize = number.parse()?;
+    number
+        .checked_mul(scale)
+        .ok_or_else(|| anyhow!("{} is too big", size))
+}
+
+/// Parses this args (could be argv) to the config & instructions
+fn parse_to_instructions<'a>(
+    argv: impl Into<Option<&'a [&'a str]>>,
+) -> Result<(Config, Vec<Instruction>)> {
+    let mut config = Config::default();
+    let app = clap_app();
+    let argv: Option<&[&str]> = argv.into();
+    let matches = match argv {
+        // from CLI args
+        None => app.get_matches(),
+
+        // From the provided args (used for testing)
+        Some(argv) => {
+            let app = app.setting(clap::AppSettings::NoBinaryName);
+            app.get_matches_from(argv)
+        }
+    };
+
+    // `osm PRESET` is shorthand for `extract` with that preset's instructions
+    if let Some(("osm", sub_matches)) = matches.subcommand() {
+        let preset = sub_matches.value_of("preset").unwrap();
+        let (_, _, args) = OSM_PRESETS
+            .iter()
+            .find(|(name, _, _)| *name == preset)
+            .unwrap();
+        let mut argv = vec!["extract"];
+        argv.extend(args.split_whitespace());
+        argv.extend(sub_matches.values_of("input").into_iter().flatten());
+        return parse_to_instructions(argv.as_slice());
+    }
+
+    let app = clap_app();
+    let (subcommand, matches) = match matches.subcommand() {
+        None => (&app, &matches),
+        Some((name, sub_matches)) => {
+            config.mode = match name {
+                "extract" => Mode::Extract,
+                "count" => Mode::Count,
+                "stats" => Mode::Stats,
+                "validate" => Mode::Validate,
+                "diff" => Mode::Diff,
+                "bench" => Mode::Bench(Bench {
+                    iterations: sub_matches
+                        .value_of("iterations")
+                        .unwrap()
+                        .parse()
+                        .context("--iterations must be a number")?,
+                    synthetic: sub_matches
+                        .value_of("synthetic")
+                        .map(|size| {
+                            parse_size(size)
+                                .with_context(|| format!("Invalid --synthetic {:?}", size))
+                        })
+                        .transpose()?,
+                }),
+                "reindent" | "rewrite" => {
+                    let indent = match sub_matches.value_of("indent") {
+                        None => None,
+                        Some("tab") => Some("\t".to_string()),
+                        Some(n) => Some(" ".repeat(n.parse().with_context(|| {
+                            format!("Invalid --indent {:?}, expected a number or tab", n)
+                        })?)),
+                    };
+                    // reindent doesn't have the renaming args
+                    let values = |arg| {
+                        (name == "rewrite")
+                            .then(|| sub_matches.values_of(arg))
+                            .flatten()
+                            .into_iter()
+                            .flatten()
+                    };
+                    Mode::Rewrite(Rewrite {
+                        indent,
+                        rename_tags: values("rename_tag")
+                            .map(Rewrite::parse_rename_tag)
+                            .collect::<Result<_>>()?,
+                        rename_attrs: values("rename_attr")
+                            .map(Rewrite::parse_rename_attr)
+                            .collect::<Result<_>>()?,
+                        drop_attrs: values("drop_attr")
+                            .map(Rewrite::parse_drop_attr)
+                            .collect::<Result<_>>()?,
+                        add_attrs: values("add_attr")
+                            .map(Rewrite::parse_add_attr)
+                            .collect::<Result<_>>()?,
+                    })
+                }
+                "completions" => Mode::Completions(
+                    sub_matches
+                        .value_of("shell")
+                        .unwrap()
+                        .parse()
+                        .map_err(|e| anyhow!("Unknown shell: {}", e))?,
+                ),
+                x => bail!("Unknown subcommand {}", x),
+            };
+            match sub_matches.subcommand() {
+                Some(("histogram", histogram_matches)) => {
+                    config.mode = Mode::Histogram {
+                        paths: histogram_matches.is_present("paths"),
+                    };
+                    (app.find_subcommand(name).unwrap(), histogram_matches)
+                }
+                _ => (app.find_subcommand(name).unwrap(), sub_matches),
+            }
+        }
+    };
+
+    if config.mode == Mode::Extract {
+        parse_config(matches, &mut config)?;
+    }
+    if let Some(header) = config.header_comment.as_mut() {
+        let args: Vec<String> = match argv {
+            None => std::env::args().skip(1).collect(),
+            Some(argv) => argv.iter().map(|a| a.to_string()).collect(),
+        };
+        header.command = std::iter::once("anglosaxon")
+            .chain(args.iter().map(|a| a.as_str()))
+            .map(shell_quote)
+            .collect::<Vec<_>>()
+            .join(" ");
+    }
+    if !matches!(config.mode, Mode::Completions(_)) {
+        config.inputs = matches
+            .values_of("input")
+            .map_or_else(|| vec!["-".to_string()], |v| v.map(String::from).collect());
+    }
+    let mut instructions = match config.mode {
+        Mode::Extract | Mode::Count | Mode::Validate | Mode::Diff | Mode::Bench(_) => {
+            parse_instructions(subcommand, matches, &mut config)?
+        }
+        _ => vec![],
+    };
+    if config.ignore_tag_case {
+        // The element names are lowercased to compare with these
+        instructions
+            .iter_mut()
+            .for_each(Instruction::lowercase_tags);
+    }
+    if config.geojson.is_some() {
+        if let Some(instruction) = instructions.iter().find(|i| {
+            !matches!(i, Instruction::StartTag { .. } | Instruction::EndTag { .. })
+                || i.is_aggregated()
+        }) {
+            bail!(
+                "--output-format geojson only works with -s/-e/-x instructions without --group-by/--aggregate, not {}",
+                instruction
+            );
+        }
+    }
+    if let Some(format) = config.record_format {
+        if let Some(instruction) = instructions.iter().find(|i| {
+            !matches!(i, Instruction::StartTag { .. } | Instruction::EndTag { .. })
+                || i.is_aggregated()
+        }) {
+            bail!(
+                "{} only works with -s/-e/-x instructions without --group-by/--aggregate, not {}",
+                format.option(),
+                instruction
+            );
+        }
+    } else if instructions
+        .iter()
+        .any(|i| i.actions().iter().any(|a| matches!(a, Action::Name(_))))
+    {
+        bail!("--name only does something with --json-record or --output-format kv/logfmt");
+    }
+    if config.checkpoint.is_some() {
+        check_checkpointable(&config, &instructions)?;
+    }
+    if config.start_offset.is_some() || config.end_offset.is_some() || config.threads.is_some() {
+        let option = if config.threads.is_some() {
+            "--threads"
+        } else {
+            "--start-offset & --end-offset"
+        };
+        if config.inputs.len() != 1 || config.inputs[0] == "-" {
+            bail!(
+                "{} only work with one input file, since stdin can't be read from part way through",
+                option
+            );
+        }
+        if config.merge_root.is_some() || config.continuous {
+            bail!("{} can't be used with --merge-root or --continuous", option);
+        }
+        if config.fragment.is_some() {
+            bail!("{} can't be used with --fragment", option);
+        }
+        if let Some(instruction) = instructions.iter().find(|i| i.needs_whole_input()) {
+            bail!(
+                "{} can't be used with --group-by/--aggregate or sibling positions, which need the whole input, as in {}",
+                option,
+                instruction
+            );
+        }
+        // Each chunk would start counting again
+        if let Some(instruction) = instructions.iter().find(|i| {
+            config.threads.is_some() && i.actions().iter().any(|a| matches!(a, Action::Counter(..)))
+        }) {
+            bail!(
+                "--threads can't be used with counters, as in {}",
+                instruction
+            );
+        }
+        if config.sync_tags.is_empty() {
+            for instruction in &instructions {
+                if let Instruction::StartTag { tag, .. } | Instruction::EndTag { tag, .. } =
+                    instruction
+                {
+                    if tag != "*" && !config.sync_tags.contains(tag) {
+                        config.sync_tags.push(tag.clone());
+                    }
+                }
+            }
+            if config.sync_tags.is_empty() {
+                bail!("{} need --sync-tag, since there are no -s/-e tags", option);
+            }
+        }
+    }
+
+    Ok((config, instructions))
+}
+
+/// Whether a run can be carried on from a `--checkpoint`. It has to be possible to start reading
+/// the input part way through, & nothing can depend on what came before apart from the open
+/// elements & the counters
+fn check_checkpointable(config: &Config, instructions: &[Instruction]) -> Result<()> {
+    if config.mode != Mode::Extract {
+        bail!("--checkpoint only works with extract");
+    }
+    if config.inputs.len() != 1 || config.inputs[0] == "-" {
+        bail!("--checkpoint only works with one input file, since stdin can't be read from part way through");
+    }
+    let unsupported = [
+        (config.sort.is_some(), "--sort"),
+        (config.unique.is_some(), "--unique"),
+        (config.merge_root.is_some(), "--merge-root"),
+        (config.continuous, "--continuous"),
+        (config.fragment.is_some(), "--fragment"),
+        (
+            config.geojson.as_ref().is_some_and(|g| !g.lines),
+            "--output-format geojson",
+        ),
+    ];
+    if let Some((_, option)) = unsupported.iter().find(|(used, _)| *used) {
+        bail!("--checkpoint can't be used with {}", option);
+    }
+    if let Some(instruction) = instructions.iter().find(|i| i.needs_whole_input()) {
+        bail!(
+            "--checkpoint can't be used with --group-by/--aggregate or sibling positions, which need the whole input, as in {}",
+            instruction
+        );
+    }
+    if let Some(resume) = &config.resume {
+        let counters = instructions
+            .iter()
+            .flat_map(|i| i.actions())
+            .filter(|a| matches!(a, Action::Counter(..)))
+            .count();
+        if resume.input != config.inputs[0] || resume.counters.len() != counters {
+            bail!(
+                "The checkpoint is for {} with {} counters, not {} with these instructions",
+                resume.input,
+                resume.counters.len(),
+                config.inputs[0]
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Turn the matched args of this (sub)command into instructions, in the order given
+fn parse_instructions(
+    app: &Command,
+    matches: &ArgMatches,
+    config: &mut Config,
+) -> Result<Vec<Instruction>> {
+    let mut instructions = vec![];
+    let args = clap_app_to_ordered_matches(app, matches);
+
+    let mut current_instruction: Option<Instruction> = None;
+    // What --has outputs
+    let mut has_tokens = ("true".to_string(), "false".to_string());
+    let mut level: usize;
+    for (name, mut value) in args.into_iter() {
+        match name.as_str() {
+            "startdoc" => {
+                if let Some(previous) = current_instruction.take() {
+                    instructions.push(previous);
+                }
+                current_instruction = Some(Instruction::StartDocument { actions: vec![] });
+            }
+            "startelement" => {
+                if let Some(previous) = current_instruction.take() {
+                    instructions.push(previous);
+                }
+                let (tag, pattern) = parse_tag(&value[0])?;
+                current_instruction = Some(Instruction::StartTag {
+                    tag,
+                    actions: pattern.into_iter().collect(),
+                });
+            }
+            "xpath" => {
+                if let Some(previous) = current_instruction.take() {
+                    instructions.push(previous);
+                }
+                let path: XPath = value[0].parse()?;
+                current_instruction = Some(Instruction::StartTag {
+                    tag: path.tag().to_string(),
+                    actions: vec![Action::XPath(path)],
+                });
+            }
+            "endelement" => {
+                if let Some(previous) = current_instruction.take() {
+                    instructions.push(previous);
+                }
+                let (tag, pattern) = parse_tag(&value[0])?;
+                current_instruction = Some(Instruction::EndTag {
+                    tag,
+                    actions: pattern.into_iter().collect(),
+                });
+            }
+            "enddoc" => {
+                if let Some(previous) = current_instruction.take() {
+                    instructions.push(previous);
+                }
+                current_instruction = Some(Instruction::EndDocument { actions: vec![] });
+            }
+            "comment" => {
+                if let Some(previous) = current_instruction.take() {
+                    instructions.push(previous);
+                }
+                current_instruction = Some(Instruction::Comment { actions: vec![] });
+            }
+            "chars" => {
+                if let Some(previous) = current_instruction.take() {
+                    instructions.push(previous);
+                }
+                let nonblank = match value.remove(0).as_str() {
+                    "all" => false,
+                    "nonblank" => true,
+                    x => bail!("Unknown --chars mode {}, expected all or nonblank", x),
+                };
+                current_instruction = Some(Instruction::Chars {
+                    nonblank,
+                    actions: vec![],
+                });
+            }
+            "doctype" => {
+                if let Some(previous) = current_instruction.take() {
+                    instructions.push(previous);
+                }
+                current_instruction = Some(Instruction::Doctype { actions: vec![] });
+            }
+            "pi" => {
+                if let Some(previous) = current_instruction.take() {
+                    instructions.push(previous);
+                }
+                current_instruction = Some(Instruction::Pi {
+                    target: value.remove(0),
+                    actions: vec![],
+                });
+            }
+
+            "raw" => match current_instruction {
+                None => {
+                    bail!("Cannot use -o before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    let mut s = value.remove(0);
+                    if config.expand_env {
+                        s = expand_env(&s)?;
+                    }
+                    i.actions_mut().push(Action::RawString(s));
+                }
+            },
+            "newline" => match current_instruction {
+                None => {
+                    bail!("Cannot use --nl before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    i.actions_mut().push(Action::RawString("\n".to_string()));
+                }
+            },
+            "name" => match current_instruction {
+                Some(ref mut i) if !i.actions().is_empty() => {
+                    i.actions_mut().push(Action::Name(value.remove(0)));
+                }
+                _ => {
+                    bail!("Cannot use --name before an action to name");
+                }
+            },
+            "tab" => match current_instruction {
+                None => {
+                    bail!("Cannot use --tab before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    i.actions_mut().push(Action::RawString("\t".to_string()));
+                }
+            },
+
+            "now" => match current_instruction {
+                None => {
+                    bail!("Cannot use --now before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    let format = value.remove(0);
+                    validate_strftime(&format)?;
+                    i.actions_mut().push(Action::Now(format));
+                }
+            },
+
+            "uuid" => match current_instruction {
+                None => {
+                    bail!("Cannot use --uuid before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    let version = match value.remove(0).as_str() {
+                        "v4" => UuidVersion::V4,
+                        "v7" => UuidVersion::V7,
+                        x => bail!("Unknown UUID version {}", x),
+                    };
+                    i.actions_mut().push(Action::Uuid(version));
+                }
+            },
+
+            "on_missing_attr" => {
+                let policy = value.remove(0).parse()?;
+                match current_instruction {
+                    None => {
+                        config.on_missing_attr = policy;
+                    }
+                    Some(ref mut i) => {
+                        i.actions_mut().push(Action::OnMissingAttr(policy));
+                    }
+                }
+            }
+
+            "doctype_name" | "public_id" | "system_id" => match current_instruction {
+                None => {
+                    bail!(
+                        "Cannot use --{} before you have done a --doctype",
+                        name.replace('_', "-")
+                    );
+                }
+                Some(ref mut i) => {
+                    i.actions_mut().push(match name.as_str() {
+                        "doctype_name" => Action::DoctypeName,
+                        "public_id" => Action::PublicId,
+                        _ => Action::SystemId,
+                    });
+                }
+            },
+
+            "text" => match current_instruction {
+                None => {
+                    bail!("Cannot use --text before you have done a --chars");
+                }
+                Some(ref mut i) => {
+                    i.actions_mut()
+                        .push(Action::Text(Filters::parse(&value.remove(0))?));
+                }
+            },
+
+            "comment_text" => match current_instruction {
+                None => {
+                    bail!("Cannot use --comment-text before you have done a --comment");
+                }
+                Some(ref mut i) => {
+                    i.actions_mut().push(Action::CommentText);
+                }
+            },
+
+            "pi_data" => match current_instruction {
+                None => {
+                    bail!("Cannot use --pi-data before you have done a -P");
+                }
+                Some(ref mut i) => {
+                    i.actions_mut().push(Action::PiData);
+                }
+            },
+
+            "offset" => match current_instruction {
+                None => {
+                    bail!("Cannot use --offset before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    i.actions_mut().push(Action::Offset);
+                }
+            },
+
+            "sibling_index" => match current_instruction {
+                None => {
+                    bail!("Cannot use --sibling-index before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    let same_name = match value.remove(0).as_str() {
+                        "all" => false,
+                        "same-name" => true,
+                        x => bail!("Unknown --sibling-index {}, expected all or same-name", x),
+                    };
+                    i.actions_mut().push(Action::SiblingIndex(same_name));
+                }
+            },
+
+            "counter" | "counter_per" => match current_instruction {
+                None => {
+                    bail!(
+                        "Cannot use --{} before you have done a -s/-e",
+                        name.replace('_', "-")
+                    );
+                }
+                Some(ref mut i) => {
+                    let reset_at = value.pop();
+                    i.actions_mut()
+                        .push(Action::Counter(reset_at, Cell::new(0)));
+                }
+            },
+
+            "matched" => match current_instruction {
+                Some(Instruction::EndDocument {
+                    ref mut actions, ..
+                }) => {
+                    actions.push(Action::Matched(value.remove(0), Cell::new(0)));
+                }
+                _ => {
+                    bail!("Can only use --matched after a -E");
+                }
+            },
 
-    let has_parent_attributes = instructions
-        .iter()
-        .any(|i| i.actions().iter().any(|a| a.is_parent_attr()));
-    let mut parent_attrs: Vec<Vec<xml::attribute::OwnedAttribute>> = vec![];
-    let mut parent_tags: Vec<String> = vec![];
+            "filename" => match current_instruction {
+                None => {
+                    bail!("Cannot use --filename before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    i.actions_mut().push(Action::Filename);
+                }
+            },
+            "basename" => match current_instruction {
+                None => {
+                    bail!("Cannot use --basename before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    i.actions_mut().push(Action::Basename);
+                }
+            },
 
-    for wev in reader {
-        match wev? {
-            XmlEvent::StartDocument {
-                version: _,
-                encoding: _,
-                standalone: _,
-            } => {
-                for instruction in instructions.iter() {
-                    if let Instruction::StartDocument { actions } = instruction {
-                        for action in actions {
-                            match action {
-                                Action::RawString(s) => {
-                                    output.write_all(s.as_bytes())?;
-                                }
-                                _ => todo!(),
-                            }
+            "value" => match current_instruction {
+                None => {
+                    bail!("Cannot use -v before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    i.actions_mut().push(parse_attribute(&value[0])?);
+                }
+            },
+            "fields" => match current_instruction {
+                None => {
+                    bail!("Cannot use --fields before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    let separator = config.field_separator.as_deref().unwrap_or("\t");
+                    for (n, field) in value[0].split(',').enumerate() {
+                        if field.is_empty() {
+                            bail!("Invalid --fields {:?}, there is an empty field", value[0]);
                         }
+                        if n > 0 {
+                            i.actions_mut()
+                                .push(Action::RawString(separator.to_string()));
+                        }
+                        i.actions_mut().push(parse_attribute(field)?);
+                    }
+                    // --rs is added to the end of every instruction anyway
+                    if config.record_separator.is_none() {
+                        i.actions_mut().push(Action::RawString("\n".to_string()));
                     }
                 }
-            }
+            },
 
-            XmlEvent::StartElement {
-                name,
-                attributes,
-                namespace: _,
-            } => {
-                for instruction in instructions.iter() {
-                    match instruction {
-                        Instruction::StartTag { tag, actions } if tag == &name.local_name => {
-                            for action in actions {
-                                match action {
-                                    Action::RawString(s) => {
-                                        output.write_all(s.as_bytes())?;
-                                    }
-                                    Action::Attribute(attr, filters) => {
-                                        let value = get_attr(&attributes, attr, tag)?;
-                                        let value = filters.apply(value);
-                                        output.write_all(value.as_bytes())?;
-                                    }
-                                    Action::AttributeWithDefault(attr, default, filters) => {
-                                        let value = attributes
-                                            .iter()
-                                            .filter_map(|a| {
-                                                if &a.name.local_name == attr {
-                                                    Some(&a.value)
-                                                } else {
-                                                    None
-                                                }
-                                            })
-                                            .next()
-                                            .unwrap_or(default);
-                                        let value = filters.apply(value);
-                                        output.write_all(value.as_bytes())?;
-                                    }
+            "aggregate" => match current_instruction {
+                None => {
+                    bail!("Cannot use --aggregate before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    let (reducer, attr) = value[0].split_once(':').ok_or_else(|| {
+                        anyhow!(
+                            "Invalid --aggregate {:?}, expected REDUCER:ATTRIBUTE, e.g. sum:len",
+                            value[0]
+                        )
+                    })?;
+                    let reducer = reducer.parse()?;
+                    let action = match parse_attribute(attr)? {
+                        Action::Attribute(attr, filters) => {
+                            Action::Aggregate(reducer, 0, attr, filters)
+                        }
+                        Action::ParentAttribute(level, attr, filters) => {
+                            Action::Aggregate(reducer, level, attr, filters)
+                        }
+                        _ => unreachable!(),
+                    };
+                    i.actions_mut().push(action);
+                }
+            },
 
-                                    Action::ParentAttribute(level, attr, filters) => {
-                                        if *level > parent_attrs.len() {
-                                            bail!("")
-                                        }
-                                        let value = get_attr(
-                                            &parent_attrs[parent_attrs.len() - level],
-                                            attr,
-                                            parent_tags[parent_attrs.len() - level].as_str(),
-                                        )?;
-                                        let value = filters.apply(value);
-                                        output.write_all(value.as_bytes())?;
-                                    }
-                                    Action::ParentAttributeWithDefault(
-                                        level,
-                                        attr,
-                                        default,
-                                        filters,
-                                    ) => {
-                                        if *level > parent_attrs.len() {
-                                            bail!("")
-                                        }
-                                        let value = parent_attrs[parent_attrs.len() - level]
-                                            .iter()
-                                            .filter_map(|a| {
-                                                if &a.name.local_name == attr {
-                                                    Some(&a.value)
-                                                } else {
-                                                    None
-                                                }
-                                            })
-                                            .next()
-                                            .unwrap_or(default);
-                                        let value = filters.apply(value);
-                                        output.write_all(value.as_bytes())?;
-                                    }
-                                }
-                            }
+            "opt_value" => match current_instruction {
+                None => {
+                    bail!("Cannot use --opt-value before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    if value.len() > 3 {
+                        bail!(
+                            "--opt-value takes an attribute, prefix & suffix, not {:?}",
+                            value
+                        );
+                    }
+                    let mut affixes = value[1..].to_vec();
+                    affixes.resize(2, String::new());
+                    if config.expand_env {
+                        for affix in affixes.iter_mut() {
+                            *affix = expand_env(affix)?;
                         }
-                        _ => {}
                     }
+                    let suffix = affixes.pop().unwrap();
+                    let prefix = affixes.pop().unwrap();
+                    let action = match parse_attribute(&value[0])? {
+                        Action::Attribute(attr, filters) => {
+                            Action::OptionalAttribute(0, attr, prefix, suffix, filters)
+                        }
+                        Action::ParentAttribute(level, attr, filters) => {
+                            Action::OptionalAttribute(level, attr, prefix, suffix, filters)
+                        }
+                        _ => unreachable!(),
+                    };
+                    i.actions_mut().push(action);
                 }
+            },
 
-                if has_parent_attributes {
-                    parent_attrs.push(attributes);
-                    parent_tags.push(name.local_name);
+            "collect" => match current_instruction {
+                Some(Instruction::EndTag {
+                    ref mut actions, ..
+                }) => {
+                    let (child, attr) = value[0].split_once('/').ok_or_else(|| {
+                        anyhow!(
+                            "Invalid --collect {:?}, expected CHILD/ATTRIBUTE, e.g. nd/ref",
+                            value[0]
+                        )
+                    })?;
+                    let (attr, filters) = Filters::parse_both(attr)?;
+                    actions.push(Action::Collect(
+                        child.to_string(),
+                        attr,
+                        value[1].clone(),
+                        filters,
+                    ));
                 }
-            }
+                _ => {
+                    bail!("Can only use --collect after a -e");
+                }
+            },
 
-            XmlEvent::EndElement { name } => {
-                for instruction in instructions.iter() {
-                    match instruction {
-                        Instruction::EndTag { tag, actions } if tag == &name.local_name => {
-                            for action in actions {
-                                match action {
-                                    Action::RawString(s) => {
-                                        output.write_all(s.as_bytes())?;
-                                    }
-                                    _ => {
-                                        todo!()
-                                    }
-                                }
-                            }
+            "collect_json" => match current_instruction {
+                Some(Instruction::EndTag {
+                    ref mut actions, ..
+                }) => {
+                    let action = match value[0].split_once('/') {
+                        Some((child, attr)) => {
+                            Action::CollectJson(child.to_string(), Some(attr.to_string()))
                         }
-                        _ => {}
-                    }
+                        None => Action::CollectJson(value[0].clone(), None),
+                    };
+                    actions.push(action);
                 }
-                if has_parent_attributes {
-                    parent_attrs.pop();
-                    parent_tags.pop();
+                _ => {
+                    bail!("Can only use --collect-json after a -e");
                 }
-            }
+            },
 
-            XmlEvent::EndDocument => {
-                for instruction in instructions.iter() {
-                    if let Instruction::EndDocument { actions } = instruction {
-                        for action in actions {
-                            match action {
-                                Action::RawString(s) => {
-                                    output.write_all(s.as_bytes())?;
-                                }
-                                _ => todo!(),
-                            }
-                        }
-                    }
+            "collect_hstore" | "collect_json_object" => match current_instruction {
+                Some(Instruction::EndTag {
+                    ref mut actions, ..
+                }) => {
+                    let (child, filters) = Filters::parse_both(&value[0])?;
+                    let (child, key, value) = match child.split('/').collect::<Vec<_>>()[..] {
+                        [child] => (child, "k", "v"),
+                        [child, key, value] => (child, key, value),
+                        _ => bail!(
+                            "Invalid --{} {:?}, expected CHILD or CHILD/KEY/VALUE, e.g. tag/k/v",
+                            name.replace('_', "-"),
+                            value[0]
+                        ),
+                    };
+                    let (child, key, value) = (child.into(), key.into(), value.into());
+                    actions.push(if name == "collect_hstore" {
+                        Action::CollectHstore(child, key, value, filters)
+                    } else {
+                        Action::CollectJsonObject(child, key, value, filters)
+                    });
                 }
-            }
+                _ => {
+                    bail!("Can only use --{} after a -e", name.replace('_', "-"));
+                }
+            },
 
-            _ => {}
-        }
-    }
+            "leaf_only" | "empty_only" => match current_instruction {
+                Some(ref mut i @ (Instruction::StartTag { .. } | Instruction::EndTag { .. })) => {
+                    let marker = if name == "leaf_only" {
+                        Action::LeafOnly
+                    } else {
+                        Action::EmptyOnly
+                    };
+                    if !i.actions().contains(&marker) {
+                        i.actions_mut().push(marker);
+                    }
+                }
+                _ => {
+                    bail!("Can only use --{} after a -s/-e", name.replace('_', "-"));
+                }
+            },
 
-    Ok(())
-}
+            "require" => match current_instruction {
+                None => {
+                    bail!("Cannot use --require before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    let action = match parse_attribute(&value[0])? {
+                        Action::Attribute(attr, _) => Action::Require(0, attr),
+                        Action::ParentAttribute(level, attr, _) => Action::Require(level, attr),
+                        _ => unreachable!(),
+                    };
+                    i.actions_mut().push(action);
+                }
+            },
 
-/// Parses this args (could be argv) to the instructions
-fn parse_to_instructions<'a>(argv: impl Into<Option<&'a [&'a str]>>) -> Result<Vec<Instruction>> {
-    let mut instructions = vec![];
-    let app = clap_app();
-    let argv: Option<&[&str]> = argv.into();
-    let args = clap_app_to_ordered_matches(app, argv);
+            "ns_uri" => match current_instruction {
+                Some(ref mut i @ (Instruction::StartTag { .. } | Instruction::EndTag { .. })) => {
+                    if i.namespace_uri().is_some() {
+                        bail!("Can only use one --ns-uri for each -s/-e");
+                    }
+                    i.actions_mut().push(Action::NamespaceUri(value[0].clone()));
+                }
+                _ => {
+                    bail!("Can only use --ns-uri after a -s/-e");
+                }
+            },
 
-    let mut current_instruction: Option<Instruction> = None;
-    let mut level: usize;
-    for (name, mut value) in args.into_iter() {
-        match name.as_str() {
-            "startdoc" => {
-                if let Some(previous) = current_instruction.take() {
-                    instructions.push(previous);
+            "copy" | "copy_json" => match current_instruction {
+                Some(ref mut i @ (Instruction::StartTag { .. } | Instruction::EndTag { .. })) => {
+                    i.actions_mut().push(if name == "copy" {
+                        Action::Copy
+                    } else {
+                        Action::CopyJson
+                    });
                 }
-                current_instruction = Some(Instruction::StartDocument { actions: vec![] });
-            }
-            "startelement" => {
-                if let Some(previous) = current_instruction.take() {
-                    instructions.push(previous);
+                _ => {
+                    bail!("Can only use --{} after a -s/-e", name.replace('_', "-"));
                 }
-                current_instruction = Some(Instruction::StartTag {
-                    tag: value.remove(0),
-                    actions: vec![],
-                });
-            }
-            "endelement" => {
-                if let Some(previous) = current_instruction.take() {
-                    instructions.push(previous);
+            },
+
+            "ancestor" => match current_instruction {
+                None => {
+                    bail!("Cannot use --ancestor before you have done a -s/-e");
                 }
-                let tag = value.remove(0);
-                current_instruction = Some(Instruction::EndTag {
-                    tag,
-                    actions: vec![],
-                });
-            }
-            "enddoc" => {
-                if let Some(previous) = current_instruction.take() {
-                    instructions.push(previous);
+                Some(ref mut i) => {
+                    i.actions_mut().push(Action::Ancestor(value[0].parse()?));
                 }
-                current_instruction = Some(Instruction::EndDocument { actions: vec![] });
-            }
+            },
 
-            "raw" => match current_instruction {
+            "wkt_point" | "wkb_point" => match current_instruction {
                 None => {
-                    bail!("Cannot use -o before you have done a -s/-e");
+                    bail!(
+                        "Cannot use --{} before you have done a -s/-e",
+                        name.replace('_', "-")
+                    );
                 }
                 Some(ref mut i) => {
-                    i.actions_mut().push(Action::RawString(value.remove(0)));
+                    let format = if name == "wkt_point" {
+                        GeometryFormat::Wkt
+                    } else {
+                        GeometryFormat::Wkb
+                    };
+                    let mut coordinates = vec![];
+                    for attr in value.iter() {
+                        coordinates.push(match parse_attribute(attr)? {
+                            Action::Attribute(attr, _) => (0, attr),
+                            Action::ParentAttribute(level, attr, _) => (level, attr),
+                            _ => unreachable!(),
+                        });
+                    }
+                    let y = coordinates.pop().unwrap();
+                    let x = coordinates.pop().unwrap();
+                    i.actions_mut().push(Action::Point(format, x, y));
                 }
             },
-            "newline" => match current_instruction {
+
+            "has_tokens" => {
+                has_tokens = (value.remove(0), value.remove(0));
+            }
+            "has" => match current_instruction {
                 None => {
-                    bail!("Cannot use --nl before you have done a -s/-e");
+                    bail!("Cannot use --has before you have done a -s/-e");
                 }
                 Some(ref mut i) => {
-                    i.actions_mut().push(Action::RawString("\n".to_string()));
+                    let (if_true, if_false) = has_tokens.clone();
+                    let action = match parse_attribute(&value[0])? {
+                        Action::Attribute(attr, _) => Action::Has(0, attr, if_true, if_false),
+                        Action::ParentAttribute(level, attr, _) => {
+                            Action::Has(level, attr, if_true, if_false)
+                        }
+                        _ => unreachable!(),
+                    };
+                    i.actions_mut().push(action);
                 }
             },
-            "tab" => match current_instruction {
+
+            "group_by" => match current_instruction {
                 None => {
-                    bail!("Cannot use --tab before you have done a -s/-e");
+                    bail!("Cannot use --group-by before you have done a -s/-e");
                 }
                 Some(ref mut i) => {
-                    i.actions_mut().push(Action::RawString("\t".to_string()));
+                    i.actions_mut().push(parse_attribute(&value[0])?);
+                    if !i.is_grouped() {
+                        i.actions_mut().push(Action::GroupBy);
+                    }
                 }
             },
 
-            "value" => {
-                // TODO is it possible do .strip_prefix (equiv.) on String, not just str
-                let attr = value.remove(0);
-                let mut attr = attr.as_str();
-                match current_instruction {
-                    None => {
-                        bail!("Cannot use -v before you have done a -s/-e");
+            "value_with_default" => match current_instruction {
+                None => {
+                    bail!("Cannot use -V before you have done a -s/-e");
+                }
+                Some(ref mut i) => {
+                    let attr = value.remove(0);
+                    let mut attr = attr.as_str();
+                    let mut default = value.remove(0);
+                    if config.expand_env {
+                        default = expand_env(&default)?;
                     }
-                    Some(ref mut i) => {
-                        level = 0;
-                        loop {
-                            if attr.starts_with("../") {
-                                level += 1;
-                                attr = attr.strip_prefix("../").unwrap();
-                                continue;
-                            } else if attr.starts_with("./") {
-                                attr = attr.strip_prefix("./").unwrap();
-                                continue;
-                            } else {
-                                break;
-                            }
-                        }
-                        let (attr, filters) = Filters::parse_both(attr)?;
-                        if level == 0 {
-                            i.actions_mut()
-                                .push(Action::Attribute(attr.to_string(), filters));
+                    level = 0;
+                    loop {
+                        if attr.starts_with("../") {
+                            level += 1;
+                            attr = attr.strip_prefix("../").unwrap();
+                            continue;
+                        } else if attr.starts_with("./") {
+                            attr = attr.strip_prefix("./").unwrap();
+                            continue;
                         } else {
-                            i.actions_mut().push(Action::ParentAttribute(
-                                level,
-                                attr.to_string(),
-                                filters,
-                            ));
+                            break;
                         }
                     }
+                    let (attr, filters) = Filters::parse_both(attr)?;
+                    if level == 0 {
+                        i.actions_mut().push(Action::AttributeWithDefault(
+                            attr.to_string(),
+                            default,
+                            filters,
+                        ));
+                    } else {
+                        i.actions_mut().push(Action::ParentAttributeWithDefault(
+                            level,
+                            attr.to_string(),
+                            default,
+                            filters,
+                        ));
+                    }
                 }
+            },
+
+            arg => {
+                bail!("unknown arg: {}", arg)
+            }
+        }
+    }
+
+    if let Some(previous) = current_instruction.take() {
+        instructions.push(previous);
+    }
+
+    if let Some(separator) = &config.record_separator {
+        // Grouped output has its own lines
+        for instruction in instructions.iter_mut().filter(|i| !i.is_aggregated()) {
+            instruction
+                .actions_mut()
+                .push(Action::RawString(separator.clone()));
+        }
+    }
+
+    for action in instructions.iter().flat_map(|i| i.actions()) {
+        if let Action::Matched(tag, _) = action {
+            if !instructions.iter().any(|i| {
+                matches!(i, Instruction::StartTag { tag: t, .. } if t == tag) && i.xpath().is_none()
+            }) {
+                bail!("--matched {} needs a -s {} instruction to count", tag, tag);
+            }
+        }
+    }
+
+    Ok(instructions)
+}
+
+/// The first `-s TAG` instruction, which `--matched TAG` counts
+fn matched_index(instructions: &[Instruction], tag: &str) -> usize {
+    instructions
+        .iter()
+        .position(|i| {
+            matches!(i, Instruction::StartTag { tag: t, .. } if t == tag) && i.xpath().is_none()
+        })
+        .expect("--matched was checked when parsing")
+}
+
+/// Default format for `--now`, RFC 3339
+const DEFAULT_NOW_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%:z";
+
+/// Return an error for an invalid strftime format, rather than failing when it's output
+fn validate_strftime(format: &str) -> Result<()> {
+    if chrono::format::StrftimeItems::new(format).any(|i| i == chrono::format::Item::Error) {
+        bail!("Invalid strftime format {:?}", format);
+    }
+    Ok(())
+}
+
+/// Parse the tag name of `-s`/`-e`, & the `TAG{text~REGEX}` predicate, if there is one
+fn parse_tag(tag: &str) -> Result<(String, Option<Action>)> {
+    let (tag, predicate) = match tag.strip_suffix('}').and_then(|t| t.split_once('{')) {
+        None => return Ok((tag.to_string(), None)),
+        Some(split) => split,
+    };
+    let regex = predicate.strip_prefix("text~").ok_or_else(|| {
+        anyhow!(
+            "Unknown predicate {{{}}} for {}, expected {{text~REGEX}}",
+            predicate,
+            tag
+        )
+    })?;
+    let regex = regex::Regex::new(regex).with_context(|| format!("Invalid regex {:?}", regex))?;
+    Ok((
+        tag.to_string(),
+        Some(Action::TextMatches(TextPattern(regex))),
+    ))
+}
+
+/// Parse an attribute (`-v`) like `../../id!tsv` into the action which outputs it
+fn parse_attribute(attr: &str) -> Result<Action> {
+    let mut attr = attr;
+    let mut level = 0;
+    loop {
+        if let Some(rest) = attr.strip_prefix("../") {
+            level += 1;
+            attr = rest;
+        } else if let Some(rest) = attr.strip_prefix("./") {
+            attr = rest;
+        } else {
+            break;
+        }
+    }
+    let (attr, filters) = Filters::parse_both(attr)?;
+    if level == 0 {
+        Ok(Action::Attribute(attr, filters))
+    } else {
+        Ok(Action::ParentAttribute(level, attr, filters))
+    }
+}
+
+/// Set the global options from the matched args
+fn parse_config(matches: &ArgMatches, config: &mut Config) -> Result<()> {
+    if let Some(separator) = matches.value_of("record_separator") {
+        config.record_separator = Some(unescape(separator).context("Invalid --rs")?);
+    }
+    if let Some(separator) = matches.value_of("field_separator") {
+        config.field_separator = Some(unescape(separator).context("Invalid --fs")?);
+    }
+    if let Some(schema) = matches.value_of("schema") {
+        config.schema = Some(schema.parse()?);
+    }
+    if let Some(policy) = matches.value_of("on_invalid") {
+        if config.schema.is_none() {
+            bail!("--on-invalid needs a --schema, to know which values are invalid");
+        }
+        config.on_invalid = policy.parse()?;
+    }
+    if matches.is_present("json_record") {
+        config.record_format = Some(RecordFormat::Json);
+    }
+    if let Some(path) = matches.value_of("invalid_to") {
+        config.invalid_to = Some(path.into());
+    }
+    if matches.is_present("expand_env") {
+        config.expand_env = true;
+    }
+    if matches.is_present("ignore_tag_case") {
+        config.ignore_tag_case = true;
+    }
+    if matches.is_present("ignore_attr_case") {
+        config.ignore_attr_case = true;
+    }
+    if let Some(policy) = matches.value_of("on_duplicate_attr") {
+        config.on_duplicate_attr = policy.parse()?;
+    }
+    if matches.is_present("recover") {
+        config.recover = true;
+    }
+    if matches.is_present("skip_leading_junk") {
+        config.skip_leading_junk = true;
+    }
+    if matches.is_present("forbid_dtd") {
+        config.forbid_dtd = true;
+    }
+    if let Some(max_nesting) = matches.value_of("max_nesting") {
+        config.max_nesting = Some(
+            max_nesting
+                .parse()
+                .with_context(|| format!("Invalid --max-nesting {:?}", max_nesting))?,
+        );
+    }
+    if matches.is_present("prune_nesting") {
+        config.prune_nesting = true;
+    }
+    if matches.is_present("fail_if_empty") {
+        config.fail_if_empty = true;
+    }
+    if matches.is_present("warn_unknown_tags") {
+        config.warn_unknown_tags = true;
+    }
+    if matches.is_present("strict_unmatched") {
+        config.strict_unmatched = true;
+    }
+    if let Some(root) = matches.value_of("merge_root") {
+        config.merge_root = Some(root.to_string());
+    }
+    if matches.is_present("continuous") {
+        config.continuous = true;
+    }
+    if let Some(root) = matches.value_of("fragment") {
+        config.fragment = Some(root.to_string());
+    }
+    if let Some(root) = matches.value_of("copy_root") {
+        config.copy_root = Some(root.to_string());
+    }
+    match matches.value_of("output_format") {
+        None | Some("text") => {}
+        Some(format @ ("kv" | "logfmt")) => {
+            if config.record_format.is_some() {
+                bail!(
+                    "--json-record can't be used with --output-format {}",
+                    format
+                );
             }
-
-            "value_with_default" => match current_instruction {
-                None => {
-                    bail!("Cannot use -V before you have done a -s/-e");
+            config.record_format = Some(match format {
+                "kv" => RecordFormat::Kv,
+                _ => RecordFormat::Logfmt,
+            });
+        }
+        Some(format) => {
+            if config.record_format.is_some() {
+                bail!("--json-record can't be used with --output-format geojson");
+            }
+            config.geojson = Some(GeoJson {
+                lat_attr: matches.value_of("lat_attr").unwrap().to_string(),
+                lon_attr: matches.value_of("lon_attr").unwrap().to_string(),
+                lines: format == "geojson-lines",
+            });
+        }
+    }
+    if config.schema.is_some() && config.geojson.is_some() {
+        bail!(
+            "--schema can't be used with --output-format geojson, since it outputs a header line"
+        );
+    }
+    if let Some(style) = matches.value_of("emit_header_comment") {
+        if config.geojson.is_some() {
+            bail!("--emit-header-comment can't be used with --output-format geojson, since JSON doesn't have comments");
+        }
+        let style = match style {
+            "hash" => CommentStyle::Hash,
+            "sql" => CommentStyle::Sql,
+            "xml" => CommentStyle::Xml,
+            _ if config.copy_root.is_some() => CommentStyle::Xml,
+            _ => CommentStyle::Hash,
+        };
+        // The command line is set once all the args are parsed
+        config.header_comment = Some(HeaderComment {
+            style,
+            command: String::new(),
+        });
+    }
+    if let Some(path) = matches.value_of("checkpoint") {
+        config.checkpoint = Some(path.into());
+        let seconds: f64 = matches
+            .value_of("checkpoint_interval")
+            .unwrap()
+            .parse()
+            .ok()
+            .filter(|s: &f64| s.is_finite() && *s >= 0.)
+            .context("--checkpoint-interval must be a number of seconds")?;
+        config.checkpoint_interval = std::time::Duration::from_secs_f64(seconds);
+        if matches.is_present("resume") {
+            // Without a checkpoint, it starts at the beginning
+            match std::fs::read_to_string(path) {
+                Ok(text) => {
+                    config.resume = Some(
+                        text.parse()
+                            .with_context(|| format!("Invalid checkpoint {}", path))?,
+                    );
                 }
-                Some(ref mut i) => {
-                    let attr = value.remove(0);
-                    let mut attr = attr.as_str();
-                    let default = value.remove(0);
-                    level = 0;
-                    loop {
-                        if attr.starts_with("../") {
-                            level += 1;
-                            attr = attr.strip_prefix("../").unwrap();
-                            continue;
-                        } else if attr.starts_with("./") {
-                            attr = attr.strip_prefix("./").unwrap();
-                            continue;
-                        } else {
-                            break;
-                        }
-                    }
-                    let (attr, filters) = Filters::parse_both(attr)?;
-                    if level == 0 {
-                        i.actions_mut().push(Action::AttributeWithDefault(
-                            attr.to_string(),
-                            default,
-                            filters,
-                        ));
-                    } else {
-                        i.actions_mut().push(Action::ParentAttributeWithDefault(
-                            level,
-                            attr.to_string(),
-                            default,
-                            filters,
-                        ));
-                    }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Cannot read checkpoint {}", path));
                 }
-            },
-
-            arg => {
-                bail!("unknown arg: {}", arg)
             }
         }
     }
+    for (arg, offset) in [
+        ("start_offset", &mut config.start_offset),
+        ("end_offset", &mut config.end_offset),
+    ] {
+        if let Some(value) = matches.value_of(arg) {
+            *offset = Some(
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid --{} {:?}", arg.replace('_', "-"), value))?,
+            );
+        }
+    }
+    if let Some(path) = matches.value_of("progress_json") {
+        let interval = matches.value_of("progress_interval").unwrap();
+        let seconds: f64 = interval
+            .parse()
+            .ok()
+            .filter(|s: &f64| s.is_finite() && *s >= 0.)
+            .with_context(|| format!("Invalid --progress-interval {:?}", interval))?;
+        config.progress = Some(ProgressOptions {
+            path: (path != "-").then(|| path.into()),
+            interval: std::time::Duration::from_secs_f64(seconds),
+        });
+    }
+    if let Some(path) = matches.value_of("summary_json") {
+        config.summary_json = Some(path.to_string());
+    }
+    if let Some(records) = matches.value_of("max_records") {
+        config.max_records = Some(
+            records
+                .parse()
+                .with_context(|| format!("Invalid --max-records {:?}", records))?,
+        );
+    }
+    if let Some(size) = matches.value_of("max_output_bytes") {
+        config.max_output_bytes = Some(
+            parse_size(size).with_context(|| format!("Invalid --max-output-bytes {:?}", size))?
+                as u64,
+        );
+    }
+    if matches.is_present("line_buffered") {
+        config.flush_every = Some(1);
+    }
+    if let Some(lines) = matches.value_of("flush_every") {
+        config.flush_every = Some(
+            lines
+                .parse()
+                .ok()
+                .filter(|lines| *lines > 0)
+                .with_context(|| format!("Invalid --flush-every {:?}", lines))?,
+        );
+    }
+    if let Some(paths) = matches.values_of("tee") {
+        if config.checkpoint.is_some() {
+            bail!("--tee can't be used with --checkpoint, since only stdout is resumed");
+        }
+        config.tee = paths.map(|p| p.into()).collect();
+    }
+    if let Some(brokers) = matches.value_of("kafka") {
+        if !cfg!(feature = "kafka") {
+            bail!("--kafka needs anglosaxon to be built with the kafka feature, e.g. cargo install anglosaxon --features kafka");
+        }
+        let topic = matches
+            .value_of("topic")
+            .context("--kafka needs a --topic")?;
+        if config.copy_root.is_some() || config.header_comment.is_some() {
+            bail!("--kafka can't be used with --copy-root or --emit-header-comment, since each record is a message");
+        }
+        if config.geojson.as_ref().is_some_and(|g| !g.lines) {
+            bail!("--kafka needs --output-format geojson-lines rather than geojson, since each record is a message");
+        }
+        if config.checkpoint.is_some() {
+            bail!("--kafka can't be used with --checkpoint");
+        }
+        let key_field = match matches.value_of("kafka_key") {
+            None => None,
+            Some(field) => Some(match field.parse::<usize>() {
+                Ok(0) => bail!("Invalid --kafka-key {:?}, the fields are numbered from 1", field),
+                Ok(number) => number - 1,
+                Err(_) => config
+                    .schema
+                    .as_ref()
+                    .and_then(|s| s.columns.iter().position(|c| c.name == field))
+                    .with_context(|| {
+                        format!(
+                            "Invalid --kafka-key {:?}, it must be a field number or a --schema column",
+                            field
+                        )
+                    })?,
+            }),
+        };
+        config.kafka = Some(KafkaOptions {
+            brokers: brokers.split(',').map(str::to_string).collect(),
+            topic: topic.to_string(),
+            key_field,
+        });
+    } else if matches.is_present("topic") || matches.is_present("kafka_key") {
+        bail!("--topic & --kafka-key need --kafka");
+    }
+    if let Some(url) = matches.value_of("pg") {
+        if !cfg!(feature = "postgres") {
+            bail!("--pg needs anglosaxon to be built with the postgres feature, e.g. cargo install anglosaxon --features postgres");
+        }
+        let table = matches.value_of("table").context("--pg needs a --table")?;
+        if config.kafka.is_some() {
+            bail!("--pg can't be used with --kafka");
+        }
+        if config.copy_root.is_some()
+            || config.header_comment.is_some()
+            || config.geojson.is_some()
+            || config.record_format.is_some()
+        {
+            bail!("--pg needs tab separated records, so can't be used with --copy-root, --emit-header-comment or --output-format");
+        }
+        if config.record_separator.is_some() {
+            bail!("--pg needs a record on each line, so can't be used with --rs");
+        }
+        if config
+            .field_separator
+            .as_ref()
+            .is_some_and(|s| s.chars().count() != 1)
+        {
+            bail!("--pg needs a --fs of one character");
+        }
+        if config.checkpoint.is_some() {
+            bail!("--pg can't be used with --checkpoint");
+        }
+        config.postgres = Some(PostgresOptions {
+            url: url.to_string(),
+            table: table.to_string(),
+        });
+    } else if matches.is_present("table") {
+        bail!("--table needs --pg");
+    }
+    if let Some(url) = matches.value_of("upload") {
+        if !cfg!(feature = "s3") {
+            bail!("--upload needs anglosaxon to be built with the s3 feature, e.g. cargo install anglosaxon --features s3");
+        }
+        if config.kafka.is_some() || config.postgres.is_some() {
+            bail!("--upload can't be used with --kafka or --pg");
+        }
+        if config.checkpoint.is_some() {
+            bail!("--upload can't be used with --checkpoint");
+        }
+        let mut upload: UploadOptions = url.parse()?;
+        upload.endpoint = matches.value_of("upload_endpoint").map(str::to_string);
+        config.upload = Some(upload);
+    } else if matches.is_present("upload_endpoint") {
+        bail!("--upload-endpoint needs --upload");
+    }
+    if let Some(command) = matches.value_of("pipe") {
+        if config.kafka.is_some() || config.postgres.is_some() || config.upload.is_some() {
+            bail!("--pipe can't be used with --kafka, --pg or --upload");
+        }
+        if config.checkpoint.is_some() {
+            bail!("--pipe can't be used with --checkpoint, since the command's output can't be resumed");
+        }
+        config.pipe = Some(command.to_string());
+    }
+    if let Some(size) = matches.value_of("max_memory") {
+        config.max_memory =
+            Some(parse_size(size).with_context(|| format!("Invalid --max-memory {:?}", size))?);
+    }
+    if let Some(threads) = matches.value_of("threads") {
+        let threads: usize = threads
+            .parse()
+            .with_context(|| format!("Invalid --threads {:?}", threads))?;
+        config.threads = Some(if threads == 0 {
+            std::thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            threads
+        });
+    }
+    if let Some(size) = matches.value_of("queue_size") {
+        if config.threads.is_none() {
+            bail!("--queue-size needs --threads");
+        }
+        let size: usize = size
+            .parse()
+            .ok()
+            .filter(|size| *size > 0)
+            .with_context(|| format!("Invalid --queue-size {:?}", size))?;
+        config.queue_size = Some(size);
+    }
+    if config.invalid_to.is_some() && config.threads.is_some() {
+        bail!("--invalid-to can't be used with --threads");
+    }
+    if let Some(tags) = matches.values_of("sync_tag") {
+        config.sync_tags = tags.map(|t| t.to_string()).collect();
+    }
+    if let Some(tags) = matches.values_of("copy_exclude") {
+        config.copy_exclude = tags.map(|t| t.to_string()).collect();
+    }
+    if matches.is_present("canonical") {
+        config.canonical = true;
+    }
+    if matches.is_present("print_stats") {
+        config.print_stats = true;
+    }
+    config.timing_detail = matches.is_present("timing_detail");
+    if matches.is_present("quiet") {
+        config.log_level = Some(tracing::level_filters::LevelFilter::ERROR);
+    }
+    if let Some(level) = matches.value_of("log_level") {
+        if matches.is_present("quiet") {
+            bail!("-q/--quiet can't be used with --log-level, since it's the same as --log-level error");
+        }
+        config.log_level = Some(level.parse()?);
+    }
+    config.log_json = matches.value_of("log_format") == Some("json");
+    if matches.is_present("trace_events") {
+        config.trace_events = true;
+    }
+    if let Some(mode) = matches.value_of("unique") {
+        config.unique = Some(mode.parse()?);
+    }
+    if let Some(field) = matches.value_of("sort") {
+        let delimiter = match matches.value_of("sort_delimiter") {
+            None => b'\t',
+            Some(d) if d.len() == 1 => d.as_bytes()[0],
+            Some(d) => bail!("--sort-delimiter must be one character, not {:?}", d),
+        };
+        let field = match field {
+            "line" => None,
+            field => match field.parse() {
+                Ok(field) if field > 0 => Some(field),
+                _ => bail!(
+                    "Invalid --sort field {:?}, expected line or a number from 1",
+                    field
+                ),
+            },
+        };
+        config.sort = Some(SortKey { field, delimiter });
+    }
+    if let Some(debug_matches) = matches.value_of("debug_matches") {
+        config.debug_matches = Some(
+            debug_matches
+                .parse()
+                .with_context(|| format!("Invalid --debug-matches {:?}", debug_matches))?,
+        );
+    }
+    Ok(())
+}
 
-    if let Some(previous) = current_instruction.take() {
-        instructions.push(previous);
+/// Replace the escapes `\n`, `\t`, `\r`, `\0` & `\\` in this string
+fn unescape(s: &str) -> Result<String> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some(c) => bail!("Unknown escape \\{} in {:?}", c, s),
+            None => bail!("{:?} ends with a \\", s),
+        });
     }
+    Ok(unescaped)
+}
 
-    Ok(instructions)
+/// Replace every `${VAR}` in `s` with the value of that environment variable
+fn expand_env(s: &str) -> Result<String> {
+    expand_vars(s, |var| std::env::var(var).ok())
 }
 
-fn clap_app_to_ordered_matches(
-    app: clap::App,
-    argv: Option<&[&str]>,
-) -> Vec<(String, Vec<String>)> {
+/// Replace every `${VAR}` in `s` using `lookup`. An error occurs for unknown variables
+fn expand_vars(s: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated ${{ in {:?}", s))?;
+        let var = &rest[start + 2..start + end];
+        let value = lookup(var).ok_or_else(|| anyhow!("Environment variable {} not set", var))?;
+        result.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn clap_app_to_ordered_matches(app: &Command, matches: &ArgMatches) -> Vec<(String, Vec<String>)> {
+    // options are handled by parse_config, the order they are in doesn't matter
+    let options = option_args();
+    let options = options
+        .iter()
+        .map(|a| a.get_name())
+        // & the subcommands' own options
+        .chain(["input", "iterations", "synthetic"])
+        .collect::<Vec<_>>();
     let args: Vec<(&str, usize)> = app
         .get_arguments()
         .map(|a| {
@@ -499,19 +8353,11 @@ fn clap_app_to_ordered_matches(
                 }),
             )
         })
-        .filter(|&(a, _)| a != "version")
+        .filter(|&(a, _)| a != "version" && !options.contains(&a))
         .collect::<Vec<_>>();
 
-    let matches = match argv {
-        // from CLI args
-        None => app.get_matches(),
-
-        // From the provided args (used for testing)
-        Some(argv) => {
-            let app = app.setting(clap::AppSettings::NoBinaryName);
-            app.get_matches_from(argv)
-        }
-    };
+    // Args which take a variable number of values
+    let variable_values = ["opt_value"];
 
     let mut results = vec![];
     for (name, num_vals) in args {
@@ -525,19 +8371,34 @@ fn clap_app_to_ordered_matches(
             results.extend(indices.map(|i| (i, (name.to_string(), vec![]))));
         } else {
             let indices = indices.collect::<Vec<_>>();
-            let indices = indices.chunks(num_vals).collect::<Vec<_>>();
             let values = matches
                 .values_of(name)
                 .unwrap()
                 .map(|v| v.to_string())
                 .collect::<Vec<_>>();
-            let values = values.chunks(num_vals).collect::<Vec<_>>();
-            results.extend(
-                indices
-                    .iter()
-                    .zip(values)
-                    .map(|(i, v)| (i[0], (name.to_string(), v.to_vec()))),
-            );
+            // How many values each occurrence has
+            let lengths: Vec<usize> = if variable_values.contains(&name) {
+                // The values of one occurrence are next to each other, & occurrences are
+                // separated by the flag
+                let mut lengths = vec![];
+                for (j, i) in indices.iter().enumerate() {
+                    if j == 0 || indices[j - 1] + 1 != *i {
+                        lengths.push(0);
+                    }
+                    *lengths.last_mut().unwrap() += 1;
+                }
+                lengths
+            } else {
+                vec![num_vals; indices.len() / num_vals]
+            };
+            let mut start = 0;
+            for len in lengths {
+                results.push((
+                    indices[start],
+                    (name.to_string(), values[start..start + len].to_vec()),
+                ));
+                start += len;
+            }
         }
     }
 
@@ -549,97 +8410,1551 @@ fn clap_app_to_ordered_matches(
         .collect()
 }
 
+/// The args which start a new instruction, i.e. say which SAX event it happens on
+fn event_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("startdoc")
+            .short('S')
+            .long("startdoc")
+            .help("Event happens once, at the start of the XML document")
+            .takes_value(false)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("startelement")
+            .short('s')
+            .long("start")
+            .help("Event happens when this tag is opened")
+            .takes_value(true)
+            .value_name("TAG")
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("xpath")
+            .short('x')
+            .long("xpath")
+            .help("Event happens when an element which this XPath selects is opened, e.g. -x '//way[@visible=\"true\"]/nd[1]'. Only the child & descendant axes, & [@ATTRIBUTE], [@ATTRIBUTE=\"VALUE\"] & [POSITION] predicates")
+            .takes_value(true)
+            .value_name("XPATH")
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("endelement")
+            .short('e')
+            .long("end")
+            .help("Event happens when this tag is closed")
+            .takes_value(true)
+            .value_name("TAG")
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("enddoc")
+            .short('E')
+            .long("enddoc")
+            .help("Event happens once, at the end of the XML document")
+            .takes_value(false)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("pi")
+            .short('P')
+            .long("pi")
+            .help("Event happens for processing instructions (<?TARGET data?>) with this target")
+            .takes_value(true)
+            .value_name("TARGET")
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("comment")
+            .long("comment")
+            .help("Event happens for every XML comment")
+            .takes_value(false)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("chars")
+            .long("chars")
+            .help("Event happens for every text node. With nonblank, text which is only whitespace is ignored")
+            .value_name("MODE")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("all")
+            .possible_values(["all", "nonblank"])
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("doctype")
+            .long("doctype")
+            .help("Event happens once, for the <!DOCTYPE> declaration, if the document has one")
+            .takes_value(false)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+    ]
+}
+
+/// The args which add an action to the current instruction
+fn action_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("raw")
+            .short('o')
+            .long("output")
+            .help("Outputs this string")
+            .takes_value(true)
+            .value_name("STRING")
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("value")
+            .short('v')
+            .long("value")
+            .help("Outputs the value of this XML attribute, an error occurs if that attribute isn't present. Text filters can follow a !, e.g. -v name!tsv or -v price!decimal:fr (decimal on its own is decimal:de)")
+            .value_name("ATTRIBUTE")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("fields")
+            .long("fields")
+            .help("Outputs these comma separated XML attributes, like -v, joined with the --fs field separator & ended with the --rs record separator (or a new line), e.g. --fields id,lat,lon")
+            .value_name("ATTRIBUTES")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("group_by")
+            .long("group-by")
+            .help("Outputs the value of this XML attribute, like -v, but rather than writing this event's output, count how often each distinct output happens, & write value<TAB>count at the end of the document")
+            .value_name("ATTRIBUTE")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("aggregate")
+            .long("aggregate")
+            .help("Don't write this event's output, but aggregate the number in this attribute, for each --group-by group, or for all events. REDUCER is sum, min, max or mean")
+            .value_name("REDUCER:ATTRIBUTE")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("value_with_default")
+            .short('V')
+            .long("value-default")
+            .help("Outputs this string")
+            .takes_value(true)
+            .value_name("ATTRIBUTE DEFAULT")
+            .number_of_values(2)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("now")
+            .long("now")
+            .help("Outputs the current time, formatted with this strftime format (default: RFC 3339), e.g. --now=%Y-%m-%d")
+            .value_name("STRFTIME")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value(DEFAULT_NOW_FORMAT)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("filename")
+            .long("filename")
+            .help("Outputs the name of the current input file (- for stdin)")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("basename")
+            .long("basename")
+            .help("Outputs the name of the current input file, without the directory")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("uuid")
+            .long("uuid")
+            .help("Outputs a new UUID, random (v4, the default) or time ordered (v7)")
+            .value_name("VERSION")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("v4")
+            .possible_values(["v4", "v7"])
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("doctype_name")
+            .long("doctype-name")
+            .help("Outputs the root element name of the DOCTYPE, for --doctype events")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("public_id")
+            .long("public-id")
+            .help("Outputs the public ID of the DOCTYPE (or nothing), for --doctype events")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("system_id")
+            .long("system-id")
+            .help("Outputs the system ID of the DOCTYPE (or nothing), for --doctype events")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("text")
+            .long("text")
+            .help("Outputs the text, for --chars events, optionally through text filters (e.g. --text=tsv)")
+            .value_name("FILTERS")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("none")
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("comment_text")
+            .long("comment-text")
+            .help("Outputs the text of the comment, for --comment events")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("pi_data")
+            .long("pi-data")
+            .help("Outputs the data of the processing instruction, for -P events")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("sibling_index")
+            .long("sibling-index")
+            .help("Outputs the element's position among its siblings (all, the default), or among its siblings with the same name (same-name), starting at 1")
+            .value_name("SIBLINGS")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("all")
+            .possible_values(["all", "same-name"])
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("counter")
+            .long("counter")
+            .help("Outputs how often this has happened in this document, starting at 1")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("counter_per")
+            .long("counter-per")
+            .help("Outputs how often this has happened since the last TAG element started, starting at 1, e.g. to number the nodes in each way: -s nd --counter-per way")
+            .value_name("TAG")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("matched")
+            .long("matched")
+            .help("Outputs how often the first -s TAG instruction matched in this document. Only for -E, e.g. for a footer: -E -o '# ' --matched node -o ' nodes'")
+            .value_name("TAG")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("offset")
+            .long("offset")
+            .help("Outputs the byte offset in the input of this element's start tag")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("opt_value")
+            .long("opt-value")
+            .help("Outputs PREFIX, the value of this XML attribute, & SUFFIX, or nothing at all if the element doesn't have that attribute")
+            .value_name("ATTRIBUTE [PREFIX] [SUFFIX]")
+            .takes_value(true)
+            .min_values(1)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("collect")
+            .long("collect")
+            .help("Outputs this attribute of all the CHILD elements, joined with SEPARATOR, e.g. -e way --collect nd/ref ';'. Only for -e")
+            .value_name("CHILD/ATTRIBUTE SEPARATOR")
+            .takes_value(true)
+            .number_of_values(2)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("collect_json")
+            .long("collect-json")
+            .help("Outputs a JSON array of this attribute of all the CHILD elements, or of objects with all their attributes, e.g. -e way --collect-json nd/ref. Only for -e")
+            .value_name("CHILD[/ATTRIBUTE]")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("collect_hstore")
+            .long("collect-hstore")
+            .help("Outputs a PostgreSQL hstore of the KEY & VALUE attributes (default k & v) of all the CHILD elements, e.g. -e way --collect-hstore 'tag!pgcopy' for COPY into an hstore column. Only for -e")
+            .value_name("CHILD[/KEY/VALUE]")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("collect_json_object")
+            .long("collect-json-object")
+            .help("Outputs a JSON object of the KEY & VALUE attributes (default k & v) of all the CHILD elements, e.g. -e way --collect-json-object tag outputs {\"highway\":\"residential\"}. Only for -e")
+            .value_name("CHILD[/KEY/VALUE]")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("leaf_only")
+            .long("leaf-only")
+            .help("This -s/-e only happens for elements without child elements. A -s then happens at the end tag")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("empty_only")
+            .long("empty-only")
+            .help("This -s/-e only happens for self-closing elements, e.g. <tag/>")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("require")
+            .long("require")
+            .help("Skip this event's record if the element doesn't have this XML attribute. With --on-missing-attr warn, the skipped records are counted in the warnings")
+            .value_name("ATTRIBUTE")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("ns_uri")
+            .long("ns-uri")
+            .help("This -s/-e only happens for elements in this XML namespace, whatever prefix the document uses, e.g. -s feed --ns-uri http://www.w3.org/2005/Atom")
+            .value_name("URI")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("copy")
+            .long("copy")
+            .help("Outputs the element, & everything inside it, as XML. A -s then happens at the end tag")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("copy_json")
+            .long("copy-json")
+            .help("Outputs the element, & everything inside it, as a JSON object, e.g. {\"name\":\"nd\",\"attributes\":{\"ref\":\"1\"},\"children\":[]}. A -s then happens at the end tag")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("ancestor")
+            .long("ancestor")
+            .help("Skip this event's record unless it's inside this element, which has these XML attributes, e.g. -s nd --ancestor 'way[visible=true]'. The tag can be *")
+            .value_name("TAG[ATTRIBUTE=VALUE]")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("has")
+            .long("has")
+            .help("Outputs true or false, for whether the element has this XML attribute")
+            .value_name("ATTRIBUTE")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("wkt_point")
+            .long("wkt-point")
+            .help("Outputs a point, POINT(LON LAT), from these 2 numeric XML attributes, e.g. --wkt-point lon lat. `../` works like -v")
+            .value_name("LON LAT")
+            .takes_value(true)
+            .number_of_values(2)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("wkb_point")
+            .long("wkb-point")
+            .help("Like --wkt-point, but outputs the point as hex encoded well known binary (WKB), as PostGIS does, e.g. for COPY into a geometry column")
+            .value_name("LON LAT")
+            .takes_value(true)
+            .number_of_values(2)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("has_tokens")
+            .long("has-tokens")
+            .help("What the following --has output, instead of true & false, e.g. --has-tokens 1 0")
+            .value_name("TRUE FALSE")
+            .takes_value(true)
+            .number_of_values(2)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("on_missing_attr")
+            .long("on-missing-attr")
+            .help("What to do when a -v attribute is missing. Before any event it's the default for all, otherwise it applies to the rest of this event's actions")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(["error", "skip-record", "empty", "warn"])
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("newline")
+            .long("nl")
+            .help("Outputs a new line character")
+            .takes_value(false)
+            .multiple_occurrences(true),
+        Arg::new("name")
+            .long("name")
+            .help("Use the previous action's output as the NAME field, for --json-record, e.g. -v k --name key, or --now %F --name date")
+            .value_name("NAME")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("tab")
+            .long("tab")
+            .help("Outputs a tab character")
+            .takes_value(false)
+            .multiple_occurrences(true),
+    ]
+}
+
+/// The args which change global settings, rather than adding to an instruction. Their order
+/// doesn't matter
+fn option_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("record_separator")
+            .long("rs")
+            .help("Output STRING at the end of every instruction's output, rather than ending each one with --nl, e.g. --rs '\\0'. \\n, \\t, \\r, \\0 & \\\\ are escapes")
+            .value_name("STRING")
+            .takes_value(true)
+            .allow_hyphen_values(true),
+        Arg::new("field_separator")
+            .long("fs")
+            .help("Output STRING between the --fields attributes, rather than a tab, e.g. --fs ,. The same escapes as --rs")
+            .value_name("STRING")
+            .takes_value(true)
+            .allow_hyphen_values(true),
+        Arg::new("schema")
+            .long("schema")
+            .help("The output's columns, as comma separated NAME:TYPE, e.g. id:int,lat:float,name:str. The names are written as a header line, joined with --fs, & an error occurs if a -v/--fields attribute with that name isn't a valid int, float, bool or str")
+            .value_name("COLUMNS")
+            .takes_value(true),
+        Arg::new("on_invalid")
+            .long("on-invalid")
+            .help("What to do when a value isn't valid for its --schema column: error, null (output an empty string) or skip-record. Either way there's a warning")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(["error", "null", "skip-record"]),
+        Arg::new("json_record")
+            .long("json-record")
+            .help("Output each -s/-e record as a line of JSON, with the -v/-V attributes, & the actions named with --name, as its fields, e.g. {\"id\":1,\"parent\":{\"id\":7}} for -s tag -v id -v ../id. --schema int, float & bool columns are JSON numbers & bools")
+            .takes_value(false),
+        Arg::new("invalid_to")
+            .long("invalid-to")
+            .help("Write the records which would stop the run or be skipped, because a -v attribute is missing or a value isn't valid for its --schema column, to FILE, with the position, the element's path, the reason & its start tag, separated by tabs. The run carries on")
+            .value_name("FILE")
+            .takes_value(true),
+        Arg::new("expand_env")
+            .long("expand-env")
+            .help("Expand ${VAR} environment variables in -o strings & -V defaults")
+            .takes_value(false),
+        Arg::new("on_duplicate_attr")
+            .long("on-duplicate-attr")
+            .help("Which value to use when an element has several attributes with the same name (e.g. x & p:x): first, last, error, or join:SEP to join them all with SEP")
+            .value_name("POLICY")
+            .takes_value(true),
+        Arg::new("ignore_tag_case")
+            .long("ignore-tag-case")
+            .help("Match element names whatever their case, so -s Item matches <item> & <ITEM> too, e.g. for HTML-ish feeds. Also for --collect, --counter-per, --ancestor & -x")
+            .takes_value(false),
+        Arg::new("ignore_attr_case")
+            .long("ignore-attr-case")
+            .help("Match attribute names whatever their case, so -v id finds ID= & Id= too, e.g. when the generator isn't consistent. For -v/-V & everything else which looks up attributes")
+            .takes_value(false),
+        Arg::new("recover")
+            .long("recover")
+            .help("Skip over malformed XML, carrying on from the next tag, rather than stopping with an error")
+            .takes_value(false),
+        Arg::new("skip_leading_junk")
+            .long("skip-leading-junk")
+            .help("Skip anything before the first < in the input, e.g. log lines")
+            .takes_value(false),
+        Arg::new("forbid_dtd")
+            .long("forbid-dtd")
+            .help("Stop with an error if a document has a DOCTYPE, e.g. for untrusted input")
+            .takes_value(false),
+        Arg::new("max_nesting")
+            .long("max-nesting")
+            .help("Stop with an error if elements are nested deeper than this")
+            .value_name("DEPTH")
+            .takes_value(true),
+        Arg::new("prune_nesting")
+            .long("prune-nesting")
+            .help("Ignore elements nested deeper than --max-nesting (and their contents) rather than stopping")
+            .requires("max_nesting")
+            .takes_value(false),
+        Arg::new("fail_if_empty")
+            .long("fail-if-empty")
+            .help("Exit with an error if no records were output for the -s/-e events, e.g. because a tag was renamed")
+            .takes_value(false),
+        Arg::new("strict_unmatched")
+            .long("strict-unmatched")
+            .help("Exit with an error if any -s/-e instruction never matched, e.g. because of a typo in the tag name, rather than only warning")
+            .takes_value(false),
+        Arg::new("warn_unknown_tags")
+            .long("warn-unknown-tags")
+            .help("Warn about the elements in the input which no instruction mentions (apart from inside --copy elements), with how often each happened, to find data which is being ignored")
+            .takes_value(false),
+        Arg::new("merge_root")
+            .long("merge-root")
+            .help("Treat all the inputs as one document, with a <TAG> root element around each input's root element. -S/-E happen once, before the first input & after the last, & counters & --group-by carry on from one input to the next")
+            .value_name("TAG")
+            .takes_value(true),
+        Arg::new("fragment")
+            .long("fragment")
+            .help("The inputs are XML fragments, a sequence of elements (& text) without one root element, so put them inside a <TAG> root element, which is <fragment> by default. -S/-E happen at it")
+            .value_name("TAG")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("fragment"),
+        Arg::new("continuous")
+            .long("continuous")
+            .help("Treat all the inputs as one run: -S happens before the first input & -E after the last, & counters, --matched & --group-by/--aggregate carry on from one input to the next. Unlike --merge-root, each input's root element is still the root element")
+            .takes_value(false),
+        Arg::new("copy_root")
+            .long("copy-root")
+            .help("Wrap the output in an XML declaration & a <TAG> root element, so the elements output with --copy are an XML document")
+            .value_name("TAG")
+            .takes_value(true),
+        Arg::new("output_format")
+            .long("output-format")
+            .help("Output each -s/-e record as a GeoJSON point feature, with the -v/-V attributes (or all the other attributes, if there aren't any) as its properties: geojson for a FeatureCollection, geojson-lines for one feature per line. Or kv for a line of NAME=VALUE pairs of the -v/-V attributes & the --name actions, with the values quoted for a shell, or logfmt for the same pairs in logfmt, for log pipelines")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(["text", "geojson", "geojson-lines", "kv", "logfmt"]),
+        Arg::new("lat_attr")
+            .long("lat-attr")
+            .help("The attribute with the latitude, for --output-format geojson")
+            .value_name("ATTRIBUTE")
+            .takes_value(true)
+            .default_value("lat"),
+        Arg::new("lon_attr")
+            .long("lon-attr")
+            .help("The attribute with the longitude, for --output-format geojson")
+            .value_name("ATTRIBUTE")
+            .takes_value(true)
+            .default_value("lon"),
+        Arg::new("emit_header_comment")
+            .long("emit-header-comment")
+            .help("Start the output with a comment with the anglosaxon version, command line, input files & date. STYLE is how lines are commented: hash (# …), sql (-- …), or xml (<!-- … -->). The default is xml with --copy-root, & hash otherwise")
+            .value_name("STYLE")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("auto")
+            .possible_values(["auto", "hash", "sql", "xml"]),
+        Arg::new("checkpoint")
+            .long("checkpoint")
+            .help("Every --checkpoint-interval seconds, save how far through the input & output the run is to FILE, so it can be carried on with --resume after a crash. FILE is deleted at the end")
+            .value_name("FILE")
+            .takes_value(true),
+        Arg::new("checkpoint_interval")
+            .long("checkpoint-interval")
+            .help("How often to save the --checkpoint")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .default_value("60"),
+        Arg::new("resume")
+            .long("resume")
+            .help("Carry on from the --checkpoint, if there is one. The output is what comes after the checkpoint, so cut the previous output back to the number of bytes printed to stderr, & append to it")
+            .requires("checkpoint")
+            .takes_value(false),
+        Arg::new("start_offset")
+            .long("start-offset")
+            .help("Only process the top level elements which start at or after this byte offset of the input, for splitting a big file into shards. It starts at the next --sync-tag element, & -S doesn't happen")
+            .value_name("BYTES")
+            .takes_value(true)
+            .conflicts_with("checkpoint"),
+        Arg::new("end_offset")
+            .long("end-offset")
+            .help("Only process the top level elements which start before this byte offset of the input. It stops at the first --sync-tag element after it, & -E doesn't happen")
+            .value_name("BYTES")
+            .takes_value(true)
+            .conflicts_with("checkpoint"),
+        Arg::new("progress_json")
+            .long("progress-json")
+            .help("Every --progress-interval seconds, write a line of JSON to stderr (or FILE, e.g. a named pipe) with the bytes read, the percent done (for files), the records output, the rates & the estimated time left, for tracking long runs. There's a last line with \"event\":\"done\" at the end")
+            .value_name("FILE")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("-"),
+        Arg::new("progress_interval")
+            .long("progress-interval")
+            .help("How often to write --progress-json")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .default_value("1"),
+        Arg::new("summary_json")
+            .long("summary-json")
+            .help("At the end, write a line of JSON to stderr (or FILE) with why it stopped (complete, error, interrupted, output-limit, end-offset, empty or unmatched), any error, the duration, the bytes read & written, the records output, how often each instruction matched, & the warnings, for checking runs in pipelines")
+            .value_name("FILE")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("-"),
+        Arg::new("max_records")
+            .long("max-records")
+            .help("Stop after N -s/-e records have been output, e.g. for a preview of a big file. -E still happens, so the output is complete")
+            .value_name("N")
+            .takes_value(true),
+        Arg::new("max_output_bytes")
+            .long("max-output-bytes")
+            .help("Stop after SIZE bytes (e.g. 10M) have been output, at the end of the record which reached it. -E still happens, so the output is complete")
+            .value_name("SIZE")
+            .takes_value(true),
+        Arg::new("line_buffered")
+            .long("line-buffered")
+            .help("Flush the output after every line, so each record is seen straight away, e.g. when piping into something live. The same as --flush-every 1")
+            .takes_value(false)
+            .conflicts_with("flush_every"),
+        Arg::new("tee")
+            .long("tee")
+            .help("Write the output to FILE too, as well as stdout, so one run makes several copies, e.g. an archive file & a pipe into something else. Can be given more than once")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple_occurrences(true),
+        Arg::new("kafka")
+            .long("kafka")
+            .help("Send each record as a message to the Kafka BROKERS (comma separated HOST:PORT), rather than to stdout. Needs the kafka feature")
+            .value_name("BROKERS")
+            .takes_value(true),
+        Arg::new("topic")
+            .long("topic")
+            .help("The Kafka topic for --kafka")
+            .value_name("TOPIC")
+            .takes_value(true),
+        Arg::new("kafka_key")
+            .long("kafka-key")
+            .help("Use this field of each record as the --kafka message key, either a number (from 1) or a --schema column")
+            .value_name("FIELD")
+            .takes_value(true),
+        Arg::new("pg")
+            .long("pg")
+            .help("Copy the records into PostgreSQL, with COPY, rather than writing them to stdout, e.g. postgres://user@localhost/db. Needs the postgres feature")
+            .value_name("URL")
+            .takes_value(true),
+        Arg::new("table")
+            .long("table")
+            .help("The table for --pg, & maybe its columns, e.g. 'nodes(id,name)'")
+            .value_name("TABLE")
+            .takes_value(true),
+        Arg::new("pipe")
+            .long("pipe")
+            .help("Write the output to this shell command, rather than stdout, e.g. 'sort -u | gzip > nodes.gz', & wait for it to finish. anglosaxon exits with the command's exit code if it fails")
+            .value_name("COMMAND")
+            .takes_value(true),
+        Arg::new("upload")
+            .long("upload")
+            .help("Upload the output to S3, rather than writing it to stdout, e.g. s3://bucket/nodes.tsv. It's compressed with zstd if it ends with .zst. Needs the s3 feature")
+            .value_name("URL")
+            .takes_value(true),
+        Arg::new("upload_endpoint")
+            .long("upload-endpoint")
+            .help("The URL of S3 compatible storage for --upload, e.g. http://localhost:9000")
+            .value_name("URL")
+            .takes_value(true),
+        Arg::new("flush_every")
+            .long("flush-every")
+            .help("Flush the output after every N lines. The output is buffered otherwise, which is faster")
+            .value_name("N")
+            .takes_value(true),
+        Arg::new("max_memory")
+            .long("max-memory")
+            .help("Stop with an error, rather than use up the memory, if more than SIZE bytes (e.g. 500M or 2G) are buffered for --collect etc., --copy, TAG{text~REGEX} or --group-by, or for --unique=all's lines. --sort writes to temporary files after SIZE bytes instead")
+            .value_name("SIZE")
+            .takes_value(true),
+        Arg::new("threads")
+            .long("threads")
+            .help("Split the input file into chunks at --sync-tag elements, & process them on N threads (0 for one per core), outputting them in order. It needs a file, not stdin")
+            .value_name("N")
+            .takes_value(true)
+            .conflicts_with_all(&[
+                "checkpoint",
+                "start_offset",
+                "end_offset",
+                "max_records",
+                "max_output_bytes",
+            ]),
+        Arg::new("queue_size")
+            .long("queue-size")
+            .help("With --threads, don't start a chunk which is N chunks ahead of the output. The finished chunks are buffered until they're output, so this is a trade off between keeping the threads busy & memory. Default: twice the threads")
+            .value_name("N")
+            .takes_value(true),
+        Arg::new("sync_tag")
+            .long("sync-tag")
+            .help("The top level (i.e. children of the root) elements which --start-offset, --end-offset & --threads split the input between, e.g. node, way & relation for OSM. Can be given more than once. The default is the -s/-e tags")
+            .value_name("TAG")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("copy_exclude")
+            .long("copy-exclude")
+            .help("Leave TAG elements, & everything inside them, out of --copy & --copy-json, e.g. --copy-exclude metadata. Can be given more than once")
+            .value_name("TAG")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_delimiter(false),
+        Arg::new("canonical")
+            .long("canonical")
+            .help("Make --copy & --copy-json the same whatever the formatting of the input: attributes are sorted by name, whitespace between tags is removed, & other runs of whitespace become one space")
+            .takes_value(false),
+        Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .help("Don't print warnings or other messages to stderr, only errors. The same as --log-level error")
+            .takes_value(false),
+        Arg::new("log_level")
+            .long("log-level")
+            .help("Which messages to print to stderr: error, warn, info (the default), debug (e.g. each input file), or trace")
+            .value_name("LEVEL")
+            .takes_value(true)
+            .possible_values(["error", "warn", "info", "debug", "trace"]),
+        Arg::new("log_format")
+            .long("log-format")
+            .help("How to write the messages on stderr: text (the default), or json, one JSON object per line with the level, the fields (e.g. a warning's count), & the input file or --threads chunk")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(["text", "json"]),
+        Arg::new("print_stats")
+            .long("print-stats")
+            .help("Print how many records were output, & how many bytes were read, to stderr at the end")
+            .takes_value(false),
+        Arg::new("timing_detail")
+            .long("timing-detail")
+            .help("Print how long was spent parsing the XML, matching, running each instruction, in filters, & writing the output, to stderr at the end, to see what's slow")
+            .takes_value(false),
+        Arg::new("trace_events")
+            .long("trace-events")
+            .help("Print every XML event, with its position & depth, to stderr, to see why a -s/-e doesn't match")
+            .takes_value(false),
+        Arg::new("unique")
+            .long("unique")
+            .help("Don't output duplicate lines. MODE is all (default, remembers every line), adjacent (only the previous line, for sorted output), or a number of recent lines to remember")
+            .value_name("MODE")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("all"),
+        Arg::new("sort")
+            .long("sort")
+            .help("Sort the output lines, by the whole line (default), or by this field (starting at 1). Large outputs are sorted with temporary files")
+            .value_name("FIELD")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("line"),
+        Arg::new("sort_delimiter")
+            .long("sort-delimiter")
+            .help("The character between the fields for --sort (default: tab)")
+            .value_name("CHAR")
+            .takes_value(true)
+            .requires("sort"),
+        Arg::new("debug_matches")
+            .long("debug-matches")
+            .help("Print which instructions matched each start & end tag, & what they output, to stderr, for the first N tags (default 100)")
+            .value_name("N")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("100"),
+    ]
+}
+
+/// The input files
+fn input_arg() -> Arg<'static> {
+    Arg::new("input")
+        .help("XML files to read, in order. stdin is read if none are given, or for -")
+        .value_name("FILE")
+        .takes_value(true)
+        .multiple_values(true)
+}
+
+/// The name, description & `extract` instructions of each `osm` preset
+const OSM_PRESETS: &[(&str, &str, &str)] = &[
+    (
+        "nodes",
+        "Each node's id, latitude, longitude & tags (as JSON)",
+        "--merge-root files -S -o id --tab -o lat --tab -o lon --tab -o tags --nl \
+         -e node -v id --tab -v lat --tab -v lon --tab --collect-json-object tag --nl",
+    ),
+    (
+        "ways",
+        "Each way's id, node ids (comma separated) & tags (as JSON)",
+        "--merge-root files -S -o id --tab -o nodes --tab -o tags --nl \
+         -e way -v id --tab --collect nd/ref , --tab --collect-json-object tag --nl",
+    ),
+    (
+        "relations",
+        "Each relation member, with the relation's id, & the member's type, id & role",
+        "--merge-root files -S -o relation_id --tab -o type --tab -o ref --tab -o role --nl \
+         -s member -v ../id --tab -v type --tab -v ref --tab -v role!tsv --nl",
+    ),
+];
+
 /// Creates our clap app
 fn clap_app() -> clap::Command<'static> {
     Command::new("anglosaxon")
         .about(clap::crate_description!())
-        .long_about("Convert XML files on stdin to text on stdout with ad-hoc streaming SAX parser. e.g.\n\n    bzcat ~/osm/data/changeset-examples.osm.bz2  | anglosaxon -S -o changeset_id,tag_key,tag_value --nl -s tag -v ../id -o,  -v k -o , -v v --nl\n\n")
-        .arg(
-            Arg::new("startdoc")
-                .short('S').long("startdoc")
-                .help("Event happens once, at the start of the XML document")
-                .takes_value(false)
-                .multiple_occurrences(true)
-                .use_delimiter(false),
+        .long_about("Convert XML files on stdin to text on stdout with ad-hoc streaming SAX parser. e.g.\n\n    bzcat ~/osm/data/changeset-examples.osm.bz2  | anglosaxon -S -o changeset_id,tag_key,tag_value --nl -s tag -v ../id -o,  -v k -o , -v v --nl\n\nWithout a subcommand, the arguments are the same as `anglosaxon extract`.")
+        // The bare (legacy) form is `extract`
+        .args(event_args())
+        .args(action_args())
+        .args(option_args())
+        .arg(input_arg())
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("extract")
+                .about("Run the instructions on the XML, and output the text (default)")
+                .args(event_args())
+                .args(action_args())
+                .args(option_args())
+                .arg(input_arg()),
         )
-        .arg(
-            Arg::new("startelement")
-                .short('s').long("start")
-                .help("Event happens when this tag is opened")
-                .takes_value(true).value_name("TAG")
-                .multiple_occurrences(true)
-                .use_delimiter(false),
+        .subcommand(
+            Command::new("count")
+                .about("Count how often each event matches, and output TSV of instruction & count")
+                .args(event_args())
+                .arg(input_arg()),
         )
-        .arg(
-            Arg::new("endelement")
-                .short('e').long("end")
-                .help("Event happens when this tag is closed")
-                .takes_value(true).value_name("TAG")
-                .multiple_occurrences(true)
-                .use_delimiter(false),
+        .subcommand(
+            Command::new("stats")
+                .about("Output some statistics about the structure of the XML document")
+                .args_conflicts_with_subcommands(true)
+                .arg(input_arg())
+                .subcommand(
+                    Command::new("histogram")
+                        .about("Output TSV of how often each element happens, most common first")
+                        .arg(
+                            Arg::new("paths")
+                                .long("paths")
+                                .help("Count element paths (like /osm/node/tag), rather than element names")
+                                .takes_value(false),
+                        )
+                        .arg(input_arg()),
+                ),
         )
-        .arg(
-            Arg::new("enddoc")
-                .short('E').long("enddoc")
-                .help("Event happens once, at the end of the XML document")
-                .takes_value(false)
-                .multiple_occurrences(true)
-                .use_delimiter(false),
+        .subcommand(
+            Command::new("validate")
+                .about("Check the XML document is well formed, exit with an error if not. Elements & attributes from the -s/-e & -v/-V instructions are expected, and differences are output as TSV")
+                .args(event_args())
+                .args(action_args())
+                .arg(input_arg()),
         )
-        .arg(
-            Arg::new("raw")
-                .short('o').long("output")
-                .help("Outputs this string")
-                .takes_value(true).value_name("STRING")
-                .multiple_occurrences(true)
-                .use_delimiter(false),
+        .subcommand(
+            Command::new("osm")
+                .about("Extract common things from OpenStreetMap XML files, as TSV with a header, without needing to write the instructions")
+                .arg(
+                    Arg::new("preset")
+                        .help("What to extract")
+                        .value_name("PRESET")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(OSM_PRESETS.iter().map(|(name, description, _)| {
+                            clap::PossibleValue::new(name).help(description)
+                        })),
+                )
+                .arg(input_arg()),
         )
-        .arg(
-            Arg::new("value")
-                .short('v').long("value")
-                .help("Outputs the value of this XML attribute, an error occurs if that attribute isn't present")
-                .value_name("ATTRIBUTE")
-                .takes_value(true)
-                .multiple_occurrences(true)
-                .use_delimiter(false),
+        .subcommand(
+            Command::new("diff")
+                .about("Run the instructions on 2 XML documents, and output the lines which are only in one of them, with + for the new document & - for the old. Exits with status 1 if there are any")
+                .args(event_args())
+                .args(action_args())
+                .arg(
+                    Arg::new("input")
+                        .help("The old & new XML files. The new one can be - for stdin")
+                        .value_names(&["OLD", "NEW"])
+                        .takes_value(true)
+                        .number_of_values(2)
+                        .required(true),
+                ),
         )
-        .arg(
-            Arg::new("value_with_default")
-                .short('V').long("value-default")
-                .help("Outputs this string")
-                .takes_value(true)
-                .value_name("ATTRIBUTE DEFAULT")
-                .number_of_values(2)
-                .multiple_occurrences(true)
-                .use_delimiter(false),
+        .subcommand(
+            Command::new("bench")
+                .about("Run the instructions on the XML several times, throwing away the output, & output how long it took (the fastest, slowest & percentiles) & the throughput. The input is read into memory first, so reading it isn't timed")
+                .args(event_args())
+                .args(action_args())
+                .arg(
+                    Arg::new("iterations")
+                        .long("iterations")
+                        .help("How many times to process the input")
+                        .value_name("N")
+                        .takes_value(true)
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("synthetic")
+                        .long("synthetic")
+                        .help("Generate an OSM-like document of about SIZE bytes (e.g. 100M), rather than reading one")
+                        .value_name("SIZE")
+                        .takes_value(true)
+                        .conflicts_with("input"),
+                )
+                .arg(input_arg()),
         )
-        .arg(
-            Arg::new("newline")
-                .long("nl")
-                .help("Outputs a new line character")
-                .takes_value(false)
-                .multiple_occurrences(true),
+        .subcommand(
+            Command::new("reindent")
+                .about("Copy the XML document, with each element on its own line & indented. Elements with text in them are copied as they are")
+                .arg(
+                    Arg::new("indent")
+                        .long("indent")
+                        .help("How many spaces to indent each level by, or tab")
+                        .value_name("N|tab")
+                        .takes_value(true)
+                        .default_value("2"),
+                )
+                .arg(input_arg()),
         )
-        .arg(
-            Arg::new("tab")
-                .long("tab")
-                .help("Outputs a tab character")
-                .takes_value(false)
-                .multiple_occurrences(true),
+        .subcommand(
+            Command::new("rewrite")
+                .about("Copy the XML document, renaming elements, & renaming, removing & adding attributes")
+                .arg(
+                    Arg::new("rename_tag")
+                        .long("rename-tag")
+                        .help("Rename OLD elements to NEW, e.g. --rename-tag node=point")
+                        .value_name("OLD=NEW")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    Arg::new("rename_attr")
+                        .long("rename-attr")
+                        .help("Rename the OLD attribute of TAG elements (or any element, for *) to NEW, e.g. --rename-attr node.uid=user_id. TAG is the name before any --rename-tag")
+                        .value_name("TAG.OLD=NEW")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    Arg::new("drop_attr")
+                        .long("drop-attr")
+                        .help("Remove the ATTRIBUTE of TAG elements (or any element, for *), e.g. --drop-attr '*.uid'")
+                        .value_name("TAG.ATTRIBUTE")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    Arg::new("add_attr")
+                        .long("add-attr")
+                        .help("Add the ATTRIBUTE to TAG elements (or any element, for *), or change its value if it's already there, e.g. --add-attr node.source=survey")
+                        .value_name("TAG.ATTRIBUTE=VALUE")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    Arg::new("indent")
+                        .long("indent")
+                        .help("Put each element on its own line, indented by this many spaces for each level, or tab, like the reindent subcommand")
+                        .value_name("N|tab")
+                        .takes_value(true),
+                )
+                .arg(input_arg()),
         )
+        .subcommand(
+            Command::new("completions")
+                .about("Print a shell completion script to stdout")
+                .hide(true)
+                .arg(
+                    Arg::new("shell")
+                        .help("Shell to generate completions for")
+                        .required(true)
+                        .possible_values(["bash", "zsh", "fish"]),
+                ),
+        )
+}
+
+/// Write the shell completion script for `shell` to `output`.
+///
+/// The clap generated script can't know about the `ATTR!filter` syntax, so a little shell
+/// specific wrapper is appended, which completes filter names after a `!`.
+fn write_completions(shell: Shell, mut output: impl Write) -> Result<()> {
+    let mut script = vec![];
+    clap_complete::generate(shell, &mut clap_app(), "anglosaxon", &mut script);
+    let script = String::from_utf8(script)?;
+    // zsh autoloads the file as the `_anglosaxon` function, & the script ends by calling it. We
+    // want to call our wrapper instead.
+    let script = match shell {
+        Shell::Zsh => script
+            .trim_end()
+            .strip_suffix("_anglosaxon \"$@\"")
+            .unwrap_or(&script),
+        _ => &script,
+    };
+    output.write_all(script.as_bytes())?;
+
+    let filters = TextFilter::NAMES.join(" ");
+    match shell {
+        Shell::Bash => {
+            writeln!(
+                output,
+                r#"
+_anglosaxon_with_filters() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    if [[ "$cur" == *'!'* ]]; then
+        COMPREPLY=( $(compgen -P "${{cur%!*}}!" -W "{filters}" -- "${{cur##*!}}") )
+        return 0
+    fi
+    _anglosaxon "$@"
+}}
+complete -F _anglosaxon_with_filters -o bashdefault -o default anglosaxon"#
+            )?;
+        }
+        Shell::Zsh => {
+            writeln!(
+                output,
+                r#"
+_anglosaxon_with_filters() {{
+    if [[ "$PREFIX" == *'!'* ]]; then
+        compset -P '*!'
+        compadd -- {filters}
+        return
+    fi
+    _anglosaxon "$@"
+}}
+compdef _anglosaxon_with_filters anglosaxon
+
+_anglosaxon_with_filters "$@""#
+            )?;
+        }
+        Shell::Fish => {
+            writeln!(
+                output,
+                r#"
+function __anglosaxon_filters
+    set -l prefix (string replace -r '![^!]*$' '!' -- (commandline -ct))
+    for filter in {filters}
+        echo $prefix$filter
+    end
+end
+complete -c anglosaxon -n 'string match -q -- "*!*" (commandline -ct)' -f -a '(__anglosaxon_filters)'"#
+            )?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Is this error from writing to a pipe which has been closed?
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+    })
+}
+
+/// The `tracing` target of the `--trace-events` messages
+const TRACE_EVENTS: &str = "anglosaxon::trace_events";
+
+/// The `tracing` target of the `--debug-matches` messages
+const DEBUG_MATCHES: &str = "anglosaxon::debug_matches";
+
+/// The `tracing` target of the `--print-stats` & `--timing-detail` messages
+const STATS: &str = "anglosaxon::stats";
+
+/// Writes messages to stderr, with the level first, e.g. `Warning: …`. Info messages, & the ones
+/// an option asked for (e.g. `--trace-events`), are written as they are
+struct StderrFormat;
+
+impl<S, N> tracing_subscriber::fmt::FormatEvent<S, N> for StderrFormat
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        let requested = [TRACE_EVENTS, DEBUG_MATCHES, STATS].contains(&event.metadata().target());
+        let prefix = match *event.metadata().level() {
+            _ if requested => "",
+            tracing::Level::ERROR => "Error: ",
+            tracing::Level::WARN => "Warning: ",
+            tracing::Level::INFO => "",
+            tracing::Level::DEBUG => "Debug: ",
+            tracing::Level::TRACE => "Trace: ",
+        };
+        // The other fields are already in the message
+        let mut message = MessageField(String::new());
+        event.record(&mut message);
+        writeln!(writer, "{}{}", prefix, message.0)
+    }
+}
+
+/// The message of an event, for `StderrFormat`
+struct MessageField(String);
+
+impl tracing::field::Visit for MessageField {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+/// Where the messages go. Without -q/--log-level, the ANGLOSAXON_LOG environment variable can
+/// choose them, e.g. `debug`
+fn init_logging(config: &Config) -> Result<()> {
+    let mut filter = match config.log_level {
+        Some(level) => tracing_subscriber::EnvFilter::default().add_directive(level.into()),
+        None if std::env::var_os("ANGLOSAXON_LOG").is_some() => {
+            tracing_subscriber::EnvFilter::try_from_env("ANGLOSAXON_LOG")
+                .context("Invalid ANGLOSAXON_LOG")?
+        }
+        None => tracing_subscriber::EnvFilter::new("info"),
+    };
+    // The messages which an option asked for are logged even with -q
+    let requested = [
+        (TRACE_EVENTS, "trace", config.trace_events),
+        (DEBUG_MATCHES, "debug", config.debug_matches.is_some()),
+        (STATS, "info", config.print_stats || config.timing_detail),
+    ];
+    for (target, level, _) in requested.iter().filter(|(_, _, on)| *on) {
+        filter = filter.add_directive(format!("{}={}", target, level).parse()?);
+    }
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(filter);
+    match config.log_json {
+        true => subscriber
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+        false => subscriber.event_format(StderrFormat).try_init(),
+    }
+    .map_err(|e| anyhow!(e))
 }
 
 fn main() -> Result<()> {
-    let mut stdin = std::io::stdin();
     let stdout = std::io::stdout();
 
-    let instructions = parse_to_instructions(None)?;
-    if instructions.is_empty() {
-        clap_app().print_long_help()?;
-        return Ok(());
+    let (mut config, instructions) = parse_to_instructions(None)?;
+    init_logging(&config)?;
+    for instruction in instructions.iter() {
+        tracing::debug!("Instruction: {}", instruction);
     }
+    let inputs = config.inputs.iter().map(|filename| open_input(filename));
+    match config.mode {
+        Mode::Completions(shell) => {
+            write_completions(shell, stdout.lock())?;
+        }
+        Mode::Extract | Mode::Count if instructions.is_empty() => {
+            clap_app().print_long_help()?;
+        }
+        Mode::Extract => {
+            // The first Ctrl-C stops at the next XML event. If that's taking too long (e.g. waiting
+            // on stdin), a second one exits immediately
+            let interrupted = config.interrupted.clone();
+            ctrlc::set_handler({
+                let interrupted = interrupted.clone();
+                move || {
+                    if interrupted.swap(true, Ordering::Relaxed) {
+                        std::process::exit(130);
+                    }
+                }
+            })?;
 
-    process(&instructions, &mut stdin, stdout)?;
+            let mut output: Box<dyn Write> = Box::new(std::io::BufWriter::new(stdout.lock()));
+            // The footer skips the --unique etc. wrappers
+            let mut footer_output: Box<dyn Write> = Box::new(std::io::stdout());
+            #[cfg(feature = "kafka")]
+            let kafka = match &config.kafka {
+                Some(options) => Some(SharedKafkaSink(Rc::new(std::cell::RefCell::new(
+                    KafkaSink::connect(options, &config)?,
+                )))),
+                None => None,
+            };
+            #[cfg(feature = "kafka")]
+            if let Some(sink) = &kafka {
+                output = Box::new(sink.clone());
+                footer_output = Box::new(sink.clone());
+            }
+            #[cfg(any(feature = "postgres", feature = "s3"))]
+            let mut output_thread = None;
+            #[cfg(feature = "postgres")]
+            if let Some(options) = &config.postgres {
+                output_thread = Some(postgres_copy(options, &config)?);
+            }
+            #[cfg(feature = "s3")]
+            if let Some(options) = &config.upload {
+                output_thread = Some(s3_upload(options)?);
+            }
+            #[cfg(any(feature = "postgres", feature = "s3"))]
+            if let Some(thread) = &output_thread {
+                output = Box::new(std::io::BufWriter::new(thread.writer()));
+                footer_output = Box::new(thread.writer());
+            }
+            let mut pipe = None;
+            if let Some(command) = &config.pipe {
+                let (reader, writer) = std::io::pipe()?;
+                pipe = Some(
+                    std::process::Command::new("sh")
+                        .arg("-c")
+                        .arg(command)
+                        .stdin(reader)
+                        .spawn()
+                        .with_context(|| format!("Cannot run --pipe {:?}", command))?,
+                );
+                footer_output = Box::new(writer.try_clone()?);
+                output = Box::new(std::io::BufWriter::new(writer));
+            }
+            // The footer is written to these after everything else is flushed
+            let mut tee_files = vec![];
+            if !config.tee.is_empty() {
+                let mut files = vec![];
+                for path in config.tee.iter() {
+                    let file = std::fs::File::create(path)
+                        .with_context(|| format!("Cannot create --tee file {}", path.display()))?;
+                    tee_files.push(file.try_clone()?);
+                    files.push(std::io::BufWriter::new(file));
+                }
+                output = Box::new(Tee {
+                    inner: output,
+                    files,
+                });
+            }
+            if let Some(lines) = config.flush_every {
+                output = Box::new(FlushEvery::new(output, lines));
+            }
+            let started = std::time::Instant::now();
+            let mut summary = Summary::default();
+            if let Some(options) = &config.progress {
+                summary.progress = Some(Progress::open(options, &config.inputs)?);
+            }
+            if let Some(path) = &config.invalid_to {
+                summary.invalid = Some(InvalidRecords::open(path, config.resume.is_some())?);
+            }
+            // The start of the output was already output before the --resume checkpoint
+            let mut header = vec![];
+            if let Some(root) = &config.copy_root {
+                writeln!(
+                    header,
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<{}>",
+                    root
+                )?;
+            }
+            if let Some(comment) = &config.header_comment {
+                let now = chrono::Local::now();
+                comment.write(
+                    &config.inputs,
+                    now.to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+                    &mut header,
+                )?;
+            }
+            // JSON records have their field names, & Kafka messages don't have a header
+            if let Some(schema) = config.schema.as_ref().filter(|_| {
+                config.record_format.is_none()
+                    && config.kafka.is_none()
+                    && config.postgres.is_none()
+            }) {
+                header.extend(
+                    schema
+                        .header(config.field_separator.as_deref().unwrap_or("\t"))
+                        .as_bytes(),
+                );
+                header.extend(
+                    config
+                        .record_separator
+                        .as_deref()
+                        .unwrap_or("\n")
+                        .as_bytes(),
+                );
+            }
+            let feature_collection = config.geojson.as_ref().is_some_and(|g| !g.lines);
+            if feature_collection {
+                writeln!(header, "{{\"type\":\"FeatureCollection\",\"features\":[")?;
+            }
+            // A shard after the first carries on from its first top level element, inside the
+            // root element
+            let empty_shard = config.start_offset.is_some() && {
+                let filename = config.inputs[0].clone();
+                !start_shard(&mut config, &filename)?
+            };
+            match &config.resume {
+                Some(resume) if config.checkpoint.is_some() => {
+                    tracing::info!(
+                        "Resuming {} from byte {}. Cut the previous output back to its first {} bytes (e.g. truncate -s {} FILE), & append this output to it",
+                        resume.input, resume.offset, resume.output, resume.output
+                    );
+                    summary.bytes_written = resume.output;
+                }
+                Some(_) => {}
+                None if empty_shard => {}
+                None => {
+                    output.write_all(&header)?;
+                    summary.bytes_written = header.len() as u64;
+                }
+            }
+            if feature_collection {
+                output = Box::new(CommaSeparatedLines::new(output));
+            }
+            if let Some(mode) = config.unique {
+                let mut unique = UniqueLines::new(output, mode);
+                unique.max_bytes = config.max_memory;
+                output = Box::new(unique);
+            }
+            // Sort before removing duplicates, so --unique=adjacent works
+            if let Some(key) = config.sort {
+                let buffer = config.max_memory.unwrap_or(SORT_BUFFER_BYTES);
+                output = Box::new(SortedLines::new(output, key, buffer));
+            }
+            let mut result = Ok(());
+            if let Some(threads) = config.threads {
+                // Each thread has its own instructions, since counters etc. aren't thread safe
+                let args: Vec<String> = std::env::args().skip(1).collect();
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                let parse = || {
+                    let (mut config, instructions) = parse_to_instructions(args.as_slice())?;
+                    config.interrupted = interrupted.clone();
+                    Ok((config, instructions))
+                };
+                let queue_size = config.queue_size.unwrap_or(2 * threads);
+                if queue_size < threads {
+                    tracing::warn!(
+                        "Only {} of the {} threads can be busy at once, since --queue-size is {}",
+                        queue_size,
+                        threads,
+                        queue_size
+                    );
+                }
+                let _span = tracing::info_span!("input", file = %config.inputs[0]).entered();
+                result = process_parallel(
+                    &config.inputs[0],
+                    threads,
+                    queue_size,
+                    PARALLEL_CHUNK_BYTES,
+                    parse,
+                    &mut output,
+                    &mut summary,
+                );
+            }
+            for filename in config
+                .inputs
+                .iter()
+                .filter(|_| !empty_shard && config.threads.is_none())
+            {
+                let _span = tracing::info_span!("input", file = %filename).entered();
+                tracing::debug!("Reading {}", filename);
+                let input = match &config.resume {
+                    Some(resume) => open_input_at(filename, resume.offset),
+                    None => open_input(filename),
+                };
+                result = input.and_then(|input| {
+                    process(
+                        &config,
+                        &instructions,
+                        filename,
+                        input,
+                        &mut output,
+                        &mut summary,
+                    )
+                });
+                if config.resume.is_some() && config.checkpoint.is_none() {
+                    result = result.context(
+                        "The --start-offset shard might have started in the middle of an element, so use --sync-tag with the top level elements",
+                    );
+                }
+                tracing::debug!(
+                    "Finished {}, {} bytes read & {} records output so far",
+                    filename,
+                    summary.bytes_read,
+                    summary.records
+                );
+                if result.is_err() || summary.interrupted.is_some() || summary.output_limit_reached
+                {
+                    break;
+                }
+            }
+            // A shard only has some of the elements
+            let all_input = result.is_ok()
+                && summary.interrupted.is_none()
+                && !summary.output_limit_reached
+                && config.start_offset.is_none()
+                && config.end_offset.is_none();
+            // The next shard has the end of the output
+            let footer = !empty_shard && !summary.reached_end_offset;
+            let result = result.and_then(|()| {
+                output.flush()?;
+                if !footer {
+                    return Ok(());
+                }
+                let mut footer = vec![];
+                if let Some(root) = &config.copy_root {
+                    writeln!(footer, "</{}>", root)?;
+                }
+                if feature_collection {
+                    writeln!(footer, "\n]}}")?;
+                }
+                footer_output.write_all(&footer)?;
+                for file in tee_files.iter_mut() {
+                    file.write_all(&footer)?;
+                }
+                Ok(())
+            });
+            // The last record might not end with the record separator
+            #[cfg(feature = "kafka")]
+            let result = match &kafka {
+                Some(sink) => result.and_then(|()| Ok(sink.0.borrow_mut().finish()?)),
+                None => result,
+            };
+            // Its error says e.g. which row was bad
+            #[cfg(any(feature = "postgres", feature = "s3"))]
+            let result = match output_thread {
+                Some(thread) => {
+                    output = Box::new(std::io::sink());
+                    footer_output = Box::new(std::io::sink());
+                    let finished = thread.finish(result.is_ok());
+                    result.and(finished)
+                }
+                None => result,
+            };
+            // Closing its input tells it that's all
+            let mut pipe_status = None;
+            let result = match pipe {
+                Some(mut child) => {
+                    drop(output);
+                    drop(footer_output);
+                    match child.wait() {
+                        Ok(status) => {
+                            pipe_status = Some(status);
+                            result
+                        }
+                        Err(e) => result.and(Err(e).context("Cannot wait for --pipe")),
+                    }
+                }
+                None => result,
+            };
+            // Whatever was reading our output has stopped (e.g. `| head`), which isn't an error
+            let result = result.or_else(|e| if is_broken_pipe(&e) { Ok(()) } else { Err(e) });
+            // Flushed here, since an interrupted run exits without dropping it
+            let result = result.and_then(|()| match summary.invalid.take() {
+                Some(mut invalid) => invalid
+                    .output
+                    .flush()
+                    .context("Cannot write to --invalid-to"),
+                None => Ok(()),
+            });
+            if let Some(mut progress) = summary.progress.take() {
+                let event = if all_input { "done" } else { "stopped" };
+                // Too late to be a problem
+                let _ = progress.write(event, summary.bytes_read, summary.records);
+            }
+            summary.log_warnings(&instructions, all_input);
+            if config.print_stats {
+                let buffered = match summary.peak_buffered {
+                    0 => String::new(),
+                    peak => format!(", at most {} bytes buffered", peak),
+                };
+                tracing::info!(
+                    target: STATS,
+                    records = summary.records,
+                    bytes_read = summary.bytes_read,
+                    peak_buffered = summary.peak_buffered,
+                    "{} records output, {} bytes read{}",
+                    summary.records,
+                    summary.bytes_read,
+                    buffered
+                );
+            }
+            if let Some(timing) = &summary.timing {
+                tracing::info!(
+                    target: STATS,
+                    parsing_secs = timing.parsing.as_secs_f64(),
+                    other_secs = timing.other.as_secs_f64(),
+                    filters_secs = timing.filters.as_secs_f64(),
+                    writing_secs = timing.writing.as_secs_f64(),
+                    "{}",
+                    timing.report(&instructions).trim_end()
+                );
+            }
+            let unmatched = match config.strict_unmatched && all_input {
+                true => summary.never_matched(&instructions).count(),
+                false => 0,
+            };
+            if let Some(path) = &config.summary_json {
+                let exit_reason = if result.is_err() {
+                    "error"
+                } else if summary.interrupted.is_some() {
+                    "interrupted"
+                } else if summary.output_limit_reached {
+                    "output-limit"
+                } else if summary.reached_end_offset {
+                    "end-offset"
+                } else if config.fail_if_empty && summary.records == 0 {
+                    "empty"
+                } else if unmatched > 0 {
+                    "unmatched"
+                } else {
+                    "complete"
+                };
+                let error = result.as_ref().err().map(|e| format!("{:#}", e));
+                let json = summary.to_json(
+                    &instructions,
+                    all_input,
+                    exit_reason,
+                    error.as_deref(),
+                    started.elapsed(),
+                );
+                if path == "-" {
+                    eprint!("{}", json);
+                } else {
+                    std::fs::write(path, json)
+                        .with_context(|| format!("Cannot write --summary-json to {}", path))?;
+                }
+            }
+            result?;
+            if let Some(status) = pipe_status.filter(|status| !status.success()) {
+                tracing::error!("The --pipe command failed ({})", status);
+                std::process::exit(status.code().unwrap_or(1));
+            }
+            if let Some(location) = &summary.interrupted {
+                tracing::warn!(
+                    "Interrupted at {}, after {} records",
+                    location,
+                    summary.records
+                );
+                std::process::exit(130);
+            }
+            if config.fail_if_empty && summary.records == 0 {
+                bail!("No records were output (--fail-if-empty)");
+            }
+            if unmatched > 0 {
+                bail!(
+                    "{} instruction(s) never matched (--strict-unmatched)",
+                    unmatched
+                );
+            }
+        }
+        Mode::Count => {
+            count(&instructions, inputs, stdout)?;
+        }
+        Mode::Diff => {
+            let mut summary = Summary::default();
+            let differences = diff(
+                &config,
+                &instructions,
+                &config.inputs[0],
+                &config.inputs[1],
+                open_input,
+                stdout.lock(),
+                &mut summary,
+            );
+            let differences =
+                differences.or_else(|e| if is_broken_pipe(&e) { Ok(1) } else { Err(e) })?;
+            summary.log_warnings(&instructions, true);
+            if differences > 0 {
+                std::process::exit(1);
+            }
+        }
+        Mode::Bench(ref options) => {
+            let inputs = match options.synthetic {
+                Some(bytes) => vec![("synthetic".to_string(), synthetic_osm(bytes))],
+                None => config
+                    .inputs
+                    .iter()
+                    .map(|filename| {
+                        let mut input = vec![];
+                        open_input(filename)?.read_to_end(&mut input)?;
+                        Ok((filename.clone(), input))
+                    })
+                    .collect::<Result<_>>()?,
+            };
+            bench(&config, &instructions, options, &inputs, stdout.lock()).or_else(|e| {
+                if is_broken_pipe(&e) {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            })?;
+        }
+        Mode::Rewrite(ref rules) => {
+            rewrite(rules, inputs, stdout.lock())?;
+        }
+        Mode::Stats => {
+            stats(inputs, stdout)?;
+        }
+        Mode::Histogram { paths } => {
+            histogram(paths, inputs, stdout)?;
+        }
+        Mode::Validate => {
+            let mut expected = Expectations::new(&instructions);
+            for filename in config.inputs.iter() {
+                validate(&mut expected, open_input(filename)?)
+                    .with_context(|| format!("{} is not valid XML", filename))?;
+            }
+            if !expected.report(stdout)? {
+                bail!("Document doesn't have the expected elements & attributes");
+            }
+        }
+    }
 
     Ok(())
 }
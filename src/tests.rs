@@ -1,5 +1,4 @@
 use super::*;
-use std::io::Cursor;
 
 macro_rules! assert_flow {
     ($name:ident, $input:expr, $instructions:expr, $expected_output:expr) => {
@@ -16,13 +15,46 @@ macro_rules! assert_flow {
             //];
             let instructions = $instructions;
 
-            process(&instructions, input.as_bytes(), Cursor::new(&mut output)).unwrap();
+            process(
+                &Config::default(),
+                &instructions,
+                "-",
+                input.as_bytes(),
+                Cursor::new(&mut output),
+                &mut Summary::default(),
+            )
+            .unwrap();
 
             assert_eq!(String::from_utf8(output).unwrap(), expected_output);
         }
     };
 }
 
+/// Parse these CLI args (split on spaces), and process the input with them
+fn run(args: &str, input: &str) -> Result<String> {
+    let args: Vec<_> = args.split(' ').collect();
+    let (config, instructions) = parse_to_instructions(args.as_slice())?;
+    let mut output: Vec<u8> = vec![];
+    process(
+        &config,
+        &instructions,
+        "-",
+        input.as_bytes(),
+        &mut output,
+        &mut Summary::default(),
+    )?;
+    Ok(String::from_utf8(output)?)
+}
+
+macro_rules! assert_run {
+    ($name:ident, $args:expr, $input:expr, $expected_output:expr) => {
+        #[test]
+        fn $name() {
+            assert_eq!(run($args, $input).unwrap(), $expected_output);
+        }
+    };
+}
+
 assert_flow!(
     simple1,
     "<note>hello</note>",
@@ -203,6 +235,91 @@ assert_flow!(
     "foo\\nbar\nok\n"
 );
 
+assert_flow!(
+    now1,
+    r#"<notes><note id="1"/></notes>"#,
+    vec![Instruction::StartTag {
+        tag: "note".to_string(),
+        actions: vec![Action::Now("at %%".to_string()),]
+    },],
+    "at %"
+);
+
+#[test]
+fn filename1() {
+    let instructions = vec![Instruction::StartTag {
+        tag: "note".to_string(),
+        actions: vec![
+            Action::Filename,
+            Action::RawString(" ".to_string()),
+            Action::Basename,
+        ],
+    }];
+    let mut output: Vec<u8> = vec![];
+    process(
+        &Config::default(),
+        &instructions,
+        "data/notes.xml",
+        "<note/>".as_bytes(),
+        &mut output,
+        &mut Summary::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "data/notes.xml notes.xml"
+    );
+}
+
+#[test]
+fn uuid1() {
+    let instructions = vec![Instruction::StartTag {
+        tag: "note".to_string(),
+        actions: vec![
+            Action::Uuid(UuidVersion::V4),
+            Action::RawString(" ".to_string()),
+            Action::Uuid(UuidVersion::V7),
+            Action::RawString("\n".to_string()),
+        ],
+    }];
+    let mut output: Vec<u8> = vec![];
+    process(
+        &Config::default(),
+        &instructions,
+        "-",
+        "<notes><note/><note/></notes>".as_bytes(),
+        &mut output,
+        &mut Summary::default(),
+    )
+    .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let uuids: Vec<uuid::Uuid> = output
+        .split_whitespace()
+        .map(|u| u.parse().unwrap())
+        .collect();
+    assert_eq!(uuids.len(), 4);
+    assert_eq!(uuids[0].get_version_num(), 4);
+    assert_eq!(uuids[1].get_version_num(), 7);
+    assert_ne!(uuids[0], uuids[2]);
+    assert_ne!(uuids[1], uuids[3]);
+}
+
+assert_flow!(
+    offset1,
+    "<notes>\n  <note>hi <!-- a < b --></note>\n  <note id=\"é\"/></notes>",
+    vec![
+        Instruction::StartTag {
+            tag: "note".to_string(),
+            actions: vec![Action::Offset, Action::RawString(" ".to_string())]
+        },
+        Instruction::EndTag {
+            tag: "note".to_string(),
+            actions: vec![Action::Offset, Action::RawString("\n".to_string())]
+        },
+    ],
+    "10 10\n43 43\n"
+);
+
 mod parse {
     use super::*;
 
@@ -419,6 +536,50 @@ mod parse {
         },]
     );
 
+    assert_parse!(
+        now1,
+        "-S --now -o , --now=%Y",
+        vec![Instruction::StartDocument {
+            actions: vec![
+                Action::Now(DEFAULT_NOW_FORMAT.to_string()),
+                Action::RawString(",".to_string()),
+                Action::Now("%Y".to_string()),
+            ]
+        },]
+    );
+
+    #[test]
+    fn now_invalid() {
+        assert!(parse_to_instructions(["-S", "--now=%Q"].as_slice()).is_err());
+    }
+
+    assert_parse!(
+        filename1,
+        "-s note --filename --basename",
+        vec![Instruction::StartTag {
+            tag: "note".to_string(),
+            actions: vec![Action::Filename, Action::Basename]
+        },]
+    );
+
+    #[test]
+    fn inputs() {
+        let (config, _) = parse_to_instructions(["-s", "note", "-v", "id"].as_slice()).unwrap();
+        assert_eq!(config.inputs, vec!["-"]);
+        let (config, _) =
+            parse_to_instructions(["-s", "note", "-v", "id", "a.xml", "b.xml"].as_slice()).unwrap();
+        assert_eq!(config.inputs, vec!["a.xml", "b.xml"]);
+    }
+
+    assert_parse!(
+        uuid1,
+        "-s note --uuid --uuid=v7",
+        vec![Instruction::StartTag {
+            tag: "note".to_string(),
+            actions: vec![Action::Uuid(UuidVersion::V4), Action::Uuid(UuidVersion::V7)]
+        },]
+    );
+
     assert_parse!(
         start_doc,
         "-S -o foo",
@@ -453,7 +614,7 @@ mod filters {
                 let expected_output = $expected_output;
                 let ff = Filters::parse_both($filters).unwrap();
                 let filters = ff.1;
-                assert_eq!(filters.apply(input), $expected_output);
+                assert_eq!(filters.apply(input), expected_output);
             }
         };
     }
@@ -470,4 +631,3918 @@ mod filters {
     assert_filter!(tsv2, "x!tsv", "foo\rbar", "foo\\rbar");
     assert_filter!(tsv3, "x!tsv", "foo\tbar", "foo\\tbar");
     assert_filter!(tsv4, "x!tsv", "foo\" \"bar", "foo\" \"bar");
+
+    assert_filter!(decimal1, "x!decimal", "1.234,5", "1234.5");
+    assert_filter!(decimal2, "x!decimal", "-12,75", "-12.75");
+    assert_filter!(decimal3, "x!decimal", " 1.000.000 ", "1000000");
+    assert_filter!(decimal4, "x!decimal", "n/a", "n/a");
+    assert_filter!(decimal5, "x!decimal", "1,2,3", "1,2,3");
+    assert_filter!(decimal_fr, "x!decimal:fr", "1\u{202f}234,5", "1234.5");
+    assert_filter!(decimal_ch, "x!decimal:de-CH", "1'234.5", "1234.5");
+    assert_filter!(decimal_en, "x!decimal:en", "+1,234.5", "+1234.5");
+    assert_filter!(decimal_en_plain, "x!decimal:en", "1234", "1234");
+    assert_filter!(decimal_short_group, "x!decimal", "12.34", "12.34");
+    assert_filter!(decimal_groups, "x!decimal", "1.2.3", "1.2.3");
+    assert_filter!(
+        decimal_long_first_group,
+        "x!decimal",
+        "1234.567",
+        "1234.567"
+    );
+    assert_filter!(decimal_en_groups, "x!decimal:en", "12,34.5", "12,34.5");
+
+    assert_filter!(nfc1, "x!nfc", "Cafe\u{301}", "Caf\u{e9}");
+    assert_filter!(nfc2, "x!nfc", "Caf\u{e9}", "Caf\u{e9}");
+    assert_filter!(nfd1, "x!nfd", "Caf\u{e9}", "Cafe\u{301}");
+    assert_filter!(nfkc1, "x!nfkc", "\u{fb01}le \u{2460}", "file 1");
+    assert_filter!(nfkd1, "x!nfkd", "\u{fb01}\u{e9}", "fie\u{301}");
+
+    assert_filter!(strip_ctrl1, "x!strip-ctrl", "a\u{0}b\tc\u{1b}[0m", "abc[0m");
+    assert_filter!(strip_ctrl2, "x!strip-ctrl", "a\u{85}b\u{7f}", "ab");
+    assert_filter!(
+        strip_ctrl3,
+        "x!strip-ctrl",
+        "caf\u{e9} \u{2028}",
+        "caf\u{e9} \u{2028}"
+    );
+    assert_filter!(strip_ctrl_replace, "x!strip-ctrl:_", "a\r\nb\u{7}", "a_b_");
+
+    assert_filter!(ascii1, "x!ascii", "Crème Brûlée", "Creme Brulee");
+    assert_filter!(ascii2, "x!ascii", "Straße in Łódź", "Strasse in Lodz");
+    assert_filter!(ascii3, "x!ascii", "Ærø – “Øresund”", "AEro - \"Oresund\"");
+    assert_filter!(ascii4, "x!ascii", "東京 Tokyo", " Tokyo");
+    assert_filter!(ascii5, "x!ascii", "plain", "plain");
+    assert_filter!(ascii_strip, "x!ascii-strip", "Crème Straße", "Crme Strae");
+
+    #[test]
+    fn decimal_normalise() {
+        let de = NumberFormat::for_locale("de").unwrap();
+        assert_eq!(de.normalise("1.234,5").as_deref(), Some("1234.5"));
+        assert_eq!(de.normalise("12.34"), None);
+        assert_eq!(de.normalise("1.2.3"), None);
+        assert_eq!(de.normalise("1..234"), None);
+        assert_eq!(de.normalise("1234,5").as_deref(), Some("1234.5"));
+    }
+
+    #[test]
+    fn decimal_unknown_locale() {
+        assert!(Filters::parse_both("x!decimal:xx").is_err());
+    }
+}
+
+mod completions {
+    use super::*;
+
+    #[test]
+    fn filters_in_all_shells() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let mut output: Vec<u8> = vec![];
+            write_completions(shell, &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.contains("_anglosaxon"));
+            assert!(output.contains("none unix tsv"));
+        }
+    }
+
+    #[test]
+    fn parse_completions() {
+        let (config, instructions) =
+            parse_to_instructions(["completions", "zsh"].as_slice()).unwrap();
+        assert_eq!(config.mode, Mode::Completions(Shell::Zsh));
+        assert!(instructions.is_empty());
+    }
+}
+
+mod subcommands {
+    use super::*;
+
+    #[test]
+    fn extract_same_as_bare() {
+        let (config, instructions) =
+            parse_to_instructions(["extract", "-s", "note", "-v", "id"].as_slice()).unwrap();
+        assert_eq!(config.mode, Mode::Extract);
+        assert_eq!(
+            instructions,
+            parse_to_instructions(["-s", "note", "-v", "id"].as_slice())
+                .unwrap()
+                .1
+        );
+    }
+
+    #[test]
+    fn count1() {
+        let (config, instructions) =
+            parse_to_instructions(["count", "-s", "note", "-e", "notes"].as_slice()).unwrap();
+        assert_eq!(config.mode, Mode::Count);
+        let mut output: Vec<u8> = vec![];
+        count(
+            &instructions,
+            [
+                Ok(r#"<notes><note id="1"/><note id="2"/></notes>"#.as_bytes()),
+                Ok(r#"<notes><note id="3"/></notes>"#.as_bytes()),
+            ],
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "-s note\t3\n-e notes\t2\n"
+        );
+    }
+
+    #[test]
+    fn diff1() {
+        let (config, instructions) = parse_to_instructions(
+            [
+                "diff", "-s", "node", "-v", "id", "-o", " ", "-v", "lat", "-o", "\n", "old.osm",
+                "new.osm",
+            ]
+            .as_slice(),
+        )
+        .unwrap();
+        assert_eq!(config.mode, Mode::Diff);
+        assert_eq!(config.inputs, ["old.osm", "new.osm"]);
+        let open = |filename: &str| -> Result<Box<dyn BufRead>> {
+            Ok(Box::new(match filename {
+                "old.osm" => r#"<osm><node id="1" lat="1"/><node id="2" lat="2"/><node id="2" lat="2"/></osm>"#.as_bytes(),
+                _ => r#"<osm><node id="2" lat="2"/><node id="1" lat="1.5"/><node id="3" lat="3"/></osm>"#.as_bytes(),
+            }))
+        };
+        let mut output: Vec<u8> = vec![];
+        let differences = diff(
+            &config,
+            &instructions,
+            "old.osm",
+            "new.osm",
+            open,
+            &mut output,
+            &mut Summary::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "+1 1.5\n+3 3\n-1 1\n-2 2\n"
+        );
+        assert_eq!(differences, 4);
+
+        assert!(diff(
+            &config,
+            &instructions,
+            "-",
+            "new.osm",
+            open,
+            std::io::sink(),
+            &mut Summary::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn bench1() {
+        let (config, instructions) = parse_to_instructions(
+            [
+                "bench",
+                "--iterations",
+                "3",
+                "--synthetic",
+                "10K",
+                "-s",
+                "node",
+                "-v",
+                "id",
+            ]
+            .as_slice(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.mode,
+            Mode::Bench(Bench {
+                iterations: 3,
+                synthetic: Some(10240)
+            })
+        );
+        assert_eq!(instructions.len(), 1);
+
+        let input = synthetic_osm(10240);
+        assert!(input.len() >= 10240 && input.len() < 11000);
+        assert_eq!(input, synthetic_osm(10240));
+        let mut output: Vec<u8> = vec![];
+        let inputs = [("synthetic".to_string(), input)];
+        let Mode::Bench(options) = &config.mode else {
+            unreachable!()
+        };
+        bench(&config, &instructions, options, &inputs, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let nodes = inputs[0].1.windows(6).filter(|w| w == b"<node ").count();
+        assert!(output.starts_with(&format!(
+            "{} bytes, {} records, 3 iterations\n\tseconds\tMB/s\nfastest\t",
+            inputs[0].1.len(),
+            nodes
+        )));
+        assert_eq!(output.lines().count(), 7);
+    }
+
+    #[test]
+    fn bench_report() {
+        let times = [4, 1, 3, 2]
+            .iter()
+            .map(|&s| std::time::Duration::from_secs(s))
+            .collect();
+        let mut output: Vec<u8> = vec![];
+        write_bench_report(4_000_000, 5, times, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "4000000 bytes, 5 records, 4 iterations\n\tseconds\tMB/s\nfastest\t1.000\t4.0\np50\t2.000\t2.0\np90\t4.000\t1.0\np99\t4.000\t1.0\nslowest\t4.000\t1.0\n"
+        );
+    }
+
+    #[test]
+    fn osm_presets() {
+        let input = r#"<osm><node id="1" lat="1.5" lon="2"><tag k="a" v="b"/></node><way id="5"><nd ref="1"/><nd ref="2"/></way><relation id="9"><member type="way" ref="5" role="outer"/></relation></osm>"#;
+        for (preset, expected) in [
+            ("nodes", "id\tlat\tlon\ttags\n1\t1.5\t2\t{\"a\":\"b\"}\n"),
+            ("ways", "id\tnodes\ttags\n5\t1,2\t{}\n"),
+            (
+                "relations",
+                "relation_id\ttype\tref\trole\n9\tway\t5\touter\n",
+            ),
+        ] {
+            let (config, instructions) =
+                parse_to_instructions(["osm", preset, "a.osm"].as_slice()).unwrap();
+            assert_eq!(config.mode, Mode::Extract);
+            assert_eq!(config.inputs, ["a.osm"]);
+            let mut output: Vec<u8> = vec![];
+            process(
+                &config,
+                &instructions,
+                "a.osm",
+                input.as_bytes(),
+                &mut output,
+                &mut Summary::default(),
+            )
+            .unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn stats1() {
+        let mut output: Vec<u8> = vec![];
+        stats(
+            [Ok(
+                r#"<notes><note id="1">hi</note><note id="2" x="y"/></notes>"#.as_bytes(),
+            )],
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "elements\t3\ndistinct_elements\t2\nattributes\t3\nmax_depth\t2\ntext_bytes\t2\n"
+        );
+    }
+
+    #[test]
+    fn histogram1() {
+        let input = r#"<osm><node><tag/><tag/></node><way><tag/></way></osm>"#;
+        let mut output: Vec<u8> = vec![];
+        histogram(false, [Ok(input.as_bytes())], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tag\t3\nnode\t1\nosm\t1\nway\t1\n"
+        );
+
+        let mut output: Vec<u8> = vec![];
+        histogram(true, [Ok(input.as_bytes())], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "/osm/node/tag\t2\n/osm\t1\n/osm/node\t1\n/osm/way\t1\n/osm/way/tag\t1\n"
+        );
+
+        let (config, _) =
+            parse_to_instructions(["stats", "histogram", "--paths", "a.xml"].as_slice()).unwrap();
+        assert_eq!(config.mode, Mode::Histogram { paths: true });
+        assert_eq!(config.inputs, vec!["a.xml"]);
+        let (config, _) = parse_to_instructions(["stats", "a.xml"].as_slice()).unwrap();
+        assert_eq!(config.mode, Mode::Stats);
+    }
+
+    #[test]
+    fn reindent1() {
+        let input = r#"<?xml version="1.0" standalone="yes"?><!DOCTYPE osm SYSTEM "osm.dtd"><osm xmlns:x="u"><!--c--><node id="1" v="&amp;"><tag/></node>
+  <name>Main <b>St</b></name><x:y></x:y></osm>"#;
+        let mut output: Vec<u8> = vec![];
+        let rules = Rewrite {
+            indent: Some("  ".to_string()),
+            ..Default::default()
+        };
+        rewrite(&rules, [Ok(input.as_bytes())], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<!DOCTYPE osm SYSTEM "osm.dtd">
+<osm xmlns:x="u">
+  <!--c-->
+  <node id="1" v="&amp;">
+    <tag/>
+  </node>
+  <name>Main <b>St</b></name>
+  <x:y/>
+</osm>
+"#
+        );
+
+        let (config, _) =
+            parse_to_instructions(["reindent", "--indent", "tab"].as_slice()).unwrap();
+        assert_eq!(
+            config.mode,
+            Mode::Rewrite(Rewrite {
+                indent: Some("\t".to_string()),
+                ..Default::default()
+            })
+        );
+        let (config, _) = parse_to_instructions(["reindent"].as_slice()).unwrap();
+        assert_eq!(
+            config.mode,
+            Mode::Rewrite(Rewrite {
+                indent: Some("  ".to_string()),
+                ..Default::default()
+            })
+        );
+        assert!(parse_to_instructions(["reindent", "--indent", "x"].as_slice()).is_err());
+    }
+
+    #[test]
+    fn rewrite1() {
+        let input = r#"<osm>
+  <node id="1" uid="2"><tag k="a" v="b"/></node>
+  <way id="3" uid="4"><nd ref="1"/></way>
+</osm>"#;
+        let (config, _) = parse_to_instructions(
+            [
+                "rewrite",
+                "--rename-tag",
+                "node=point",
+                "--rename-tag",
+                "nd=ref",
+                "--rename-attr",
+                "node.id=ID",
+                "--rename-attr",
+                "*.uid=user_id",
+            ]
+            .as_slice(),
+        )
+        .unwrap();
+        let rules = match config.mode {
+            Mode::Rewrite(rules) => rules,
+            mode => panic!("{:?}", mode),
+        };
+        assert_eq!(rules.indent, None);
+        let mut output: Vec<u8> = vec![];
+        rewrite(&rules, [Ok(input.as_bytes())], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm>
+  <point ID="1" user_id="2"><tag k="a" v="b"/></point>
+  <way id="3" user_id="4"><ref ref="1"/></way>
+</osm>
+"#
+        );
+
+        let (config, _) = parse_to_instructions(
+            [
+                "rewrite",
+                "--drop-attr",
+                "*.uid",
+                "--drop-attr",
+                "way.id",
+                "--add-attr",
+                "node.source=a=b",
+                "--add-attr",
+                "tag.v=",
+            ]
+            .as_slice(),
+        )
+        .unwrap();
+        let rules = match config.mode {
+            Mode::Rewrite(rules) => rules,
+            mode => panic!("{:?}", mode),
+        };
+        let mut output: Vec<u8> = vec![];
+        rewrite(&rules, [Ok(input.as_bytes())], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm>
+  <node id="1" source="a=b"><tag k="a" v=""/></node>
+  <way><nd ref="1"/></way>
+</osm>
+"#
+        );
+
+        assert!(parse_to_instructions(["rewrite", "--rename-tag", "node"].as_slice()).is_err());
+        assert!(parse_to_instructions(["rewrite", "--rename-attr", "id=ID"].as_slice()).is_err());
+        assert!(parse_to_instructions(["rewrite", "--drop-attr", "node.id=1"].as_slice()).is_err());
+        assert!(parse_to_instructions(["rewrite", "--add-attr", "node.id"].as_slice()).is_err());
+    }
+
+    #[test]
+    fn validate1() {
+        let mut expected = Expectations::default();
+        assert!(validate(&mut expected, "<notes><note/></notes>".as_bytes()).is_ok());
+        assert!(validate(&mut expected, "<notes><note></notes>".as_bytes()).is_err());
+    }
+
+    /// Validate the input with the instructions from these args, returning the report
+    fn validate_structure(args: &str, input: &str) -> (bool, String) {
+        let args: Vec<_> = args.split(' ').collect();
+        let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+        assert_eq!(config.mode, Mode::Validate);
+        let mut expected = Expectations::new(&instructions);
+        validate(&mut expected, input.as_bytes()).unwrap();
+        let mut output: Vec<u8> = vec![];
+        let ok = expected.report(&mut output).unwrap();
+        (ok, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn validate_structure1() {
+        let input = r#"<notes><note id="1" x="y"/><note/><comment/><comment/></notes>"#;
+        assert_eq!(
+            validate_structure("validate -s notes -s note -v id -V x - -s note", input),
+            (
+                false,
+                "missing_attribute\tnote\tid\t1\nunexpected_element\tcomment\t2\n".to_string()
+            )
+        );
+        assert_eq!(
+            validate_structure("validate -s notes -s note -v id -s author", input),
+            (
+                false,
+                "missing_attribute\tnote\tid\t1\nunexpected_attribute\tnote\tx\t1\nmissing_element\tauthor\nunexpected_element\tcomment\t2\n".to_string()
+            )
+        );
+        assert_eq!(
+            validate_structure("validate -s notes -s note -V id - -e comment", input),
+            (true, "unexpected_attribute\tnote\tx\t1\n".to_string())
+        );
+        assert_eq!(
+            validate_structure("validate", input),
+            (true, "".to_string())
+        );
+    }
+}
+
+mod expand_env {
+    use super::*;
+
+    fn lookup(var: &str) -> Option<String> {
+        match var {
+            "BATCH" => Some("42".to_string()),
+            "EMPTY" => Some("".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expand1() {
+        assert_eq!(expand_vars("foo", lookup).unwrap(), "foo");
+        assert_eq!(expand_vars("${BATCH}", lookup).unwrap(), "42");
+        assert_eq!(expand_vars("a${BATCH}b${BATCH}", lookup).unwrap(), "a42b42");
+        assert_eq!(expand_vars("$x${EMPTY}$", lookup).unwrap(), "$x$");
+        assert!(expand_vars("${NOTSET}", lookup).is_err());
+        assert!(expand_vars("${BATCH", lookup).is_err());
+    }
+
+    #[test]
+    fn parse1() {
+        std::env::set_var("ANGLOSAXON_TEST_BATCH", "42");
+        let (_config, instructions) = parse_to_instructions(
+            [
+                "-s",
+                "note",
+                "-o",
+                "${ANGLOSAXON_TEST_BATCH}",
+                "-V",
+                "id",
+                "${ANGLOSAXON_TEST_BATCH}",
+                "--expand-env",
+            ]
+            .as_slice(),
+        )
+        .unwrap();
+        assert_eq!(
+            instructions,
+            vec![Instruction::StartTag {
+                tag: "note".to_string(),
+                actions: vec![
+                    Action::RawString("42".to_string()),
+                    Action::AttributeWithDefault(
+                        "id".to_string(),
+                        "42".to_string(),
+                        Filters::default()
+                    ),
+                ]
+            }]
+        );
+
+        // not expanded by default
+        let (_config, instructions) =
+            parse_to_instructions(["-s", "note", "-o", "${ANGLOSAXON_TEST_BATCH}"].as_slice())
+                .unwrap();
+        assert_eq!(
+            instructions[0].actions(),
+            &[Action::RawString("${ANGLOSAXON_TEST_BATCH}".to_string())]
+        );
+    }
+}
+
+mod missing_attr {
+    use super::*;
+
+    const INPUT: &str = r#"<notes><note id="1" a="x"/><note id="2"/><note id="3" a="z"/></notes>"#;
+
+    #[test]
+    fn error() {
+        assert!(run("-s note -v id -v a --nl", INPUT).is_err());
+        assert!(run("--on-missing-attr error -s note -v id -v a --nl", INPUT).is_err());
+    }
+
+    assert_run!(
+        skip_record,
+        "--on-missing-attr skip-record -s note -v id -o , -v a --nl",
+        INPUT,
+        "1,x\n3,z\n"
+    );
+    assert_run!(
+        empty,
+        "--on-missing-attr empty -s note -v id -o , -v a --nl",
+        INPUT,
+        "1,x\n2,\n3,z\n"
+    );
+    assert_run!(
+        warn,
+        "--on-missing-attr warn -s note -v id -o , -v a --nl",
+        INPUT,
+        "1,x\n2,\n3,z\n"
+    );
+    assert_run!(
+        per_instruction,
+        "--on-missing-attr skip-record -s note -v id --nl -e note --on-missing-attr empty -o end --nl -s note -v a --nl",
+        INPUT,
+        "1\nx\nend\n2\nend\n3\nz\nend\n"
+    );
+
+    #[test]
+    fn parse() {
+        let (config, instructions) = parse_to_instructions(
+            [
+                "--on-missing-attr",
+                "warn",
+                "-s",
+                "note",
+                "--on-missing-attr",
+                "skip-record",
+                "-v",
+                "id",
+            ]
+            .as_slice(),
+        )
+        .unwrap();
+        assert_eq!(config.on_missing_attr, MissingAttrPolicy::Warn);
+        assert_eq!(
+            instructions[0].actions(),
+            &[
+                Action::OnMissingAttr(MissingAttrPolicy::SkipRecord),
+                Action::Attribute("id".to_string(), Filters::default())
+            ]
+        );
+    }
+}
+
+#[test]
+fn records1() {
+    let count_records = |args: &str, input: &str| {
+        let args: Vec<_> = args.split(' ').collect();
+        let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+        assert!(config.fail_if_empty);
+        let mut summary = Summary::default();
+        process(
+            &config,
+            &instructions,
+            "-",
+            input.as_bytes(),
+            std::io::sink(),
+            &mut summary,
+        )
+        .unwrap();
+        summary.records
+    };
+    let input = r#"<notes><note id="1"/><note/></notes>"#;
+    assert_eq!(
+        count_records(
+            "--fail-if-empty -S -o header -s note -V id - -e notes",
+            input
+        ),
+        3
+    );
+    assert_eq!(
+        count_records("--fail-if-empty -S -o header -s comment -v id -E", input),
+        0
+    );
+    assert_eq!(
+        count_records(
+            "--fail-if-empty --on-missing-attr skip-record -s note -v id",
+            input
+        ),
+        1
+    );
+}
+
+#[test]
+fn summary1() {
+    let args = "--recover --on-missing-attr warn -s note -v a -s comment -o x";
+    let args: Vec<_> = args.split(' ').collect();
+    let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+    let mut summary = Summary::default();
+    process(
+        &config,
+        &instructions,
+        "notes.xml",
+        r#"<notes><note/><note a="1"/><note/>&bad;<note/></notes>"#.as_bytes(),
+        std::io::sink(),
+        &mut summary,
+    )
+    .unwrap();
+    assert_eq!(summary.records, 4);
+    assert_eq!(summary.matched, vec![4, 0]);
+
+    let never_matched: Vec<_> = summary
+        .never_matched(&instructions)
+        .map(|instruction| instruction.to_string())
+        .collect();
+    assert_eq!(never_matched, ["-s comment"]);
+    let warnings: Vec<_> = summary
+        .warnings
+        .iter()
+        .map(|(message, (count, location))| warning_message(message, *count, location))
+        .collect();
+    assert_eq!(
+        warnings,
+        [
+            "No attribute a found for element note (3 times, first at notes.xml:1:8)",
+            "Skipped malformed XML in element /notes: Unexpected entity: bad (at notes.xml:1:39, bytes 39 to 39)",
+        ]
+    );
+}
+
+#[test]
+fn strict_unmatched1() {
+    let args: Vec<_> = "--strict-unmatched -s note -o x -e comment -o y -E -o end"
+        .split(' ')
+        .collect();
+    let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+    assert!(config.strict_unmatched);
+    let mut summary = Summary::default();
+    process(
+        &config,
+        &instructions,
+        "-",
+        r#"<notes><note/></notes>"#.as_bytes(),
+        std::io::sink(),
+        &mut summary,
+    )
+    .unwrap();
+    // -E always happens, so it's never unmatched
+    let unmatched: Vec<_> = summary
+        .never_matched(&instructions)
+        .map(|i| i.to_string())
+        .collect();
+    assert_eq!(unmatched, vec!["-e comment"]);
+}
+
+#[test]
+fn interrupted1() {
+    let args: Vec<_> = "-s note -o x -E -o end".split(' ').collect();
+    let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+    config.interrupted.store(true, Ordering::Relaxed);
+    let mut summary = Summary::default();
+    let mut output: Vec<u8> = vec![];
+    process(
+        &config,
+        &instructions,
+        "-",
+        "<notes><note/></notes>".as_bytes(),
+        &mut output,
+        &mut summary,
+    )
+    .unwrap();
+    assert_eq!(output, b"");
+    assert_eq!(summary.interrupted, Some("-:1:1, byte 0".to_string()));
+}
+
+/// Like `| head -1`, a writer which is closed after the first line
+struct ClosedAfterFirstLine(bool);
+
+impl Write for ClosedAfterFirstLine {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.0 {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        self.0 = buf.contains(&b'\n');
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn broken_pipe1() {
+    let args: Vec<_> = "-s note -v id --nl".split(' ').collect();
+    let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+    let mut summary = Summary::default();
+    let err = process(
+        &config,
+        &instructions,
+        "-",
+        r#"<notes><note id="1"/><note id="2"/><note id="3"/></notes>"#.as_bytes(),
+        ClosedAfterFirstLine(false),
+        &mut summary,
+    )
+    .unwrap_err();
+    assert!(is_broken_pipe(&err));
+    assert!(!is_broken_pipe(&anyhow!("other error")));
+    assert_eq!(summary.records, 1);
+    assert_eq!(summary.bytes_read, 35);
+}
+
+#[test]
+fn describe_event1() {
+    let events: Vec<String> = EventReader::new(
+        r#"<a xmlns:s="u"><s:b id="1">hello</s:b><c>aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa</c></a>"#
+            .as_bytes(),
+    )
+    .into_iter()
+    .map(|e| describe_event(&e.unwrap()))
+    .collect();
+    assert_eq!(
+        events,
+        vec![
+            "-S",
+            "-s a",
+            r#"-s b (s:b) id="1""#,
+            r#"text "hello""#,
+            "-e b",
+            "-s c",
+            r#"text "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"…"#,
+            "-e c",
+            "-e a",
+            "-E"
+        ]
+    );
+}
+
+#[test]
+fn debug_matches1() {
+    let parse = |args: &str| {
+        let args: Vec<_> = args.split(' ').collect();
+        parse_to_instructions(args.as_slice())
+            .unwrap()
+            .0
+            .debug_matches
+    };
+    assert_eq!(parse("-s note -o x"), None);
+    assert_eq!(parse("--debug-matches -s note -o x"), Some(100));
+    assert_eq!(parse("--debug-matches=2 -s note -o x"), Some(2));
+
+    // Doesn't change the output
+    assert_eq!(
+        run(
+            "--debug-matches=3 --on-missing-attr skip-record -s note -v id --nl",
+            r#"<notes><note id="1"/><note/><note id="3"/></notes>"#
+        )
+        .unwrap(),
+        "1\n3\n"
+    );
+}
+
+#[test]
+fn root_element_path() {
+    let err = run("-s notes -v id", "<notes/>").unwrap_err();
+    assert!(format!("{:#}", err).starts_with("At -:1:1 in element /notes: "));
+}
+
+mod unique {
+    use super::*;
+
+    fn unique(mode: &str, writes: &[&str]) -> String {
+        let mut output: Vec<u8> = vec![];
+        let mut unique = UniqueLines::new(&mut output, mode.parse().unwrap());
+        for w in writes {
+            unique.write_all(w.as_bytes()).unwrap();
+        }
+        unique.flush().unwrap();
+        drop(unique);
+        String::from_utf8(output).unwrap()
+    }
+
+    const LINES: &[&str] = &["a\n", "b\n", "a\n", "a\n", "c\n", "b\n"];
+
+    #[test]
+    fn all() {
+        assert_eq!(unique("all", LINES), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn adjacent() {
+        assert_eq!(unique("adjacent", LINES), "a\nb\na\nc\nb\n");
+    }
+
+    #[test]
+    fn recent() {
+        assert_eq!(unique("2", LINES), "a\nb\nc\n");
+        assert_eq!(unique("2", &["a\n", "b\n", "c\n", "a\n"]), "a\nb\nc\na\n");
+    }
+
+    #[test]
+    fn partial_writes() {
+        // Lines made from several records, & a last line without a newline
+        assert_eq!(
+            unique("all", &["1", ",x", "\n1,", "x\n2\n1,y"]),
+            "1,x\n2\n1,y"
+        );
+        assert_eq!(unique("all", &["a\nb\na\n", "b"]), "a\nb\n");
+    }
+
+    #[test]
+    fn parse() {
+        assert!("0".parse::<UniqueMode>().is_err());
+        assert!("some".parse::<UniqueMode>().is_err());
+        let (config, _) =
+            parse_to_instructions(["--unique", "-s", "a", "-o", "x"].as_slice()).unwrap();
+        assert_eq!(config.unique, Some(UniqueMode::All));
+        let (config, _) =
+            parse_to_instructions(["--unique=10", "-s", "a", "-o", "x"].as_slice()).unwrap();
+        assert_eq!(config.unique, Some(UniqueMode::Recent(10)));
+    }
+}
+
+mod sort {
+    use super::*;
+
+    fn sort(key: SortKey, max_buffered_bytes: usize, writes: &[&str]) -> String {
+        let mut output: Vec<u8> = vec![];
+        let mut sorted = SortedLines::new(&mut output, key, max_buffered_bytes);
+        for w in writes {
+            sorted.write_all(w.as_bytes()).unwrap();
+        }
+        sorted.flush().unwrap();
+        assert!(sorted.runs.is_empty());
+        drop(sorted);
+        String::from_utf8(output).unwrap()
+    }
+
+    const LINE: SortKey = SortKey {
+        field: None,
+        delimiter: b'\t',
+    };
+    const FIELD2: SortKey = SortKey {
+        field: Some(2),
+        delimiter: b',',
+    };
+    const LINES: &[&str] = &["c,1\n", "a,2\n", "d,1\n", "b", ",3\n", "e"];
+
+    #[test]
+    fn in_memory() {
+        assert_eq!(
+            sort(LINE, SORT_BUFFER_BYTES, LINES),
+            "a,2\nb,3\nc,1\nd,1\ne\n"
+        );
+        assert_eq!(
+            sort(FIELD2, SORT_BUFFER_BYTES, LINES),
+            "e\nc,1\nd,1\na,2\nb,3\n"
+        );
+    }
+
+    #[test]
+    fn temporary_files() {
+        // Every line is written to a temporary file, but the result is the same & still stable
+        assert_eq!(sort(LINE, 1, LINES), "a,2\nb,3\nc,1\nd,1\ne\n");
+        assert_eq!(sort(FIELD2, 1, LINES), "e\nc,1\nd,1\na,2\nb,3\n");
+        assert_eq!(sort(FIELD2, 8, LINES), "e\nc,1\nd,1\na,2\nb,3\n");
+    }
+
+    #[test]
+    fn parse() {
+        let sort = |args: &[&str]| parse_to_instructions(args).map(|(c, _)| c.sort);
+        assert_eq!(sort(&["--sort", "-s", "a", "-o", "x"]).unwrap(), Some(LINE));
+        assert_eq!(
+            sort(&["--sort=2", "--sort-delimiter", ",", "-s", "a", "-o", "x"]).unwrap(),
+            Some(FIELD2)
+        );
+        assert!(sort(&["--sort=0", "-s", "a", "-o", "x"]).is_err());
+        assert!(sort(&["--sort", "--sort-delimiter", "ab", "-s", "a", "-o", "x"]).is_err());
+    }
+}
+
+mod group_by {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1"><tag k="a" v="1"/><tag k="b" v="x	y"/></node><way id="2"><tag k="a" v="3"/></way></osm>"#;
+
+    assert_run!(
+        simple,
+        "-S -o start --nl -s tag --group-by k -E -o end --nl",
+        INPUT,
+        "start\na\t2\nb\t1\nend\n"
+    );
+    assert_run!(
+        with_other_actions,
+        "-s tag -v k -o = --group-by v!tsv",
+        INPUT,
+        "a=1\t1\na=3\t1\nb=x\\ty\t1\n"
+    );
+    assert_run!(parent, "-s tag --group-by ../id", INPUT, "1\t2\n2\t1\n");
+    assert_run!(
+        alongside_other_instructions,
+        "-s node -v id --nl -s tag --group-by k -s way -v id --nl",
+        INPUT,
+        "1\n2\na\t2\nb\t1\n"
+    );
+    assert_run!(
+        skipped_records,
+        "--on-missing-attr skip-record -s tag --group-by k -v x",
+        INPUT,
+        ""
+    );
+
+    #[test]
+    fn parse() {
+        let (_, instructions) =
+            parse_to_instructions(["-s", "tag", "--group-by", "k", "--group-by", "v"].as_slice())
+                .unwrap();
+        assert_eq!(
+            instructions,
+            vec![Instruction::StartTag {
+                tag: "tag".to_string(),
+                actions: vec![
+                    Action::Attribute("k".to_string(), Filters::default()),
+                    Action::GroupBy,
+                    Action::Attribute("v".to_string(), Filters::default()),
+                ]
+            }]
+        );
+        assert!(parse_to_instructions(["--group-by", "k"].as_slice()).is_err());
+    }
+}
+
+mod aggregate {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="1" len="2.5" t="a"/><way id="7" len="4" t="b"/><way id="3" len="1.5" t="a"/><way id="4" t="a"/></osm>"#;
+
+    assert_run!(
+        global,
+        "--on-missing-attr empty -s way --aggregate max:id --aggregate sum:len --aggregate mean:len --aggregate min:len",
+        INPUT,
+        "7\t8\t2.6666666666666665\t1.5\n"
+    );
+    assert_run!(
+        grouped,
+        "--on-missing-attr empty -s way --group-by t --aggregate sum:len --aggregate max:len",
+        INPUT,
+        "a\t3\t4\t2.5\nb\t1\t4\t4\n"
+    );
+    assert_run!(
+        no_numbers,
+        "--on-missing-attr empty -s way --group-by t --aggregate sum:x --aggregate mean:x",
+        INPUT,
+        "a\t3\t0\t\nb\t1\t0\t\n"
+    );
+    assert_run!(
+        skip_record,
+        "--on-missing-attr skip-record -s way --aggregate sum:len",
+        INPUT,
+        "8\n"
+    );
+    assert_run!(
+        parent,
+        "-s nd --aggregate sum:../len",
+        r#"<osm><way len="2"><nd/><nd/></way></osm>"#,
+        "4\n"
+    );
+
+    #[test]
+    fn errors() {
+        assert!(run("-s way --aggregate sum:t", INPUT).is_err());
+        assert!(run("-s way --aggregate median:len", INPUT).is_err());
+        assert!(run("-s way --aggregate len", INPUT).is_err());
+    }
+
+    #[test]
+    fn aggregator() {
+        let mut max = Aggregator::new(Reducer::Max);
+        assert_eq!(max.result(), None);
+        max.add(-1.);
+        max.add(-3.);
+        assert_eq!(max.result(), Some(-1.));
+    }
+}
+
+mod pi {
+    use super::*;
+
+    const INPUT: &str = r#"<?xml version="1.0"?><?meta source=osm?><osm><?meta generator=x?><node id="1"/><?other y?></osm>"#;
+
+    assert_run!(
+        simple,
+        "-P meta --pi-data --nl",
+        INPUT,
+        "source=osm\ngenerator=x\n"
+    );
+    assert_run!(
+        alongside_elements,
+        "-P other -o pi: --pi-data --nl -s node -v id --nl",
+        INPUT,
+        "1\npi:y\n"
+    );
+    assert_run!(
+        empty_data,
+        "-P meta -o [ --pi-data -o ]",
+        "<?meta?><a/>",
+        "[]"
+    );
+
+    #[test]
+    fn pi_data_outside_pi() {
+        assert!(run("-s node --pi-data", INPUT).is_err());
+    }
+}
+
+mod comment {
+    use super::*;
+
+    const INPUT: &str = r#"<!-- generated by x --><osm><!--node 1--><node id="1"/></osm>"#;
+
+    assert_run!(
+        simple,
+        "--comment -o [ --comment-text -o ] --nl",
+        INPUT,
+        "[ generated by x ]\n[node 1]\n"
+    );
+    assert_run!(
+        alongside_elements,
+        "-s node -v id --nl --comment -o # --comment-text --nl",
+        r#"<a><!--x--><node id="1"/><!--y--></a>"#,
+        "#x\n1\n#y\n"
+    );
+
+    #[test]
+    fn comment_text_outside_comment() {
+        assert!(run("-s node --comment-text", INPUT).is_err());
+    }
+}
+
+mod doctype {
+    use super::*;
+
+    const INPUT: &str = r#"<?xml version="1.0"?>
+<!-- x -->
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN" "http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd">
+<html><p id="1"/></html>"#;
+
+    assert_run!(
+        simple,
+        "--doctype --doctype-name --tab --public-id --tab --system-id --nl -s p -v id --nl",
+        INPUT,
+        "html\t-//W3C//DTD XHTML 1.0 Strict//EN\thttp://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\n1\n"
+    );
+    assert_run!(
+        none,
+        "--doctype -o doctype -s p -v id",
+        "<html><p id=\"1\"/></html>",
+        "1"
+    );
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "<!DOCTYPE osm>".parse::<Doctype>().unwrap(),
+            Doctype {
+                name: "osm".to_string(),
+                public_id: None,
+                system_id: None
+            }
+        );
+        assert_eq!(
+            "<!DOCTYPE note SYSTEM 'note.dtd' [<!ELEMENT note (#PCDATA)>]>"
+                .parse::<Doctype>()
+                .unwrap(),
+            Doctype {
+                name: "note".to_string(),
+                public_id: None,
+                system_id: Some("note.dtd".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn read_prolog1() {
+        let input = r#"<?xml version="1.0"?><!DOCTYPE a [<!ENTITY x "]>">]><a/>"#;
+        let (prolog, doctype) = read_prolog(&mut input.as_bytes()).unwrap();
+        assert_eq!(
+            String::from_utf8(prolog).unwrap(),
+            r#"<?xml version="1.0"?><!DOCTYPE a [<!ENTITY x "]>">]>"#
+        );
+        assert_eq!(doctype.unwrap().name, "a");
+
+        let (prolog, doctype) = read_prolog(&mut "<a/>".as_bytes()).unwrap();
+        assert_eq!(prolog, b"<a/");
+        assert!(doctype.is_none());
+    }
+
+    #[test]
+    fn forbid_dtd() {
+        assert!(run("--forbid-dtd -s p -v id", INPUT).is_err());
+        assert_eq!(
+            run("--forbid-dtd -s p -v id", "<html><p id=\"1\"/></html>").unwrap(),
+            "1"
+        );
+    }
+}
+
+mod chars {
+    use super::*;
+
+    const INPUT: &str = "<a id=\"1\">\n  <b>hello\tworld</b>\n  <c><![CDATA[x<y]]></c>\n</a>";
+
+    assert_run!(
+        nonblank,
+        "--chars=nonblank -v ../../id --tab --text=tsv --nl",
+        INPUT,
+        "1\thello\\tworld\n1\tx<y\n"
+    );
+    assert_run!(
+        all,
+        "--chars -o [ --text -o ]",
+        "<a> <b>x</b></a>",
+        "[ ][x]"
+    );
+
+    #[test]
+    fn text_outside_chars() {
+        assert!(run("-s a --text", INPUT).is_err());
+    }
+}
+
+mod root_attributes {
+    use super::*;
+
+    const INPUT: &str =
+        r#"<?xml version="1.0"?><!-- c --><osm version="0.6" generator="x"><node id="1"/></osm>"#;
+
+    assert_run!(
+        start_document,
+        "-S -o # -v generator -o , -V copyright none --nl -s node -v id --nl",
+        INPUT,
+        "#x,none\n1\n"
+    );
+    assert_run!(
+        end_document,
+        "-s node -v id --nl -E -o end, -v version --nl",
+        INPUT,
+        "1\nend,0.6\n"
+    );
+    assert_run!(
+        start_document_order,
+        "-S -o a --nl --comment -o c --nl -S -v version --nl",
+        INPUT,
+        "a\nc\n0.6\n"
+    );
+}
+
+mod has {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="1" oneway="yes"><nd ref="1"/></way><way id="2"/></osm>"#;
+
+    assert_run!(
+        simple,
+        "-s way -v id --tab --has oneway --nl",
+        INPUT,
+        "1\ttrue\n2\tfalse\n"
+    );
+    assert_run!(
+        tokens,
+        "-s way --has-tokens 1 0 --has oneway --tab --has-tokens Y N --has id --nl",
+        INPUT,
+        "1\tY\n0\tY\n"
+    );
+    assert_run!(
+        parent,
+        "--has-tokens t f -s nd -v ref --tab --has ../oneway --nl",
+        INPUT,
+        "1\tt\n"
+    );
+}
+
+mod opt_value {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1" name="a	b"/><node id="2"/></osm>"#;
+
+    assert_run!(
+        prefix_suffix,
+        "-s node -v id --opt-value name!tsv ,name=\" \" --nl",
+        INPUT,
+        "1,name=\"a\\tb\"\n2\n"
+    );
+    assert_run!(
+        just_value,
+        "-s node -o [ --opt-value name -o ] --nl",
+        INPUT,
+        "[a\tb]\n[]\n"
+    );
+    assert_run!(
+        parent,
+        "-s nd --opt-value ../name < > --nl",
+        r#"<w name="x"><nd/></w><w><nd/></w>"#,
+        "<x>\n\n"
+    );
+    assert_run!(
+        several,
+        "-s node --opt-value id --opt-value name , --opt-value id ( ) --nl",
+        INPUT,
+        "1,a\tb(1)\n2(2)\n"
+    );
+}
+
+mod collect {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="1"><nd ref="10"/><tag k="a"/><nd ref="11"/><nd/></way><way id="2"/><way id="3"><nd ref="1	2"/></way></osm>"#;
+
+    assert_run!(
+        simple,
+        "-e way -v id --tab --collect nd/ref ; --nl",
+        INPUT,
+        "1\t10;11\n2\t\n3\t1\t2\n"
+    );
+    assert_run!(
+        filters_and_several,
+        "-e way --collect nd/ref!tsv , --tab --collect tag/k , --nl",
+        INPUT,
+        "10,11\ta\n\t\n1\\t2\t\n"
+    );
+    assert_run!(
+        nested,
+        "-e r -v id -o : --collect r/id , --nl",
+        r#"<r id="a"><r id="b"><r id="c"/></r><r id="d"/></r>"#,
+        "c:\nb:c\nd:\na:b,d\n"
+    );
+
+    assert_run!(
+        json_values,
+        "-e way -v id --tab --collect-json nd/ref --nl",
+        INPUT,
+        "1\t[\"10\",\"11\"]\n2\t[]\n3\t[\"1\\t2\"]\n"
+    );
+    assert_run!(
+        json_objects,
+        "-e way --collect-json nd --nl",
+        r#"<way xmlns:x="u"><nd ref="1" x:role="a&quot;b"/><nd/></way>"#,
+        "[{\"ref\":\"1\",\"x:role\":\"a\\\"b\"},{}]\n"
+    );
+    assert_run!(
+        end_attributes,
+        "-e nd -V ref - --tab -v ../id --nl",
+        INPUT,
+        "10\t1\n11\t1\n-\t1\n1\t2\t3\n"
+    );
+
+    #[test]
+    fn only_end() {
+        assert!(run("-s way --collect nd/ref ;", INPUT).is_err());
+    }
+}
+
+mod counter {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="1"><nd ref="10"/><nd ref="11"/></way><way id="2"><nd ref="12"/></way></osm>"#;
+
+    assert_run!(
+        per_document,
+        "-s nd --counter -o : -v ref --nl",
+        INPUT,
+        "1:10\n2:11\n3:12\n"
+    );
+    assert_run!(
+        per_parent,
+        "-s nd -v ../id --tab --counter-per way --tab -v ref --nl",
+        INPUT,
+        "1\t1\t10\n1\t2\t11\n2\t1\t12\n"
+    );
+    assert_run!(
+        separate,
+        "-s way --counter --nl -s nd --counter --nl",
+        INPUT,
+        "1\n1\n2\n2\n3\n"
+    );
+}
+
+mod sibling_index {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1"/><way id="2"><nd ref="1"/><tag k="a"/><nd ref="3"/></way><way id="4"/></osm>"#;
+
+    assert_run!(
+        all,
+        "-s way -v id -o : --sibling-index --nl",
+        INPUT,
+        "2:2\n4:3\n"
+    );
+    assert_run!(
+        same_name,
+        "-s nd -v ref -o : --sibling-index=same-name -o , --sibling-index --nl -e way -v id -o : --sibling-index=same-name --nl",
+        INPUT,
+        "1:1,1\n3:2,3\n2:1\n4:2\n"
+    );
+    assert_run!(root, "-s osm --sibling-index", INPUT, "1");
+}
+
+mod require {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1" name="a"/><node id="2"/><way id="3"><nd ref="1"/></way><way id="4" name="b"><nd ref="2"/></way></osm>"#;
+
+    assert_run!(
+        simple,
+        "-s node -v id --tab --require name -v name --nl",
+        INPUT,
+        "1\ta\n"
+    );
+    assert_run!(parent, "-s nd --require ../name -v ref --nl", INPUT, "2\n");
+
+    #[test]
+    fn warn() {
+        let args = "--on-missing-attr warn -s node --require name -v id --nl";
+        let args: Vec<_> = args.split(' ').collect();
+        let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+        let mut summary = Summary::default();
+        let mut output: Vec<u8> = vec![];
+        process(
+            &config,
+            &instructions,
+            "-",
+            INPUT.as_bytes(),
+            &mut output,
+            &mut summary,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n");
+        assert_eq!(summary.records, 1);
+        assert_eq!(
+            summary.warnings.into_iter().collect::<Vec<_>>(),
+            vec![(
+                "Skipped records for element node without required attribute name".to_string(),
+                (1, "-:1:29".to_string())
+            )]
+        );
+    }
+}
+
+mod leaf_only {
+    use super::*;
+
+    const INPUT: &str =
+        r#"<a><item id="1"><item id="2"/><item id="3"></item></item><item id="4">text</item></a>"#;
+
+    assert_run!(leaf, "-s item --leaf-only -v id --nl", INPUT, "2\n3\n4\n");
+    assert_run!(
+        empty,
+        "-s item --empty-only -v id --nl -e item --empty-only -o end -v id --nl",
+        INPUT,
+        "2\nend2\n"
+    );
+    assert_run!(
+        end,
+        "-e item --leaf-only -v id -o , --sibling-index --nl",
+        INPUT,
+        "2,1\n3,2\n4,2\n"
+    );
+    assert_run!(
+        mixed,
+        "-s item -v id --nl -s item --leaf-only -o leaf -v id --nl",
+        INPUT,
+        "1\n2\nleaf2\n3\nleaf3\n4\nleaf4\n"
+    );
+
+    #[test]
+    fn only_elements() {
+        assert!(run("-S --leaf-only", INPUT).is_err());
+    }
+}
+
+mod text_predicate {
+    use super::*;
+
+    const INPUT: &str = r#"<list><item id="1">apple pie</item><item id="2">banana</item><item id="3">green <b>apple</b></item></list>"#;
+
+    assert_run!(start, "-s item{text~apple} -v id --nl", INPUT, "1\n3\n");
+    assert_run!(
+        end,
+        "-e item{text~^ban} -v id --nl -s b{text~x} -o never",
+        INPUT,
+        "2\n"
+    );
+    assert_run!(
+        anchored,
+        "-s item{text~^apple$} -v id --nl -s item -o all --nl",
+        INPUT,
+        "all\nall\nall\n"
+    );
+
+    #[test]
+    fn parse() {
+        assert!(run("-s item{text=x}", INPUT).is_err());
+        assert!(run("-s item{text~(}", INPUT).is_err());
+        let (_, instructions) = parse_to_instructions(["-s", "item{text~a.b}"].as_slice()).unwrap();
+        assert_eq!(instructions[0].to_string(), "-s item{text~a.b}");
+    }
+}
+
+mod ancestor {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="1" visible="true"><nd ref="1"/></way><way id="2" visible="false"><nd ref="2"/></way><relation id="3"><member><nd ref="3"/></member></relation></osm>"#;
+
+    assert_run!(
+        attribute_value,
+        "-s nd --ancestor way[visible=true] -v ref --nl",
+        INPUT,
+        "1\n"
+    );
+    assert_run!(
+        quoted,
+        "-s nd --ancestor way[visible='false'] -v ref --nl",
+        INPUT,
+        "2\n"
+    );
+    assert_run!(
+        has_attribute,
+        "-s nd --ancestor way[visible] -v ref --nl",
+        INPUT,
+        "1\n2\n"
+    );
+    assert_run!(
+        several_levels_up,
+        "-s nd --ancestor relation[id=3] -v ref --nl",
+        INPUT,
+        "3\n"
+    );
+    assert_run!(
+        any_tag,
+        "-s nd --ancestor *[id=2] -v ref --nl",
+        INPUT,
+        "2\n"
+    );
+    assert_run!(not_itself, "-e way --ancestor way -v id --nl", INPUT, "");
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "way[a=1][b]".parse::<ElementPredicate>().unwrap(),
+            ElementPredicate {
+                tag: "way".to_string(),
+                conditions: vec![
+                    ("a".to_string(), Some("1".to_string())),
+                    ("b".to_string(), None)
+                ],
+            }
+        );
+        assert!("[a=1]".parse::<ElementPredicate>().is_err());
+        assert!("way[a=1".parse::<ElementPredicate>().is_err());
+        assert!("way[=1]".parse::<ElementPredicate>().is_err());
+    }
+}
+
+mod ns_uri {
+    use super::*;
+
+    const INPUT: &str = r#"<root xmlns:a="http://example.com/a" xmlns:b="http://example.com/b"><a:item id="1"/><b:item id="2"/><item xmlns="http://example.com/a" id="3"/><item id="4"/></root>"#;
+
+    assert_run!(
+        any_prefix,
+        "-s item --ns-uri http://example.com/a -v id --nl",
+        INPUT,
+        "1\n3\n"
+    );
+    assert_run!(
+        end_tag,
+        "-e item --ns-uri http://example.com/b -v id --nl",
+        INPUT,
+        "2\n"
+    );
+    assert_run!(without, "-s item -v id --nl", INPUT, "1\n2\n3\n4\n");
+
+    #[test]
+    fn errors() {
+        assert!(
+            parse_to_instructions(["-S", "--ns-uri", "http://example.com/a"].as_slice()).is_err()
+        );
+        assert!(parse_to_instructions(
+            [
+                "-s",
+                "item",
+                "--ns-uri",
+                "http://example.com/a",
+                "--ns-uri",
+                "http://example.com/b"
+            ]
+            .as_slice()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn display() {
+        let (_, instructions) =
+            parse_to_instructions(["-s", "item", "--ns-uri", "http://example.com/a"].as_slice())
+                .unwrap();
+        assert_eq!(instructions[0].to_string(), "-s {http://example.com/a}item");
+    }
+}
+
+mod xpath {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="1" visible="true"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way><way id="2" visible="false"><nd ref="3"/><tag k="name" v="B"/></way><relation id="3"><member><way id="4"><nd ref="4"/></way></member></relation></osm>"#;
+
+    assert_run!(child, "-x /osm/way -v id --nl", INPUT, "1\n2\n");
+    assert_run!(descendant, "-x //way -v id --nl", INPUT, "1\n2\n4\n");
+    assert_run!(relative, "-x way/nd -v ref --nl", INPUT, "1\n2\n3\n4\n");
+    assert_run!(
+        descendant_in_middle,
+        "-x /osm/relation//nd -v ref --nl",
+        INPUT,
+        "4\n"
+    );
+    assert_run!(
+        attribute_value,
+        r#"-x //way/tag[@k="highway"] -v v --nl"#,
+        INPUT,
+        "primary\n"
+    );
+    assert_run!(
+        ancestor_attribute,
+        "-x //way[@visible='false']/nd -v ref --nl",
+        INPUT,
+        "3\n"
+    );
+    assert_run!(
+        has_attribute,
+        "-x //way[@visible]/nd[2] -v ref --nl",
+        INPUT,
+        "2\n"
+    );
+    assert_run!(position, "-x /osm/way[2]/nd[1] -v ref --nl", INPUT, "3\n");
+    assert_run!(
+        any_tag_position,
+        "-x /osm/way/*[3] -v k --nl",
+        INPUT,
+        "highway\n"
+    );
+    assert_run!(
+        with_s,
+        "-x //member/way -v id --nl -s relation -v id --nl",
+        INPUT,
+        "3\n4\n"
+    );
+
+    #[test]
+    fn parse() {
+        let path: XPath = r#"//way[@k="a/b]"][2]/nd"#.parse().unwrap();
+        assert_eq!(path.steps.len(), 2);
+        assert!(path.steps[0].descendant);
+        assert_eq!(
+            path.steps[0].element.conditions,
+            vec![("k".to_string(), Some("a/b]".to_string()))]
+        );
+        assert_eq!(path.steps[0].position, Some(2));
+        assert!(!path.steps[1].descendant);
+        assert_eq!(path.tag(), "nd");
+
+        for invalid in [
+            "/",
+            "//way/",
+            "//way[1",
+            "//way[last()]",
+            "//way/@id",
+            "//way[0]",
+            "../way",
+        ] {
+            assert!(invalid.parse::<XPath>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn display() {
+        let (_, instructions) = parse_to_instructions(["-x", "//way/nd"].as_slice()).unwrap();
+        assert_eq!(instructions[0].to_string(), "-x //way/nd");
+    }
+}
+
+mod copy {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1"/><way id="2"><nd ref="1"/><!-- c --><tag k="name" v="A &amp; &quot;B&quot;">x &lt; y</tag></way><way id="3"/></osm>"#;
+
+    assert_run!(
+        end,
+        "-e way --copy --nl",
+        INPUT,
+        "<way id=\"2\"><nd ref=\"1\"/><!-- c --><tag k=\"name\" v=\"A &amp; &quot;B&quot;\">x &lt; y</tag></way>\n<way id=\"3\"/>\n"
+    );
+    assert_run!(
+        start,
+        "-s node --copy --nl -s way -v id --nl",
+        INPUT,
+        "<node id=\"1\"/>\n2\n3\n"
+    );
+    assert_run!(
+        with_values,
+        "-s tag -v k --tab --copy --nl",
+        INPUT,
+        "name\t<tag k=\"name\" v=\"A &amp; &quot;B&quot;\">x &lt; y</tag>\n"
+    );
+    assert_run!(
+        nested,
+        "-e a --copy --nl",
+        "<r><a><a>1</a></a></r>",
+        "<a>1</a>\n<a><a>1</a></a>\n"
+    );
+    assert_run!(
+        namespaces,
+        "-e b --copy --nl",
+        r#"<r xmlns="http://example.com/" xmlns:x="http://example.com/x"><b x:y="1"><x:c/></b></r>"#,
+        "<b xmlns=\"http://example.com/\" xmlns:x=\"http://example.com/x\" x:y=\"1\"><x:c/></b>\n"
+    );
+
+    assert_run!(
+        exclude,
+        "--copy-exclude tag --copy-exclude x -e way --copy --nl",
+        INPUT,
+        "<way id=\"2\"><nd ref=\"1\"/><!-- c --></way>\n<way id=\"3\"/>\n"
+    );
+    assert_run!(
+        exclude_json,
+        "--copy-exclude nd -e way --copy-json --nl",
+        "<way><nd><x>1</x></nd>a<tag/></way>",
+        r#"{"name":"way","attributes":{},"children":["a",{"name":"tag","attributes":{},"children":[]}]}"#.to_string() + "\n"
+    );
+    assert_run!(
+        exclude_not_copied,
+        "--copy-exclude tag -s tag --copy --nl",
+        INPUT,
+        "<tag k=\"name\" v=\"A &amp; &quot;B&quot;\">x &lt; y</tag>\n"
+    );
+
+    assert_run!(
+        canonical,
+        "--canonical -e way --copy --nl",
+        "<osm><way v='1' id='2'>\n  <nd ref='1'/>\n  <name>Main\n   St <![CDATA[ x ]]></name>\n</way></osm>",
+        "<way id=\"2\" v=\"1\"><nd ref=\"1\"/><name>Main St x </name></way>\n"
+    );
+    assert_run!(
+        canonical_json,
+        "--canonical -e way --copy-json --nl",
+        "<way v='1' id='2'>a\n b</way>",
+        r#"{"name":"way","attributes":{"id":"2","v":"1"},"children":["a b"]}"#.to_string() + "\n"
+    );
+
+    #[test]
+    fn errors() {
+        assert!(parse_to_instructions(["-S", "--copy"].as_slice()).is_err());
+        let (config, _) =
+            parse_to_instructions(["--copy-root", "osm", "-e", "way", "--copy"].as_slice())
+                .unwrap();
+        assert_eq!(config.copy_root.as_deref(), Some("osm"));
+    }
+}
+
+mod copy_json {
+    use super::*;
+
+    assert_run!(
+        simple,
+        "-e way --copy-json --nl",
+        r#"<osm><way id="1"><nd ref="2"/><tag k="name" v="A &quot;B&quot;"/></way></osm>"#,
+        r#"{"name":"way","attributes":{"id":"1"},"children":[{"name":"nd","attributes":{"ref":"2"},"children":[]},{"name":"tag","attributes":{"k":"name","v":"A \"B\""},"children":[]}]}"#.to_string() + "\n"
+    );
+    assert_run!(
+        text,
+        "-s p --copy-json --nl",
+        "<doc>\n  <p>a <b>bold</b> <![CDATA[<c>]]> d</p>\n</doc>",
+        r#"{"name":"p","attributes":{},"children":["a ",{"name":"b","attributes":{},"children":["bold"]}," <c> d"]}"#.to_string() + "\n"
+    );
+    assert_run!(
+        indented,
+        "-e a --copy-json --nl",
+        "<a>\n  <b/>\n</a>",
+        r#"{"name":"a","attributes":{},"children":[{"name":"b","attributes":{},"children":[]}]}"#
+            .to_string()
+            + "\n"
+    );
+    assert_run!(
+        with_copy,
+        "-e b --copy --tab --copy-json --nl",
+        "<a><b>x</b></a>",
+        r#"<b>x</b>	{"name":"b","attributes":{},"children":["x"]}"#.to_string() + "\n"
+    );
+}
+
+mod merge_root {
+    use super::*;
+
+    /// Process each input in turn, with these CLI args, as if they were files
+    fn run_merged(args: &str, inputs: &[&str]) -> Result<String> {
+        let filenames: Vec<_> = (1..=inputs.len()).map(|i| format!("{}.osm", i)).collect();
+        let mut args: Vec<_> = args.split(' ').collect();
+        args.extend(filenames.iter().map(|f| f.as_str()));
+        let (config, instructions) = parse_to_instructions(args.as_slice())?;
+        assert_eq!(config.inputs, filenames);
+        let mut output: Vec<u8> = vec![];
+        let mut summary = Summary::default();
+        for (filename, input) in filenames.iter().zip(inputs) {
+            process(
+                &config,
+                &instructions,
+                filename,
+                input.as_bytes(),
+                &mut output,
+                &mut summary,
+            )?;
+        }
+        Ok(String::from_utf8(output)?)
+    }
+
+    const INPUTS: [&str; 2] = [
+        r#"<osm><node id="1"/><node id="2"/></osm>"#,
+        r#"<?xml version="1.0"?><osm><node id="3"/></osm>"#,
+    ];
+
+    #[test]
+    fn header_footer() {
+        let args = "-S -o start -s node -v id -E -o end";
+        assert_eq!(run_merged(args, &INPUTS).unwrap(), "start12endstart3end");
+        assert_eq!(
+            run_merged(&format!("--merge-root shards {}", args), &INPUTS).unwrap(),
+            "start123end"
+        );
+    }
+
+    #[test]
+    fn counter() {
+        let args = "-s node --counter -o ,";
+        assert_eq!(run_merged(args, &INPUTS).unwrap(), "1,2,1,");
+        assert_eq!(
+            run_merged(&format!("--merge-root shards {}", args), &INPUTS).unwrap(),
+            "1,2,3,"
+        );
+    }
+
+    #[test]
+    fn group_by() {
+        let inputs = [
+            r#"<osm><node><tag k="a"/><tag k="b"/></node></osm>"#,
+            r#"<osm><tag k="a"/></osm>"#,
+        ];
+        assert_eq!(
+            run_merged("--merge-root shards -s tag --group-by k", &inputs).unwrap(),
+            "a\t2\nb\t1\n"
+        );
+    }
+
+    #[test]
+    fn roots_are_children() {
+        assert_eq!(
+            run_merged(
+                "--merge-root shards -s osm --sibling-index -o , -x /shards/osm[2]/node -v id",
+                &INPUTS
+            )
+            .unwrap(),
+            "1,2,3"
+        );
+        assert_eq!(
+            run_merged(
+                "--merge-root shards -s node --ancestor shards -v id -o ,",
+                &INPUTS
+            )
+            .unwrap(),
+            "1,2,3,"
+        );
+    }
+
+    #[test]
+    fn continuous() {
+        let inputs = [
+            r#"<osm v="1"><node id="1"/><node id="2"/></osm>"#,
+            r#"<osm v="2"><node id="3"/></osm>"#,
+        ];
+        assert_eq!(
+            run_merged(
+                "--continuous -S -v v -o [ -s node --counter -o : -v id -o , -E -o ] --matched node -v v",
+                &inputs
+            )
+            .unwrap(),
+            "1[1:1,2:2,3:3,]32"
+        );
+        // Each input's root element is still the root element
+        assert_eq!(
+            run_merged(
+                "--continuous -x /osm/node -v id -s node --group-by id",
+                &inputs
+            )
+            .unwrap(),
+            "1231\t1\n2\t1\n3\t1\n"
+        );
+    }
+}
+
+mod geojson {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1" lat="51.50" lon="-0.1" name="A &quot;B&quot;"/><node id="2" lat="x" lon="1"/><node id="3" lat="1" lon="2"><tag k="a"/></node></osm>"#;
+
+    assert_run!(
+        all_attributes,
+        "--output-format geojson-lines -s node",
+        INPUT,
+        concat!(
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[-0.1,51.5]},"properties":{"id":"1","name":"A \"B\""}}"#,
+            "\n",
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[2,1]},"properties":{"id":"3"}}"#,
+            "\n"
+        )
+    );
+    assert_run!(
+        fields,
+        "--output-format geojson-lines --on-missing-attr empty -e node -v id -V name unnamed -v name!unix",
+        INPUT,
+        concat!(
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[-0.1,51.5]},"properties":{"id":"1","name":"A \"B\"","name":"A \\\"B\\\""}}"#,
+            "\n",
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[2,1]},"properties":{"id":"3","name":"unnamed","name":null}}"#,
+            "\n"
+        )
+    );
+    assert_run!(
+        other_attrs,
+        "--output-format geojson-lines --lat-attr id --lon-attr id -s node -v lat",
+        INPUT,
+        concat!(
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[1,1]},"properties":{"lat":"51.50"}}"#,
+            "\n",
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[2,2]},"properties":{"lat":"x"}}"#,
+            "\n",
+            r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[3,3]},"properties":{"lat":"1"}}"#,
+            "\n"
+        )
+    );
+
+    #[test]
+    fn only_records() {
+        assert!(run("--output-format geojson -S -o x -s node", INPUT).is_err());
+        assert!(run("--output-format geojson -s node --group-by id", INPUT).is_err());
+    }
+
+    #[test]
+    fn comma_separated() {
+        let mut output: Vec<u8> = vec![];
+        let mut lines = CommaSeparatedLines::new(&mut output);
+        lines.write_all(b"{\"a\":1}\n{").unwrap();
+        lines.write_all(b"\"b\":2}\n").unwrap();
+        lines.write_all(b"{}\n").unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"a\":1},\n{\"b\":2},\n{}"
+        );
+    }
+}
+
+mod point {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1" lat="51.5" lon="-0.1"><tag k="a"/></node><node id="2" lon="3"/></osm>"#;
+
+    assert_run!(
+        wkt,
+        "--on-missing-attr skip-record -s node -v id -o , --wkt-point lon lat --nl",
+        INPUT,
+        "1,POINT(-0.1 51.5)\n"
+    );
+    assert_run!(
+        wkb,
+        "--on-missing-attr empty -s node -v id -o , --wkb-point lon lat --nl",
+        INPUT,
+        "1,01010000009A9999999999B9BF0000000000C04940\n2,\n"
+    );
+    assert_run!(
+        parent,
+        "-s tag -v k -o , --wkt-point ../lon ../lat",
+        INPUT,
+        "a,POINT(-0.1 51.5)"
+    );
+    assert_run!(
+        end_tag,
+        "-e node --wkt-point lon lat --on-missing-attr empty",
+        r#"<node lat="1" lon="2"><tag/></node>"#,
+        "POINT(2 1)"
+    );
+
+    #[test]
+    fn not_a_number() {
+        assert!(run("-s node --wkt-point lon lat", r#"<node lat="1" lon="x"/>"#).is_err());
+        assert!(run("-s node --wkt-point lon lat", r#"<node lat="1"/>"#).is_err());
+    }
+}
+
+mod hstore {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="1"><tag k="name" v="a &quot;b&quot; \ c"/><tag k="x"/><tag v="nokey"/><tag k="t" v="1&#9;2"/></way><way id="2"/></osm>"#;
+
+    assert_run!(
+        tags,
+        "-e way -v id -o , --collect-hstore tag --nl",
+        INPUT,
+        "1,\"name\"=>\"a \\\"b\\\" \\\\ c\", \"x\"=>NULL, \"t\"=>\"1\t2\"\n2,\n"
+    );
+    assert_run!(
+        pgcopy,
+        "-e way --collect-hstore tag!pgcopy --nl",
+        INPUT,
+        "\"name\"=>\"a \\\\\"b\\\\\" \\\\\\\\ c\", \"x\"=>NULL, \"t\"=>\"1\\t2\"\n\n"
+    );
+    assert_run!(
+        key_value,
+        "-e a --collect-hstore b/key/val",
+        r#"<a><b key="k" val="v" k="x"/></a>"#,
+        "\"k\"=>\"v\""
+    );
+
+    #[test]
+    fn invalid() {
+        assert!(run("-s way --collect-hstore tag", INPUT).is_err());
+        assert!(run("-e way --collect-hstore tag/k", INPUT).is_err());
+    }
+}
+
+mod json_object {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="1"><tag k="name" v="a &quot;b&quot; \ c"/><tag k="x"/><tag v="nokey"/></way><way id="2"/></osm>"#;
+
+    assert_run!(
+        tags,
+        "-e way -v id --tab --collect-json-object tag --nl",
+        INPUT,
+        "1\t{\"name\":\"a \\\"b\\\" \\\\ c\",\"x\":null}\n2\t{}\n"
+    );
+    assert_run!(
+        pgcopy,
+        "-e way --collect-json-object tag!pgcopy --nl",
+        INPUT,
+        "{\"name\":\"a \\\\\"b\\\\\" \\\\\\\\ c\",\"x\":null}\n{}\n"
+    );
+    assert_run!(
+        key_value,
+        "-e a --collect-json-object b/key/val --collect-hstore b/key/val",
+        r#"<a><b key="k" val="v" k="x"/></a>"#,
+        "{\"k\":\"v\"}\"k\"=>\"v\""
+    );
+
+    #[test]
+    fn invalid() {
+        assert!(run("-S --collect-json-object tag", INPUT).is_err());
+        assert!(run("-e way --collect-json-object tag/k/v/x", INPUT).is_err());
+    }
+}
+
+mod header_comment {
+    use super::*;
+
+    fn header(args: &[&str]) -> String {
+        let (config, _) = parse_to_instructions(args).unwrap();
+        let mut output: Vec<u8> = vec![];
+        config
+            .header_comment
+            .unwrap()
+            .write(&config.inputs, "2024-01-02T03:04:05+00:00", &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn hash() {
+        assert_eq!(
+            header(&["--emit-header-comment", "-s", "node", "-o", "a b", "my file.osm"]),
+            format!(
+                "# Generated by anglosaxon {}\n# Command: anglosaxon --emit-header-comment -s node -o 'a b' 'my file.osm'\n# Input: 'my file.osm'\n# Date: 2024-01-02T03:04:05+00:00\n",
+                clap::crate_version!()
+            )
+        );
+    }
+
+    #[test]
+    fn styles() {
+        assert!(header(&["--emit-header-comment=sql", "-s", "a"]).starts_with("-- Generated"));
+        assert!(
+            header(&["--emit-header-comment=hash", "--copy-root", "r", "-s", "a"])
+                .starts_with("# Generated")
+        );
+        assert!(
+            header(&["--copy-root", "r", "--emit-header-comment", "-s", "a"]).contains(
+                "\n<!-- Command: anglosaxon - -copy-root r - -emit-header-comment -s a -->\n"
+            )
+        );
+    }
+
+    #[test]
+    fn not_json() {
+        assert!(parse_to_instructions(
+            [
+                "--output-format",
+                "geojson",
+                "--emit-header-comment",
+                "-s",
+                "a"
+            ]
+            .as_slice()
+        )
+        .is_err());
+        assert_eq!(
+            parse_to_instructions(["-s", "a"].as_slice())
+                .unwrap()
+                .0
+                .header_comment,
+            None
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("a-b_c/d.e=f"), "a-b_c/d.e=f");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+    }
+}
+
+mod checkpoint {
+    use super::*;
+
+    const INPUT: &str = "<osm xmlns:x=\"urn:x\"><way k=\"a&#10;&amp;\"><nd ref=\"1\"/><nd ref=\"2\"/></way><way k=\"b\"><nd ref=\"3\"/><x:nd ref=\"4\"/></way><x:way k=\"c\"><nd ref=\"5\"/></x:way></osm>";
+    const ARGS: &str = "-S -o start\n -s nd --counter -o , -v ../k -o , -v ref -o \n -E -o end";
+
+    /// Output which fails after this many bytes, like a crash
+    struct Crash {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Crash {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.written.len() + buf.len() > self.limit {
+                return Err(std::io::Error::other("crash"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(args: &str, input: &[u8], output: impl Write) -> Result<()> {
+        let args: Vec<_> = args.split(' ').collect();
+        let (config, instructions) = parse_to_instructions(args.as_slice())?;
+        let mut summary = Summary::default();
+        process(
+            &config,
+            &instructions,
+            "in.osm",
+            input,
+            output,
+            &mut summary,
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let checkpoint = Checkpoint {
+            input: "in.osm".to_string(),
+            offset: 10,
+            output: 20,
+            records: 3,
+            counters: vec![3, 0],
+            open: vec!["<osm>".to_string(), "<way k=\"a&#10;b\">".to_string()],
+        };
+        let text = checkpoint.to_string();
+        assert_eq!(
+            text,
+            "input in.osm\noffset 10\noutput 20\nrecords 3\ncounters 3 0\nopen <osm>\nopen <way k=\"a&#10;b\">\n"
+        );
+        assert_eq!(text.parse::<Checkpoint>().unwrap(), checkpoint);
+        assert!("offset x".parse::<Checkpoint>().is_err());
+    }
+
+    #[test]
+    fn resume() {
+        let path =
+            std::env::temp_dir().join(format!("anglosaxon-{}.checkpoint", std::process::id()));
+        let args = format!(
+            "--checkpoint {} --checkpoint-interval 0 {} in.osm",
+            path.display(),
+            ARGS
+        );
+        let mut full: Vec<u8> = vec![];
+        run(&args, INPUT.as_bytes(), &mut full).unwrap();
+        assert_eq!(
+            String::from_utf8(full.clone()).unwrap(),
+            "start\n1,a\n&,1\n2,a\n&,2\n3,b,3\n4,b,4\n5,c,5\nend"
+        );
+        assert!(!path.exists());
+
+        for limit in 0..full.len() {
+            let mut crash = Crash {
+                written: vec![],
+                limit,
+            };
+            assert!(run(&args, INPUT.as_bytes(), &mut crash).is_err());
+            let mut output = crash.written;
+            let checkpoint: Checkpoint = std::fs::read_to_string(&path)
+                .map(|text| text.parse().unwrap())
+                .unwrap_or_default();
+            output.truncate(checkpoint.output as usize);
+            let resumed = format!("--resume {}", args);
+            let input = &INPUT.as_bytes()[checkpoint.offset as usize..];
+            run(&resumed, input, &mut output).unwrap();
+            assert_eq!(output, full, "crashed after {} bytes", limit);
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(parse_to_instructions(&["--checkpoint", "c", "-s", "a", "-"][..]).is_err());
+        assert!(parse_to_instructions(&["--checkpoint", "c", "-s", "a", "x", "y"][..]).is_err());
+        assert!(
+            parse_to_instructions(&["--checkpoint", "c", "--sort", "-s", "a", "x"][..]).is_err()
+        );
+        assert!(parse_to_instructions(
+            &["--checkpoint", "c", "-s", "a", "--sibling-index", "x"][..]
+        )
+        .is_err());
+        assert!(parse_to_instructions(&["--checkpoint", "c", "-s", "a", "x"][..]).is_ok());
+    }
+}
+
+mod offsets {
+    use super::*;
+
+    const INPUT: &str = r#"<x:osm xmlns:x="urn:x" v="1"><node id="1"/><way id="2"><nd ref="1"/><x:nd ref="3"/></way><node id="4"/></x:osm>"#;
+    const ARGS: &str = "-S -o start -s node -v id -o , -s nd -v ../id -o : -v ref -o , -E -o end --sync-tag node --sync-tag way";
+
+    /// The output for the elements which start in `start..end`, like main does it
+    fn shard(start: usize, end: usize) -> String {
+        let args = format!(
+            "{} --start-offset {} --end-offset {} in.osm",
+            ARGS, start, end
+        );
+        let args: Vec<_> = args.split(' ').collect();
+        let (mut config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+        let mut input = INPUT.as_bytes();
+        if start > 0 {
+            let tags = &config.sync_tags;
+            match find_sync_point(&INPUT.as_bytes()[start..], start as u64, tags).unwrap() {
+                Some(offset) => {
+                    input = &INPUT.as_bytes()[offset as usize..];
+                    config.resume = Some(Checkpoint {
+                        input: "in.osm".to_string(),
+                        offset,
+                        open: vec![read_root_tag(INPUT.as_bytes()).unwrap()],
+                        ..Default::default()
+                    });
+                }
+                None => return String::new(),
+            }
+        }
+        let mut output: Vec<u8> = vec![];
+        let mut summary = Summary::default();
+        process(
+            &config,
+            &instructions,
+            "in.osm",
+            input,
+            &mut output,
+            &mut summary,
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn sync_point() {
+        let tags = ["node".to_string(), "way".to_string()];
+        assert_eq!(
+            find_sync_point(&b"d/><nd ref=\"1\"/></way><x:way>"[..], 100, &tags).unwrap(),
+            Some(122)
+        );
+        assert_eq!(
+            find_sync_point(&b"<nodes/><node>"[..], 0, &tags).unwrap(),
+            Some(8)
+        );
+        assert_eq!(
+            find_sync_point(&b"<nd/></osm>"[..], 0, &tags).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn root_tag() {
+        assert_eq!(
+            read_root_tag(INPUT.as_bytes()).unwrap(),
+            r#"<x:osm xmlns:x="urn:x" v="1">"#
+        );
+        assert!(read_root_tag(&b"<?xml version=\"1.0\"?>"[..]).is_err());
+    }
+
+    #[test]
+    fn shards() {
+        let full = shard(0, INPUT.len());
+        assert_eq!(full, "start1,2:1,2:3,4,end");
+        for split in 1..INPUT.len() {
+            assert_eq!(
+                shard(0, split) + &shard(split, INPUT.len()),
+                full,
+                "split at {}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(parse_to_instructions(&["--start-offset", "5", "-s", "a", "-"][..]).is_err());
+        assert!(parse_to_instructions(&["--start-offset", "5", "-S", "x"][..]).is_err());
+        assert!(parse_to_instructions(
+            &["--end-offset", "5", "-s", "a", "--sibling-index", "x"][..]
+        )
+        .is_err());
+    }
+}
+
+mod threads {
+    use super::*;
+
+    const INPUT: &str = r#"<osm v="1"><node id="1"/><way id="2"><nd ref="1"/><nd ref="3"/></way><node id="4"><tag k="a"/></node><way id="5"/></osm>"#;
+
+    #[test]
+    fn chunks() {
+        let path =
+            std::env::temp_dir().join(format!("anglosaxon-{}-threads.osm", std::process::id()));
+        std::fs::write(&path, INPUT).unwrap();
+        let filename = path.to_str().unwrap();
+        let args = format!(
+            "--threads 2 --sync-tag node --sync-tag way -S -o start -s node -v id -o , -s nd -v ../id -o : -v ref -o , -E -o end {}",
+            filename
+        );
+        let args: Vec<_> = args.split(' ').collect();
+        let (config, _) = parse_to_instructions(args.as_slice()).unwrap();
+        assert_eq!(config.threads, Some(2));
+        for threads in 1..=3 {
+            for chunk_bytes in 1..=INPUT.len() as u64 + 1 {
+                let mut output: Vec<u8> = vec![];
+                let mut summary = Summary::default();
+                process_parallel(
+                    filename,
+                    threads,
+                    2 * threads,
+                    chunk_bytes,
+                    || parse_to_instructions(args.as_slice()),
+                    &mut output,
+                    &mut summary,
+                )
+                .unwrap();
+                assert_eq!(
+                    String::from_utf8(output).unwrap(),
+                    "start1,2:1,2:3,4,end",
+                    "{} threads, {} byte chunks",
+                    threads,
+                    chunk_bytes
+                );
+                assert_eq!(summary.records, 4);
+                assert_eq!(summary.matched, vec![0, 2, 2, 0]);
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn queue_size() {
+        let path = std::env::temp_dir().join(format!(
+            "anglosaxon-{}-threads-queue.osm",
+            std::process::id()
+        ));
+        std::fs::write(&path, INPUT).unwrap();
+        let filename = path.to_str().unwrap();
+        let args = format!(
+            "--threads 3 --queue-size 1 --sync-tag node --sync-tag way -s node -v id -o , {}",
+            filename
+        );
+        let args: Vec<_> = args.split(' ').collect();
+        let (config, _) = parse_to_instructions(args.as_slice()).unwrap();
+        assert_eq!(config.queue_size, Some(1));
+        for queue_size in 1..=4 {
+            let mut output: Vec<u8> = vec![];
+            process_parallel(
+                filename,
+                3,
+                queue_size,
+                10,
+                || parse_to_instructions(args.as_slice()),
+                &mut output,
+                &mut Summary::default(),
+            )
+            .unwrap();
+            assert_eq!(output, b"1,4,", "--queue-size {}", queue_size);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(parse_to_instructions(&["--queue-size", "2", "-s", "a", "x"][..]).is_err());
+        assert!(parse_to_instructions(
+            &["--threads", "2", "--queue-size", "0", "-s", "a", "x"][..]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let path =
+            std::env::temp_dir().join(format!("anglosaxon-{}-threads-bad.osm", std::process::id()));
+        std::fs::write(&path, "<osm><node id=\"1\"/><node id=\"2\"></way></osm>").unwrap();
+        let filename = path.to_str().unwrap();
+        let args = format!("--threads 2 -s node -v id {}", filename);
+        let args: Vec<_> = args.split(' ').collect();
+        let mut output: Vec<u8> = vec![];
+        let result = process_parallel(
+            filename,
+            2,
+            4,
+            10,
+            || parse_to_instructions(args.as_slice()),
+            &mut output,
+            &mut Summary::default(),
+        );
+        assert!(result.is_err());
+        assert_eq!(output, b"1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(parse_to_instructions(&["--threads", "2", "-s", "a", "-"][..]).is_err());
+        assert!(
+            parse_to_instructions(&["--threads", "2", "-s", "a", "--counter", "x"][..]).is_err()
+        );
+        assert!(parse_to_instructions(&["--threads", "2", "-s", "a", "x"][..]).is_ok());
+    }
+}
+
+mod max_memory {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="1"><nd ref="1000"/><nd ref="2000"/><nd ref="3000"/></way><way id="2"><nd ref="4"/></way></osm>"#;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_size("2k").unwrap(), 2048);
+        assert_eq!(parse_size("500M").unwrap(), 500 * 1024 * 1024);
+        assert_eq!(parse_size("1G").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("").is_err());
+    }
+
+    #[test]
+    fn collect() {
+        let args = "-e way -v id -o : --collect nd/ref , --nl";
+        let expected = "1:1000,2000,3000\n2:4\n";
+        assert_eq!(run(args, INPUT).unwrap(), expected);
+        assert_eq!(
+            run(&format!("--max-memory 12 {}", args), INPUT).unwrap(),
+            expected
+        );
+        let error = run(&format!("--max-memory 11 {}", args), INPUT).unwrap_err();
+        assert!(
+            error.to_string().ends_with("mostly for --collect etc."),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn copy() {
+        assert!(run("--max-memory 1K -s way --copy", INPUT).is_ok());
+        assert!(run("--max-memory 20 -s way --copy", INPUT).is_err());
+    }
+
+    #[test]
+    fn group_by() {
+        assert!(run("--max-memory 1K -s nd --group-by ref", INPUT).is_ok());
+        assert!(run("--max-memory 50 -s nd --group-by ref", INPUT).is_err());
+    }
+
+    #[test]
+    fn peak() {
+        let args: Vec<_> = "-e way --collect nd/ref ,".split(' ').collect();
+        let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+        let mut summary = Summary::default();
+        process(
+            &config,
+            &instructions,
+            "-",
+            INPUT.as_bytes(),
+            std::io::sink(),
+            &mut summary,
+        )
+        .unwrap();
+        assert_eq!(summary.peak_buffered, 12);
+    }
+
+    #[test]
+    fn unique() {
+        let mut output: Vec<u8> = vec![];
+        let mut unique = UniqueLines::new(&mut output, UniqueMode::All);
+        unique.max_bytes = Some(4);
+        unique.write_all(b"ab\nab\ncd\n").unwrap();
+        assert!(unique.write_all(b"ef\n").is_err());
+        drop(unique);
+        assert_eq!(output, b"ab\ncd\n");
+    }
+}
+
+mod flush_every {
+    use super::*;
+
+    /// Records what had been written at each flush
+    #[derive(Default)]
+    struct Flushes {
+        written: Vec<u8>,
+        flushed: Vec<String>,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushed
+                .push(String::from_utf8(self.written.clone()).unwrap());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let mut flushes = Flushes::default();
+        let mut output = FlushEvery::new(&mut flushes, 2);
+        output.write_all(b"a\nb").unwrap();
+        output.write_all(b"\nc\n").unwrap();
+        output.write_all(b"d\ne\nf\ng").unwrap();
+        output.flush().unwrap();
+        assert_eq!(
+            flushes.flushed,
+            [
+                "a\nb\n",
+                "a\nb\nc\nd\n",
+                "a\nb\nc\nd\ne\nf\n",
+                "a\nb\nc\nd\ne\nf\ng"
+            ]
+        );
+    }
+
+    #[test]
+    fn args() {
+        let parse = |args: &[&str]| parse_to_instructions(args).unwrap().0.flush_every;
+        assert_eq!(parse(&["-s", "a"]), None);
+        assert_eq!(parse(&["--line-buffered", "-s", "a"]), Some(1));
+        assert_eq!(parse(&["--flush-every", "100", "-s", "a"]), Some(100));
+        assert!(parse_to_instructions(&["--flush-every", "0", "-s", "a"][..]).is_err());
+    }
+}
+
+mod output_limits {
+    use super::*;
+
+    const INPUT: &str =
+        r#"<osm><node id="1"/><node id="2"/><way id="3"><nd ref="1"/></way><node id="4"/></osm>"#;
+
+    assert_run!(
+        records,
+        "--max-records 2 -S -o [ -s node -v id -o , -E -o ]",
+        INPUT,
+        "[1,2,]"
+    );
+    assert_run!(
+        bytes,
+        "--max-output-bytes 3 -S -o [ -s node -v id -o , -E -o ]",
+        INPUT,
+        "[1,]"
+    );
+    assert_run!(
+        not_reached,
+        "--max-records 10 -S -o [ -s node -v id -o , -E -o ]",
+        INPUT,
+        "[1,2,4,]"
+    );
+    assert_run!(
+        open_elements,
+        "--max-records 1 -s nd -v ref -e way -v id -E -o .",
+        INPUT,
+        "1."
+    );
+
+    #[test]
+    fn merged() {
+        let args: Vec<_> = "--merge-root files --max-records 1 -s node -v id -E -o . a.osm b.osm"
+            .split(' ')
+            .collect();
+        let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+        let mut output: Vec<u8> = vec![];
+        let mut summary = Summary::default();
+        process(
+            &config,
+            &instructions,
+            "-",
+            INPUT.as_bytes(),
+            &mut output,
+            &mut summary,
+        )
+        .unwrap();
+        assert!(summary.output_limit_reached);
+        // -E happens at once, not after the last input
+        assert_eq!(output, b"1.");
+    }
+}
+
+mod progress_json {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn line() {
+        assert_eq!(
+            progress_json("progress", 250, Some(1000), 10, Duration::from_secs(2)),
+            "{\"event\":\"progress\",\"bytes_read\":250,\"total_bytes\":1000,\"percent\":25.0,\"records\":10,\"elapsed_seconds\":2.0,\"bytes_per_second\":125,\"records_per_second\":5,\"eta_seconds\":6.0}\n"
+        );
+    }
+
+    #[test]
+    fn stdin() {
+        assert_eq!(
+            progress_json("done", 0, None, 0, Duration::ZERO),
+            "{\"event\":\"done\",\"bytes_read\":0,\"total_bytes\":null,\"percent\":null,\"records\":0,\"elapsed_seconds\":0.0,\"bytes_per_second\":0,\"records_per_second\":0,\"eta_seconds\":null}\n"
+        );
+    }
+
+    #[test]
+    fn options() {
+        let (config, _) = parse_to_instructions(
+            &[
+                "--progress-json=p.jsonl",
+                "--progress-interval",
+                "0.5",
+                "-s",
+                "node",
+                "-v",
+                "id",
+                "-",
+            ][..],
+        )
+        .unwrap();
+        assert_eq!(
+            config.progress,
+            Some(ProgressOptions {
+                path: Some("p.jsonl".into()),
+                interval: Duration::from_millis(500)
+            })
+        );
+        let (config, _) =
+            parse_to_instructions(&["--progress-json", "-s", "node", "-v", "id", "-"][..]).unwrap();
+        assert_eq!(config.progress.unwrap().path, None);
+    }
+
+    #[test]
+    fn written() {
+        let path =
+            std::env::temp_dir().join(format!("anglosaxon-progress-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let input: String = std::iter::once("<osm>".to_string())
+            .chain((0..2000).map(|i| format!("<node id=\"{}\"/>", i)))
+            .chain(std::iter::once("</osm>".to_string()))
+            .collect();
+        let (config, instructions) =
+            parse_to_instructions(&["-s", "node", "-v", "id", "-"][..]).unwrap();
+        let options = ProgressOptions {
+            path: Some(path.clone()),
+            interval: Duration::ZERO,
+        };
+        let mut summary = Summary {
+            progress: Some(Progress::open(&options, &["-".to_string()]).unwrap()),
+            ..Default::default()
+        };
+        let mut output: Vec<u8> = vec![];
+        process(
+            &config,
+            &instructions,
+            "-",
+            input.as_bytes(),
+            &mut output,
+            &mut summary,
+        )
+        .unwrap();
+        drop(summary);
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(written.lines().count() >= 2, "{}", written);
+        assert!(written
+            .lines()
+            .all(|l| l.starts_with("{\"event\":\"progress\",")));
+    }
+}
+
+mod summary_json {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let (config, instructions) = parse_to_instructions(
+            &["-s", "node", "-v", "id", "-s", "relation", "-v", "id", "-"][..],
+        )
+        .unwrap();
+        let mut output: Vec<u8> = vec![];
+        let mut summary = Summary::default();
+        process(
+            &config,
+            &instructions,
+            "-",
+            r#"<osm><node id="1"/><node id="2"/></osm>"#.as_bytes(),
+            &mut output,
+            &mut summary,
+        )
+        .unwrap();
+        summary.warn("a \"quoted\" problem".to_string(), "line 1".to_string());
+        assert_eq!(
+            summary.to_json(
+                &instructions,
+                true,
+                "complete",
+                None,
+                std::time::Duration::from_millis(1500)
+            ),
+            concat!(
+                r#"{"exit_reason":"complete","error":null,"duration_seconds":1.500,"bytes_read":39,"bytes_written":2,"records":2,"#,
+                r#""instructions":[{"instruction":"-s node","matched":2},{"instruction":"-s relation","matched":0}],"#,
+                r#""warnings":[{"message":"-s relation never matched","count":1,"first_at":null},{"message":"a \"quoted\" problem","count":1,"first_at":"line 1"}]}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn error() {
+        let (_, instructions) =
+            parse_to_instructions(&["-s", "node", "-v", "id", "-"][..]).unwrap();
+        let json = Summary::default().to_json(
+            &instructions,
+            false,
+            "error",
+            Some("Bad XML"),
+            std::time::Duration::ZERO,
+        );
+        assert!(json.starts_with(r#"{"exit_reason":"error","error":"Bad XML","#));
+        assert!(json
+            .contains(r#""instructions":[{"instruction":"-s node","matched":0}],"warnings":[]"#));
+    }
+}
+
+mod matched {
+    use super::*;
+
+    const INPUT: &str =
+        r#"<osm><node id="1"/><node id="2"/><way id="3"><nd ref="1"/></way><node id="4"/></osm>"#;
+
+    assert_run!(
+        footer,
+        "-s node -v id --nl -s way -v id --nl -E -o # --matched node -o , --matched way",
+        INPUT,
+        "1\n2\n3\n4\n#3,1"
+    );
+    assert_run!(
+        filtered,
+        "-s node --require version -v id -E --matched node",
+        INPUT,
+        "3"
+    );
+    assert_run!(none, "-s relation -v id -E --matched relation", INPUT, "0");
+
+    #[test]
+    fn needs_instruction() {
+        assert!(parse_to_instructions(&["-s", "node", "-E", "--matched", "way", "-"][..]).is_err());
+        assert!(parse_to_instructions(&["-s", "node", "--matched", "node", "-"][..]).is_err());
+    }
+}
+
+mod warn_unknown_tags {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1"><tag k="a"/></node><way id="2"><nd ref="1"/><nd ref="1"/></way></osm>"#;
+
+    fn unknown_tags(args: &str) -> Vec<(String, u64)> {
+        let args: Vec<_> = args.split(' ').collect();
+        let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+        let mut summary = Summary::default();
+        process(
+            &config,
+            &instructions,
+            "-",
+            INPUT.as_bytes(),
+            std::io::sink(),
+            &mut summary,
+        )
+        .unwrap();
+        summary
+            .warnings
+            .into_iter()
+            .map(|(message, (count, _))| (message, count))
+            .collect()
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(
+            unknown_tags("--warn-unknown-tags -s node -v id"),
+            vec![
+                ("No instruction for <nd> elements".to_string(), 2),
+                ("No instruction for <tag> elements".to_string(), 1),
+                ("No instruction for <way> elements".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn mentioned() {
+        assert_eq!(
+            unknown_tags("--warn-unknown-tags -s node -v id -e way --collect nd/ref , -s tag --ancestor node -v k"),
+            vec![]
+        );
+        assert_eq!(
+            unknown_tags("--warn-unknown-tags -s way --copy -s node --copy"),
+            vec![]
+        );
+        assert_eq!(unknown_tags("--warn-unknown-tags -x //* -o x"), vec![]);
+        assert_eq!(unknown_tags("-s node -v id"), vec![]);
+    }
+}
+
+mod irrelevant_elements {
+    use super::*;
+
+    const INPUT: &str = r#"<osm version="0.6"><bounds/><node id="1"><tag k="a" v="b"/></node><way id="2"><nd ref="1"/><nd ref="3"/></way></osm>"#;
+
+    assert_run!(
+        nested,
+        "-s tag -v k -o = -v v --nl -E -v version",
+        INPUT,
+        "a=b\n0.6"
+    );
+    assert_run!(
+        collect,
+        "-e way -v id -o : --collect nd/ref , --nl",
+        INPUT,
+        "2:1,3\n"
+    );
+    assert_run!(
+        merged,
+        "--merge-root files -S -o [ -s nd -v ref -o , -E -o ]",
+        INPUT,
+        "[1,3,]"
+    );
+
+    #[test]
+    fn error_path() {
+        let err = run("-s tag -v x", INPUT).unwrap_err();
+        assert!(format!("{:#}", err).contains("/osm/node/tag"), "{:#}", err);
+    }
+}
+
+mod raw_strings_only {
+    use super::*;
+
+    const INPUT: &str = r#"<osm version="0.6"><node id="1"/><way id="2"><nd ref="1"/></way></osm>"#;
+
+    assert_run!(
+        only_raw,
+        "-S -o start --nl -s node -o n --nl -e way -o w --nl -E -o end",
+        INPUT,
+        "start\nn\nw\nend"
+    );
+    assert_run!(
+        root_attributes,
+        "-S -v version --nl -s node -o n --nl -E -v version",
+        INPUT,
+        "0.6\nn\n0.6"
+    );
+}
+
+mod ignore_tag_case {
+    use super::*;
+
+    const INPUT: &str = r#"<RSS><Channel><Item id="1"><Title t="a"/></Item><item id="2"><TITLE t="b"/></item></Channel></RSS>"#;
+
+    assert_run!(case_sensitive, "-s item -v id --nl", INPUT, "2\n");
+    assert_run!(
+        start,
+        "--ignore-tag-case -s Item -v id --nl",
+        INPUT,
+        "1\n2\n"
+    );
+    assert_run!(
+        end_collect,
+        "--ignore-tag-case -e ITEM -v id -o : --collect title/t , --nl",
+        INPUT,
+        "1:a\n2:b\n"
+    );
+    assert_run!(
+        ancestor_xpath,
+        "--ignore-tag-case -s title --ancestor item[id=2] -v t --nl -x /rss/channel/item[1] -v id --nl",
+        INPUT,
+        "1\nb\n"
+    );
+    assert_run!(
+        counter_per,
+        "--ignore-tag-case -s title --counter-per CHANNEL --nl",
+        INPUT,
+        "1\n2\n"
+    );
+}
+
+mod ignore_attr_case {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node ID="1" Version="2"/><node id="3"/><node Id="4" xlink:HREF="x" xmlns:xlink="http://www.w3.org/1999/xlink"/></osm>"#;
+
+    assert_run!(
+        case_sensitive,
+        "-s node -V id - -o , -V version - --nl",
+        INPUT,
+        "-,-\n3,-\n-,-\n"
+    );
+    assert_run!(
+        attributes,
+        "--ignore-attr-case -s node -v id -o , -V version - --nl",
+        INPUT,
+        "1,2\n3,-\n4,-\n"
+    );
+    assert_run!(
+        prefixed,
+        "--ignore-attr-case -s node --require xlink:href -v id --nl",
+        INPUT,
+        "4\n"
+    );
+    assert_run!(
+        predicates,
+        "--ignore-attr-case -s node --has version --nl -x //node[@VERSION] -o x --nl",
+        INPUT,
+        "true\nx\nfalse\nfalse\n"
+    );
+}
+
+mod fragment {
+    use super::*;
+
+    const INPUT: &str = "<?xml version=\"1.0\"?>\n<item id=\"1\"/>\ntext <item id=\"2\"/>";
+
+    assert_run!(
+        items,
+        "--fragment -S -o [ -s item -v id -o @ --offset -o , -E -o ]",
+        INPUT,
+        "[1@22,2@42,]"
+    );
+    assert_run!(
+        root_name,
+        "--fragment=items -x /items/item[2] -v id --chars=nonblank -o T",
+        INPUT,
+        "T2"
+    );
+
+    #[test]
+    fn not_a_fragment() {
+        assert!(run("-s item -v id", INPUT).is_err());
+        assert!(run("--fragment -s a -o a", "<!DOCTYPE a><a/>").is_err());
+    }
+
+    #[test]
+    fn declaration() {
+        assert_eq!(xml_declaration_len(b"<?xml version=\"1.0\"?><a/>"), 21);
+        assert_eq!(xml_declaration_len(b"<?xml-stylesheet href=\"a\"?><a/>"), 0);
+        assert_eq!(xml_declaration_len(b"<a/>"), 0);
+    }
+}
+
+mod record_separator {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1"/><node id="2"><tag k="a"/></node></osm>"#;
+
+    assert_run!(
+        newline,
+        r"--rs \n -S -o id -s node -v id -E -o end",
+        INPUT,
+        "id\n1\n2\nend\n"
+    );
+    assert_run!(
+        multi_character,
+        r"--rs ;\0 -s node -v id -o ,",
+        INPUT,
+        "1,;\u{0}2,;\u{0}"
+    );
+    assert_run!(grouped, r"--rs \0 -s tag --group-by k", INPUT, "a\t1\n");
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape(r"a\tb\\n\r\0").unwrap(), "a\tb\\n\r\0");
+        assert!(unescape(r"\x").is_err());
+        assert!(unescape(r"a\").is_err());
+    }
+}
+
+mod fields {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1" lat="51.5" lon="-0.1"/><node id="2" lat="48.8" lon="2.3"><tag k="a"/></node></osm>"#;
+
+    assert_run!(
+        simple,
+        "-s node --fields id,lat,lon",
+        INPUT,
+        "1\t51.5\t-0.1\n2\t48.8\t2.3\n"
+    );
+    assert_run!(
+        field_separator,
+        "--fs , -s node --fields id,lon",
+        INPUT,
+        "1,-0.1\n2,2.3\n"
+    );
+    assert_run!(
+        record_separator,
+        r"--rs \0 --fs \t -s node --fields id,lat",
+        INPUT,
+        "1\t51.5\u{0}2\t48.8\u{0}"
+    );
+    assert_run!(
+        parent_and_filters,
+        "-s tag --fields ../id,k!tsv",
+        INPUT,
+        "2\ta\n"
+    );
+
+    #[test]
+    fn empty_field() {
+        assert!(run("-s node --fields id,,lat", INPUT).is_err());
+    }
+}
+
+mod schema {
+    use super::*;
+
+    const INPUT: &str =
+        r#"<osm><node id="1" lat="51.5" name="A"/><node id="x" lat="48.8" name="B"/></osm>"#;
+
+    assert_run!(
+        valid,
+        "--schema id:int,lat:float,name -s node --fields id,lat,name",
+        r#"<osm><node id="1" lat="51.5" name="A"/><node id="-2" lat="4e1" name="B"/></osm>"#,
+        "1\t51.5\tA\n-2\t4e1\tB\n"
+    );
+    assert_run!(
+        other_attributes,
+        "--schema lat:float -s node --fields id,lat",
+        INPUT,
+        "1\t51.5\nx\t48.8\n"
+    );
+
+    #[test]
+    fn invalid() {
+        let error = run("--schema id:int -s node --fields id,lat", INPUT).unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "Value \"x\" of the id column isn't a valid int"
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let schema: Schema = "id:int,ok:bool,name".parse().unwrap();
+        assert_eq!(schema.columns[1].column_type, ColumnType::Bool);
+        assert_eq!(schema.columns[2].column_type, ColumnType::Str);
+        assert_eq!(schema.header(","), "id,ok,name");
+        assert!("id:int,id:str".parse::<Schema>().is_err());
+        assert!("id:integer".parse::<Schema>().is_err());
+        assert!(":int".parse::<Schema>().is_err());
+    }
+
+    #[test]
+    fn types() {
+        let coerce = |t: ColumnType, v: &'static str| t.coerce(Cow::Borrowed(v)).ok();
+        assert_eq!(coerce(ColumnType::Int, "-12").as_deref(), Some("-12"));
+        assert_eq!(coerce(ColumnType::Int, " 12 ").as_deref(), Some("12"));
+        assert_eq!(coerce(ColumnType::Int, "1.5"), None);
+        assert_eq!(coerce(ColumnType::Float, "1e3").as_deref(), Some("1e3"));
+        assert_eq!(coerce(ColumnType::Float, "1,5").as_deref(), Some("1.5"));
+        assert_eq!(coerce(ColumnType::Float, "1,000.5"), None);
+        assert_eq!(coerce(ColumnType::Float, "NaN"), None);
+        assert_eq!(coerce(ColumnType::Bool, "Yes").as_deref(), Some("true"));
+        assert_eq!(coerce(ColumnType::Bool, "0").as_deref(), Some("false"));
+        assert_eq!(coerce(ColumnType::Bool, "maybe"), None);
+        assert_eq!(coerce(ColumnType::Str, " a ").as_deref(), Some(" a "));
+    }
+
+    const INVALID: &str =
+        r#"<osm><node id=" 1 " lat="51,5"/><node id="x" lat="2"/><node id="3" lat="?"/></osm>"#;
+
+    assert_run!(
+        coerced,
+        "--schema id:int,lat:float -s node --fields id,lat",
+        r#"<osm><node id=" 1 " lat="51,5"/></osm>"#,
+        "1\t51.5\n"
+    );
+    assert_run!(
+        null,
+        "--schema id:int,lat:float --on-invalid null -s node --fields id,lat",
+        INVALID,
+        "1\t51.5\n\t2\n3\t\n"
+    );
+    assert_run!(
+        skip_record,
+        "--schema id:int,lat:float --on-invalid skip-record -s node --fields id,lat",
+        INVALID,
+        "1\t51.5\n"
+    );
+
+    #[test]
+    fn on_invalid_needs_schema() {
+        assert!(run("--on-invalid null -s node -v id", INVALID).is_err());
+    }
+
+    #[test]
+    fn invalid_warnings() {
+        let (config, instructions) = parse_to_instructions(
+            [
+                "--schema",
+                "id:int",
+                "--on-invalid",
+                "skip-record",
+                "-s",
+                "node",
+                "-v",
+                "id",
+            ]
+            .as_slice(),
+        )
+        .unwrap();
+        let mut summary = Summary::default();
+        process(
+            &config,
+            &instructions,
+            "-",
+            INVALID.as_bytes(),
+            &mut vec![],
+            &mut summary,
+        )
+        .unwrap();
+        assert_eq!(
+            summary.warnings.keys().collect::<Vec<_>>(),
+            ["Records with invalid int values of the id column were skipped"]
+        );
+    }
+}
+
+mod invalid_to {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><node id="1" lat="2"/><node id="x" lat="3"/><node id="4"/><node id="5" lat="6"/></osm>"#;
+
+    /// The output, the rejected records & the warnings
+    fn run_invalid(args: &[&str], name: &str) -> (String, String, Vec<String>) {
+        let path = std::env::temp_dir().join(format!(
+            "anglosaxon-invalid-{}-{}.tsv",
+            name,
+            std::process::id()
+        ));
+        let (config, instructions) = parse_to_instructions(args).unwrap();
+        let mut summary = Summary {
+            invalid: Some(InvalidRecords::open(&path, false).unwrap()),
+            ..Default::default()
+        };
+        let mut output: Vec<u8> = vec![];
+        process(
+            &config,
+            &instructions,
+            "-",
+            INPUT.as_bytes(),
+            &mut output,
+            &mut summary,
+        )
+        .unwrap();
+        let warnings = summary.warnings.keys().cloned().collect();
+        drop(summary);
+        let invalid = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (String::from_utf8(output).unwrap(), invalid, warnings)
+    }
+
+    #[test]
+    fn rejected() {
+        let (output, invalid, warnings) = run_invalid(
+            &["--schema", "id:int", "-s", "node", "--fields", "id,lat"],
+            "rejected",
+        );
+        assert_eq!(output, "1\t2\n5\t6\n");
+        assert_eq!(
+            invalid,
+            "-:1:28\t/osm/node\tValue \"x\" of the id column isn't a valid int\t<node id=\"x\" lat=\"3\">\n-:1:50\t/osm/node\tNo attribute lat found for element node\t<node id=\"4\">\n"
+        );
+        assert_eq!(warnings, ["Rejected records were written to --invalid-to"]);
+    }
+
+    #[test]
+    fn not_rejected() {
+        // Output as empty, so not rejected
+        let (output, invalid, _) = run_invalid(
+            &[
+                "--schema",
+                "id:int",
+                "--on-invalid",
+                "null",
+                "--on-missing-attr",
+                "empty",
+                "-s",
+                "node",
+                "--fields",
+                "id,lat",
+            ],
+            "not_rejected",
+        );
+        assert_eq!(output, "1\t2\n\t3\n4\t\n5\t6\n");
+        assert_eq!(invalid, "");
+    }
+
+    #[test]
+    fn threads() {
+        assert!(run("--invalid-to bad.tsv --threads 2 -s node -v id", INPUT).is_err());
+    }
+}
+
+mod log_level {
+    use super::*;
+
+    fn log_level(args: &[&str]) -> Result<Option<tracing::level_filters::LevelFilter>> {
+        Ok(parse_to_instructions(args)?.0.log_level)
+    }
+
+    #[test]
+    fn parsed() {
+        assert_eq!(log_level(&["-s", "a"]).unwrap(), None);
+        assert_eq!(
+            log_level(&["-q", "-s", "a"]).unwrap(),
+            Some(tracing::level_filters::LevelFilter::ERROR)
+        );
+        assert_eq!(
+            log_level(&["--log-level", "debug", "-s", "a"]).unwrap(),
+            Some(tracing::level_filters::LevelFilter::DEBUG)
+        );
+        assert!(log_level(&["-q", "--log-level", "warn", "-s", "a"]).is_err());
+    }
+
+    #[test]
+    fn json() {
+        let log_json = |args: &[&str]| parse_to_instructions(args).map(|(c, _)| c.log_json);
+        assert!(!log_json(&["-s", "a"]).unwrap());
+        assert!(!log_json(&["--log-format", "text", "-s", "a"]).unwrap());
+        assert!(log_json(&["--log-format", "json", "-s", "a"]).unwrap());
+    }
+}
+
+mod json_record {
+    use super::*;
+
+    const INPUT: &str =
+        r#"<osm><way id="7"><tag k="name" v="A &quot;B&quot;"/><tag k="x" n="3"/></way></osm>"#;
+
+    assert_run!(
+        attributes,
+        "--json-record -s tag -v k -V v - -v ../id",
+        INPUT,
+        "{\"k\":\"name\",\"v\":\"A \\\"B\\\"\",\"parent\":{\"id\":\"7\"}}\n{\"k\":\"x\",\"v\":\"-\",\"parent\":{\"id\":\"7\"}}\n"
+    );
+    assert_run!(
+        named,
+        "--json-record -s tag -v k --name key --counter --name n -o osm --name source --tab",
+        INPUT,
+        "{\"key\":\"name\",\"n\":1,\"source\":\"osm\"}\n{\"key\":\"x\",\"n\":2,\"source\":\"osm\"}\n"
+    );
+    assert_run!(
+        typed,
+        "--json-record --on-missing-attr empty --schema id:int,n:float -s tag -v n -v ../id",
+        INPUT,
+        "{\"n\":null,\"parent\":{\"id\":7}}\n{\"n\":3,\"parent\":{\"id\":7}}\n"
+    );
+    assert_run!(
+        fields,
+        "--json-record -s way --fields id",
+        INPUT,
+        "{\"id\":\"7\"}\n"
+    );
+
+    #[test]
+    fn invalid() {
+        assert!(run("-s tag -v k --name key", INPUT).is_err());
+        assert!(run("--json-record -s tag --name key", INPUT).is_err());
+        assert!(run("--json-record -S -o x", INPUT).is_err());
+        assert!(run("--json-record -s tag --group-by k", INPUT).is_err());
+    }
+}
+
+mod kv {
+    use super::*;
+
+    const INPUT: &str =
+        r#"<osm><way id="7"><tag k="name" v="High St"/><tag k="it&apos;s" v=""/></way></osm>"#;
+
+    assert_run!(
+        quoted,
+        "--output-format kv -s tag -v k -v v -v ../id",
+        INPUT,
+        "k=name v='High St' parent.id=7\nk='it'\\''s' v='' parent.id=7\n"
+    );
+    assert_run!(
+        named,
+        "--output-format kv -s tag --counter --name n -v k --name the_key",
+        INPUT,
+        "n=1 the_key=name\nn=2 the_key='it'\\''s'\n"
+    );
+    assert_run!(
+        missing,
+        "--output-format kv --on-missing-attr empty -s way -v id -v name --name the/name",
+        INPUT,
+        "id=7 the_name=''\n"
+    );
+
+    #[test]
+    fn invalid() {
+        assert!(run("--output-format kv -S -o x", INPUT).is_err());
+        assert!(run("--output-format kv --json-record -s way -v id", INPUT).is_err());
+    }
+}
+
+mod logfmt {
+    use super::*;
+
+    const INPUT: &str = r#"<osm><way id="7"><tag k="name" v="High &quot;St&quot;"/><tag k="x=y" v="a&#10;b"/></way></osm>"#;
+
+    assert_run!(
+        quoted,
+        "--output-format logfmt -s tag -v k -v v -v ../id",
+        INPUT,
+        "k=name v=\"High \\\"St\\\"\" parent.id=7\nk=\"x=y\" v=\"a\\nb\" parent.id=7\n"
+    );
+    assert_run!(
+        missing,
+        "--output-format logfmt --on-missing-attr empty -s way -v id -v name -V ref  -o debug --name level",
+        INPUT,
+        "id=7 name= ref=\"\" level=debug\n"
+    );
+}
+
+mod tee {
+    use super::*;
+
+    #[test]
+    fn writes_to_all() {
+        let path = std::env::temp_dir().join(format!("anglosaxon-{}.tee", std::process::id()));
+        let mut stdout = vec![];
+        {
+            let mut tee = Tee {
+                inner: &mut stdout,
+                files: vec![std::io::BufWriter::new(
+                    std::fs::File::create(&path).unwrap(),
+                )],
+            };
+            tee.write_all(b"a\n").unwrap();
+            tee.write_all(b"b\n").unwrap();
+            tee.flush().unwrap();
+        }
+        let file = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(String::from_utf8(stdout).unwrap(), "a\nb\n");
+        assert_eq!(file, "a\nb\n");
+    }
+
+    #[test]
+    fn not_with_checkpoint() {
+        let args = vec![
+            "anglosaxon",
+            "--checkpoint",
+            "x",
+            "--tee",
+            "y",
+            "-s",
+            "a",
+            "-o",
+            "a",
+        ];
+        assert!(parse_to_instructions(args.as_slice()).is_err());
+    }
+}
+
+mod kafka {
+    use super::*;
+
+    #[test]
+    fn needs_topic() {
+        let args = vec![
+            "anglosaxon",
+            "--kafka",
+            "localhost:9092",
+            "-s",
+            "a",
+            "-o",
+            "a",
+        ];
+        assert!(parse_to_instructions(args.as_slice()).is_err());
+    }
+
+    #[test]
+    fn topic_needs_kafka() {
+        let args = vec!["anglosaxon", "--topic", "t", "-s", "a", "-o", "a"];
+        assert!(parse_to_instructions(args.as_slice()).is_err());
+    }
+
+    #[cfg(feature = "kafka")]
+    #[test]
+    fn key() {
+        let args = vec![
+            "anglosaxon",
+            "--kafka",
+            "localhost:9092",
+            "--topic",
+            "t",
+            "--schema",
+            "id:int,name",
+            "--kafka-key",
+            "name",
+            "-s",
+            "a",
+            "-o",
+            "a",
+        ];
+        let (config, _) = parse_to_instructions(args.as_slice()).unwrap();
+        assert_eq!(config.kafka.unwrap().key_field, Some(1));
+        let args = vec![
+            "anglosaxon",
+            "--kafka",
+            "localhost:9092",
+            "--topic",
+            "t",
+            "--kafka-key",
+            "0",
+            "-s",
+            "a",
+            "-o",
+            "a",
+        ];
+        assert!(parse_to_instructions(args.as_slice()).is_err());
+    }
+
+    #[cfg(feature = "kafka")]
+    #[test]
+    fn fields() {
+        assert_eq!(nth_field(b"1\tx\t", b"\t", 0), b"1");
+        assert_eq!(nth_field(b"1\tx\t", b"\t", 1), b"x");
+        assert_eq!(nth_field(b"1\tx\t", b"\t", 2), b"");
+        assert_eq!(nth_field(b"1\tx\t", b"\t", 3), b"");
+        assert_eq!(nth_field(b"1::x", b"::", 1), b"x");
+    }
+}
+
+mod postgres {
+    use super::*;
+
+    #[test]
+    fn needs_table() {
+        let args = vec![
+            "anglosaxon",
+            "--pg",
+            "postgres://localhost/db",
+            "-s",
+            "a",
+            "-o",
+            "a",
+        ];
+        assert!(parse_to_instructions(args.as_slice()).is_err());
+    }
+
+    #[test]
+    fn table_needs_pg() {
+        let args = vec!["anglosaxon", "--table", "t", "-s", "a", "-o", "a"];
+        assert!(parse_to_instructions(args.as_slice()).is_err());
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn not_with_rs() {
+        let args = vec![
+            "anglosaxon",
+            "--pg",
+            "postgres://localhost/db",
+            "--table",
+            "t",
+            "--rs",
+            ";",
+            "-s",
+            "a",
+            "-o",
+            "a",
+        ];
+        assert!(parse_to_instructions(args.as_slice()).is_err());
+    }
+
+    #[test]
+    fn copy_statement() {
+        let options = PostgresOptions {
+            url: "postgres://localhost/db".to_string(),
+            table: "nodes(id,name)".to_string(),
+        };
+        assert_eq!(
+            options.copy_statement(None),
+            "COPY nodes(id,name) FROM STDIN"
+        );
+        assert_eq!(
+            options.copy_statement(Some("'")),
+            "COPY nodes(id,name) FROM STDIN WITH (DELIMITER '''')"
+        );
+    }
+}
+
+mod upload {
+    use super::*;
+
+    #[test]
+    fn url() {
+        let upload: UploadOptions = "s3://bucket/dir/nodes.tsv.zst".parse().unwrap();
+        assert_eq!(upload.bucket, "bucket");
+        assert_eq!(upload.key, "dir/nodes.tsv.zst");
+        assert!("s3://bucket".parse::<UploadOptions>().is_err());
+        assert!("s3://bucket/".parse::<UploadOptions>().is_err());
+        assert!("https://bucket/key".parse::<UploadOptions>().is_err());
+    }
+
+    #[test]
+    fn endpoint_needs_upload() {
+        let args = vec![
+            "anglosaxon",
+            "--upload-endpoint",
+            "http://localhost:9000",
+            "-s",
+            "a",
+            "-o",
+            "a",
+        ];
+        assert!(parse_to_instructions(args.as_slice()).is_err());
+    }
+}
+
+mod pipe {
+    use super::*;
+
+    #[test]
+    fn command() {
+        let args = vec!["anglosaxon", "--pipe", "sort -u", "-s", "a", "-o", "a"];
+        let (config, _) = parse_to_instructions(args.as_slice()).unwrap();
+        assert_eq!(config.pipe.as_deref(), Some("sort -u"));
+    }
+
+    #[test]
+    fn not_with_checkpoint() {
+        let args = vec![
+            "anglosaxon",
+            "--checkpoint",
+            "x",
+            "--pipe",
+            "sort",
+            "-s",
+            "a",
+            "-o",
+            "a",
+        ];
+        assert!(parse_to_instructions(args.as_slice()).is_err());
+    }
+}
+
+mod interner {
+    use super::*;
+
+    #[test]
+    fn shares() {
+        let mut interner = Interner::default();
+        let a = interner.intern("highway");
+        let b = interner.intern("highway");
+        assert!(Rc::ptr_eq(&a, &b));
+        assert_eq!(&*a, "highway");
+        assert!(!Rc::ptr_eq(&a, &interner.intern("name")));
+    }
+
+    #[test]
+    fn long() {
+        let mut interner = Interner::default();
+        let long = "x".repeat(INTERN_MAX_BYTES + 1);
+        assert_eq!(&*interner.intern(&long), long);
+        assert!(interner.strings.is_empty());
+    }
+
+    #[test]
+    fn full() {
+        let mut interner = Interner::default();
+        for i in 0..INTERN_MAX_STRINGS + 10 {
+            interner.intern(&i.to_string());
+        }
+        assert!(interner.strings.len() <= INTERN_MAX_STRINGS);
+    }
+}
+
+mod spares {
+    use super::*;
+
+    #[test]
+    fn reused() {
+        let mut spares = Spares::default();
+        let mut text = spares.text();
+        text.push_str("some text");
+        let capacity = text.capacity();
+        spares.reuse_text(text);
+        let text = spares.text();
+        assert_eq!(text, "");
+        assert_eq!(text.capacity(), capacity);
+
+        let mut values = spares.values();
+        values.push(Rc::from("a"));
+        spares.reuse_values(values);
+        assert!(spares.values().is_empty());
+    }
+
+    #[test]
+    fn too_big() {
+        let mut spares = Spares::default();
+        spares.reuse_text(String::with_capacity(SPARE_MAX_BYTES + 1));
+        assert!(spares.text.is_empty());
+        for _ in 0..SPARE_BUFFERS + 1 {
+            spares.reuse_text(String::with_capacity(1));
+        }
+        assert_eq!(spares.text.len(), SPARE_BUFFERS);
+    }
+}
+
+mod timing_detail {
+    use super::*;
+
+    #[test]
+    fn timed() {
+        let args = vec![
+            "--timing-detail",
+            "-s",
+            "a",
+            "-v",
+            "x!tsv",
+            "-s",
+            "b",
+            "-o",
+            "b",
+        ];
+        let (config, instructions) = parse_to_instructions(args.as_slice()).unwrap();
+        let mut output: Vec<u8> = vec![];
+        let mut summary = Summary::default();
+        process(
+            &config,
+            &instructions,
+            "-",
+            r#"<r><a x="1"/><a x="2"/></r>"#.as_bytes(),
+            &mut output,
+            &mut summary,
+        )
+        .unwrap();
+        assert_eq!(output, b"12");
+        let timing = summary.timing.unwrap();
+        assert!(timing.instructions.len() <= instructions.len());
+        // -s b never happened
+        assert!(timing.instructions.get(1).is_none_or(|t| t.is_zero()));
+        let report = timing.report(&instructions);
+        assert!(report.contains("reading & parsing the XML"));
+        assert!(!report.contains("-s b"));
+    }
+
+    #[test]
+    fn report() {
+        let (_, instructions) =
+            parse_to_instructions(&["-s", "a", "-o", "a", "-s", "b", "-o", "b"][..]).unwrap();
+        let timing = Timing {
+            parsing: Duration::from_millis(500),
+            instructions: vec![Duration::from_millis(300), Duration::ZERO],
+            filters: Duration::from_millis(100),
+            writing: Duration::from_millis(150),
+            other: Duration::from_millis(50),
+        };
+        let report = timing.report(&instructions);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].ends_with("50.0%  reading & parsing the XML"));
+        assert!(lines[1].ends_with("matching the instructions, & everything else"));
+        assert!(lines[2].ends_with("30.0%  running the instructions:"));
+        assert!(lines[3].contains("-s a") && lines[3].ends_with("(#1)"));
+        assert!(lines[4].ends_with("(of which the filters)"));
+        assert!(lines[5].ends_with("15.0%  writing the output"));
+        // -s b didn't take any time
+        assert!(!report.contains("-s b"));
+    }
+
+    #[test]
+    fn not_timed() {
+        let mut summary = Summary::default();
+        let (config, instructions) = parse_to_instructions(&["-s", "a", "-v", "x"][..]).unwrap();
+        process(
+            &config,
+            &instructions,
+            "-",
+            r#"<a x="1"/>"#.as_bytes(),
+            &mut vec![],
+            &mut summary,
+        )
+        .unwrap();
+        assert!(summary.timing.is_none());
+    }
+
+    #[test]
+    fn add() {
+        let mut timing = Timing {
+            instructions: vec![Duration::from_secs(1)],
+            ..Default::default()
+        };
+        timing.add(Timing {
+            parsing: Duration::from_secs(2),
+            instructions: vec![Duration::from_secs(1), Duration::from_secs(3)],
+            ..Default::default()
+        });
+        assert_eq!(timing.parsing, Duration::from_secs(2));
+        assert_eq!(
+            timing.instructions,
+            vec![Duration::from_secs(2), Duration::from_secs(3)]
+        );
+    }
+}
+
+mod namespaced_attr {
+    use super::*;
+
+    const INPUT: &str = r#"<svg xmlns:xlink="http://www.w3.org/1999/xlink"><a href="/plain" xlink:href="/linked"/></svg>"#;
+
+    assert_run!(plain, "-s a -v href --nl", INPUT, "/plain\n");
+    assert_run!(prefixed, "-s a -v xlink:href --nl", INPUT, "/linked\n");
+    assert_run!(
+        clark,
+        "-s a -v {http://www.w3.org/1999/xlink}href --nl",
+        INPUT,
+        "/linked\n"
+    );
+    assert_run!(
+        with_default,
+        "-s a -V other:href none --nl -V {urn:other}href none --nl",
+        INPUT,
+        "none\nnone\n"
+    );
+    assert_run!(
+        parent,
+        "-s b -v ../xlink:href --nl",
+        r#"<a xmlns:xlink="http://www.w3.org/1999/xlink" xlink:href="/linked"><b/></a>"#,
+        "/linked\n"
+    );
+
+    #[test]
+    fn missing() {
+        let err = run("-s a -v other:href --nl", INPUT).unwrap_err();
+        assert!(format!("{:#}", err).contains(r#"xlink:href="/linked""#));
+    }
+}
+
+mod duplicate_attr {
+    use super::*;
+
+    const INPUT: &str = r#"<a xmlns:p="urn:p" p:x="1" x="2"/>"#;
+
+    assert_run!(default_first, "-s a -v x --nl", INPUT, "1\n");
+    assert_run!(
+        first,
+        "--on-duplicate-attr first -s a -v x --nl",
+        INPUT,
+        "1\n"
+    );
+    assert_run!(
+        last,
+        "--on-duplicate-attr last -s a -v x --nl",
+        INPUT,
+        "2\n"
+    );
+    assert_run!(
+        join,
+        "--on-duplicate-attr join:; -s a -v x --nl",
+        INPUT,
+        "1;2\n"
+    );
+    assert_run!(
+        join_default,
+        "--on-duplicate-attr join:, -s a -V x none --nl -V y none --nl",
+        INPUT,
+        "1,2\nnone\n"
+    );
+    assert_run!(
+        no_duplicates,
+        "--on-duplicate-attr error -s a -v x --nl",
+        r#"<a xmlns:p="urn:p" p:y="1" x="2"/>"#,
+        "2\n"
+    );
+
+    #[test]
+    fn error() {
+        let err = run("--on-duplicate-attr error -s a -v x --nl", INPUT).unwrap_err();
+        assert!(format!("{:#}", err).contains("Attribute x appears more than once"));
+    }
+
+    #[test]
+    fn unknown_policy() {
+        assert!(run("--on-duplicate-attr middle -s a -v x --nl", INPUT).is_err());
+    }
+}
+
+mod errors {
+    use super::*;
+
+    #[test]
+    fn missing_attr() {
+        let err = run(
+            "-s nd -v ref",
+            "<osm>\n<way id=\"1\">\n <nd x=\"y\"/></way></osm>",
+        )
+        .unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.contains("-:3:2"), "{}", err);
+        assert!(err.contains("/osm/way/nd"), "{}", err);
+        assert!(err.contains(r#"x="y""#), "{}", err);
+    }
+
+    #[test]
+    fn parent_too_far() {
+        let err = run("-s nd -v ../../../ref", "<osm><nd/></osm>").unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.contains("only nested 1 deep"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_xml() {
+        let err = run("-s nd -v ref", "<osm><way></wy></osm>").unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.contains("/osm/way"), "{}", err);
+        assert!(err.contains("1:15"), "{}", err);
+    }
+
+    #[test]
+    fn snippet() {
+        assert_eq!(attributes_snippet(&[]), "(none)");
+        let attrs = vec![OwnedAttribute::new(
+            xml::name::OwnedName::local("k"),
+            "a".repeat(40),
+        )];
+        assert_eq!(
+            attributes_snippet(&attrs),
+            format!("k=\"{}\"…", "a".repeat(30))
+        );
+    }
+}
+
+mod recover {
+    use super::*;
+
+    #[test]
+    fn error_without() {
+        assert!(run(
+            "-s nd -v ref --nl",
+            r#"<way><nd ref="1"/><nd ref="&foo;"/><nd ref="3"/></way>"#
+        )
+        .is_err());
+    }
+
+    assert_run!(
+        bad_entity,
+        "--recover -s nd -v ref --nl -E -o end",
+        r#"<way><nd ref="1"/><nd ref="&foo;"/><nd ref="3"/></way>"#,
+        "1\n3\nend"
+    );
+    assert_run!(
+        stray_ampersand,
+        "--recover -s nd -v ../id -o , -v ref --nl",
+        r#"<osm><way id="1"><nd ref="1"/>fish & chips<nd ref="3"/></way><way id="2"><nd ref="4"/></way></osm>"#,
+        "1,1\n1,3\n2,4\n"
+    );
+    assert_run!(
+        mismatched_tag,
+        "--recover -s nd -v ref --nl -E -o end",
+        r#"<osm><way><nd ref="1"/></wy><nd ref="2"/></osm>"#,
+        "1\n2\nend"
+    );
+    assert_run!(
+        truncated,
+        "--recover -s nd -v ref --nl -E -o end",
+        r#"<osm><way><nd ref="1"/><nd ref="2"/><n"#,
+        "1\n2\nend"
+    );
+}
+
+mod max_nesting {
+    use super::*;
+
+    const INPUT: &str = r#"<a id="1"><a id="2"><a id="3"><a id="4"/></a></a><a id="5"/></a>"#;
+
+    assert_run!(unlimited, "-s a -v id", INPUT, "12345");
+    assert_run!(deep_enough, "--max-nesting 4 -s a -v id", INPUT, "12345");
+    assert_run!(
+        prune,
+        "--max-nesting 2 --prune-nesting -s a -v id -e a -o .",
+        INPUT,
+        "12.5.."
+    );
+
+    #[test]
+    fn too_deep() {
+        let err = run("--max-nesting 3 -s a -v id", INPUT).unwrap_err();
+        assert!(err.to_string().contains("/a/a/a/a"), "{}", err);
+    }
+}
+
+mod leading_junk {
+    use super::*;
+
+    assert_run!(bom, "-s a -v x", "\u{FEFF}<a x=\"1\"/>", "1");
+    assert_run!(
+        bom_and_decl,
+        "-s a -v x",
+        "\u{FEFF}<?xml version=\"1.0\"?><a x=\"1\"/>",
+        "1"
+    );
+    assert_run!(
+        junk,
+        "--skip-leading-junk -s a -v x -o , --offset",
+        "INFO: dumped\n<a x=\"1\"/>",
+        "1,13"
+    );
+    assert_run!(
+        bom_and_junk,
+        "--skip-leading-junk -s a -v x -o , --offset",
+        "\u{FEFF}\n\n<?xml version=\"1.0\"?>\n<a x=\"1\"/>",
+        "1,27"
+    );
+
+    #[test]
+    fn junk_without_option() {
+        assert!(run("-s a -v x", "INFO: dumped\n<a x=\"1\"/>").is_err());
+    }
+
+    #[test]
+    fn validate_bom() {
+        assert!(validate(&mut Expectations::default(), "\u{FEFF}<a/>".as_bytes()).is_ok());
+    }
 }